@@ -0,0 +1,272 @@
+//! Encodes JSON args of dex methods to borsh, following the
+//! schemas that the engine returns from `dex_abi`.
+
+use std::str::FromStr;
+
+use intear_dex_types::{AssetId, DexAbiFields, DexAbiMethod, DexAbiType, DexId};
+use near_sdk::{
+    AccountId, PublicKey,
+    borsh::BorshSerialize,
+    json_types::Base64VecU8,
+    serde_json::{self, Map, Value},
+};
+
+use crate::ClientError;
+
+/// Finds `method` in the ABI of a dex.
+pub fn find_method<'a>(
+    abi: &'a [DexAbiMethod],
+    method: &str,
+) -> Result<&'a DexAbiMethod, ClientError> {
+    abi.iter()
+        .find(|abi_method| abi_method.name == method)
+        .ok_or_else(|| ClientError::Abi(format!("Method {method} is not in the ABI")))
+}
+
+/// Encodes `args`, an object with the parameters of `method`,
+/// to its borsh `args`. Missing parameters are only allowed
+/// for `Option`s, and are encoded as `None`.
+pub fn encode_args(method: &DexAbiMethod, args: &Value) -> Result<Vec<u8>, ClientError> {
+    let empty = Map::new();
+    let fields = match args {
+        Value::Object(fields) => fields,
+        Value::Null => &empty,
+        _ => return Err(error(&method.name, "expected an object")),
+    };
+    let mut out = Vec::new();
+    encode_named(&method.name, &method.args, fields, &mut out)?;
+    Ok(out)
+}
+
+/// Encodes a JSON value of type `ty` to borsh. The JSON forms
+/// are the same as in the engine: integers can be strings,
+/// assets and dex IDs are strings, and so on.
+pub fn encode_value(ty: &DexAbiType, value: &Value) -> Result<Vec<u8>, ClientError> {
+    let mut out = Vec::new();
+    encode(ty, value, "args", &mut out)?;
+    Ok(out)
+}
+
+fn error(path: &str, message: impl std::fmt::Display) -> ClientError {
+    ClientError::Abi(format!("{path}: {message}"))
+}
+
+fn write(value: &impl BorshSerialize, out: &mut Vec<u8>) {
+    value.serialize(out).expect("Writing to a Vec doesn't fail");
+}
+
+fn write_len(len: usize, path: &str, out: &mut Vec<u8>) -> Result<(), ClientError> {
+    let len = u32::try_from(len).map_err(|_| error(path, "too many elements"))?;
+    write(&len, out);
+    Ok(())
+}
+
+fn parse_str<'a>(value: &'a Value, path: &str) -> Result<&'a str, ClientError> {
+    value
+        .as_str()
+        .ok_or_else(|| error(path, "expected a string"))
+}
+
+/// Integers are accepted both as numbers and as strings, since
+/// `U128` and `U64` are strings in JSON.
+fn parse_integer<T: FromStr>(value: &Value, path: &str) -> Result<T, ClientError> {
+    let text = match value {
+        Value::Number(number) => number.to_string(),
+        Value::String(text) => text.clone(),
+        _ => return Err(error(path, "expected an integer")),
+    };
+    text.parse()
+        .map_err(|_| error(path, format!("invalid integer {text}")))
+}
+
+fn parse_array<'a>(value: &'a Value, path: &str) -> Result<&'a Vec<Value>, ClientError> {
+    value
+        .as_array()
+        .ok_or_else(|| error(path, "expected an array"))
+}
+
+fn encode(
+    ty: &DexAbiType,
+    value: &Value,
+    path: &str,
+    out: &mut Vec<u8>,
+) -> Result<(), ClientError> {
+    match ty {
+        DexAbiType::Bool => {
+            let value = value
+                .as_bool()
+                .ok_or_else(|| error(path, "expected a boolean"))?;
+            write(&value, out);
+        }
+        DexAbiType::U8 => write(&parse_integer::<u8>(value, path)?, out),
+        DexAbiType::U16 => write(&parse_integer::<u16>(value, path)?, out),
+        DexAbiType::U32 => write(&parse_integer::<u32>(value, path)?, out),
+        DexAbiType::U64 => write(&parse_integer::<u64>(value, path)?, out),
+        DexAbiType::U128 => write(&parse_integer::<u128>(value, path)?, out),
+        DexAbiType::I8 => write(&parse_integer::<i8>(value, path)?, out),
+        DexAbiType::I16 => write(&parse_integer::<i16>(value, path)?, out),
+        DexAbiType::I32 => write(&parse_integer::<i32>(value, path)?, out),
+        DexAbiType::I64 => write(&parse_integer::<i64>(value, path)?, out),
+        DexAbiType::I128 => write(&parse_integer::<i128>(value, path)?, out),
+        DexAbiType::String => write(&parse_str(value, path)?, out),
+        DexAbiType::AccountId => {
+            let account_id =
+                AccountId::from_str(parse_str(value, path)?).map_err(|err| error(path, err))?;
+            write(&account_id, out);
+        }
+        DexAbiType::AssetId => {
+            let asset_id =
+                AssetId::from_str(parse_str(value, path)?).map_err(|err| error(path, err))?;
+            write(&asset_id, out);
+        }
+        DexAbiType::DexId => {
+            let dex_id =
+                DexId::from_str(parse_str(value, path)?).map_err(|err| error(path, err))?;
+            write(&dex_id, out);
+        }
+        DexAbiType::PublicKey => {
+            let key =
+                PublicKey::from_str(parse_str(value, path)?).map_err(|err| error(path, err))?;
+            write(&key, out);
+        }
+        DexAbiType::Base64 => {
+            let Base64VecU8(bytes) =
+                serde_json::from_value(value.clone()).map_err(|err| error(path, err))?;
+            write(&bytes, out);
+        }
+        DexAbiType::Vec(element) => {
+            let elements = parse_array(value, path)?;
+            write_len(elements.len(), path, out)?;
+            for (index, element_value) in elements.iter().enumerate() {
+                encode(element, element_value, &format!("{path}[{index}]"), out)?;
+            }
+        }
+        DexAbiType::Option(element) => {
+            if value.is_null() {
+                write(&0u8, out);
+            } else {
+                write(&1u8, out);
+                encode(element, value, path, out)?;
+            }
+        }
+        DexAbiType::Map(key, map_value) => match value {
+            Value::Object(entries) => {
+                write_len(entries.len(), path, out)?;
+                for (entry_key, entry_value) in entries {
+                    let entry_path = format!("{path}.{entry_key}");
+                    encode(key, &Value::String(entry_key.clone()), &entry_path, out)?;
+                    encode(map_value, entry_value, &entry_path, out)?;
+                }
+            }
+            _ => {
+                let entries = parse_array(value, path)?;
+                write_len(entries.len(), path, out)?;
+                for (index, entry) in entries.iter().enumerate() {
+                    let entry_path = format!("{path}[{index}]");
+                    let [entry_key, entry_value] = parse_array(entry, &entry_path)?.as_slice()
+                    else {
+                        return Err(error(&entry_path, "expected a [key, value] pair"));
+                    };
+                    encode(key, entry_key, &entry_path, out)?;
+                    encode(map_value, entry_value, &entry_path, out)?;
+                }
+            }
+        },
+        DexAbiType::Tuple(elements) => encode_unnamed(elements, value, path, out)?,
+        DexAbiType::Struct { fields, .. } => encode_fields(fields, value, path, out)?,
+        DexAbiType::Enum { variants, .. } => {
+            // Unit variants are strings, and the others are
+            // objects with the variant as the only key
+            let (variant_name, variant_value) = match value {
+                Value::String(variant_name) => (variant_name.as_str(), &Value::Null),
+                Value::Object(object) if object.len() == 1 => {
+                    let (variant_name, variant_value) = object.iter().next().unwrap();
+                    (variant_name.as_str(), variant_value)
+                }
+                _ => return Err(error(path, "expected an enum variant")),
+            };
+            let Some(index) = variants.iter().position(|(name, _)| name == variant_name) else {
+                return Err(error(path, format!("unknown variant {variant_name}")));
+            };
+            let index = u8::try_from(index).map_err(|_| error(path, "too many variants"))?;
+            write(&index, out);
+            let (_, fields) = &variants[usize::from(index)];
+            encode_fields(
+                fields,
+                variant_value,
+                &format!("{path}.{variant_name}"),
+                out,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Fields of a struct or an enum variant, in the same JSON form
+/// as serde: an object, a single value for a newtype, an array
+/// for a tuple, or nothing for a unit.
+fn encode_fields(
+    fields: &DexAbiFields,
+    value: &Value,
+    path: &str,
+    out: &mut Vec<u8>,
+) -> Result<(), ClientError> {
+    match fields {
+        DexAbiFields::Named(fields) => {
+            let object = value
+                .as_object()
+                .ok_or_else(|| error(path, "expected an object"))?;
+            encode_named(path, fields, object, out)
+        }
+        DexAbiFields::Unnamed(elements) if elements.len() == 1 => {
+            encode(&elements[0], value, path, out)
+        }
+        DexAbiFields::Unnamed(elements) => encode_unnamed(elements, value, path, out),
+        DexAbiFields::Unit => Ok(()),
+    }
+}
+
+fn encode_named(
+    path: &str,
+    fields: &[(String, DexAbiType)],
+    object: &Map<String, Value>,
+    out: &mut Vec<u8>,
+) -> Result<(), ClientError> {
+    if let Some(unknown) = object
+        .keys()
+        .find(|key| !fields.iter().any(|(name, _)| name == *key))
+    {
+        return Err(error(path, format!("unknown field {unknown}")));
+    }
+    for (name, ty) in fields {
+        let field_path = format!("{path}.{name}");
+        match (object.get(name), ty) {
+            (Some(value), _) => encode(ty, value, &field_path, out)?,
+            (None, DexAbiType::Option(_)) => write(&0u8, out),
+            (None, _) => return Err(error(&field_path, "missing field")),
+        }
+    }
+    Ok(())
+}
+
+fn encode_unnamed(
+    elements: &[DexAbiType],
+    value: &Value,
+    path: &str,
+    out: &mut Vec<u8>,
+) -> Result<(), ClientError> {
+    if elements.is_empty() && value.is_null() {
+        return Ok(());
+    }
+    let values = parse_array(value, path)?;
+    if values.len() != elements.len() {
+        return Err(error(
+            path,
+            format!("expected {} elements, got {}", elements.len(), values.len()),
+        ));
+    }
+    for (index, (element, element_value)) in elements.iter().zip(values).enumerate() {
+        encode(element, element_value, &format!("{path}[{index}]"), out)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,437 @@
+use std::collections::HashMap;
+
+use intear_dex_types::{
+    AccountOrDexId, AssetId, DexAbiMethod, DexId, DexMetadata, DexMethod, DexPair, Operation,
+    Referral, SwapOperationAmount, SwapRequest, SwapRequestAmount, SwapResponse,
+};
+use near_contract_standards::storage_management::StorageBalance;
+use near_sdk::{
+    AccountId, NearToken,
+    borsh::{BorshDeserialize, BorshSerialize},
+    json_types::{Base64VecU8, U128},
+    serde::Serialize,
+    serde_json::{self, Value, json},
+};
+
+use crate::{
+    ClientError, DEFAULT_GAS, FunctionCall, ViewCall, abi, decode_dex_response, decode_json,
+};
+
+/// Builds calls to a dex engine contract.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub struct DexEngineClient {
+    pub contract_id: AccountId,
+}
+
+impl DexEngineClient {
+    pub fn new(contract_id: AccountId) -> Self {
+        Self { contract_id }
+    }
+
+    fn call(&self, method_name: &str, args: impl Serialize, deposit: NearToken) -> FunctionCall {
+        FunctionCall {
+            receiver_id: self.contract_id.clone(),
+            method_name: method_name.to_string(),
+            args: serde_json::to_vec(&args).expect("Failed to serialize args"),
+            deposit,
+            gas: DEFAULT_GAS,
+        }
+    }
+
+    fn view<T>(
+        &self,
+        method_name: &str,
+        args: impl Serialize,
+        decode: fn(&[u8]) -> Result<T, ClientError>,
+    ) -> ViewCall<T> {
+        ViewCall {
+            receiver_id: self.contract_id.clone(),
+            method_name: method_name.to_string(),
+            args: serde_json::to_vec(&args).expect("Failed to serialize args"),
+            decode,
+        }
+    }
+
+    pub fn swap_simple(
+        &self,
+        dex_id: DexId,
+        asset_in: AssetId,
+        asset_out: AssetId,
+        amount: SwapRequestAmount,
+    ) -> SwapSimple {
+        SwapSimple {
+            engine: self.clone(),
+            dex_id,
+            message: Vec::new(),
+            asset_in,
+            asset_out,
+            amount,
+            referral: None,
+            max_storage_payment: NearToken::from_yoctonear(0),
+        }
+    }
+
+    pub fn dex_call(&self, dex_id: DexId, method: impl Into<String>) -> DexCall {
+        DexCall {
+            engine: self.clone(),
+            dex_id,
+            method: method.into(),
+            args: Vec::new(),
+            attached_assets: HashMap::new(),
+            max_storage_payment: NearToken::from_yoctonear(0),
+        }
+    }
+
+    pub fn execute_operations(&self, operations: Vec<Operation>) -> FunctionCall {
+        self.call(
+            "execute_operations",
+            json!({ "operations": operations }),
+            NearToken::from_yoctonear(1),
+        )
+    }
+
+    /// Deposits NEAR to the inner balance, or executes
+    /// `operations` with it as sandboxed assets.
+    pub fn deposit_near(
+        &self,
+        amount: NearToken,
+        operations: Option<Vec<Operation>>,
+    ) -> FunctionCall {
+        self.call("deposit_near", json!({ "operations": operations }), amount)
+    }
+
+    /// Deposits a NEP-141 token with `ft_transfer_call`, or
+    /// executes `operations` with it as sandboxed assets.
+    pub fn ft_deposit(
+        &self,
+        token_id: AccountId,
+        amount: U128,
+        operations: Option<Vec<Operation>>,
+    ) -> FunctionCall {
+        let msg = match operations {
+            Some(operations) => {
+                serde_json::to_string(&operations).expect("Failed to serialize operations")
+            }
+            None => String::new(),
+        };
+        FunctionCall {
+            receiver_id: token_id,
+            method_name: "ft_transfer_call".to_string(),
+            args: serde_json::to_vec(&json!({
+                "receiver_id": self.contract_id,
+                "amount": amount,
+                "msg": msg,
+            }))
+            .expect("Failed to serialize args"),
+            deposit: NearToken::from_yoctonear(1),
+            gas: DEFAULT_GAS,
+        }
+    }
+
+    pub fn withdraw(
+        &self,
+        asset_id: AssetId,
+        amount: Option<U128>,
+        withdraw_to: Option<AccountId>,
+    ) -> FunctionCall {
+        self.call(
+            "withdraw",
+            json!({
+                "asset_id": asset_id,
+                "amount": amount,
+                "withdraw_to": withdraw_to,
+            }),
+            NearToken::from_yoctonear(1),
+        )
+    }
+
+    pub fn transfer_asset(
+        &self,
+        to: AccountOrDexId,
+        asset_id: AssetId,
+        amount: U128,
+    ) -> FunctionCall {
+        self.call(
+            "transfer_asset",
+            json!({ "to": to, "asset_id": asset_id, "amount": amount }),
+            NearToken::from_yoctonear(1),
+        )
+    }
+
+    pub fn storage_deposit(
+        &self,
+        account_id: Option<AccountId>,
+        amount: NearToken,
+    ) -> FunctionCall {
+        self.call(
+            "storage_deposit",
+            json!({ "account_id": account_id }),
+            amount,
+        )
+    }
+
+    pub fn dex_storage_deposit(&self, dex_id: DexId, amount: NearToken) -> FunctionCall {
+        self.call("dex_storage_deposit", json!({ "dex_id": dex_id }), amount)
+    }
+
+    pub fn register_assets(
+        &self,
+        asset_ids: Vec<AssetId>,
+        r#for: Option<AccountOrDexId>,
+    ) -> FunctionCall {
+        self.call(
+            "register_assets",
+            json!({ "asset_ids": asset_ids, "for": r#for }),
+            NearToken::from_yoctonear(1),
+        )
+    }
+
+    pub fn deploy_dex_code(
+        &self,
+        last_part_of_id: impl Into<String>,
+        code: Vec<u8>,
+    ) -> FunctionCall {
+        self.call(
+            "deploy_dex_code",
+            json!({
+                "last_part_of_id": last_part_of_id.into(),
+                "code_base64": Base64VecU8(code),
+            }),
+            NearToken::from_yoctonear(1),
+        )
+    }
+
+    pub fn asset_balance_of(
+        &self,
+        of: AccountOrDexId,
+        asset_id: AssetId,
+    ) -> ViewCall<Option<U128>> {
+        self.view(
+            "asset_balance_of",
+            json!({ "of": of, "asset_id": asset_id }),
+            decode_json,
+        )
+    }
+
+    pub fn registered_assets_of(&self, account_id: AccountId) -> ViewCall<Vec<AssetId>> {
+        self.view(
+            "registered_assets_of",
+            json!({ "account_id": account_id }),
+            decode_json,
+        )
+    }
+
+    pub fn total_in_custody(&self, asset_id: AssetId) -> ViewCall<Option<U128>> {
+        self.view(
+            "total_in_custody",
+            json!({ "asset_id": asset_id }),
+            decode_json,
+        )
+    }
+
+    pub fn storage_balance_of(&self, account_id: AccountId) -> ViewCall<Option<StorageBalance>> {
+        self.view(
+            "storage_balance_of",
+            json!({ "account_id": account_id }),
+            decode_json,
+        )
+    }
+
+    pub fn dex_storage_balance_of(&self, dex_id: DexId) -> ViewCall<Option<StorageBalance>> {
+        self.view(
+            "dex_storage_balance_of",
+            json!({ "dex_id": dex_id }),
+            decode_json,
+        )
+    }
+
+    /// Calls a view method of a dex with borsh `args`, and
+    /// decodes its borsh result as `T`.
+    pub fn dex_view<T: BorshDeserialize>(
+        &self,
+        dex_id: DexId,
+        method: impl Into<String>,
+        args: &impl BorshSerialize,
+    ) -> ViewCall<T> {
+        self.view(
+            "dex_view",
+            json!({
+                "dex_id": dex_id,
+                "method": method.into(),
+                "args": Base64VecU8(near_sdk::borsh::to_vec(args).expect("Failed to serialize args")),
+            }),
+            decode_dex_response::<T>,
+        )
+    }
+
+    pub fn dex_methods(&self, dex_id: DexId) -> ViewCall<Vec<DexMethod>> {
+        self.view("dex_methods", json!({ "dex_id": dex_id }), decode_json)
+    }
+
+    pub fn dex_abi(&self, dex_id: DexId) -> ViewCall<Vec<DexAbiMethod>> {
+        self.view("dex_abi", json!({ "dex_id": dex_id }), decode_json)
+    }
+
+    pub fn dex_quote(&self, dex_id: DexId, request: SwapRequest) -> ViewCall<SwapResponse> {
+        self.view(
+            "dex_quote",
+            json!({ "dex_id": dex_id, "request": request }),
+            decode_json,
+        )
+    }
+
+    pub fn dex_metadata(&self, dex_id: DexId) -> ViewCall<DexMetadata> {
+        self.view("dex_metadata", json!({ "dex_id": dex_id }), decode_json)
+    }
+
+    pub fn dex_pairs(
+        &self,
+        dex_id: DexId,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> ViewCall<Vec<DexPair>> {
+        self.view(
+            "dex_pairs",
+            json!({ "dex_id": dex_id, "from_index": from_index, "limit": limit }),
+            decode_json,
+        )
+    }
+}
+
+/// Builder of a swap on a single dex.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub struct SwapSimple {
+    engine: DexEngineClient,
+    dex_id: DexId,
+    message: Vec<u8>,
+    asset_in: AssetId,
+    asset_out: AssetId,
+    amount: SwapRequestAmount,
+    referral: Option<Referral>,
+    max_storage_payment: NearToken,
+}
+
+impl SwapSimple {
+    /// Dex-specific message, such as the pool to swap in.
+    pub fn message(mut self, message: Vec<u8>) -> Self {
+        self.message = message;
+        self
+    }
+
+    pub fn referral(mut self, referral: Referral) -> Self {
+        self.referral = Some(referral);
+        self
+    }
+
+    /// The most that the dex may charge the caller for the
+    /// storage it creates. 0 by default.
+    pub fn max_storage_payment(mut self, max_storage_payment: NearToken) -> Self {
+        self.max_storage_payment = max_storage_payment;
+        self
+    }
+
+    pub fn build(self) -> FunctionCall {
+        let engine = self.engine.clone();
+        engine.call(
+            "swap_simple",
+            json!({
+                "dex_id": self.dex_id,
+                "message": Base64VecU8(self.message),
+                "asset_in": self.asset_in,
+                "asset_out": self.asset_out,
+                "amount": self.amount,
+                "referral": self.referral,
+                "max_storage_payment": self.max_storage_payment,
+            }),
+            NearToken::from_yoctonear(1),
+        )
+    }
+
+    pub fn into_operation(self) -> Operation {
+        Operation::SwapSimple {
+            dex_id: self.dex_id,
+            message: Base64VecU8(self.message),
+            asset_in: self.asset_in,
+            asset_out: self.asset_out,
+            amount: SwapOperationAmount::Amount(self.amount),
+            referral: self.referral,
+            max_storage_payment: self.max_storage_payment,
+        }
+    }
+}
+
+/// Builder of a call to a dex method.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub struct DexCall {
+    engine: DexEngineClient,
+    dex_id: DexId,
+    method: String,
+    args: Vec<u8>,
+    attached_assets: HashMap<AssetId, U128>,
+    max_storage_payment: NearToken,
+}
+
+impl DexCall {
+    /// Raw args, passed to the dex as is.
+    pub fn args(mut self, args: Vec<u8>) -> Self {
+        self.args = args;
+        self
+    }
+
+    pub fn borsh_args(self, args: &impl BorshSerialize) -> Self {
+        self.args(near_sdk::borsh::to_vec(args).expect("Failed to serialize args"))
+    }
+
+    /// Encodes JSON args with the schema of the method in `abi`,
+    /// as returned by [`DexEngineClient::dex_abi`].
+    pub fn json_args(self, abi: &[DexAbiMethod], args: &Value) -> Result<Self, ClientError> {
+        let args = abi::encode_args(abi::find_method(abi, &self.method)?, args)?;
+        Ok(self.args(args))
+    }
+
+    /// Attaches assets from the inner balance of the caller.
+    /// Attaching the same asset twice adds up the amounts.
+    pub fn attach(mut self, asset_id: AssetId, amount: U128) -> Self {
+        let attached = self.attached_assets.entry(asset_id).or_default();
+        attached.0 = attached
+            .0
+            .checked_add(amount.0)
+            .expect("Attached amount overflow");
+        self
+    }
+
+    /// The most that the dex may charge the caller for the
+    /// storage it creates. 0 by default.
+    pub fn max_storage_payment(mut self, max_storage_payment: NearToken) -> Self {
+        self.max_storage_payment = max_storage_payment;
+        self
+    }
+
+    pub fn build(self) -> FunctionCall {
+        let engine = self.engine.clone();
+        engine.call(
+            "dex_call",
+            json!({
+                "dex_id": self.dex_id,
+                "method": self.method,
+                "args": Base64VecU8(self.args),
+                "attached_assets": self.attached_assets,
+                "max_storage_payment": self.max_storage_payment,
+            }),
+            NearToken::from_yoctonear(1),
+        )
+    }
+
+    pub fn into_operation(self) -> Operation {
+        Operation::DexCall {
+            dex_id: self.dex_id,
+            method: self.method,
+            args: Base64VecU8(self.args),
+            attached_assets: self.attached_assets,
+            max_storage_payment: self.max_storage_payment,
+        }
+    }
+}
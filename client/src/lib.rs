@@ -0,0 +1,88 @@
+//! Typed builders for calls to the dex engine and the dexes
+//! bundled with it. Calls are built as [`FunctionCall`] and
+//! [`ViewCall`] values, so they can be sent with any RPC client.
+
+use std::fmt;
+
+use near_sdk::{AccountId, Gas, NearToken, borsh::BorshDeserialize, json_types::Base64VecU8};
+
+pub mod abi;
+pub mod engine;
+pub mod otc;
+pub mod simple_amm;
+
+pub use engine::{DexCall, DexEngineClient, SwapSimple};
+pub use otc::Otc;
+pub use simple_amm::SimpleAmm;
+
+/// Gas attached to calls by default.
+pub const DEFAULT_GAS: Gas = Gas::from_tgas(300);
+
+/// A function call action, ready to be signed and sent.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub struct FunctionCall {
+    pub receiver_id: AccountId,
+    pub method_name: String,
+    pub args: Vec<u8>,
+    pub deposit: NearToken,
+    pub gas: Gas,
+}
+
+impl FunctionCall {
+    pub fn with_gas(mut self, gas: Gas) -> Self {
+        self.gas = gas;
+        self
+    }
+
+    pub fn with_deposit(mut self, deposit: NearToken) -> Self {
+        self.deposit = deposit;
+        self
+    }
+}
+
+/// A view call, and how to decode its result.
+pub struct ViewCall<T> {
+    pub receiver_id: AccountId,
+    pub method_name: String,
+    pub args: Vec<u8>,
+    decode: fn(&[u8]) -> Result<T, ClientError>,
+}
+
+impl<T> ViewCall<T> {
+    /// Decodes the raw bytes returned by the view call.
+    pub fn decode(&self, result: &[u8]) -> Result<T, ClientError> {
+        (self.decode)(result)
+    }
+}
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub enum ClientError {
+    Json(near_sdk::serde_json::Error),
+    Borsh(std::io::Error),
+    /// JSON args that don't match the ABI of the dex.
+    Abi(String),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Json(error) => write!(f, "Failed to decode JSON: {error}"),
+            Self::Borsh(error) => write!(f, "Failed to decode borsh: {error}"),
+            Self::Abi(error) => write!(f, "Invalid args: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+fn decode_json<T: near_sdk::serde::de::DeserializeOwned>(result: &[u8]) -> Result<T, ClientError> {
+    near_sdk::serde_json::from_slice(result).map_err(ClientError::Json)
+}
+
+/// Decodes the borsh response of a dex that the engine returns
+/// base64-encoded from `dex_call` and `dex_view`.
+pub fn decode_dex_response<T: BorshDeserialize>(result: &[u8]) -> Result<T, ClientError> {
+    let Base64VecU8(bytes) = decode_json(result)?;
+    near_sdk::borsh::from_slice(&bytes).map_err(ClientError::Borsh)
+}
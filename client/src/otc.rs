@@ -0,0 +1,210 @@
+use intear_dex_types::{AssetId, DexId};
+use near_sdk::{
+    AccountId, BlockHeight, NearToken, PublicKey,
+    json_types::{Base64VecU8, U64, U128},
+    near,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{DexCall, DexEngineClient, ViewCall};
+
+pub type Nonce = U128;
+
+/// What a user agrees to trade. Intents are matched by
+/// `match` if the net change of every asset is zero.
+#[derive(Clone, PartialEq)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh, json])]
+pub struct TradeIntent {
+    pub user_id: AccountId,
+    pub asset_in: AssetId,
+    pub asset_out: AssetId,
+    pub amount_in: U128,
+    pub amount_out: U128,
+    #[serde(default)]
+    pub validity: Validity,
+}
+
+#[derive(Clone, Default, PartialEq)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh, json])]
+pub struct Validity {
+    pub expiry: Option<ExpiryCondition>,
+    /// Can only be used once if set.
+    pub nonce: Option<Nonce>,
+    pub only_for_whitelisted_parties: Option<Vec<AccountId>>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh, json])]
+pub enum ExpiryCondition {
+    BlockHeight(BlockHeight),
+    Timestamp { milliseconds: U64 },
+}
+
+#[derive(Clone, PartialEq)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh, json])]
+pub enum AuthorizationMethod {
+    /// Signature of [`TradeIntent::hash`] by the authorized key
+    /// of the user.
+    Signature(Base64VecU8),
+    /// The user is the caller of `match`.
+    Predecessor,
+}
+
+#[derive(Clone, PartialEq)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh, json])]
+pub struct AuthorizedTradeIntent {
+    pub trade_intent: TradeIntent,
+    pub authorization_method: AuthorizationMethod,
+}
+
+/// Where the output of matched intents goes.
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh])]
+pub enum OutputDestination {
+    InternalOtcBalance,
+    IntearDexBalance,
+    WithdrawToUser,
+}
+
+/// A withdrawal from the balance of the caller in the dex.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh])]
+pub struct WithdrawRequest {
+    pub asset_id: AssetId,
+    /// The entire balance if `None`.
+    pub amount: Option<U128>,
+    /// The caller if `None`.
+    pub to: Option<AccountId>,
+    pub to_inner_balance: bool,
+}
+
+impl TradeIntent {
+    /// The hash that is signed to authorize this intent.
+    pub fn hash(&self) -> [u8; 32] {
+        let data = near_sdk::borsh::to_vec(self).expect("Failed to serialize intent");
+        Sha256::digest(data).into()
+    }
+
+    /// Authorizes the intent with a signature of the key that
+    /// the user set with `set_authorized_key`.
+    pub fn sign(self, key: &near_crypto::SecretKey) -> AuthorizedTradeIntent {
+        let signature = match key.sign(&self.hash()) {
+            near_crypto::Signature::ED25519(signature) => signature.to_bytes().to_vec(),
+            near_crypto::Signature::SECP256K1(signature) => <[u8; 65]>::from(signature).to_vec(),
+        };
+        AuthorizedTradeIntent {
+            trade_intent: self,
+            authorization_method: AuthorizationMethod::Signature(Base64VecU8(signature)),
+        }
+    }
+
+    /// Authorizes the intent of the caller of `match`.
+    pub fn by_predecessor(self) -> AuthorizedTradeIntent {
+        AuthorizedTradeIntent {
+            trade_intent: self,
+            authorization_method: AuthorizationMethod::Predecessor,
+        }
+    }
+}
+
+#[near(serializers=[borsh])]
+struct MatchArgs {
+    authorized_trade_intents: Vec<AuthorizedTradeIntent>,
+    output_destination: OutputDestination,
+}
+
+#[near(serializers=[borsh])]
+struct SetAuthorizedKeyArgs {
+    key: PublicKey,
+}
+
+#[near(serializers=[borsh])]
+struct StorageDepositArgs;
+
+#[near(serializers=[borsh])]
+struct DepositAssetsArgs;
+
+#[near(serializers=[borsh])]
+struct WithdrawAssetsArgs {
+    assets: Vec<WithdrawRequest>,
+}
+
+/// Builds calls to a deployed OTC dex.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub struct Otc {
+    pub engine: DexEngineClient,
+    pub dex_id: DexId,
+}
+
+impl Otc {
+    pub fn new(engine: DexEngineClient, dex_id: DexId) -> Self {
+        Self { engine, dex_id }
+    }
+
+    /// Matches the intents. If the caller has an intent among
+    /// them, it can attach its `asset_in` instead of using its
+    /// balance in the dex.
+    pub fn r#match(
+        &self,
+        authorized_trade_intents: Vec<AuthorizedTradeIntent>,
+        output_destination: OutputDestination,
+    ) -> DexCall {
+        self.engine
+            .dex_call(self.dex_id.clone(), "match")
+            .borsh_args(&MatchArgs {
+                authorized_trade_intents,
+                output_destination,
+            })
+    }
+
+    /// Sets the key that signs intents of the caller.
+    pub fn set_authorized_key(&self, key: PublicKey) -> DexCall {
+        self.engine
+            .dex_call(self.dex_id.clone(), "set_authorized_key")
+            .borsh_args(&SetAuthorizedKeyArgs { key })
+    }
+
+    pub fn storage_deposit(&self, amount: NearToken) -> DexCall {
+        self.engine
+            .dex_call(self.dex_id.clone(), "storage_deposit")
+            .borsh_args(&StorageDepositArgs)
+            .attach(AssetId::Near, U128(amount.as_yoctonear()))
+    }
+
+    /// Deposits the attached assets to the balance of the
+    /// caller in the dex.
+    pub fn deposit_assets(&self) -> DexCall {
+        self.engine
+            .dex_call(self.dex_id.clone(), "deposit_assets")
+            .borsh_args(&DepositAssetsArgs)
+    }
+
+    pub fn withdraw_assets(&self, assets: Vec<WithdrawRequest>) -> DexCall {
+        self.engine
+            .dex_call(self.dex_id.clone(), "withdraw_assets")
+            .borsh_args(&WithdrawAssetsArgs { assets })
+    }
+
+    pub fn get_authorized_key(&self, account_id: AccountId) -> ViewCall<Option<PublicKey>> {
+        self.engine
+            .dex_view(self.dex_id.clone(), "get_authorized_key", &account_id)
+    }
+
+    pub fn get_balance(&self, account_id: AccountId, asset_id: AssetId) -> ViewCall<Option<U128>> {
+        self.engine
+            .dex_view(self.dex_id.clone(), "get_balance", &(account_id, asset_id))
+    }
+
+    pub fn is_nonce_used(&self, account_id: AccountId, nonce: Nonce) -> ViewCall<bool> {
+        self.engine
+            .dex_view(self.dex_id.clone(), "is_nonce_used", &(nonce, account_id))
+    }
+}
@@ -0,0 +1,132 @@
+use intear_dex_types::{AssetId, DexId, SwapRequestAmount};
+use near_sdk::{AccountId, NearToken, json_types::U128, near};
+
+use crate::{ClientError, DexCall, DexEngineClient, SwapSimple, ViewCall, decode_dex_response};
+
+pub type PoolId = u64;
+
+/// A pool of the simple-amm dex, as returned by `get_pool`.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh])]
+pub struct SimplePool {
+    pub assets: (AssetWithBalance, AssetWithBalance),
+    pub owner_id: AccountId,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh])]
+pub struct AssetWithBalance {
+    pub asset_id: AssetId,
+    pub balance: U128,
+}
+
+#[near(serializers=[borsh])]
+struct SwapArgs {
+    pool_id: PoolId,
+}
+
+#[near(serializers=[borsh])]
+struct CreatePoolArgs {
+    assets: (AssetId, AssetId),
+}
+
+#[near(serializers=[borsh])]
+struct CreatePoolResponse {
+    pool_id: PoolId,
+}
+
+#[near(serializers=[borsh])]
+struct AddLiquidityArgs {
+    pool_id: PoolId,
+}
+
+#[near(serializers=[borsh])]
+struct RemoveLiquidityArgs {
+    pool_id: PoolId,
+    assets_to_remove: (U128, U128),
+}
+
+/// Builds calls to a deployed simple-amm dex.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub struct SimpleAmm {
+    pub engine: DexEngineClient,
+    pub dex_id: DexId,
+}
+
+impl SimpleAmm {
+    pub fn new(engine: DexEngineClient, dex_id: DexId) -> Self {
+        Self { engine, dex_id }
+    }
+
+    /// Initializes the dex after it's deployed.
+    pub fn init(&self) -> DexCall {
+        self.engine.dex_call(self.dex_id.clone(), "new")
+    }
+
+    /// Message of a swap in `pool_id`.
+    pub fn swap_message(pool_id: PoolId) -> Vec<u8> {
+        near_sdk::borsh::to_vec(&SwapArgs { pool_id }).expect("Failed to serialize message")
+    }
+
+    pub fn swap(
+        &self,
+        pool_id: PoolId,
+        asset_in: AssetId,
+        asset_out: AssetId,
+        amount: SwapRequestAmount,
+    ) -> SwapSimple {
+        self.engine
+            .swap_simple(self.dex_id.clone(), asset_in, asset_out, amount)
+            .message(Self::swap_message(pool_id))
+    }
+
+    /// Creates an empty pool. `storage` is attached as NEAR to
+    /// pay for the pool, and the unused part is returned to the
+    /// inner balance of the caller.
+    pub fn create_pool(&self, assets: (AssetId, AssetId), storage: NearToken) -> DexCall {
+        self.engine
+            .dex_call(self.dex_id.clone(), "create_pool")
+            .borsh_args(&CreatePoolArgs { assets })
+            .attach(AssetId::Near, U128(storage.as_yoctonear()))
+    }
+
+    /// Decodes the id of the pool from the result of
+    /// `create_pool`.
+    pub fn create_pool_result(result: &[u8]) -> Result<PoolId, ClientError> {
+        decode_dex_response::<CreatePoolResponse>(result).map(|response| response.pool_id)
+    }
+
+    /// Adds both assets of the pool to its reserves. Only the
+    /// owner of the pool can add liquidity.
+    pub fn add_liquidity(
+        &self,
+        pool_id: PoolId,
+        first: (AssetId, U128),
+        second: (AssetId, U128),
+    ) -> DexCall {
+        self.engine
+            .dex_call(self.dex_id.clone(), "add_liquidity")
+            .borsh_args(&AddLiquidityArgs { pool_id })
+            .attach(first.0, first.1)
+            .attach(second.0, second.1)
+    }
+
+    /// Removes reserves of the pool to the inner balance of its
+    /// owner, in the order of the pool's assets.
+    pub fn remove_liquidity(&self, pool_id: PoolId, assets_to_remove: (U128, U128)) -> DexCall {
+        self.engine
+            .dex_call(self.dex_id.clone(), "remove_liquidity")
+            .borsh_args(&RemoveLiquidityArgs {
+                pool_id,
+                assets_to_remove,
+            })
+    }
+
+    pub fn get_pool(&self, pool_id: PoolId) -> ViewCall<Option<SimplePool>> {
+        self.engine
+            .dex_view(self.dex_id.clone(), "get_pool", &pool_id)
+    }
+}
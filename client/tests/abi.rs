@@ -0,0 +1,315 @@
+use intear_dex_client::{
+    ClientError, DexEngineClient, Otc,
+    abi::{encode_args, encode_value, find_method},
+    otc::{
+        AuthorizationMethod, AuthorizedTradeIntent, ExpiryCondition, OutputDestination,
+        TradeIntent, Validity, WithdrawRequest,
+    },
+};
+use intear_dex_types::{AssetId, DexAbiFields, DexAbiMethod, DexAbiType, DexId};
+use near_crypto::{KeyType, SecretKey};
+use near_sdk::{
+    AccountId,
+    json_types::{Base64VecU8, U64, U128},
+    serde_json::{self, Value, json},
+};
+
+fn named(fields: Vec<(&str, DexAbiType)>) -> Vec<(String, DexAbiType)> {
+    fields
+        .into_iter()
+        .map(|(name, ty)| (name.to_string(), ty))
+        .collect()
+}
+
+fn option(ty: DexAbiType) -> DexAbiType {
+    DexAbiType::Option(Box::new(ty))
+}
+
+fn vec_of(ty: DexAbiType) -> DexAbiType {
+    DexAbiType::Vec(Box::new(ty))
+}
+
+/// ABI of the otc dex methods, as the engine returns it from
+/// `dex_abi`.
+fn otc_abi() -> Vec<DexAbiMethod> {
+    let validity = DexAbiType::Struct {
+        name: "Validity".to_string(),
+        fields: DexAbiFields::Named(named(vec![
+            (
+                "expiry",
+                option(DexAbiType::Enum {
+                    name: "ExpiryCondition".to_string(),
+                    variants: vec![
+                        (
+                            "BlockHeight".to_string(),
+                            DexAbiFields::Unnamed(vec![DexAbiType::U64]),
+                        ),
+                        (
+                            "Timestamp".to_string(),
+                            DexAbiFields::Named(named(vec![("milliseconds", DexAbiType::U64)])),
+                        ),
+                    ],
+                }),
+            ),
+            ("nonce", option(DexAbiType::U128)),
+            (
+                "only_for_whitelisted_parties",
+                option(vec_of(DexAbiType::AccountId)),
+            ),
+        ])),
+    };
+    let trade_intent = DexAbiType::Struct {
+        name: "TradeIntent".to_string(),
+        fields: DexAbiFields::Named(named(vec![
+            ("user_id", DexAbiType::AccountId),
+            ("asset_in", DexAbiType::AssetId),
+            ("asset_out", DexAbiType::AssetId),
+            ("amount_in", DexAbiType::U128),
+            ("amount_out", DexAbiType::U128),
+            ("validity", validity),
+        ])),
+    };
+    let authorized_trade_intent = DexAbiType::Struct {
+        name: "AuthorizedTradeIntent".to_string(),
+        fields: DexAbiFields::Named(named(vec![
+            ("trade_intent", trade_intent),
+            (
+                "authorization_method",
+                DexAbiType::Enum {
+                    name: "AuthorizationMethod".to_string(),
+                    variants: vec![
+                        (
+                            "Signature".to_string(),
+                            DexAbiFields::Unnamed(vec![DexAbiType::Base64]),
+                        ),
+                        ("Predecessor".to_string(), DexAbiFields::Unit),
+                    ],
+                },
+            ),
+        ])),
+    };
+    let output_destination = DexAbiType::Enum {
+        name: "OutputDestination".to_string(),
+        variants: ["InternalOtcBalance", "IntearDexBalance", "WithdrawToUser"]
+            .into_iter()
+            .map(|name| (name.to_string(), DexAbiFields::Unit))
+            .collect(),
+    };
+    let withdraw_request = DexAbiType::Struct {
+        name: "WithdrawRequest".to_string(),
+        fields: DexAbiFields::Named(named(vec![
+            ("asset_id", DexAbiType::AssetId),
+            ("amount", option(DexAbiType::U128)),
+            ("to", option(DexAbiType::AccountId)),
+            ("to_inner_balance", DexAbiType::Bool),
+        ])),
+    };
+    vec![
+        DexAbiMethod {
+            name: "match".to_string(),
+            args: named(vec![
+                ("authorized_trade_intents", vec_of(authorized_trade_intent)),
+                ("output_destination", output_destination),
+            ]),
+            result: None,
+        },
+        DexAbiMethod {
+            name: "set_authorized_key".to_string(),
+            args: named(vec![("key", DexAbiType::PublicKey)]),
+            result: None,
+        },
+        DexAbiMethod {
+            name: "withdraw_assets".to_string(),
+            args: named(vec![("assets", vec_of(withdraw_request))]),
+            result: None,
+        },
+        DexAbiMethod {
+            name: "get_balance".to_string(),
+            args: named(vec![
+                ("account_id", DexAbiType::AccountId),
+                ("asset_id", DexAbiType::AssetId),
+            ]),
+            result: Some(option(DexAbiType::U128)),
+        },
+    ]
+}
+
+fn engine() -> DexEngineClient {
+    DexEngineClient::new("dex.intear.near".parse().unwrap())
+}
+
+fn otc_id() -> DexId {
+    "user.near/otc".parse().unwrap()
+}
+
+fn otc() -> Otc {
+    Otc::new(engine(), otc_id())
+}
+
+fn user() -> AccountId {
+    "user.near".parse().unwrap()
+}
+
+fn ft() -> AssetId {
+    AssetId::Nep141("ft.near".parse().unwrap())
+}
+
+/// Borsh args of a `dex_call` built by the client.
+fn dex_call_args(args: &[u8]) -> Vec<u8> {
+    let args: Value = serde_json::from_slice(args).unwrap();
+    serde_json::from_value::<Base64VecU8>(args["args"].clone())
+        .unwrap()
+        .0
+}
+
+fn abi_error(result: Result<Vec<u8>, ClientError>) -> String {
+    match result {
+        Err(ClientError::Abi(error)) => error,
+        _ => panic!("Expected an ABI error"),
+    }
+}
+
+#[test]
+fn test_find_method() {
+    let abi = otc_abi();
+    assert_eq!(
+        find_method(&abi, "get_balance").unwrap().result,
+        Some(option(DexAbiType::U128))
+    );
+    assert_eq!(
+        abi_error(find_method(&abi, "swap").map(|_| Vec::new())),
+        "Method swap is not in the ABI"
+    );
+}
+
+#[test]
+fn test_encode_args() {
+    let abi = otc_abi();
+    let key = SecretKey::from_random(KeyType::ED25519);
+    let public_key = key.public_key().to_string();
+
+    let set_key = engine()
+        .dex_call(otc_id(), "set_authorized_key")
+        .json_args(&abi, &json!({ "key": public_key }))
+        .unwrap()
+        .build();
+    assert_eq!(
+        dex_call_args(&set_key.args),
+        dex_call_args(
+            &otc()
+                .set_authorized_key(public_key.parse().unwrap())
+                .build()
+                .args
+        )
+    );
+
+    // Optional fields can be left out
+    let withdraw = find_method(&abi, "withdraw_assets").unwrap();
+    let args = encode_args(
+        withdraw,
+        &json!({
+            "assets": [
+                { "asset_id": "near", "to_inner_balance": true },
+                { "asset_id": "nep141:ft.near", "amount": "100", "to": "user.near", "to_inner_balance": false },
+            ],
+        }),
+    )
+    .unwrap();
+    let expected = otc().withdraw_assets(vec![
+        WithdrawRequest {
+            asset_id: AssetId::Near,
+            amount: None,
+            to: None,
+            to_inner_balance: true,
+        },
+        WithdrawRequest {
+            asset_id: ft(),
+            amount: Some(U128(100)),
+            to: Some(user()),
+            to_inner_balance: false,
+        },
+    ]);
+    assert_eq!(args, dex_call_args(&expected.build().args));
+
+    // The JSON form of the client types is accepted as is
+    let intents = vec![
+        TradeIntent {
+            user_id: user(),
+            asset_in: ft(),
+            asset_out: AssetId::Near,
+            amount_in: U128(100),
+            amount_out: U128(200),
+            validity: Validity {
+                expiry: Some(ExpiryCondition::Timestamp {
+                    milliseconds: U64(1_000),
+                }),
+                nonce: Some(U128(7)),
+                only_for_whitelisted_parties: Some(vec![user()]),
+            },
+        }
+        .sign(&key),
+        AuthorizedTradeIntent {
+            trade_intent: TradeIntent {
+                user_id: user(),
+                asset_in: AssetId::Near,
+                asset_out: ft(),
+                amount_in: U128(200),
+                amount_out: U128(100),
+                validity: Validity {
+                    expiry: Some(ExpiryCondition::BlockHeight(5)),
+                    ..Default::default()
+                },
+            },
+            authorization_method: AuthorizationMethod::Predecessor,
+        },
+    ];
+    let args = encode_args(
+        find_method(&abi, "match").unwrap(),
+        &json!({
+            "authorized_trade_intents": intents,
+            "output_destination": "IntearDexBalance",
+        }),
+    )
+    .unwrap();
+    let expected = otc().r#match(intents, OutputDestination::IntearDexBalance);
+    assert_eq!(args, dex_call_args(&expected.build().args));
+}
+
+#[test]
+fn test_encode_errors() {
+    let abi = otc_abi();
+    let withdraw = find_method(&abi, "withdraw_assets").unwrap();
+    assert_eq!(
+        abi_error(encode_args(withdraw, &json!({}))),
+        "withdraw_assets.assets: missing field"
+    );
+    assert_eq!(
+        abi_error(encode_args(withdraw, &json!({ "assets": [], "extra": 1 }))),
+        "withdraw_assets: unknown field extra"
+    );
+    assert_eq!(
+        abi_error(encode_args(
+            withdraw,
+            &json!({ "assets": [{ "asset_id": "near", "amount": "-1", "to_inner_balance": true }] }),
+        )),
+        "withdraw_assets.assets[0].amount: invalid integer -1"
+    );
+
+    let r#match = find_method(&abi, "match").unwrap();
+    assert_eq!(
+        abi_error(encode_args(
+            r#match,
+            &json!({ "authorized_trade_intents": [], "output_destination": "Elsewhere" }),
+        )),
+        "match.output_destination: unknown variant Elsewhere"
+    );
+
+    assert_eq!(
+        encode_value(&DexAbiType::U64, &json!(u64::MAX)).unwrap(),
+        u64::MAX.to_le_bytes()
+    );
+    assert_eq!(
+        abi_error(encode_value(&DexAbiType::U8, &json!(256))),
+        "args: invalid integer 256"
+    );
+}
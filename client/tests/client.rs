@@ -0,0 +1,239 @@
+use intear_dex_client::{
+    DexEngineClient, Otc, SimpleAmm,
+    otc::{AuthorizationMethod, OutputDestination, TradeIntent, Validity},
+    simple_amm::{AssetWithBalance, SimplePool},
+};
+use intear_dex_types::{
+    AccountOrDexId, AssetId, DexId, Operation, SwapOperationAmount, SwapRequestAmount,
+};
+use near_crypto::{KeyType, SecretKey, Signature};
+use near_sdk::{
+    AccountId, NearToken, PublicKey,
+    json_types::{Base64VecU8, U128},
+    serde_json::{self, Value, json},
+};
+
+fn engine() -> DexEngineClient {
+    DexEngineClient::new("dex.intear.near".parse().unwrap())
+}
+
+fn user() -> AccountId {
+    "user.near".parse().unwrap()
+}
+
+fn ft() -> AssetId {
+    AssetId::Nep141("ft.near".parse().unwrap())
+}
+
+fn json_args(args: &[u8]) -> Value {
+    serde_json::from_slice(args).unwrap()
+}
+
+/// Borsh args of a `dex_call` built by the client.
+fn dex_call_args(args: &[u8]) -> Vec<u8> {
+    serde_json::from_value::<Base64VecU8>(json_args(args)["args"].clone())
+        .unwrap()
+        .0
+}
+
+#[test]
+fn test_engine_calls() {
+    let dex_id: DexId = "user.near/amm".parse().unwrap();
+    let call = engine()
+        .dex_call(dex_id.clone(), "add_liquidity")
+        .args(vec![1, 2, 3])
+        .attach(AssetId::Near, U128(10))
+        .attach(AssetId::Near, U128(5))
+        .build();
+    assert_eq!(
+        call.receiver_id,
+        "dex.intear.near".parse::<AccountId>().unwrap()
+    );
+    assert_eq!(call.method_name, "dex_call");
+    assert_eq!(call.deposit, NearToken::from_yoctonear(1));
+    assert_eq!(
+        json_args(&call.args),
+        json!({
+            "dex_id": "user.near/amm",
+            "method": "add_liquidity",
+            "args": "AQID",
+            "attached_assets": { "near": "15" },
+            "max_storage_payment": "0",
+        })
+    );
+
+    let swap = engine()
+        .swap_simple(
+            dex_id.clone(),
+            AssetId::Near,
+            ft(),
+            SwapRequestAmount::ExactIn(U128(100)),
+        )
+        .message(vec![0; 8])
+        .max_storage_payment(NearToken::from_millinear(1));
+    assert_eq!(
+        json_args(&swap.clone().build().args),
+        json!({
+            "dex_id": "user.near/amm",
+            "message": "AAAAAAAAAAA=",
+            "asset_in": "near",
+            "asset_out": "nep141:ft.near",
+            "amount": { "ExactIn": "100" },
+            "referral": null,
+            "max_storage_payment": "1000000000000000000000",
+        })
+    );
+    assert!(matches!(
+        swap.into_operation(),
+        Operation::SwapSimple {
+            amount: SwapOperationAmount::Amount(SwapRequestAmount::ExactIn(U128(100))),
+            ..
+        }
+    ));
+
+    let deposit = engine().ft_deposit(
+        "ft.near".parse().unwrap(),
+        U128(100),
+        Some(vec![Operation::TransferAsset {
+            to: AccountOrDexId::Account(user()),
+            asset_id: ft(),
+            amount: U128(100),
+        }]),
+    );
+    assert_eq!(deposit.receiver_id, "ft.near".parse::<AccountId>().unwrap());
+    let msg = json_args(&deposit.args)["msg"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let operations: Vec<Operation> = serde_json::from_str(&msg).unwrap();
+    assert!(matches!(operations[..], [Operation::TransferAsset { .. }]));
+
+    let view = engine().asset_balance_of(AccountOrDexId::Account(user()), ft());
+    assert_eq!(
+        json_args(&view.args),
+        json!({ "of": { "Account": "user.near" }, "asset_id": "nep141:ft.near" })
+    );
+    assert_eq!(view.decode(b"\"42\"").unwrap(), Some(U128(42)));
+    assert_eq!(view.decode(b"null").unwrap(), None);
+
+    let pairs = engine().dex_pairs(dex_id, None, Some(10));
+    assert_eq!(
+        json_args(&pairs.args),
+        json!({ "dex_id": "user.near/amm", "from_index": null, "limit": 10 })
+    );
+    let decoded = pairs
+        .decode(br#"[{ "assets": ["near", "nep141:ft.near"], "message": "AAAAAAAAAAA=" }]"#)
+        .unwrap();
+    assert_eq!(decoded.len(), 1);
+    assert_eq!(decoded[0].assets, (AssetId::Near, ft()));
+    assert_eq!(decoded[0].message.0, vec![0; 8]);
+}
+
+#[test]
+fn test_simple_amm_calls() {
+    let amm = SimpleAmm::new(engine(), "user.near/amm".parse().unwrap());
+
+    let create_pool = amm
+        .create_pool((AssetId::Near, ft()), NearToken::from_millinear(10))
+        .build();
+    assert_eq!(
+        dex_call_args(&create_pool.args),
+        near_sdk::borsh::to_vec(&(AssetId::Near, ft())).unwrap()
+    );
+    assert_eq!(
+        json_args(&create_pool.args)["attached_assets"],
+        json!({ "near": NearToken::from_millinear(10).as_yoctonear().to_string() })
+    );
+    let result = serde_json::to_vec(&Base64VecU8(near_sdk::borsh::to_vec(&7u64).unwrap())).unwrap();
+    assert_eq!(SimpleAmm::create_pool_result(&result).unwrap(), 7);
+
+    let remove_liquidity = amm.remove_liquidity(7, (U128(1), U128(2))).build();
+    assert_eq!(
+        dex_call_args(&remove_liquidity.args),
+        near_sdk::borsh::to_vec(&(7u64, U128(1), U128(2))).unwrap()
+    );
+
+    assert_eq!(SimpleAmm::swap_message(7), 7u64.to_le_bytes());
+
+    let get_pool = amm.get_pool(7);
+    assert_eq!(get_pool.method_name, "dex_view");
+    assert_eq!(json_args(&get_pool.args)["args"], json!("BwAAAAAAAAA="));
+    let pool = SimplePool {
+        assets: (
+            AssetWithBalance {
+                asset_id: AssetId::Near,
+                balance: U128(1),
+            },
+            AssetWithBalance {
+                asset_id: ft(),
+                balance: U128(2),
+            },
+        ),
+        owner_id: user(),
+    };
+    let result = serde_json::to_vec(&Base64VecU8(
+        near_sdk::borsh::to_vec(&Some(pool.clone())).unwrap(),
+    ))
+    .unwrap();
+    assert_eq!(get_pool.decode(&result).unwrap(), Some(pool));
+}
+
+#[test]
+fn test_otc_intent_signing() {
+    let otc = Otc::new(engine(), "user.near/otc".parse().unwrap());
+    let key = SecretKey::from_random(KeyType::ED25519);
+    let public_key: PublicKey = key.public_key().to_string().parse().unwrap();
+
+    let set_key = otc.set_authorized_key(public_key.clone()).build();
+    // Curve type and key bytes, length-prefixed
+    let key_bytes = [vec![0], key.public_key().key_data().to_vec()].concat();
+    assert_eq!(
+        dex_call_args(&set_key.args),
+        near_sdk::borsh::to_vec(&key_bytes).unwrap()
+    );
+
+    let intent = TradeIntent {
+        user_id: user(),
+        asset_in: ft(),
+        asset_out: AssetId::Near,
+        amount_in: U128(100),
+        amount_out: U128(200),
+        validity: Validity {
+            nonce: Some(U128(1)),
+            ..Default::default()
+        },
+    };
+    let hash = intent.hash();
+    let authorized = intent.clone().sign(&key);
+    assert_eq!(authorized.trade_intent, intent);
+    let AuthorizationMethod::Signature(signature) = &authorized.authorization_method else {
+        panic!("Expected a signature");
+    };
+    let signature = Signature::from_parts(KeyType::ED25519, &signature.0).unwrap();
+    assert!(signature.verify(&hash, &key.public_key()));
+
+    let counterparty = TradeIntent {
+        user_id: "other.near".parse().unwrap(),
+        asset_in: AssetId::Near,
+        asset_out: ft(),
+        amount_in: U128(200),
+        amount_out: U128(100),
+        validity: Validity::default(),
+    }
+    .by_predecessor();
+    let r#match = otc
+        .r#match(
+            vec![authorized.clone(), counterparty.clone()],
+            OutputDestination::WithdrawToUser,
+        )
+        .attach(AssetId::Near, U128(200))
+        .build();
+    let mut expected = near_sdk::borsh::to_vec(&vec![authorized, counterparty]).unwrap();
+    expected.extend(near_sdk::borsh::to_vec(&OutputDestination::WithdrawToUser).unwrap());
+    assert_eq!(dex_call_args(&r#match.args), expected);
+
+    let get_balance = otc.get_balance(user(), ft());
+    let args = serde_json::from_value::<Base64VecU8>(json_args(&get_balance.args)["args"].clone())
+        .unwrap();
+    assert_eq!(args.0, near_sdk::borsh::to_vec(&(user(), ft())).unwrap());
+}
@@ -3,12 +3,13 @@
 use std::collections::HashMap;
 
 use crypto_bigint::{ConstChoice, I256, U256};
+use intear_dex_sdk::{abi::AbiType, dex, state::DexState};
 use intear_dex_types::{
-    AssetId, AssetWithdrawRequest, AssetWithdrawalType, Dex, DexCallResponse, SwapRequest,
-    SwapResponse, expect,
+    AssetId, AssetWithdrawRequest, AssetWithdrawalType, Dex, DexCallResponse, DexFeeModel,
+    DexMetadata, DexPair, SwapRequest, SwapResponse, expect,
 };
 use near_sdk::{
-    AccountId, BlockHeight, BorshStorageKey, CurveType, NearToken, PublicKey, assert_one_yocto,
+    AccountId, BlockHeight, BorshStorageKey, CurveType, NearToken, PublicKey,
     json_types::{Base64VecU8, U64, U128},
     near,
     store::{LookupMap, LookupSet, TreeMap},
@@ -27,7 +28,7 @@ enum StorageKey {
     UsedNonces,
 }
 
-#[derive(Default)]
+#[derive(Default, AbiType)]
 #[near(serializers=[borsh, json])]
 pub struct StorageBalance {
     total: NearToken,
@@ -40,7 +41,7 @@ impl StorageBalance {
     }
 }
 
-#[near(contract_state)]
+#[near(serializers=[borsh])]
 pub struct OtcDex {
     balances: LookupMap<(AccountId, AssetId), U128>,
     authorized_keys: LookupMap<AccountId, PublicKey>,
@@ -52,12 +53,14 @@ pub struct OtcDex {
 
 pub type Nonce = U128;
 
+#[derive(AbiType)]
 #[near(serializers=[json, borsh])]
 enum AuthorizationMethod {
     Signature(Base64VecU8),
     Predecessor,
 }
 
+#[derive(AbiType)]
 #[near(serializers=[json, borsh])]
 pub struct AuthorizedTradeIntent {
     trade_intent: TradeIntent,
@@ -143,6 +146,7 @@ impl AuthorizedTradeIntent {
     }
 }
 
+#[derive(AbiType)]
 #[near(serializers=[json, borsh])]
 #[derive(Debug)]
 pub struct TradeIntent {
@@ -155,7 +159,7 @@ pub struct TradeIntent {
     validity: Validity,
 }
 
-#[derive(Default, PartialEq, Debug)]
+#[derive(Default, PartialEq, Debug, AbiType)]
 #[near(serializers=[json, borsh])]
 pub struct Validity {
     expiry: Option<ExpiryCondition>,
@@ -167,7 +171,7 @@ fn is_default<T: Default + PartialEq>(value: &T) -> bool {
     value == &T::default()
 }
 
-#[derive(PartialEq, Clone, Copy, Debug)]
+#[derive(PartialEq, Clone, Copy, Debug, AbiType)]
 #[near(serializers=[borsh, json])]
 pub enum ExpiryCondition {
     BlockHeight(BlockHeight),
@@ -187,17 +191,39 @@ enum OtcTradeEvent {
     },
 }
 
-#[near]
+#[dex]
 impl Dex for OtcDex {
-    #[result_serializer(borsh)]
-    fn swap(
-        &mut self,
-        #[allow(unused_variables)]
-        #[serializer(borsh)]
-        request: SwapRequest,
-    ) -> SwapResponse {
+    fn swap(&mut self, #[allow(unused_variables)] request: SwapRequest) -> SwapResponse {
         panic!("Method `swap` cannot be used for OtcDex. Use dex_call with `match` method instead.")
     }
+
+    fn quote(&self, #[allow(unused_variables)] request: SwapRequest) -> SwapResponse {
+        panic!("OtcDex can't quote swaps, prices are set by the signed trade intents")
+    }
+
+    fn metadata(&self) -> DexMetadata {
+        DexMetadata {
+            name: "OTC".to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            fee_model: DexFeeModel::NoFee,
+        }
+    }
+
+    /// Trade intents are matched off-chain, so there are no
+    /// pairs to swap with `swap`.
+    fn pairs(
+        &self,
+        #[allow(unused_variables)] from_index: u64,
+        #[allow(unused_variables)] limit: u64,
+    ) -> Vec<DexPair> {
+        Vec::new()
+    }
+}
+
+impl DexState for OtcDex {
+    fn uninitialized() -> Self {
+        Self::default()
+    }
 }
 
 impl Default for OtcDex {
@@ -217,34 +243,34 @@ impl Default for OtcDex {
     }
 }
 
-#[near]
+#[derive(AbiType)]
+#[near(serializers=[borsh])]
+pub enum OutputDestination {
+    InternalOtcBalance,
+    IntearDexBalance,
+    WithdrawToUser,
+}
+
+#[derive(AbiType)]
+#[near(serializers=[borsh])]
+pub struct WithdrawRequest {
+    pub asset_id: AssetId,
+    // If None, the entire balance of the asset will be withdrawn.
+    pub amount: Option<U128>,
+    // If None, the assets will be withdrawn to the user's own account.
+    pub to: Option<AccountId>,
+    pub to_inner_balance: bool,
+}
+
+#[dex]
 impl OtcDex {
-    #[payable]
-    #[result_serializer(borsh)]
+    #[dex_method]
     pub fn r#match(
         &mut self,
-        #[serializer(borsh)] attached_assets: HashMap<AssetId, U128>,
-        #[serializer(borsh)] args: Vec<u8>,
+        attached_assets: HashMap<AssetId, U128>,
+        authorized_trade_intents: Vec<AuthorizedTradeIntent>,
+        output_destination: OutputDestination,
     ) -> DexCallResponse {
-        #[near(serializers=[borsh])]
-        enum OutputDestination {
-            InternalOtcBalance,
-            IntearDexBalance,
-            WithdrawToUser,
-        }
-        #[near(serializers=[borsh])]
-        struct MatchArgs {
-            authorized_trade_intents: Vec<AuthorizedTradeIntent>,
-            output_destination: OutputDestination,
-        }
-        let Ok(MatchArgs {
-            authorized_trade_intents,
-            output_destination,
-        }) = near_sdk::borsh::from_slice(&args)
-        else {
-            near_sdk::env::panic_str("Invalid args");
-        };
-
         let mut all_required_assets_were_attached = false;
         if !attached_assets.is_empty() {
             let mut required_assets = HashMap::<AssetId, U128>::new();
@@ -498,20 +524,11 @@ impl OtcDex {
         }
     }
 
-    #[payable]
-    #[result_serializer(borsh)]
+    #[dex_method]
     pub fn storage_deposit(
         &mut self,
-        #[allow(unused_mut)]
-        #[serializer(borsh)]
         mut attached_assets: HashMap<AssetId, U128>,
-        #[serializer(borsh)] args: Vec<u8>,
     ) -> DexCallResponse {
-        #[near(serializers=[borsh])]
-        struct StorageDepositArgs;
-        let Ok(StorageDepositArgs) = near_sdk::borsh::from_slice(&args) else {
-            near_sdk::env::panic_str("Invalid args");
-        };
         let predecessor_id = near_sdk::env::predecessor_account_id();
         let Some(attached_near) = attached_assets.remove(&AssetId::Near) else {
             panic!("Near not attached");
@@ -539,21 +556,12 @@ impl OtcDex {
         }
     }
 
-    #[payable]
-    #[result_serializer(borsh)]
+    #[dex_method(authorized)]
     pub fn set_authorized_key(
         &mut self,
-        #[serializer(borsh)] attached_assets: HashMap<AssetId, U128>,
-        #[serializer(borsh)] args: Vec<u8>,
+        attached_assets: HashMap<AssetId, U128>,
+        key: PublicKey,
     ) -> DexCallResponse {
-        assert_one_yocto();
-        #[near(serializers=[borsh])]
-        struct SetAuthorizedKeyArgs {
-            key: PublicKey,
-        }
-        let Ok(SetAuthorizedKeyArgs { key }) = near_sdk::borsh::from_slice(&args) else {
-            near_sdk::env::panic_str("Invalid args");
-        };
         expect!(attached_assets.is_empty(), "No assets should be attached");
         let storage_usage_before = near_sdk::env::storage_usage();
         self.authorized_keys
@@ -573,19 +581,8 @@ impl OtcDex {
         DexCallResponse::default()
     }
 
-    #[payable]
-    #[result_serializer(borsh)]
-    pub fn deposit_assets(
-        &mut self,
-        #[serializer(borsh)] attached_assets: HashMap<AssetId, U128>,
-        #[serializer(borsh)] args: Vec<u8>,
-    ) -> DexCallResponse {
-        assert_one_yocto();
-        #[near(serializers=[borsh])]
-        struct DepositAssetsArgs;
-        let Ok(DepositAssetsArgs) = near_sdk::borsh::from_slice(&args) else {
-            near_sdk::env::panic_str("Invalid args");
-        };
+    #[dex_method(authorized)]
+    pub fn deposit_assets(&mut self, attached_assets: HashMap<AssetId, U128>) -> DexCallResponse {
         let storage_usage_before = near_sdk::env::storage_usage();
         for (asset_id, amount) in attached_assets.iter() {
             self.balances
@@ -603,30 +600,12 @@ impl OtcDex {
         DexCallResponse::default()
     }
 
-    #[payable]
-    #[result_serializer(borsh)]
+    #[dex_method(authorized)]
     pub fn withdraw_assets(
         &mut self,
-        #[serializer(borsh)] attached_assets: HashMap<AssetId, U128>,
-        #[serializer(borsh)] args: Vec<u8>,
+        attached_assets: HashMap<AssetId, U128>,
+        assets: Vec<WithdrawRequest>,
     ) -> DexCallResponse {
-        assert_one_yocto();
-        #[near(serializers=[borsh])]
-        struct WithdrawAssetsArgs {
-            assets: Vec<WithdrawRequest>,
-        }
-        #[near(serializers=[borsh])]
-        struct WithdrawRequest {
-            asset_id: AssetId,
-            // If None, the entire balance of the asset will be withdrawn.
-            amount: Option<U128>,
-            // If None, the assets will be withdrawn to the user's own account.
-            to: Option<AccountId>,
-            to_inner_balance: bool,
-        }
-        let Ok(WithdrawAssetsArgs { assets }) = near_sdk::borsh::from_slice(&args) else {
-            near_sdk::env::panic_str("Invalid args");
-        };
         expect!(attached_assets.is_empty(), "No assets should be attached");
         let storage_usage_before = near_sdk::env::storage_usage();
         let mut asset_withdraw_requests = Vec::new();
@@ -682,37 +661,23 @@ impl OtcDex {
         }
     }
 
-    #[result_serializer(borsh)]
-    pub fn storage_balance_of(
-        &self,
-        #[serializer(borsh)] account_id: AccountId,
-    ) -> Option<&StorageBalance> {
+    #[dex_method(view)]
+    pub fn storage_balance_of(&self, account_id: AccountId) -> Option<&StorageBalance> {
         self.storage_balances.get(&account_id)
     }
 
-    #[result_serializer(borsh)]
-    pub fn get_authorized_key(
-        &self,
-        #[serializer(borsh)] account_id: &AccountId,
-    ) -> Option<&PublicKey> {
+    #[dex_method(view)]
+    pub fn get_authorized_key(&self, account_id: &AccountId) -> Option<&PublicKey> {
         self.authorized_keys.get(account_id)
     }
 
-    #[result_serializer(borsh)]
-    pub fn is_nonce_used(
-        &self,
-        #[serializer(borsh)] nonce: Nonce,
-        #[serializer(borsh)] account_id: AccountId,
-    ) -> bool {
+    #[dex_method(view)]
+    pub fn is_nonce_used(&self, nonce: Nonce, account_id: AccountId) -> bool {
         self.used_nonces.contains(&(account_id, nonce))
     }
 
-    #[result_serializer(borsh)]
-    pub fn get_balance(
-        &self,
-        #[serializer(borsh)] account_id: AccountId,
-        #[serializer(borsh)] asset_id: AssetId,
-    ) -> Option<&U128> {
+    #[dex_method(view)]
+    pub fn get_balance(&self, account_id: AccountId, asset_id: AssetId) -> Option<&U128> {
         self.balances.get(&(account_id.clone(), asset_id.clone()))
     }
 }
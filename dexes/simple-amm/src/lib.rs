@@ -3,158 +3,145 @@
 use std::collections::HashMap;
 
 use crypto_bigint::U256;
+use intear_dex_sdk::{abi::AbiType, dex, state::DexState};
 use intear_dex_types::{
-    AssetId, AssetWithdrawRequest, AssetWithdrawalType, Dex, DexCallResponse, SwapRequest,
-    SwapRequestAmount, SwapResponse, expect,
+    AssetId, AssetWithdrawRequest, AssetWithdrawalType, Dex, DexCallResponse, DexFeeModel,
+    DexMetadata, DexPair, FlashLoanDex, FlashLoanFeeRequest, SwapRequest, SwapRequestAmount,
+    SwapResponse, expect,
 };
 use near_sdk::{
-    AccountId, BorshStorageKey, NearToken, PanicOnDefault, assert_one_yocto, json_types::U128,
-    near, store::LookupMap,
+    AccountId, BorshStorageKey, NearToken,
+    json_types::{Base64VecU8, U128},
+    near,
+    store::LookupMap,
 };
 
-#[global_allocator]
-static ALLOCATOR: talc::Talck<talc::locking::AssumeUnlockable, talc::ClaimOnOom> = {
-    static mut MEMORY: [u8; 0x8000] = [0; 0x8000]; // 32KB
-    let span = talc::Span::from_array(core::ptr::addr_of!(MEMORY).cast_mut());
-    talc::Talc::new(unsafe { talc::ClaimOnOom::new(span) }).lock()
-};
+intear_dex_sdk::setup_allocator!(0x8000); // 32KB
 
 type PoolId = u64;
 
+/// Fee for flash loans, in basis points. The fee is not added
+/// to any pool and stays in the dex balance.
+const FLASH_LOAN_FEE_BPS: u128 = 30;
+
 /// The simplest possible x*y=k pool, with just one
 /// liquidity provider. Demonstrates the basic functionality
 /// of swaps, adding / withdrawing liquidity, storage
 /// management, and event emission.
-#[near(contract_state)]
-#[derive(PanicOnDefault)]
+#[near(serializers=[borsh])]
 pub struct SimpleAmmDex {
     pools: LookupMap<PoolId, SimplePool>,
     pool_counter: PoolId,
 }
 
+impl DexState for SimpleAmmDex {}
+
 #[near(serializers=[borsh])]
 #[derive(BorshStorageKey)]
 enum StorageKey {
     Pools,
 }
 
-#[near]
+#[dex]
 impl Dex for SimpleAmmDex {
-    #[result_serializer(borsh)]
-    fn swap(&mut self, #[serializer(borsh)] request: SwapRequest) -> SwapResponse {
-        #[near(serializers=[borsh])]
-        struct SwapArgs {
-            pool_id: PoolId,
-        }
-        let Ok(SwapArgs { pool_id }) = near_sdk::borsh::from_slice(&request.message.0) else {
-            panic!("Invalid message");
-        };
+    fn swap(&mut self, request: SwapRequest) -> SwapResponse {
+        let pool_id = pool_id_from_message(&request);
         let Some(pool) = self.pools.get_mut(&pool_id) else {
             panic!("Pool not found");
         };
-        expect!(
-            pool.assets.0.asset_id == request.asset_in
-                || pool.assets.1.asset_id == request.asset_in,
-            "Invalid asset in"
-        );
-        expect!(
-            pool.assets.0.asset_id == request.asset_out
-                || pool.assets.1.asset_id == request.asset_out,
-            "Invalid asset out"
-        );
-        expect!(
-            pool.assets.0.balance.0 > 0 && pool.assets.1.balance.0 > 0,
-            "Pool is empty"
-        );
-        let first_in = pool.assets.0.asset_id == request.asset_in;
+        let response = pool.quote(&request);
+        let (in_balance, out_balance) = if pool.assets.0.asset_id == request.asset_in {
+            (&mut pool.assets.0.balance.0, &mut pool.assets.1.balance.0)
+        } else {
+            (&mut pool.assets.1.balance.0, &mut pool.assets.0.balance.0)
+        };
+        *in_balance = in_balance
+            .checked_add(response.amount_in.0)
+            .expect("Overflow");
+        *out_balance = out_balance
+            .checked_sub(response.amount_out.0)
+            .expect("Underflow");
+        response
+    }
 
-        match request.amount {
-            SwapRequestAmount::ExactIn(exact_amount_in) => {
-                expect!(exact_amount_in.0 > 0, "Amount must be greater than 0");
-                let (in_balance, out_balance) = if first_in {
-                    (&mut pool.assets.0.balance.0, &mut pool.assets.1.balance.0)
-                } else {
-                    (&mut pool.assets.1.balance.0, &mut pool.assets.0.balance.0)
-                };
-                // in_balance was checked to be positive
-                #[allow(clippy::arithmetic_side_effects)]
-                let amount_out = u128::from_le_bytes(
-                    *(U256::from(exact_amount_in.0) * U256::from(*out_balance)
-                        / (U256::from(*in_balance) + U256::from(exact_amount_in.0)))
-                    .to_le_bytes()
-                    .first_chunk()
-                    .unwrap(),
-                );
-                *in_balance = in_balance.checked_add(exact_amount_in.0).expect("Overflow");
-                *out_balance = out_balance.checked_sub(amount_out).expect("Underflow");
-                SwapResponse {
-                    amount_in: exact_amount_in,
-                    amount_out: U128(amount_out),
-                }
-            }
-            SwapRequestAmount::ExactOut(exact_amount_out) => {
-                expect!(exact_amount_out.0 > 0, "Amount must be greater than 0");
-                let (in_balance, out_balance) = if first_in {
-                    (&mut pool.assets.0.balance.0, &mut pool.assets.1.balance.0)
-                } else {
-                    (&mut pool.assets.1.balance.0, &mut pool.assets.0.balance.0)
-                };
-                expect!(
-                    exact_amount_out.0 < *out_balance,
-                    "Amount must be less than out balance"
-                );
-                // amount_out was checked to be less than out_balance
-                #[allow(clippy::arithmetic_side_effects)]
-                let amount_in = u128::from_le_bytes(
-                    *((U256::from(*in_balance) * U256::from(exact_amount_out.0))
-                        / (U256::from(*out_balance) - U256::from(exact_amount_out.0)))
-                    .saturating_add(&U256::ONE)
-                    .to_le_bytes()
-                    .first_chunk()
-                    .unwrap(),
-                );
-                *in_balance = in_balance.checked_add(amount_in).expect("Overflow");
-                *out_balance = out_balance
-                    .checked_sub(exact_amount_out.0)
-                    .expect("Underflow");
-                SwapResponse {
-                    amount_in: U128(amount_in),
-                    amount_out: U128(exact_amount_out.0),
-                }
-            }
+    fn quote(&self, request: SwapRequest) -> SwapResponse {
+        let pool_id = pool_id_from_message(&request);
+        let Some(pool) = self.pools.get(&pool_id) else {
+            panic!("Pool not found");
+        };
+        pool.quote(&request)
+    }
+
+    fn metadata(&self) -> DexMetadata {
+        DexMetadata {
+            name: "Simple AMM".to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            fee_model: DexFeeModel::NoFee,
         }
     }
+
+    fn pairs(&self, from_index: u64, limit: u64) -> Vec<DexPair> {
+        (from_index..from_index.saturating_add(limit).min(self.pool_counter))
+            .filter_map(|pool_id| {
+                let pool = self.pools.get(&pool_id)?;
+                Some(DexPair {
+                    assets: (
+                        pool.assets.0.asset_id.clone(),
+                        pool.assets.1.asset_id.clone(),
+                    ),
+                    message: Base64VecU8(
+                        near_sdk::borsh::to_vec(&SwapArgs { pool_id })
+                            .expect("Failed to serialize"),
+                    ),
+                })
+            })
+            .collect()
+    }
 }
 
-#[near]
+#[near(serializers=[borsh])]
+struct SwapArgs {
+    pool_id: PoolId,
+}
+
+fn pool_id_from_message(request: &SwapRequest) -> PoolId {
+    let Ok(SwapArgs { pool_id }) = near_sdk::borsh::from_slice(&request.message.0) else {
+        panic!("Invalid message");
+    };
+    pool_id
+}
+
+#[dex]
+impl FlashLoanDex for SimpleAmmDex {
+    fn flash_loan_fee(&self, request: FlashLoanFeeRequest) -> U128 {
+        U128(
+            request
+                .amount
+                .0
+                .checked_mul(FLASH_LOAN_FEE_BPS)
+                .expect("Overflow")
+                .div_ceil(10_000),
+        )
+    }
+}
+
+#[dex]
 impl SimpleAmmDex {
-    #[init]
-    #[payable]
+    #[dex_method(init, authorized)]
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
-        assert_one_yocto();
         Self {
             pools: LookupMap::new(StorageKey::Pools),
             pool_counter: 0,
         }
     }
 
-    #[payable]
-    #[result_serializer(borsh)]
+    #[dex_method(authorized)]
     pub fn create_pool(
         &mut self,
-        #[serializer(borsh)]
-        #[allow(unused_mut)]
         mut attached_assets: HashMap<AssetId, U128>,
-        #[serializer(borsh)] args: Vec<u8>,
+        assets: (AssetId, AssetId),
     ) -> DexCallResponse {
-        assert_one_yocto();
-        #[near(serializers=[borsh])]
-        struct CreatePoolArgs {
-            assets: (AssetId, AssetId),
-        }
-        let Ok(CreatePoolArgs { assets }) = near_sdk::borsh::from_slice(&args) else {
-            near_sdk::env::panic_str("Invalid args");
-        };
         expect!(assets.0 != assets.1, "Assets must be different");
 
         let pool_id = self.pool_counter;
@@ -228,26 +215,16 @@ impl SimpleAmmDex {
             },
             add_storage_deposit: storage_cost,
             response: near_sdk::borsh::to_vec(&response).expect("Failed to serialize response"),
+            trader_storage_bytes: 0,
         }
     }
 
-    #[payable]
-    #[result_serializer(borsh)]
+    #[dex_method(authorized)]
     pub fn add_liquidity(
         &mut self,
-        #[allow(unused_mut)]
-        #[serializer(borsh)]
         mut attached_assets: HashMap<AssetId, U128>,
-        #[serializer(borsh)] args: Vec<u8>,
+        pool_id: PoolId,
     ) -> DexCallResponse {
-        assert_one_yocto();
-        #[near(serializers=[borsh])]
-        struct AddLiquidityArgs {
-            pool_id: PoolId,
-        }
-        let Ok(AddLiquidityArgs { pool_id }) = near_sdk::borsh::from_slice(&args) else {
-            near_sdk::env::panic_str("Invalid args");
-        };
         let Some(pool) = self.pools.get_mut(&pool_id) else {
             panic!("Pool not found");
         };
@@ -289,26 +266,13 @@ impl SimpleAmmDex {
         }
     }
 
-    #[payable]
-    #[result_serializer(borsh)]
+    #[dex_method(authorized)]
     pub fn remove_liquidity(
         &mut self,
-        #[serializer(borsh)] attached_assets: HashMap<AssetId, U128>,
-        #[serializer(borsh)] args: Vec<u8>,
+        attached_assets: HashMap<AssetId, U128>,
+        pool_id: PoolId,
+        assets_to_remove: (U128, U128),
     ) -> DexCallResponse {
-        assert_one_yocto();
-        #[near(serializers=[borsh])]
-        struct RemoveLiquidityArgs {
-            pool_id: PoolId,
-            assets_to_remove: (U128, U128),
-        }
-        let Ok(RemoveLiquidityArgs {
-            pool_id,
-            assets_to_remove,
-        }) = near_sdk::borsh::from_slice(&args)
-        else {
-            near_sdk::env::panic_str("Invalid args");
-        };
         expect!(attached_assets.is_empty(), "No assets should be attached");
         let Some(pool) = self.pools.get_mut(&pool_id) else {
             panic!("Pool not found");
@@ -357,18 +321,87 @@ impl SimpleAmmDex {
         }
     }
 
-    #[result_serializer(borsh)]
-    pub fn get_pool(&self, #[serializer(borsh)] pool_id: PoolId) -> Option<&SimplePool> {
+    #[dex_method(view)]
+    pub fn get_pool(&self, pool_id: PoolId) -> Option<&SimplePool> {
         self.pools.get(&pool_id)
     }
 }
 
+#[derive(AbiType)]
 #[near(serializers=[borsh])]
 pub struct SimplePool {
     assets: (AssetWithBalance, AssetWithBalance),
     owner_id: AccountId,
 }
 
+impl SimplePool {
+    /// Amounts of a swap in this pool, without executing it.
+    fn quote(&self, request: &SwapRequest) -> SwapResponse {
+        expect!(
+            self.assets.0.asset_id == request.asset_in
+                || self.assets.1.asset_id == request.asset_in,
+            "Invalid asset in"
+        );
+        expect!(
+            self.assets.0.asset_id == request.asset_out
+                || self.assets.1.asset_id == request.asset_out,
+            "Invalid asset out"
+        );
+        expect!(
+            self.assets.0.balance.0 > 0 && self.assets.1.balance.0 > 0,
+            "Pool is empty"
+        );
+        let (in_balance, out_balance) = if self.assets.0.asset_id == request.asset_in {
+            (self.assets.0.balance.0, self.assets.1.balance.0)
+        } else {
+            (self.assets.1.balance.0, self.assets.0.balance.0)
+        };
+
+        match request.amount {
+            SwapRequestAmount::ExactIn(exact_amount_in) => {
+                expect!(exact_amount_in.0 > 0, "Amount must be greater than 0");
+                // in_balance was checked to be positive
+                #[allow(clippy::arithmetic_side_effects)]
+                let amount_out = u128::from_le_bytes(
+                    *(U256::from(exact_amount_in.0) * U256::from(out_balance)
+                        / (U256::from(in_balance) + U256::from(exact_amount_in.0)))
+                    .to_le_bytes()
+                    .first_chunk()
+                    .unwrap(),
+                );
+                SwapResponse {
+                    amount_in: exact_amount_in,
+                    amount_out: U128(amount_out),
+                    trader_storage_bytes: 0,
+                }
+            }
+            SwapRequestAmount::ExactOut(exact_amount_out) => {
+                expect!(exact_amount_out.0 > 0, "Amount must be greater than 0");
+                expect!(
+                    exact_amount_out.0 < out_balance,
+                    "Amount must be less than out balance"
+                );
+                // amount_out was checked to be less than out_balance
+                #[allow(clippy::arithmetic_side_effects)]
+                let amount_in = u128::from_le_bytes(
+                    *((U256::from(in_balance) * U256::from(exact_amount_out.0))
+                        / (U256::from(out_balance) - U256::from(exact_amount_out.0)))
+                    .saturating_add(&U256::ONE)
+                    .to_le_bytes()
+                    .first_chunk()
+                    .unwrap(),
+                );
+                SwapResponse {
+                    amount_in: U128(amount_in),
+                    amount_out: U128(exact_amount_out.0),
+                    trader_storage_bytes: 0,
+                }
+            }
+        }
+    }
+}
+
+#[derive(AbiType)]
 #[near(serializers=[borsh])]
 pub struct AssetWithBalance {
     asset_id: AssetId,
@@ -0,0 +1,53 @@
+#![no_std]
+#![deny(clippy::arithmetic_side_effects)]
+
+use intear_dex_sdk::{
+    NearToken, env, io,
+    types::{DexCallResponse, DexStorageBalanceBounds, SwapRequestAmount, SwapResponse},
+};
+
+intear_dex_sdk::setup_allocator!(0x1000); // 4KB
+
+#[unsafe(no_mangle)]
+fn swap() {
+    let request = io::swap_request();
+    let amount = match request.amount {
+        SwapRequestAmount::ExactIn(amount) => amount,
+        SwapRequestAmount::ExactOut(amount) => amount,
+    };
+    intear_dex_sdk::require!(
+        request.asset_in == request.asset_out,
+        "Asset in and asset out must be the same, since this dex is a no-op"
+    );
+    // A non-empty message is stored at the expense of the trader
+    let trader_storage_bytes = if request.message.0.is_empty() {
+        0
+    } else {
+        env::storage_write(b"last_message", &request.message.0);
+        u64::MAX
+    };
+    io::return_swap_response(&SwapResponse {
+        amount_in: amount,
+        amount_out: amount,
+        trader_storage_bytes,
+    });
+}
+
+/// Stores the args at the expense of the caller.
+#[unsafe(no_mangle)]
+fn store_message() {
+    let request = io::dex_call_request();
+    env::storage_write(b"last_message", &request.args);
+    io::return_dex_call_response(&DexCallResponse {
+        trader_storage_bytes: u64::MAX,
+        ..Default::default()
+    });
+}
+
+#[unsafe(no_mangle)]
+fn storage_balance_bounds() {
+    io::return_value(&DexStorageBalanceBounds {
+        min: NearToken::from_millinear(100),
+        max: Some(NearToken::from_near(10)),
+    });
+}
@@ -0,0 +1,128 @@
+use std::fmt;
+
+pub use intear_dex_types::IntearDexEvent;
+use near_sdk::{
+    serde::{Deserialize, de::DeserializeOwned},
+    serde_json::{self, Value},
+};
+
+/// Prefix of all NEP-297 event logs.
+pub const EVENT_JSON_PREFIX: &str = "EVENT_JSON:";
+
+/// Standard of the events emitted by the dex engine.
+pub const STANDARD: &str = "inteardex";
+
+/// A parsed `inteardex` event log.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub struct EventLog {
+    /// Version of this event, not of the standard.
+    pub version: String,
+    pub event: IntearDexEvent,
+}
+
+/// NEP-297 event as logged by a dex, wrapped in
+/// [`IntearDexEvent::DexEvent`].
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DexEventPayload {
+    pub standard: String,
+    pub version: String,
+    pub event: String,
+    #[serde(default)]
+    pub data: Option<Value>,
+}
+
+impl DexEventPayload {
+    /// Parses the `event` of an [`IntearDexEvent::DexEvent`].
+    pub fn parse(event: &Value) -> Result<Self, ParseError> {
+        Self::deserialize(event).map_err(ParseError::Json)
+    }
+
+    /// Deserializes `data` into a dex-specific type. Data in a
+    /// one-element array, as in NEP-141 and NEP-171 events, is
+    /// unwrapped first.
+    pub fn parse_data<T: DeserializeOwned>(&self) -> Result<T, ParseError> {
+        let data = match &self.data {
+            Some(Value::Array(items)) if items.len() == 1 => &items[0],
+            Some(data) => data,
+            None => &Value::Null,
+        };
+        T::deserialize(data).map_err(ParseError::Json)
+    }
+}
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub enum ParseError {
+    /// The log is valid JSON, but not a valid NEP-297 event.
+    InvalidEnvelope(String),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidEnvelope(reason) => write!(f, "Invalid event envelope: {reason}"),
+            Self::Json(error) => write!(f, "Failed to parse event: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+struct RawEvent {
+    standard: String,
+    version: String,
+    event: String,
+    data: Value,
+}
+
+/// Parses a single log line of a receipt. Returns `Ok(None)`
+/// for logs that aren't `inteardex` events, such as plain text
+/// logs or events of token contracts.
+pub fn parse_log(log: &str) -> Result<Option<EventLog>, ParseError> {
+    let Some(json) = log.strip_prefix(EVENT_JSON_PREFIX) else {
+        return Ok(None);
+    };
+    let raw: RawEvent = serde_json::from_str(json).map_err(ParseError::Json)?;
+    if raw.standard != STANDARD {
+        return Ok(None);
+    }
+    // Engine events are emitted with near-sdk, which logs the
+    // fields of the variant as an object
+    let data = match raw.data {
+        Value::Object(fields) => Value::Object(fields),
+        _ => {
+            return Err(ParseError::InvalidEnvelope(format!(
+                "data of {} must be an object",
+                raw.event
+            )));
+        }
+    };
+    let mut tagged = serde_json::Map::new();
+    tagged.insert("event".to_string(), Value::String(raw.event));
+    tagged.insert("data".to_string(), data);
+    let event = serde_json::from_value(Value::Object(tagged)).map_err(ParseError::Json)?;
+    Ok(Some(EventLog {
+        version: raw.version,
+        event,
+    }))
+}
+
+/// Parses all `inteardex` events in `logs`, skipping other
+/// logs.
+pub fn parse_logs<'a>(
+    logs: impl IntoIterator<Item = &'a str>,
+) -> Result<Vec<EventLog>, ParseError> {
+    let mut events = Vec::new();
+    for log in logs {
+        if let Some(event) = parse_log(log)? {
+            events.push(event);
+        }
+    }
+    Ok(events)
+}
@@ -0,0 +1,8 @@
+//! Typed parsing of `inteardex` event logs, and a replay engine
+//! that rebuilds balances of the dex engine from them.
+
+pub mod events;
+pub mod replay;
+
+pub use events::{DexEventPayload, EventLog, IntearDexEvent, ParseError, parse_log, parse_logs};
+pub use replay::{Replay, ReplayState, Violation, ViolationKind};
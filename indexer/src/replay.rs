@@ -0,0 +1,479 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use intear_dex_types::{AccountOrDexId, AssetId, DexId};
+use near_sdk::{AccountId, json_types::U128};
+
+use crate::events::{IntearDexEvent, ParseError, parse_log};
+
+/// State of the dex engine that can be rebuilt from its events.
+/// A balance entry exists for every registered asset, same as
+/// in the contract, so it's `0` right after registration.
+#[derive(Clone, Default)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub struct ReplayState {
+    pub user_balances: BTreeMap<(AccountId, AssetId), U128>,
+    pub dex_balances: BTreeMap<(DexId, AssetId), U128>,
+    /// Assets of failed withdrawals that couldn't be returned
+    /// because the asset was unregistered in the meantime.
+    pub held_assets: BTreeMap<(AccountOrDexId, AssetId), U128>,
+    /// Sum of all balances and held assets. Assets of pending
+    /// withdrawals are not included, same as in the contract.
+    pub total_in_custody: BTreeMap<AssetId, U128>,
+}
+
+impl ReplayState {
+    pub fn balance_of(&self, owner: &AccountOrDexId, asset_id: &AssetId) -> Option<U128> {
+        match owner {
+            AccountOrDexId::Account(account) => self
+                .user_balances
+                .get(&(account.clone(), asset_id.clone()))
+                .copied(),
+            AccountOrDexId::Dex(dex_id) => self
+                .dex_balances
+                .get(&(dex_id.clone(), asset_id.clone()))
+                .copied(),
+        }
+    }
+
+    fn balance_mut(&mut self, owner: &AccountOrDexId, asset_id: &AssetId) -> Option<&mut U128> {
+        match owner {
+            AccountOrDexId::Account(account) => self
+                .user_balances
+                .get_mut(&(account.clone(), asset_id.clone())),
+            AccountOrDexId::Dex(dex_id) => self
+                .dex_balances
+                .get_mut(&(dex_id.clone(), asset_id.clone())),
+        }
+    }
+
+    fn insert_balance(&mut self, owner: &AccountOrDexId, asset_id: &AssetId, balance: U128) {
+        match owner {
+            AccountOrDexId::Account(account) => self
+                .user_balances
+                .insert((account.clone(), asset_id.clone()), balance),
+            AccountOrDexId::Dex(dex_id) => self
+                .dex_balances
+                .insert((dex_id.clone(), asset_id.clone()), balance),
+        };
+    }
+
+    fn remove_balance(&mut self, owner: &AccountOrDexId, asset_id: &AssetId) -> Option<U128> {
+        match owner {
+            AccountOrDexId::Account(account) => self
+                .user_balances
+                .remove(&(account.clone(), asset_id.clone())),
+            AccountOrDexId::Dex(dex_id) => self
+                .dex_balances
+                .remove(&(dex_id.clone(), asset_id.clone())),
+        }
+    }
+}
+
+/// What went wrong in a [`Violation`].
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub enum ViolationKind {
+    /// A balance changed for an asset that isn't registered.
+    BalanceOfUnregisteredAsset {
+        owner: AccountOrDexId,
+        asset_id: AssetId,
+    },
+    /// An asset was unregistered while its balance wasn't zero.
+    UnregisteredWithBalance {
+        owner: AccountOrDexId,
+        asset_id: AssetId,
+        balance: U128,
+    },
+    /// `UserDeposit` wasn't preceded by a balance update of the
+    /// same amount.
+    DepositMismatch {
+        account_id: AccountId,
+        asset_id: AssetId,
+        amount: U128,
+    },
+    /// `AssetTransfer` wasn't preceded by balance updates of
+    /// the same amount on both sides.
+    TransferMismatch {
+        from: AccountOrDexId,
+        to: AccountOrDexId,
+        asset_id: AssetId,
+        amount: U128,
+    },
+    /// Held assets were withdrawn in a different amount than
+    /// what was held.
+    HeldAssetsMismatch {
+        owner: AccountOrDexId,
+        asset_id: AssetId,
+        replayed: U128,
+        withdrawn: U128,
+    },
+    /// `total_in_custody` reported by the contract differs from
+    /// the replayed one.
+    CustodyMismatch {
+        asset_id: AssetId,
+        replayed: U128,
+        reported: U128,
+    },
+    /// The contract holds less than it owes.
+    CustodyDeficit { asset_id: AssetId, deficit: U128 },
+    /// Replayed custody would go below zero or above
+    /// `u128::MAX`.
+    CustodyOverflow { asset_id: AssetId },
+}
+
+/// An invariant that didn't hold after applying an event.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub struct Violation {
+    /// Index of the event in the stream, counting only
+    /// `inteardex` events.
+    pub event_index: usize,
+    pub kind: ViolationKind,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Event #{}: ", self.event_index)?;
+        match &self.kind {
+            ViolationKind::BalanceOfUnregisteredAsset { owner, asset_id } => {
+                write!(f, "balance of unregistered {asset_id} changed for {owner}")
+            }
+            ViolationKind::UnregisteredWithBalance {
+                owner,
+                asset_id,
+                balance,
+            } => write!(
+                f,
+                "{asset_id} was unregistered for {owner} with a balance of {}",
+                balance.0
+            ),
+            ViolationKind::DepositMismatch {
+                account_id,
+                asset_id,
+                amount,
+            } => write!(
+                f,
+                "deposit of {} {asset_id} to {account_id} doesn't match its balance update",
+                amount.0
+            ),
+            ViolationKind::TransferMismatch {
+                from,
+                to,
+                asset_id,
+                amount,
+            } => write!(
+                f,
+                "transfer of {} {asset_id} from {from} to {to} doesn't match its balance updates",
+                amount.0
+            ),
+            ViolationKind::HeldAssetsMismatch {
+                owner,
+                asset_id,
+                replayed,
+                withdrawn,
+            } => write!(
+                f,
+                "{} held {asset_id} of {owner} were withdrawn, but {} were held",
+                withdrawn.0, replayed.0
+            ),
+            ViolationKind::CustodyMismatch {
+                asset_id,
+                replayed,
+                reported,
+            } => write!(
+                f,
+                "total in custody of {asset_id} is {}, but {} was replayed",
+                reported.0, replayed.0
+            ),
+            ViolationKind::CustodyDeficit { asset_id, deficit } => {
+                write!(f, "custody of {asset_id} has a deficit of {}", deficit.0)
+            }
+            ViolationKind::CustodyOverflow { asset_id } => {
+                write!(f, "replayed custody of {asset_id} overflowed")
+            }
+        }
+    }
+}
+
+/// Rebuilds [`ReplayState`] from an ordered stream of events,
+/// starting from the deployment of the engine or from a known
+/// state, and checks invariants along the way.
+#[derive(Default)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub struct Replay {
+    state: ReplayState,
+    violations: Vec<Violation>,
+    events_applied: usize,
+    /// Balance changes (before, after) since the last event
+    /// that wasn't a balance update. Events like `UserDeposit`
+    /// are emitted right after the balance updates they cause.
+    recent_changes: BTreeMap<(AccountOrDexId, AssetId), (u128, u128)>,
+}
+
+impl Replay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_state(state: ReplayState) -> Self {
+        Self {
+            state,
+            ..Self::default()
+        }
+    }
+
+    pub const fn state(&self) -> &ReplayState {
+        &self.state
+    }
+
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    pub fn into_parts(self) -> (ReplayState, Vec<Violation>) {
+        (self.state, self.violations)
+    }
+
+    /// Parses and applies a log line, ignoring logs that
+    /// aren't `inteardex` events.
+    pub fn apply_log(&mut self, log: &str) -> Result<(), ParseError> {
+        if let Some(log) = parse_log(log)? {
+            self.apply(&log.event);
+        }
+        Ok(())
+    }
+
+    pub fn apply_logs<'a>(
+        &mut self,
+        logs: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(), ParseError> {
+        for log in logs {
+            self.apply_log(log)?;
+        }
+        Ok(())
+    }
+
+    pub fn apply(&mut self, event: &IntearDexEvent) {
+        match event {
+            IntearDexEvent::UserBalanceUpdate {
+                account_id,
+                asset_id,
+                balance,
+            } => {
+                self.update_balance(
+                    AccountOrDexId::Account(account_id.clone()),
+                    asset_id,
+                    *balance,
+                );
+            }
+            IntearDexEvent::DexBalanceUpdate {
+                dex_id,
+                asset_id,
+                balance,
+            } => {
+                self.update_balance(AccountOrDexId::Dex(dex_id.clone()), asset_id, *balance);
+            }
+            event => {
+                self.apply_other(event);
+                self.recent_changes.clear();
+            }
+        }
+        self.events_applied = self.events_applied.saturating_add(1);
+    }
+
+    fn update_balance(&mut self, owner: AccountOrDexId, asset_id: &AssetId, balance: U128) {
+        let before = match self.state.balance_mut(&owner, asset_id) {
+            Some(current) => std::mem::replace(current, balance).0,
+            None => {
+                self.report(ViolationKind::BalanceOfUnregisteredAsset {
+                    owner: owner.clone(),
+                    asset_id: asset_id.clone(),
+                });
+                self.state.insert_balance(&owner, asset_id, balance);
+                0
+            }
+        };
+        self.change_custody(asset_id, before, balance.0);
+        self.recent_changes
+            .entry((owner, asset_id.clone()))
+            .and_modify(|(_, after)| *after = balance.0)
+            .or_insert((before, balance.0));
+    }
+
+    fn apply_other(&mut self, event: &IntearDexEvent) {
+        match event {
+            IntearDexEvent::AssetsRegistered {
+                r#for, asset_ids, ..
+            } => {
+                for asset_id in asset_ids {
+                    if self.state.balance_of(r#for, asset_id).is_none() {
+                        self.state.insert_balance(r#for, asset_id, U128(0));
+                    }
+                    self.state
+                        .total_in_custody
+                        .entry(asset_id.clone())
+                        .or_default();
+                }
+            }
+            IntearDexEvent::AssetsUnregistered {
+                r#for, asset_ids, ..
+            } => {
+                for asset_id in asset_ids {
+                    let Some(balance) = self.state.remove_balance(r#for, asset_id) else {
+                        continue;
+                    };
+                    if balance.0 != 0 {
+                        self.change_custody(asset_id, balance.0, 0);
+                        self.report(ViolationKind::UnregisteredWithBalance {
+                            owner: r#for.clone(),
+                            asset_id: asset_id.clone(),
+                            balance,
+                        });
+                    }
+                }
+            }
+            IntearDexEvent::UserDeposit {
+                account_id,
+                asset_id,
+                amount,
+            } => {
+                let owner = AccountOrDexId::Account(account_id.clone());
+                if self.recent_increase(&owner, asset_id) != Some(amount.0) {
+                    self.report(ViolationKind::DepositMismatch {
+                        account_id: account_id.clone(),
+                        asset_id: asset_id.clone(),
+                        amount: *amount,
+                    });
+                }
+            }
+            IntearDexEvent::AssetTransfer {
+                from,
+                to,
+                asset_id,
+                amount,
+                sandboxed,
+            } => {
+                // Transfers to self don't change the balance
+                if from != to {
+                    let received = self.recent_increase(to, asset_id) == Some(amount.0);
+                    let sent = *sandboxed || self.recent_decrease(from, asset_id) == Some(amount.0);
+                    if !received || !sent {
+                        self.report(ViolationKind::TransferMismatch {
+                            from: from.clone(),
+                            to: to.clone(),
+                            asset_id: asset_id.clone(),
+                            amount: *amount,
+                        });
+                    }
+                }
+            }
+            IntearDexEvent::AssetsHeld {
+                owner,
+                asset_id,
+                amount,
+            } => {
+                let held = self
+                    .state
+                    .held_assets
+                    .entry((owner.clone(), asset_id.clone()))
+                    .or_default();
+                let before = held.0;
+                held.0 = held.0.saturating_add(amount.0);
+                let after = held.0;
+                self.change_custody(asset_id, before, after);
+            }
+            IntearDexEvent::HeldAssetsWithdrawn {
+                owner,
+                asset_id,
+                amount,
+            } => {
+                let held = self
+                    .state
+                    .held_assets
+                    .remove(&(owner.clone(), asset_id.clone()))
+                    .unwrap_or_default();
+                self.change_custody(asset_id, held.0, 0);
+                if held != *amount {
+                    self.report(ViolationKind::HeldAssetsMismatch {
+                        owner: owner.clone(),
+                        asset_id: asset_id.clone(),
+                        replayed: held,
+                        withdrawn: *amount,
+                    });
+                }
+            }
+            IntearDexEvent::CustodyReconciled {
+                asset_id,
+                total_in_custody,
+                deficit,
+                ..
+            } => {
+                let replayed = self
+                    .state
+                    .total_in_custody
+                    .get(asset_id)
+                    .copied()
+                    .unwrap_or_default();
+                if replayed != *total_in_custody {
+                    self.report(ViolationKind::CustodyMismatch {
+                        asset_id: asset_id.clone(),
+                        replayed,
+                        reported: *total_in_custody,
+                    });
+                }
+                if deficit.0 != 0 {
+                    self.report(ViolationKind::CustodyDeficit {
+                        asset_id: asset_id.clone(),
+                        deficit: *deficit,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn recent_increase(&self, owner: &AccountOrDexId, asset_id: &AssetId) -> Option<u128> {
+        let (before, after) = self
+            .recent_changes
+            .get(&(owner.clone(), asset_id.clone()))?;
+        after.checked_sub(*before)
+    }
+
+    fn recent_decrease(&self, owner: &AccountOrDexId, asset_id: &AssetId) -> Option<u128> {
+        let (before, after) = self
+            .recent_changes
+            .get(&(owner.clone(), asset_id.clone()))?;
+        before.checked_sub(*after)
+    }
+
+    /// Replaces `before` with `after` in the custody of
+    /// `asset_id`.
+    fn change_custody(&mut self, asset_id: &AssetId, before: u128, after: u128) {
+        let custody = self
+            .state
+            .total_in_custody
+            .entry(asset_id.clone())
+            .or_default();
+        match custody
+            .0
+            .checked_sub(before)
+            .and_then(|c| c.checked_add(after))
+        {
+            Some(new) => custody.0 = new,
+            None => {
+                custody.0 = custody.0.saturating_sub(before).saturating_add(after);
+                self.report(ViolationKind::CustodyOverflow {
+                    asset_id: asset_id.clone(),
+                });
+            }
+        }
+    }
+
+    fn report(&mut self, kind: ViolationKind) {
+        self.violations.push(Violation {
+            event_index: self.events_applied,
+            kind,
+        });
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+
+use intear_dex_indexer::{IntearDexEvent, parse_log};
+use intear_dex_types::{
+    AccountOrDexId, AssetId, AssetWithdrawRequest, AssetWithdrawalType, CandidateQuote, DexId,
+    ExecutedHop, StorageCategory, SwapFees, SwapRequest, SwapRequestAmount,
+};
+use near_sdk::{
+    AccountId, NearToken,
+    json_types::{Base64VecU8, U128},
+    serde_json::json,
+};
+
+fn user1() -> AccountId {
+    "user1.test.near".parse().unwrap()
+}
+
+fn dex() -> DexId {
+    "user1.test.near/dex".parse().unwrap()
+}
+
+fn swap_request() -> SwapRequest {
+    SwapRequest {
+        message: Base64VecU8(vec![0; 8]),
+        asset_in: AssetId::Near,
+        asset_out: AssetId::Nep141("ft1.test.near".parse().unwrap()),
+        amount: SwapRequestAmount::ExactIn(U128(1_000)),
+    }
+}
+
+/// Logs the events the same way `emit` does, and parses them back.
+#[test]
+fn test_parse_emitted_events() {
+    let events = [
+        IntearDexEvent::DexDeployed {
+            dex_id: dex(),
+            code_hash: [7; 32].into(),
+        },
+        IntearDexEvent::DexEvent {
+            dex_id: dex(),
+            event: json!({ "standard": "simpleswap", "version": "1.0.0", "event": "test" }),
+        },
+        IntearDexEvent::Withdraw {
+            from: AccountOrDexId::Account(user1()),
+            to: user1(),
+            asset_id: AssetId::Near,
+            amount: U128(5),
+            storage_deposit: None,
+        },
+        IntearDexEvent::Withdraw {
+            from: AccountOrDexId::Dex(dex()),
+            to: user1(),
+            asset_id: AssetId::Nep141("ft1.test.near".parse().unwrap()),
+            amount: U128(5),
+            storage_deposit: Some(NearToken::from_millinear(1)),
+        },
+        IntearDexEvent::Swap {
+            dex_id: dex(),
+            request: swap_request(),
+            amount_in: U128(1_000),
+            amount_out: U128(990),
+            trader: user1(),
+            quotes: Some(vec![CandidateQuote {
+                dex_id: dex(),
+                amount_in: Some(U128(1_000)),
+                amount_out: None,
+            }]),
+            fees: Some(SwapFees {
+                asset_id: AssetId::Near,
+                protocol_fee: U128(10),
+                protocol_fee_recipient: user1(),
+                referral_fee: U128(0),
+                referral: None,
+            }),
+        },
+        IntearDexEvent::SwapRoute {
+            asset_in: AssetId::Near,
+            asset_out: AssetId::Nep141("ft1.test.near".parse().unwrap()),
+            amount_in: U128(1_000),
+            amount_out: U128(990),
+            hops: vec![ExecutedHop {
+                dex_id: dex(),
+                request: swap_request(),
+                amount_in: U128(1_000),
+                amount_out: U128(990),
+            }],
+            trader: user1(),
+            fees: None,
+        },
+        IntearDexEvent::SwapFeeSkipped {
+            recipient: user1(),
+            asset_id: AssetId::Near,
+            amount: U128(3),
+        },
+        IntearDexEvent::FeeConfigUpdated {
+            protocol_fee_bps: 30,
+            protocol_fee_recipient: user1(),
+            max_referral_fee_bps: 100,
+        },
+        IntearDexEvent::TreasuryUpdated {
+            old_treasury: user1(),
+            new_treasury: "treasury.near".parse().unwrap(),
+        },
+        IntearDexEvent::EnginePaused {},
+        IntearDexEvent::StorageToppedUp {
+            r#for: AccountOrDexId::Dex(dex()),
+            amount: NearToken::from_millinear(1),
+            used: NearToken::from_millinear(3),
+            cap: NearToken::from_near(1),
+        },
+        IntearDexEvent::StorageThresholdCrossed {
+            of: AccountOrDexId::Dex(dex()),
+            category: StorageCategory::Code,
+            bytes: 181_346,
+            threshold: 180_000,
+        },
+        IntearDexEvent::DexCall {
+            dex_id: dex(),
+            method: "create_pool".to_string(),
+            caller: user1(),
+            sandboxed: false,
+            attached_assets: HashMap::from([(AssetId::Near, U128(10))]),
+            asset_withdraw_requests: vec![AssetWithdrawRequest {
+                asset_id: AssetId::Near,
+                amount: U128(3),
+                withdrawal_type: AssetWithdrawalType::ToInternalUserBalance(user1()),
+            }],
+        },
+    ];
+    for event in events {
+        let log = format!("EVENT_JSON:{}", event.to_json());
+        let parsed = parse_log(&log).unwrap().unwrap();
+        assert_eq!(parsed.version, event.version());
+        assert_eq!(parsed.event.to_json(), event.to_json());
+    }
+}
@@ -0,0 +1,220 @@
+use intear_dex_indexer::{
+    DexEventPayload, IntearDexEvent, Replay, Violation, ViolationKind, parse_log, parse_logs,
+};
+use intear_dex_types::{AccountOrDexId, AssetId, DexId};
+use near_sdk::{AccountId, PublicKey, json_types::U128, serde::Deserialize};
+
+/// Logs of `setup_simple_amm_pools` followed by a withdrawal and
+/// two custody reconciliations, in the order they were emitted.
+/// Regenerated from a sandbox run with
+/// `UPDATE_INDEXER_FIXTURES=1 cargo test --test engine test_indexer_replays_engine_logs`
+/// in the repository root.
+const SIMPLE_AMM_SETUP: &str = include_str!("fixtures/simple_amm_setup.log");
+
+fn user1() -> AccountId {
+    "user1.test.near".parse().unwrap()
+}
+
+fn dex() -> DexId {
+    "user1.test.near/dex".parse().unwrap()
+}
+
+fn ft(name: &str) -> AssetId {
+    AssetId::Nep141(format!("{name}.test.near").parse().unwrap())
+}
+
+fn replay_logs(logs: &str) -> Replay {
+    let mut replay = Replay::new();
+    replay.apply_logs(logs.lines()).unwrap();
+    replay
+}
+
+#[test]
+fn test_parse_engine_events() {
+    let events = parse_logs(SIMPLE_AMM_SETUP.lines()).unwrap();
+    // nep141 events of the token contracts are skipped
+    assert_eq!(events.len(), 27);
+    assert!(matches!(
+        &events[0].event,
+        IntearDexEvent::DexStorageDeposit { dex_id, .. } if *dex_id == dex()
+    ));
+    let withdraw = events
+        .iter()
+        .find(|log| matches!(log.event, IntearDexEvent::Withdraw { .. }))
+        .unwrap();
+    assert_eq!(withdraw.version, "1.1.0");
+    let IntearDexEvent::Withdraw {
+        from,
+        asset_id,
+        amount,
+        storage_deposit,
+        ..
+    } = &withdraw.event
+    else {
+        unreachable!()
+    };
+    assert_eq!(*from, AccountOrDexId::Account(user1()));
+    assert_eq!(*asset_id, ft("ft2"));
+    assert_eq!(*amount, U128(250_000));
+    assert!(storage_deposit.is_none());
+
+    assert!(
+        parse_log("Transfer 1000000 from user1.test.near")
+            .unwrap()
+            .is_none()
+    );
+    assert!(
+        parse_log(r#"EVENT_JSON:{"standard":"inteardex","version":"1.0.0","event":"engine_paused","data":[]}"#)
+            .is_err()
+    );
+}
+
+#[test]
+fn test_parse_dex_event() {
+    #[derive(Deserialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct AuthorizedKeyChanged {
+        account_id: AccountId,
+        key: PublicKey,
+    }
+
+    let log = r#"EVENT_JSON:{"standard":"inteardex","version":"1.0.0","event":"dex_event","data":{"dex_id":"user1.test.near/otc","event":{"standard":"simpleswap","version":"1.0.0","event":"authorized_key_changed","data":{"account_id":"user2.test.near","key":"ed25519:6E8sCci9badyRkXb3JoRpBj5p8C6Tw41ELDZoiihKEtp"}}}}"#;
+    let IntearDexEvent::DexEvent { dex_id, event } = parse_log(log).unwrap().unwrap().event else {
+        panic!("Expected a dex event");
+    };
+    assert_eq!(dex_id, "user1.test.near/otc".parse().unwrap());
+    let payload = DexEventPayload::parse(&event).unwrap();
+    assert_eq!(payload.standard, "simpleswap");
+    assert_eq!(payload.event, "authorized_key_changed");
+    let data: AuthorizedKeyChanged = payload.parse_data().unwrap();
+    assert_eq!(
+        data.account_id,
+        "user2.test.near".parse::<AccountId>().unwrap()
+    );
+    assert_eq!(
+        data.key,
+        "ed25519:6E8sCci9badyRkXb3JoRpBj5p8C6Tw41ELDZoiihKEtp"
+            .parse()
+            .unwrap()
+    );
+}
+
+#[test]
+fn test_replay_simple_amm_setup() {
+    let replay = replay_logs(SIMPLE_AMM_SETUP);
+    assert_eq!(replay.violations(), []);
+
+    let state = replay.state();
+    let user = AccountOrDexId::Account(user1());
+    let dex = AccountOrDexId::Dex(dex());
+    assert_eq!(
+        state.balance_of(&user, &AssetId::Near),
+        Some(U128(3_990_000_000_000_000_000_000_000))
+    );
+    assert_eq!(state.balance_of(&user, &ft("ft1")), Some(U128(500_000)));
+    assert_eq!(state.balance_of(&user, &ft("ft2")), Some(U128(750_000)));
+    assert_eq!(
+        state.balance_of(&dex, &AssetId::Near),
+        Some(U128(1_000_000_000_000_000_000_000_000))
+    );
+    assert_eq!(state.balance_of(&dex, &ft("ft1")), Some(U128(500_000)));
+    // Registered, but never received anything
+    assert_eq!(state.balance_of(&dex, &ft("ft2")), Some(U128(0)));
+
+    // NEAR moved to the storage balance of the dex is no longer
+    // in custody
+    assert_eq!(
+        state.total_in_custody.get(&AssetId::Near),
+        Some(&U128(4_990_000_000_000_000_000_000_000))
+    );
+    assert_eq!(
+        state.total_in_custody.get(&ft("ft1")),
+        Some(&U128(1_000_000))
+    );
+    assert_eq!(state.total_in_custody.get(&ft("ft2")), Some(&U128(750_000)));
+}
+
+#[test]
+fn test_replay_tampered_logs() {
+    // Drop the balance update of the ft1 deposit
+    let logs = SIMPLE_AMM_SETUP
+        .lines()
+        .filter(|log| !log.contains(r#""asset_id":"nep141:ft1.test.near","balance":"1000000"}"#))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let replay = replay_logs(&logs);
+    assert_eq!(
+        replay.violations(),
+        [Violation {
+            event_index: 8,
+            kind: ViolationKind::DepositMismatch {
+                account_id: user1(),
+                asset_id: ft("ft1"),
+                amount: U128(1_000_000),
+            },
+        }]
+    );
+
+    let logs = SIMPLE_AMM_SETUP.replace(
+        r#""total_in_custody":"750000""#,
+        r#""total_in_custody":"760000""#,
+    );
+    let replay = replay_logs(&logs);
+    assert_eq!(
+        replay.violations(),
+        [Violation {
+            event_index: 26,
+            kind: ViolationKind::CustodyMismatch {
+                asset_id: ft("ft2"),
+                replayed: U128(750_000),
+                reported: U128(760_000),
+            },
+        }]
+    );
+}
+
+#[test]
+fn test_replay_held_assets_and_unregistration() {
+    let logs = [
+        r#"EVENT_JSON:{"standard":"inteardex","version":"1.0.0","event":"assets_registered","data":{"for":{"Account":"user1.test.near"},"asset_ids":["nep141:ft1.test.near"],"storage_payer":"user1.test.near"}}"#,
+        r#"EVENT_JSON:{"standard":"inteardex","version":"1.0.0","event":"user_balance_update","data":{"account_id":"user1.test.near","asset_id":"nep141:ft1.test.near","balance":"100"}}"#,
+        r#"EVENT_JSON:{"standard":"inteardex","version":"1.0.0","event":"user_deposit","data":{"account_id":"user1.test.near","asset_id":"nep141:ft1.test.near","amount":"100"}}"#,
+        r#"EVENT_JSON:{"standard":"inteardex","version":"1.0.0","event":"assets_unregistered","data":{"for":{"Account":"user1.test.near"},"asset_ids":["nep141:ft1.test.near"],"storage_refunded_to":"user1.test.near"}}"#,
+        r#"EVENT_JSON:{"standard":"inteardex","version":"1.0.0","event":"assets_held","data":{"owner":{"Account":"user1.test.near"},"asset_id":"nep141:ft1.test.near","amount":"40"}}"#,
+        r#"EVENT_JSON:{"standard":"inteardex","version":"1.0.0","event":"custody_reconciled","data":{"asset_id":"nep141:ft1.test.near","balance":"40","total_in_custody":"40","pending_withdrawals":"0","surplus":"0","deficit":"0"}}"#,
+        r#"EVENT_JSON:{"standard":"inteardex","version":"1.0.0","event":"held_assets_withdrawn","data":{"owner":{"Account":"user1.test.near"},"asset_id":"nep141:ft1.test.near","amount":"40"}}"#,
+        r#"EVENT_JSON:{"standard":"inteardex","version":"1.0.0","event":"user_balance_update","data":{"account_id":"user1.test.near","asset_id":"nep141:ft1.test.near","balance":"5"}}"#,
+    ];
+    let mut replay = Replay::new();
+    replay.apply_logs(logs).unwrap();
+    let kinds = replay
+        .violations()
+        .iter()
+        .map(|violation| (violation.event_index, violation.kind.clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        kinds,
+        [
+            (
+                3,
+                ViolationKind::UnregisteredWithBalance {
+                    owner: AccountOrDexId::Account(user1()),
+                    asset_id: ft("ft1"),
+                    balance: U128(100),
+                }
+            ),
+            (
+                7,
+                ViolationKind::BalanceOfUnregisteredAsset {
+                    owner: AccountOrDexId::Account(user1()),
+                    asset_id: ft("ft1"),
+                }
+            ),
+        ]
+    );
+    assert!(replay.state().held_assets.is_empty());
+    assert_eq!(
+        replay.state().total_in_custody.get(&ft("ft1")),
+        Some(&U128(5))
+    );
+}
@@ -0,0 +1,518 @@
+//! `#[dex]` and `#[dex_method]`, re-exported by `intear-dex-sdk`.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    Data, DeriveInput, Fields, FnArg, ImplItem, ImplItemFn, ItemImpl, Pat, ReturnType, Type,
+    ext::IdentExt, parse_macro_input, spanned::Spanned,
+};
+
+/// Must match `intear_dex_types::DEX_METHODS_SECTION`.
+const DEX_METHODS_SECTION: &str = "intear_dex_methods";
+/// Must match `intear_dex_types::DEX_ABI_SECTION`.
+const DEX_ABI_SECTION: &str = "intear_dex_abi";
+
+/// Exports methods of the dex from an `impl` block.
+///
+/// On an inherent `impl`, exports the methods marked with
+/// `#[dex_method]`. Methods called with `dex_call` receive the
+/// borsh-decoded args as their parameters, and the attached
+/// assets as a parameter named `attached_assets`. They return
+/// `DexCallResponse`, or any other value that is returned as
+/// its `response`.
+///
+/// On a trait `impl`, such as `Dex` or `FlashLoanDex`, exports
+/// every method with its parameters decoded from the input and
+/// its result returned as is.
+///
+/// The state is loaded with `intear_dex_sdk::state::load` before
+/// the method, and saved after methods that take `&mut self`.
+///
+/// The borsh schema of each `#[dex_method]` is embedded in the
+/// `intear_dex_abi` section, so the types of its parameters and
+/// result must implement `intear_dex_sdk::abi::AbiType`.
+#[proc_macro_attribute]
+pub fn dex(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return syn::Error::new(
+            TokenStream2::from(attr).span(),
+            "#[dex] doesn't take arguments",
+        )
+        .to_compile_error()
+        .into();
+    }
+    let mut item_impl = parse_macro_input!(item as ItemImpl);
+    match expand_dex(&mut item_impl) {
+        Ok(exports) => quote! {
+            #item_impl
+            #exports
+        }
+        .into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// Marks a method of a `#[dex]` impl block as callable by the
+/// engine. Accepts the flags:
+/// - `view`: called with `dex_view`, with the args as input.
+/// - `init`: creates the state, once.
+/// - `authorized`: can only be called with `dex_call` from the
+///   caller's own balance, not from sandboxed operations.
+#[proc_macro_attribute]
+pub fn dex_method(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    let item = TokenStream2::from(item);
+    syn::Error::new(
+        item.span(),
+        "#[dex_method] can only be used on methods of a #[dex] impl block",
+    )
+    .to_compile_error()
+    .into()
+}
+
+/// Implements `intear_dex_sdk::abi::AbiType` for a struct or an
+/// enum whose fields implement it. Fields with `#[borsh(skip)]`
+/// are left out, like borsh does.
+#[proc_macro_derive(AbiType, attributes(borsh))]
+pub fn derive_abi_type(item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as DeriveInput);
+    match expand_abi_type(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn expand_abi_type(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new(
+            input.generics.span(),
+            "AbiType can't be derived for generic types",
+        ));
+    }
+    let abi = quote!(::intear_dex_sdk::abi);
+    let ident = &input.ident;
+    let name = ident.unraw().to_string();
+    let schema = match &input.data {
+        Data::Struct(data) => {
+            let fields = abi_fields(&data.fields);
+            quote!(#abi::Schema::Struct { name: #name, fields: #fields })
+        }
+        Data::Enum(data) => {
+            let variants = data.variants.iter().map(|variant| {
+                let variant_name = variant.ident.unraw().to_string();
+                let fields = abi_fields(&variant.fields);
+                quote!((#variant_name, #fields))
+            });
+            quote!(#abi::Schema::Enum { name: #name, variants: &[#(#variants),*] })
+        }
+        Data::Union(_) => {
+            return Err(syn::Error::new(
+                input.span(),
+                "AbiType can't be derived for unions",
+            ));
+        }
+    };
+    Ok(quote! {
+        impl #abi::AbiType for #ident {
+            const SCHEMA: #abi::Schema = #schema;
+        }
+    })
+}
+
+fn abi_fields(fields: &Fields) -> TokenStream2 {
+    let abi = quote!(::intear_dex_sdk::abi);
+    match fields {
+        Fields::Named(fields) => {
+            let fields = fields
+                .named
+                .iter()
+                .filter(|field| !is_borsh_skipped(field))
+                .map(|field| {
+                    let name = field.ident.as_ref().map(|ident| ident.unraw().to_string());
+                    let ty = &field.ty;
+                    quote!((#name, &<#ty as #abi::AbiType>::SCHEMA))
+                });
+            quote!(#abi::Fields::Named(&[#(#fields),*]))
+        }
+        Fields::Unnamed(fields) => {
+            let fields = fields
+                .unnamed
+                .iter()
+                .filter(|field| !is_borsh_skipped(field))
+                .map(|field| {
+                    let ty = &field.ty;
+                    quote!(&<#ty as #abi::AbiType>::SCHEMA)
+                });
+            quote!(#abi::Fields::Unnamed(&[#(#fields),*]))
+        }
+        Fields::Unit => quote!(#abi::Fields::Unit),
+    }
+}
+
+fn is_borsh_skipped(field: &syn::Field) -> bool {
+    let mut skipped = false;
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("borsh"))
+    {
+        // Other borsh arguments are checked by borsh itself
+        let _ = attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("skip") {
+                skipped = true;
+            } else if meta.input.peek(syn::Token![=]) {
+                meta.value()?.parse::<syn::Expr>()?;
+            }
+            Ok(())
+        });
+    }
+    skipped
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum MethodKind {
+    Call,
+    View,
+    Init,
+    /// A method of a trait impl.
+    Export,
+}
+
+impl MethodKind {
+    /// Variant index of `intear_dex_types::DexMethodKind`.
+    fn manifest_index(self) -> Option<u8> {
+        match self {
+            MethodKind::Call => Some(0),
+            MethodKind::View => Some(1),
+            MethodKind::Init => Some(2),
+            MethodKind::Export => None,
+        }
+    }
+
+    fn receives_dex_call_request(self) -> bool {
+        matches!(self, MethodKind::Call | MethodKind::Init)
+    }
+}
+
+struct MethodAttr {
+    kind: MethodKind,
+    authorized: bool,
+}
+
+fn expand_dex(item_impl: &mut ItemImpl) -> syn::Result<TokenStream2> {
+    if !item_impl.generics.params.is_empty() {
+        return Err(syn::Error::new(
+            item_impl.generics.span(),
+            "#[dex] can't be used on generic impl blocks",
+        ));
+    }
+    let self_ty = &item_impl.self_ty;
+    let qualified_self = match &item_impl.trait_ {
+        Some((_, trait_path, _)) => quote!(<#self_ty as #trait_path>),
+        None => quote!(<#self_ty>),
+    };
+    let is_trait_impl = item_impl.trait_.is_some();
+
+    let mut exports = TokenStream2::new();
+    for item in item_impl.items.iter_mut() {
+        let ImplItem::Fn(method) = item else {
+            continue;
+        };
+        let attr = take_method_attr(method)?;
+        let attr = match (attr, is_trait_impl) {
+            (Some(_), true) => {
+                return Err(syn::Error::new(
+                    method.sig.span(),
+                    "Methods of trait impls are always exported, #[dex_method] is not needed",
+                ));
+            }
+            (None, true) => MethodAttr {
+                kind: MethodKind::Export,
+                authorized: false,
+            },
+            (Some(attr), false) => attr,
+            (None, false) => continue,
+        };
+        exports.extend(expand_method(&qualified_self, self_ty, method, &attr)?);
+    }
+    Ok(exports)
+}
+
+/// Removes `#[dex_method]` from the method and parses it.
+fn take_method_attr(method: &mut ImplItemFn) -> syn::Result<Option<MethodAttr>> {
+    let Some(index) = method.attrs.iter().position(|attr| {
+        attr.path()
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "dex_method")
+    }) else {
+        return Ok(None);
+    };
+    let attr = method.attrs.remove(index);
+    let (mut view, mut init, mut authorized) = (false, false, false);
+    if !matches!(attr.meta, syn::Meta::Path(_)) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("view") {
+                view = true;
+            } else if meta.path.is_ident("init") {
+                init = true;
+            } else if meta.path.is_ident("authorized") {
+                authorized = true;
+            } else {
+                return Err(meta.error("Expected `view`, `init` or `authorized`"));
+            }
+            Ok(())
+        })?;
+    }
+    let kind = match (view, init) {
+        (true, true) => {
+            return Err(syn::Error::new(
+                attr.span(),
+                "A method can't be both `view` and `init`",
+            ));
+        }
+        (true, false) => MethodKind::View,
+        (false, true) => MethodKind::Init,
+        (false, false) => MethodKind::Call,
+    };
+    if kind == MethodKind::View && authorized {
+        return Err(syn::Error::new(
+            attr.span(),
+            "Views don't have a caller, so they can't be `authorized`",
+        ));
+    }
+    Ok(Some(MethodAttr { kind, authorized }))
+}
+
+fn expand_method(
+    qualified_self: &TokenStream2,
+    self_ty: &Type,
+    method: &ImplItemFn,
+    attr: &MethodAttr,
+) -> syn::Result<TokenStream2> {
+    let sig = &method.sig;
+    let method_ident = &sig.ident;
+    let name = method_ident.unraw().to_string();
+    let sdk = quote!(::intear_dex_sdk);
+
+    let receiver = sig.receiver();
+    match (attr.kind, receiver) {
+        (MethodKind::Init, Some(receiver)) => {
+            return Err(syn::Error::new(
+                receiver.span(),
+                "`init` methods create the state, so they can't take `self`",
+            ));
+        }
+        (MethodKind::Init, None) => {}
+        (_, None) => {
+            return Err(syn::Error::new(
+                sig.span(),
+                "Dex methods must take `&self` or `&mut self`",
+            ));
+        }
+        (_, Some(receiver)) if receiver.reference.is_none() => {
+            return Err(syn::Error::new(
+                receiver.span(),
+                "Dex methods must take `&self` or `&mut self`",
+            ));
+        }
+        (MethodKind::View, Some(receiver)) if receiver.mutability.is_some() => {
+            return Err(syn::Error::new(
+                receiver.span(),
+                "Views can't take `&mut self`",
+            ));
+        }
+        _ => {}
+    }
+    let mutates_state = receiver.is_some_and(|receiver| receiver.mutability.is_some());
+
+    // Parameters other than `attached_assets` are decoded from the
+    // args as a tuple, which has the same borsh layout as a struct
+    // with the same fields.
+    let mut arg_idents = Vec::new();
+    let mut arg_names = Vec::new();
+    let mut arg_types = Vec::new();
+    let mut call_args = Vec::new();
+    let mut takes_attached_assets = false;
+    for input in sig.inputs.iter() {
+        let FnArg::Typed(pat_type) = input else {
+            continue;
+        };
+        let pat_ident = match &*pat_type.pat {
+            Pat::Ident(pat_ident) => Some(&pat_ident.ident),
+            _ => None,
+        };
+        if pat_ident.is_some_and(|ident| ident == "attached_assets")
+            && attr.kind.receives_dex_call_request()
+        {
+            takes_attached_assets = true;
+            call_args.push(quote!(__attached_assets));
+            continue;
+        }
+        let ident = format_ident!("__arg{}", arg_idents.len());
+        arg_names.push(match pat_ident {
+            Some(pat_ident) => pat_ident.unraw().to_string(),
+            None => ident.to_string(),
+        });
+        match &*pat_type.ty {
+            Type::Reference(reference) => {
+                let elem = &reference.elem;
+                arg_types.push(quote!(#elem));
+                call_args.push(match reference.mutability {
+                    Some(_) => quote!(&mut #ident),
+                    None => quote!(&#ident),
+                });
+            }
+            ty => {
+                arg_types.push(quote!(#ty));
+                call_args.push(quote!(#ident));
+            }
+        }
+        arg_idents.push(ident);
+    }
+
+    let read_args = if attr.kind.receives_dex_call_request() {
+        let attached_assets = if takes_attached_assets {
+            quote!(__attached_assets)
+        } else {
+            quote!(_)
+        };
+        quote! {
+            let #sdk::types::DexCallRequest {
+                attached_assets: #attached_assets,
+                args: __args,
+            } = #sdk::io::dex_call_request();
+        }
+    } else {
+        quote! {
+            let __args = #sdk::env::input();
+        }
+    };
+    let decode_args = quote! {
+        #[allow(unused_mut)]
+        let (#(mut #arg_idents,)*): (#(#arg_types,)*) = #sdk::io::decode_args(&__args);
+    };
+    let authorize = if attr.authorized {
+        quote!(#sdk::env::assert_one_yocto();)
+    } else {
+        quote!()
+    };
+
+    let returns_unit = match &sig.output {
+        ReturnType::Default => true,
+        ReturnType::Type(_, ty) => matches!(&**ty, Type::Tuple(tuple) if tuple.elems.is_empty()),
+    };
+    let returns_dex_call_response = match &sig.output {
+        ReturnType::Type(_, ty) => matches!(
+            &**ty,
+            Type::Path(path) if path
+                .path
+                .segments
+                .last()
+                .is_some_and(|segment| segment.ident == "DexCallResponse")
+        ),
+        ReturnType::Default => false,
+    };
+
+    let body = if attr.kind == MethodKind::Init {
+        quote! {
+            if #sdk::state::exists() {
+                #sdk::env::panic_str("The dex is already initialized");
+            }
+            let __state: #self_ty = #qualified_self::#method_ident(#(#call_args),*);
+            #sdk::state::save(&__state);
+            #sdk::io::return_dex_call_response(&::core::default::Default::default());
+        }
+    } else {
+        let load_state = if mutates_state {
+            quote!(let mut __state: #self_ty = #sdk::state::load();)
+        } else {
+            quote!(let __state: #self_ty = #sdk::state::load();)
+        };
+        let state_ref = if mutates_state {
+            quote!(&mut __state)
+        } else {
+            quote!(&__state)
+        };
+        let save_state = if mutates_state {
+            quote!(#sdk::state::save(&__state);)
+        } else {
+            quote!()
+        };
+        let return_result = match attr.kind {
+            MethodKind::Call if returns_dex_call_response => {
+                quote!(#sdk::io::return_dex_call_response(&__result);)
+            }
+            MethodKind::Call if returns_unit => {
+                quote!(#sdk::io::return_dex_call_response(&::core::default::Default::default());)
+            }
+            MethodKind::Call => quote!(#sdk::io::return_dex_call_value(&__result);),
+            _ if returns_unit => quote!(),
+            _ => quote!(#sdk::io::return_value(&__result);),
+        };
+        quote! {
+            #load_state
+            #[allow(clippy::let_unit_value)]
+            let __result = #qualified_self::#method_ident(#state_ref, #(#call_args),*);
+            #save_state
+            #return_result
+        }
+    };
+
+    let export_ident = format_ident!("__intear_dex_export_{}", name);
+    let mut tokens = quote! {
+        #[cfg(target_family = "wasm")]
+        #[unsafe(export_name = #name)]
+        pub extern "C" fn #export_ident() {
+            #sdk::env::setup_panic_hook();
+            #authorize
+            #read_args
+            #decode_args
+            #body
+        }
+    };
+
+    if let Some(kind_index) = attr.kind.manifest_index() {
+        let abi = quote!(#sdk::abi);
+        let result = match &sig.output {
+            ReturnType::Type(_, ty)
+                if !returns_unit && !returns_dex_call_response && attr.kind != MethodKind::Init =>
+            {
+                quote!(::core::option::Option::Some(&<#ty as #abi::AbiType>::SCHEMA))
+            }
+            _ => quote!(::core::option::Option::None),
+        };
+        tokens.extend(quote! {
+            #[cfg(target_family = "wasm")]
+            const _: () = {
+                const METHOD: #abi::Method = #abi::Method {
+                    name: #name,
+                    args: &[#((#arg_names, &<#arg_types as #abi::AbiType>::SCHEMA)),*],
+                    result: #result,
+                };
+                const LEN: usize = #abi::method_len(&METHOD);
+                #[unsafe(link_section = #DEX_ABI_SECTION)]
+                #[used]
+                static ABI: [u8; LEN] = #abi::method_bytes::<LEN>(&METHOD);
+            };
+        });
+
+        // Borsh layout of `intear_dex_types::DexMethod`
+        let name_len = u32::try_from(name.len())
+            .map_err(|_| syn::Error::new(method_ident.span(), "Method name is too long"))?;
+        let mut manifest = name_len.to_le_bytes().to_vec();
+        manifest.extend(name.as_bytes());
+        manifest.push(kind_index);
+        manifest.push(u8::from(attr.authorized));
+        let manifest_len = manifest.len();
+        let manifest_ident = format_ident!("__INTEAR_DEX_METHOD_{}", name);
+        tokens.extend(quote! {
+            #[cfg(target_family = "wasm")]
+            #[unsafe(link_section = #DEX_METHODS_SECTION)]
+            #[used]
+            #[allow(non_upper_case_globals)]
+            static #manifest_ident: [u8; #manifest_len] = [#(#manifest),*];
+        });
+    }
+    Ok(tokens)
+}
@@ -0,0 +1,307 @@
+//! Borsh schemas of dex methods, computed at compile time so
+//! `#[dex]` can embed them in the `intear_dex_abi` section.
+//! [`Schema`] mirrors `intear_dex_types::DexAbiType`, and is
+//! serialized with the same borsh layout.
+
+use alloc::collections::BTreeMap;
+
+use intear_dex_types::{AssetId, DexId};
+use near_sdk::{
+    AccountId, Gas, NearToken, PublicKey,
+    json_types::{Base64VecU8, U64, U128},
+};
+
+pub use intear_dex_macros::AbiType;
+
+/// Borsh layout of a type, known at compile time.
+pub enum Schema {
+    Bool,
+    U8,
+    U16,
+    U32,
+    U64,
+    U128,
+    I8,
+    I16,
+    I32,
+    I64,
+    I128,
+    String,
+    AccountId,
+    AssetId,
+    DexId,
+    PublicKey,
+    Base64,
+    Vec(&'static Schema),
+    Option(&'static Schema),
+    Map(&'static Schema, &'static Schema),
+    Tuple(&'static [&'static Schema]),
+    Struct {
+        name: &'static str,
+        fields: Fields,
+    },
+    Enum {
+        name: &'static str,
+        variants: &'static [(&'static str, Fields)],
+    },
+}
+
+pub enum Fields {
+    Named(&'static [(&'static str, &'static Schema)]),
+    Unnamed(&'static [&'static Schema]),
+    Unit,
+}
+
+/// A `#[dex_method]`, as embedded in the `intear_dex_abi` section.
+pub struct Method {
+    pub name: &'static str,
+    pub args: &'static [(&'static str, &'static Schema)],
+    pub result: Option<&'static Schema>,
+}
+
+/// A type that can be used in the args or the result of a
+/// `#[dex_method]`. Derive it for structs and enums with
+/// `#[derive(AbiType)]`. Recursive types are not supported.
+pub trait AbiType {
+    const SCHEMA: Schema;
+}
+
+macro_rules! impl_abi_type {
+    ($($ty:ty => $schema:expr),* $(,)?) => {
+        $(
+            impl AbiType for $ty {
+                const SCHEMA: Schema = $schema;
+            }
+        )*
+    };
+}
+
+impl_abi_type! {
+    bool => Schema::Bool,
+    u8 => Schema::U8,
+    u16 => Schema::U16,
+    u32 => Schema::U32,
+    u64 => Schema::U64,
+    u128 => Schema::U128,
+    i8 => Schema::I8,
+    i16 => Schema::I16,
+    i32 => Schema::I32,
+    i64 => Schema::I64,
+    i128 => Schema::I128,
+    alloc::string::String => Schema::String,
+    str => Schema::String,
+    U64 => Schema::U64,
+    U128 => Schema::U128,
+    NearToken => Schema::U128,
+    Gas => Schema::U64,
+    AccountId => Schema::AccountId,
+    AssetId => Schema::AssetId,
+    DexId => Schema::DexId,
+    PublicKey => Schema::PublicKey,
+    Base64VecU8 => Schema::Base64,
+}
+
+impl<T: AbiType + ?Sized> AbiType for &T {
+    const SCHEMA: Schema = T::SCHEMA;
+}
+
+impl<T: AbiType + ?Sized> AbiType for &mut T {
+    const SCHEMA: Schema = T::SCHEMA;
+}
+
+impl<T: AbiType + ?Sized> AbiType for alloc::boxed::Box<T> {
+    const SCHEMA: Schema = T::SCHEMA;
+}
+
+impl<T: AbiType> AbiType for alloc::vec::Vec<T> {
+    const SCHEMA: Schema = Schema::Vec(&T::SCHEMA);
+}
+
+impl<T: AbiType> AbiType for [T] {
+    const SCHEMA: Schema = Schema::Vec(&T::SCHEMA);
+}
+
+impl<T: AbiType> AbiType for Option<T> {
+    const SCHEMA: Schema = Schema::Option(&T::SCHEMA);
+}
+
+impl<K: AbiType, V: AbiType> AbiType for BTreeMap<K, V> {
+    const SCHEMA: Schema = Schema::Map(&K::SCHEMA, &V::SCHEMA);
+}
+
+macro_rules! impl_abi_type_for_tuples {
+    ($(($($name:ident),*)),* $(,)?) => {
+        $(
+            impl<$($name: AbiType),*> AbiType for ($($name,)*) {
+                const SCHEMA: Schema = Schema::Tuple(&[$(&$name::SCHEMA),*]);
+            }
+        )*
+    };
+}
+
+impl_abi_type_for_tuples! {
+    (),
+    (A),
+    (A, B),
+    (A, B, C),
+    (A, B, C, D),
+    (A, B, C, D, E),
+    (A, B, C, D, E, F),
+}
+
+/// Length of the borsh-serialized `method`.
+pub const fn method_len(method: &Method) -> usize {
+    let mut writer = Writer {
+        out: &mut [],
+        position: 0,
+    };
+    writer.method(method);
+    writer.position
+}
+
+/// Borsh-serialized `method`. `N` must be [`method_len`].
+pub const fn method_bytes<const N: usize>(method: &Method) -> [u8; N] {
+    let mut out = [0; N];
+    let mut writer = Writer {
+        out: &mut out,
+        position: 0,
+    };
+    writer.method(method);
+    out
+}
+
+/// Writes borsh bytes, or only counts them if `out` is empty.
+struct Writer<'a> {
+    out: &'a mut [u8],
+    position: usize,
+}
+
+// Sizes are bounded by the length of the output array
+#[allow(clippy::arithmetic_side_effects)]
+impl Writer<'_> {
+    const fn byte(&mut self, byte: u8) {
+        if !self.out.is_empty() {
+            self.out[self.position] = byte;
+        }
+        self.position += 1;
+    }
+
+    const fn len(&mut self, len: usize) {
+        let bytes = (len as u32).to_le_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            self.byte(bytes[i]);
+            i += 1;
+        }
+    }
+
+    const fn str(&mut self, s: &str) {
+        let bytes = s.as_bytes();
+        self.len(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            self.byte(bytes[i]);
+            i += 1;
+        }
+    }
+
+    const fn named(&mut self, fields: &[(&str, &Schema)]) {
+        self.len(fields.len());
+        let mut i = 0;
+        while i < fields.len() {
+            self.str(fields[i].0);
+            self.schema(fields[i].1);
+            i += 1;
+        }
+    }
+
+    const fn unnamed(&mut self, elements: &[&Schema]) {
+        self.len(elements.len());
+        let mut i = 0;
+        while i < elements.len() {
+            self.schema(elements[i]);
+            i += 1;
+        }
+    }
+
+    const fn fields(&mut self, fields: &Fields) {
+        match fields {
+            Fields::Named(fields) => {
+                self.byte(0);
+                self.named(fields);
+            }
+            Fields::Unnamed(elements) => {
+                self.byte(1);
+                self.unnamed(elements);
+            }
+            Fields::Unit => self.byte(2),
+        }
+    }
+
+    const fn schema(&mut self, schema: &Schema) {
+        match schema {
+            Schema::Bool => self.byte(0),
+            Schema::U8 => self.byte(1),
+            Schema::U16 => self.byte(2),
+            Schema::U32 => self.byte(3),
+            Schema::U64 => self.byte(4),
+            Schema::U128 => self.byte(5),
+            Schema::I8 => self.byte(6),
+            Schema::I16 => self.byte(7),
+            Schema::I32 => self.byte(8),
+            Schema::I64 => self.byte(9),
+            Schema::I128 => self.byte(10),
+            Schema::String => self.byte(11),
+            Schema::AccountId => self.byte(12),
+            Schema::AssetId => self.byte(13),
+            Schema::DexId => self.byte(14),
+            Schema::PublicKey => self.byte(15),
+            Schema::Base64 => self.byte(16),
+            Schema::Vec(element) => {
+                self.byte(17);
+                self.schema(element);
+            }
+            Schema::Option(element) => {
+                self.byte(18);
+                self.schema(element);
+            }
+            Schema::Map(key, value) => {
+                self.byte(19);
+                self.schema(key);
+                self.schema(value);
+            }
+            Schema::Tuple(elements) => {
+                self.byte(20);
+                self.unnamed(elements);
+            }
+            Schema::Struct { name, fields } => {
+                self.byte(21);
+                self.str(name);
+                self.fields(fields);
+            }
+            Schema::Enum { name, variants } => {
+                self.byte(22);
+                self.str(name);
+                self.len(variants.len());
+                let mut i = 0;
+                while i < variants.len() {
+                    self.str(variants[i].0);
+                    self.fields(&variants[i].1);
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    const fn method(&mut self, method: &Method) {
+        self.str(method.name);
+        self.named(method.args);
+        match method.result {
+            Some(result) => {
+                self.byte(1);
+                self.schema(result);
+            }
+            None => self.byte(0),
+        }
+    }
+}
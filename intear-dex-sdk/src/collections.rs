@@ -0,0 +1,89 @@
+//! Typed storage over the `storage_*` host functions. Nothing
+//! is cached, every access reads or writes storage directly.
+
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+
+use crate::env;
+
+fn serialize(value: &impl BorshSerialize) -> Vec<u8> {
+    borsh::to_vec(value).unwrap_or_else(|_| env::panic_str("Failed to serialize value"))
+}
+
+fn deserialize<T: BorshDeserialize>(bytes: &[u8]) -> T {
+    borsh::from_slice(bytes).unwrap_or_else(|_| env::panic_str("Failed to deserialize value"))
+}
+
+/// A single value stored under a fixed key.
+pub struct StorageValue<T> {
+    key: Vec<u8>,
+    _marker: PhantomData<T>,
+}
+
+impl<T: BorshSerialize + BorshDeserialize> StorageValue<T> {
+    pub fn new(key: impl Into<Vec<u8>>) -> Self {
+        Self {
+            key: key.into(),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn get(&self) -> Option<T> {
+        env::storage_read(&self.key).map(|bytes| deserialize(&bytes))
+    }
+
+    pub fn set(&mut self, value: &T) {
+        env::storage_write(&self.key, &serialize(value));
+    }
+
+    pub fn remove(&mut self) -> Option<T> {
+        env::storage_remove(&self.key).map(|bytes| deserialize(&bytes))
+    }
+
+    pub fn exists(&self) -> bool {
+        env::storage_has_key(&self.key)
+    }
+}
+
+/// A map that stores each value under `prefix` followed by the
+/// borsh-serialized key, the same layout as near-sdk's
+/// `LookupMap` with the default hasher.
+pub struct LookupMap<K, V> {
+    prefix: Vec<u8>,
+    _marker: PhantomData<(K, V)>,
+}
+
+impl<K: BorshSerialize, V: BorshSerialize + BorshDeserialize> LookupMap<K, V> {
+    pub fn new(prefix: impl Into<Vec<u8>>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            _marker: PhantomData,
+        }
+    }
+
+    fn storage_key(&self, key: &K) -> Vec<u8> {
+        let mut storage_key = self.prefix.clone();
+        key.serialize(&mut storage_key)
+            .unwrap_or_else(|_| env::panic_str("Failed to serialize key"));
+        storage_key
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        env::storage_read(&self.storage_key(key)).map(|bytes| deserialize(&bytes))
+    }
+
+    /// Returns whether the key was already present.
+    pub fn insert(&mut self, key: &K, value: &V) -> bool {
+        env::storage_write(&self.storage_key(key), &serialize(value))
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        env::storage_remove(&self.storage_key(key)).map(|bytes| deserialize(&bytes))
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        env::storage_has_key(&self.storage_key(key))
+    }
+}
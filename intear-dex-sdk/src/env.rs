@@ -0,0 +1,225 @@
+//! Safe wrappers of the host functions in [`crate::sys`].
+
+use alloc::{string::String, vec, vec::Vec};
+use near_sdk::{AccountId, Gas, NearToken};
+
+use crate::sys;
+
+/// Register for the results of host calls. They are read right
+/// after the call, so nothing else uses it.
+const ATOMIC_REGISTER_ID: u64 = u64::MAX;
+
+/// Cost of storing one byte, the same as on NEAR.
+pub const STORAGE_BYTE_COST: NearToken = NearToken::from_yoctonear(10_000_000_000_000_000_000);
+
+fn read_register(register_id: u64) -> Option<Vec<u8>> {
+    let len = unsafe { sys::register_len(register_id) };
+    if len == u64::MAX {
+        return None;
+    }
+    let mut buf = vec![0; usize::try_from(len).unwrap_or_else(|_| panic_str("Register too long"))];
+    unsafe { sys::read_register(register_id, buf.as_mut_ptr() as u64) };
+    Some(buf)
+}
+
+fn read_atomic_register() -> Vec<u8> {
+    read_register(ATOMIC_REGISTER_ID).unwrap_or_else(|| panic_str("Register is empty"))
+}
+
+fn read_fixed<const N: usize>(load: unsafe extern "C" fn(u64)) -> [u8; N] {
+    unsafe { load(ATOMIC_REGISTER_ID) };
+    read_atomic_register()
+        .try_into()
+        .unwrap_or_else(|_| panic_str("Unexpected register length"))
+}
+
+fn hash<const N: usize>(function: unsafe extern "C" fn(u64, u64, u64), value: &[u8]) -> [u8; N] {
+    unsafe {
+        function(
+            value.len() as u64,
+            value.as_ptr() as u64,
+            ATOMIC_REGISTER_ID,
+        )
+    };
+    read_atomic_register()
+        .try_into()
+        .unwrap_or_else(|_| panic_str("Unexpected hash length"))
+}
+
+/// Raw input of the current export: a borsh-serialized
+/// request of the engine.
+pub fn input() -> Vec<u8> {
+    unsafe { sys::input(ATOMIC_REGISTER_ID) };
+    read_atomic_register()
+}
+
+/// Returns `value` to the engine. Only the last value returned
+/// during an export is used.
+pub fn value_return(value: &[u8]) {
+    unsafe { sys::value_return(value.len() as u64, value.as_ptr() as u64) };
+}
+
+/// The account that called the dex. Panics in views and swap
+/// simulations.
+pub fn predecessor_account_id() -> AccountId {
+    unsafe { sys::predecessor_account_id(ATOMIC_REGISTER_ID) };
+    String::from_utf8(read_atomic_register())
+        .ok()
+        .and_then(|account_id| account_id.parse().ok())
+        .unwrap_or_else(|| panic_str("Invalid predecessor account id"))
+}
+
+/// 1 yoctoNEAR if the caller attached 1 yoctoNEAR to the engine
+/// call, 0 otherwise. Actual assets are attached in
+/// [`DexCallRequest::attached_assets`](intear_dex_types::DexCallRequest::attached_assets).
+pub fn attached_deposit() -> NearToken {
+    let mut balance = [0u8; 16];
+    unsafe { sys::attached_deposit(balance.as_mut_ptr() as u64) };
+    NearToken::from_yoctonear(u128::from_le_bytes(balance))
+}
+
+pub fn assert_one_yocto() {
+    if attached_deposit() != NearToken::from_yoctonear(1) {
+        panic_str("Requires attached deposit of exactly 1 yoctoNEAR");
+    }
+}
+
+pub fn block_height() -> u64 {
+    unsafe { sys::block_index() }
+}
+
+/// Nanoseconds since the Unix epoch.
+pub fn block_timestamp() -> u64 {
+    unsafe { sys::block_timestamp() }
+}
+
+pub fn epoch_height() -> u64 {
+    unsafe { sys::epoch_height() }
+}
+
+/// Bytes used by the dex, including the writes of the current
+/// export.
+pub fn storage_usage() -> u64 {
+    unsafe { sys::storage_usage() }
+}
+
+pub fn prepaid_gas() -> Gas {
+    Gas::from_gas(unsafe { sys::prepaid_gas() })
+}
+
+pub fn used_gas() -> Gas {
+    Gas::from_gas(unsafe { sys::used_gas() })
+}
+
+pub fn random_seed() -> [u8; 32] {
+    read_fixed(sys::random_seed)
+}
+
+pub fn sha256(value: &[u8]) -> [u8; 32] {
+    hash(sys::sha256, value)
+}
+
+pub fn keccak256(value: &[u8]) -> [u8; 32] {
+    hash(sys::keccak256, value)
+}
+
+pub fn keccak512(value: &[u8]) -> [u8; 64] {
+    hash(sys::keccak512, value)
+}
+
+pub fn ripemd160(value: &[u8]) -> [u8; 20] {
+    hash(sys::ripemd160, value)
+}
+
+/// Recovers the 64-byte secp256k1 public key that signed
+/// `hash`, or `None` if the signature is invalid.
+pub fn ecrecover(
+    hash: &[u8; 32],
+    signature: &[u8; 64],
+    v: u8,
+    malleability_flag: bool,
+) -> Option<[u8; 64]> {
+    let recovered = unsafe {
+        sys::ecrecover(
+            hash.len() as u64,
+            hash.as_ptr() as u64,
+            signature.len() as u64,
+            signature.as_ptr() as u64,
+            u64::from(v),
+            u64::from(malleability_flag),
+            ATOMIC_REGISTER_ID,
+        )
+    };
+    if recovered == 0 {
+        return None;
+    }
+    read_atomic_register().try_into().ok()
+}
+
+pub fn ed25519_verify(signature: &[u8; 64], message: &[u8], public_key: &[u8; 32]) -> bool {
+    unsafe {
+        sys::ed25519_verify(
+            signature.len() as u64,
+            signature.as_ptr() as u64,
+            message.len() as u64,
+            message.as_ptr() as u64,
+            public_key.len() as u64,
+            public_key.as_ptr() as u64,
+        ) == 1
+    }
+}
+
+/// Logs a message, prefixed with the id of the dex.
+pub fn log_str(message: &str) {
+    unsafe { sys::log_utf8(message.len() as u64, message.as_ptr() as u64) };
+}
+
+/// Aborts the export with a message. Any changes to storage
+/// are reverted, and the assets of the operation are refunded.
+pub fn panic_str(message: &str) -> ! {
+    unsafe { sys::panic_utf8(message.len() as u64, message.as_ptr() as u64) }
+}
+
+/// Makes panics abort the export with their message, instead of
+/// a bare trap.
+pub fn setup_panic_hook() {
+    near_sdk::env::setup_panic_hook();
+}
+
+pub fn abort() -> ! {
+    unsafe { sys::panic() }
+}
+
+/// Writes `value` under `key`, and returns whether the key was
+/// already present.
+pub fn storage_write(key: &[u8], value: &[u8]) -> bool {
+    unsafe {
+        sys::storage_write(
+            key.len() as u64,
+            key.as_ptr() as u64,
+            value.len() as u64,
+            value.as_ptr() as u64,
+            ATOMIC_REGISTER_ID,
+        ) == 1
+    }
+}
+
+pub fn storage_read(key: &[u8]) -> Option<Vec<u8>> {
+    match unsafe { sys::storage_read(key.len() as u64, key.as_ptr() as u64, ATOMIC_REGISTER_ID) } {
+        0 => None,
+        _ => Some(read_atomic_register()),
+    }
+}
+
+/// Removes `key`, and returns the value it had.
+pub fn storage_remove(key: &[u8]) -> Option<Vec<u8>> {
+    match unsafe { sys::storage_remove(key.len() as u64, key.as_ptr() as u64, ATOMIC_REGISTER_ID) }
+    {
+        0 => None,
+        _ => Some(read_atomic_register()),
+    }
+}
+
+pub fn storage_has_key(key: &[u8]) -> bool {
+    unsafe { sys::storage_has_key(key.len() as u64, key.as_ptr() as u64) == 1 }
+}
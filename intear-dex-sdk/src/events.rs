@@ -0,0 +1,23 @@
+//! NEP-297 events. The engine re-emits them as `dex_event`,
+//! tagged with the id of the dex.
+
+use alloc::{format, string::ToString};
+use near_sdk::{serde::Serialize, serde_json};
+
+use crate::env;
+
+/// Emits a NEP-297 event with `data` as its only data entry.
+pub fn emit_event(standard: &str, version: &str, event: &str, data: &impl Serialize) {
+    let event = serde_json::json!({
+        "standard": standard,
+        "version": version,
+        "event": event,
+        "data": [data],
+    });
+    emit_event_json(&event.to_string());
+}
+
+/// Emits an event that is already serialized to JSON.
+pub fn emit_event_json(event: &str) {
+    env::log_str(&format!("EVENT_JSON:{event}"));
+}
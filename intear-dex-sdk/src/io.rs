@@ -0,0 +1,54 @@
+//! Reading requests of the engine and returning responses to
+//! it. Every export reads its input at most once, and returns
+//! a borsh-serialized value.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use intear_dex_types::{DexCallRequest, DexCallResponse, SwapRequest, SwapResponse};
+
+use crate::env;
+
+/// Deserializes the input of the current export.
+pub fn read_input<T: BorshDeserialize>() -> T {
+    borsh::from_slice(&env::input()).unwrap_or_else(|_| env::panic_str("Invalid request"))
+}
+
+/// Deserializes the args of a method, as passed to `dex_call`
+/// or `dex_view`.
+pub fn decode_args<T: BorshDeserialize>(args: &[u8]) -> T {
+    borsh::from_slice(args).unwrap_or_else(|_| env::panic_str("Invalid args"))
+}
+
+/// Returns a borsh-serialized value to the engine.
+pub fn return_value(value: &impl BorshSerialize) {
+    let value =
+        borsh::to_vec(value).unwrap_or_else(|_| env::panic_str("Failed to serialize response"));
+    env::value_return(&value);
+}
+
+/// Input of the `swap` export.
+pub fn swap_request() -> SwapRequest {
+    read_input()
+}
+
+pub fn return_swap_response(response: &SwapResponse) {
+    return_value(response);
+}
+
+/// Input of an export called with `dex_call`.
+pub fn dex_call_request() -> DexCallRequest {
+    read_input()
+}
+
+pub fn return_dex_call_response(response: &DexCallResponse) {
+    return_value(response);
+}
+
+/// Returns `value` as the response of a `dex_call`, without
+/// any withdrawals or storage deposit.
+pub fn return_dex_call_value(value: &impl BorshSerialize) {
+    return_dex_call_response(&DexCallResponse {
+        response: borsh::to_vec(value)
+            .unwrap_or_else(|_| env::panic_str("Failed to serialize response")),
+        ..Default::default()
+    });
+}
@@ -0,0 +1,94 @@
+//! Bindings for writing dexes that run inside the engine,
+//! without the parts of near-sdk that the engine doesn't
+//! support. Only the host functions in [`sys`] are available
+//! to dexes, and the promise API is not among them.
+//!
+//! A dex is a `cdylib` that exports `swap` and any methods
+//! callable with `dex_call`:
+//!
+//! ```ignore
+//! intear_dex_sdk::setup_allocator!(0x1000);
+//!
+//! #[unsafe(no_mangle)]
+//! fn swap() {
+//!     let request = intear_dex_sdk::io::swap_request();
+//!     // ...
+//!     intear_dex_sdk::io::return_swap_response(&response);
+//! }
+//! ```
+//!
+//! Or, with [`dex`] and [`dex_method`], exports are generated
+//! from an `impl` block, with the state kept in a
+//! [`state::DexState`]:
+//!
+//! ```ignore
+//! #[dex]
+//! impl MyDex {
+//!     #[dex_method(init, authorized)]
+//!     pub fn new() -> Self { /* ... */ }
+//!
+//!     #[dex_method]
+//!     pub fn deposit(&mut self, attached_assets: HashMap<AssetId, U128>, pool_id: u64) { /* ... */ }
+//!
+//!     #[dex_method(view)]
+//!     pub fn get_pool(&self, pool_id: u64) -> Option<Pool> { /* ... */ }
+//! }
+//! ```
+//!
+//! The declared methods are also embedded in the
+//! `intear_dex_methods` section, so the engine can list them
+//! with `dex_methods`.
+
+#![no_std]
+#![deny(clippy::arithmetic_side_effects)]
+
+extern crate alloc;
+
+pub mod abi;
+pub mod collections;
+pub mod env;
+pub mod events;
+pub mod io;
+pub mod state;
+pub mod sys;
+
+#[doc(hidden)]
+pub use alloc::format as __format;
+pub use borsh;
+pub use intear_dex_macros::{dex, dex_method};
+pub use intear_dex_types as types;
+pub use near_sdk::{
+    AccountId, Gas, NearToken,
+    json_types::{Base64VecU8, U128},
+};
+#[doc(hidden)]
+pub use talc;
+
+/// Sets up a global allocator over a static buffer of `$size`
+/// bytes. Dexes are short-lived, so a small fixed heap is
+/// usually enough, and it's much smaller than the default
+/// allocator.
+#[macro_export]
+macro_rules! setup_allocator {
+    ($size:expr) => {
+        #[global_allocator]
+        static ALLOCATOR: $crate::talc::Talck<
+            $crate::talc::locking::AssumeUnlockable,
+            $crate::talc::ClaimOnOom,
+        > = {
+            static mut MEMORY: [u8; $size] = [0; $size];
+            let span = $crate::talc::Span::from_array(::core::ptr::addr_of!(MEMORY).cast_mut());
+            $crate::talc::Talc::new(unsafe { $crate::talc::ClaimOnOom::new(span) }).lock()
+        };
+    };
+}
+
+/// Panics with a formatted message if `$condition` is false.
+#[macro_export]
+macro_rules! require {
+    ($condition:expr, $message:literal $(, $fmt_args:expr)* $(,)?) => {
+        if !$condition {
+            $crate::env::panic_str(&$crate::__format!($message $(, $fmt_args)*));
+        }
+    };
+}
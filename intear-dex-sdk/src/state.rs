@@ -0,0 +1,36 @@
+//! The state of a dex, stored under the same key as near-sdk
+//! contract state, so dexes can switch between the two.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+
+use crate::env;
+
+pub const STATE_KEY: &[u8] = b"STATE";
+
+/// A type that `#[dex]` loads before each method and saves
+/// after methods that take `&mut self`.
+pub trait DexState: BorshSerialize + BorshDeserialize {
+    /// The state before the dex is initialized. Panics by
+    /// default, so only `init` methods can create the state.
+    fn uninitialized() -> Self {
+        env::panic_str("The dex is not initialized")
+    }
+}
+
+pub fn exists() -> bool {
+    env::storage_has_key(STATE_KEY)
+}
+
+pub fn load<T: DexState>() -> T {
+    match env::storage_read(STATE_KEY) {
+        Some(bytes) => borsh::from_slice(&bytes)
+            .unwrap_or_else(|_| env::panic_str("Failed to deserialize the state")),
+        None => T::uninitialized(),
+    }
+}
+
+pub fn save<T: DexState>(state: &T) {
+    let bytes =
+        borsh::to_vec(state).unwrap_or_else(|_| env::panic_str("Failed to serialize the state"));
+    env::storage_write(STATE_KEY, &bytes);
+}
@@ -0,0 +1,66 @@
+//! Raw imports of the host functions that the engine provides
+//! to dexes. Anything not listed here traps when called.
+
+unsafe extern "C" {
+    // #############
+    // # Registers #
+    // #############
+    pub fn read_register(register_id: u64, ptr: u64);
+    pub fn register_len(register_id: u64) -> u64;
+    pub fn write_register(register_id: u64, data_len: u64, data_ptr: u64);
+    // ###############
+    // # Context API #
+    // ###############
+    pub fn input(register_id: u64);
+    pub fn predecessor_account_id(register_id: u64);
+    pub fn attached_deposit(balance_ptr: u64);
+    pub fn block_index() -> u64;
+    pub fn block_timestamp() -> u64;
+    pub fn epoch_height() -> u64;
+    pub fn storage_usage() -> u64;
+    pub fn prepaid_gas() -> u64;
+    pub fn used_gas() -> u64;
+    pub fn random_seed(register_id: u64);
+    // ################
+    // # Math and I/O #
+    // ################
+    pub fn sha256(value_len: u64, value_ptr: u64, register_id: u64);
+    pub fn keccak256(value_len: u64, value_ptr: u64, register_id: u64);
+    pub fn keccak512(value_len: u64, value_ptr: u64, register_id: u64);
+    pub fn ripemd160(value_len: u64, value_ptr: u64, register_id: u64);
+    pub fn ecrecover(
+        hash_len: u64,
+        hash_ptr: u64,
+        sig_len: u64,
+        sig_ptr: u64,
+        v: u64,
+        malleability_flag: u64,
+        register_id: u64,
+    ) -> u64;
+    pub fn ed25519_verify(
+        signature_len: u64,
+        signature_ptr: u64,
+        message_len: u64,
+        message_ptr: u64,
+        public_key_len: u64,
+        public_key_ptr: u64,
+    ) -> u64;
+    pub fn value_return(value_len: u64, value_ptr: u64);
+    pub fn panic() -> !;
+    pub fn panic_utf8(len: u64, ptr: u64) -> !;
+    pub fn log_utf8(len: u64, ptr: u64);
+    pub fn log_utf16(len: u64, ptr: u64);
+    // ###############
+    // # Storage API #
+    // ###############
+    pub fn storage_write(
+        key_len: u64,
+        key_ptr: u64,
+        value_len: u64,
+        value_ptr: u64,
+        register_id: u64,
+    ) -> u64;
+    pub fn storage_read(key_len: u64, key_ptr: u64, register_id: u64) -> u64;
+    pub fn storage_remove(key_len: u64, key_ptr: u64, register_id: u64) -> u64;
+    pub fn storage_has_key(key_len: u64, key_ptr: u64) -> u64;
+}
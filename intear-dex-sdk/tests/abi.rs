@@ -0,0 +1,118 @@
+// Only the schemas of the types in this file are used
+#![allow(dead_code)]
+
+use intear_dex_sdk::abi::{AbiType, Method, method_bytes, method_len};
+use intear_dex_types::{AssetId, DexAbiFields, DexAbiMethod, DexAbiType};
+use near_sdk::{
+    AccountId,
+    borsh::{self, BorshDeserialize},
+    json_types::U128,
+};
+
+#[derive(AbiType)]
+struct WithdrawRequest {
+    asset_id: AssetId,
+    amount: Option<U128>,
+    to: Option<AccountId>,
+    to_inner_balance: bool,
+    #[borsh(skip)]
+    cached: u8,
+}
+
+#[derive(AbiType)]
+enum ExpiryCondition {
+    BlockHeight(u64),
+    Timestamp { milliseconds: u64 },
+    Never,
+}
+
+/// Serializes a method at compile time like `#[dex]` does, and
+/// decodes it the way the engine reads the `intear_dex_abi`
+/// section.
+macro_rules! embedded {
+    ($method:expr) => {{
+        const LEN: usize = method_len(&$method);
+        const BYTES: [u8; LEN] = method_bytes::<LEN>(&$method);
+        DexAbiMethod::try_from_slice(&BYTES).unwrap()
+    }};
+}
+
+fn named(fields: Vec<(&str, DexAbiType)>) -> Vec<(String, DexAbiType)> {
+    fields
+        .into_iter()
+        .map(|(name, ty)| (name.to_string(), ty))
+        .collect()
+}
+
+#[test]
+fn test_embedded_method() {
+    const WITHDRAW_ASSETS: Method = Method {
+        name: "withdraw_assets",
+        args: &[
+            ("assets", &<Vec<WithdrawRequest> as AbiType>::SCHEMA),
+            ("expiry", &<ExpiryCondition as AbiType>::SCHEMA),
+        ],
+        result: Some(&<Option<&U128> as AbiType>::SCHEMA),
+    };
+    let expected = DexAbiMethod {
+        name: "withdraw_assets".to_string(),
+        args: named(vec![
+            (
+                "assets",
+                DexAbiType::Vec(Box::new(DexAbiType::Struct {
+                    name: "WithdrawRequest".to_string(),
+                    fields: DexAbiFields::Named(named(vec![
+                        ("asset_id", DexAbiType::AssetId),
+                        ("amount", DexAbiType::Option(Box::new(DexAbiType::U128))),
+                        ("to", DexAbiType::Option(Box::new(DexAbiType::AccountId))),
+                        ("to_inner_balance", DexAbiType::Bool),
+                    ])),
+                })),
+            ),
+            (
+                "expiry",
+                DexAbiType::Enum {
+                    name: "ExpiryCondition".to_string(),
+                    variants: vec![
+                        (
+                            "BlockHeight".to_string(),
+                            DexAbiFields::Unnamed(vec![DexAbiType::U64]),
+                        ),
+                        (
+                            "Timestamp".to_string(),
+                            DexAbiFields::Named(named(vec![("milliseconds", DexAbiType::U64)])),
+                        ),
+                        ("Never".to_string(), DexAbiFields::Unit),
+                    ],
+                },
+            ),
+        ]),
+        result: Some(DexAbiType::Option(Box::new(DexAbiType::U128))),
+    };
+    let method = embedded!(WITHDRAW_ASSETS);
+    assert_eq!(method, expected);
+    assert_eq!(
+        borsh::to_vec(&method).unwrap().len(),
+        method_len(&WITHDRAW_ASSETS)
+    );
+}
+
+#[test]
+fn test_embedded_tuples() {
+    const CREATE_POOL: Method = Method {
+        name: "create_pool",
+        args: &[("assets", &<(AssetId, AssetId) as AbiType>::SCHEMA)],
+        result: Some(&<() as AbiType>::SCHEMA),
+    };
+    assert_eq!(
+        embedded!(CREATE_POOL),
+        DexAbiMethod {
+            name: "create_pool".to_string(),
+            args: named(vec![(
+                "assets",
+                DexAbiType::Tuple(vec![DexAbiType::AssetId, DexAbiType::AssetId]),
+            )]),
+            result: Some(DexAbiType::Tuple(Vec::new())),
+        }
+    );
+}
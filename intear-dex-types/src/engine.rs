@@ -0,0 +1,308 @@
+//! Types of the dex engine's interface: operations, the
+//! arguments of swaps, and the data of its events. Shared by
+//! the engine, the client and the indexer.
+
+use std::{collections::HashMap, fmt::Display};
+
+use near_sdk::{
+    AccountId, NearToken,
+    json_types::{Base64VecU8, U128},
+    near,
+};
+
+use crate::{AssetId, DexId, SwapRequest, SwapRequestAmount};
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh, json])]
+pub enum AccountOrDexId {
+    Account(AccountId),
+    Dex(DexId),
+}
+
+impl Display for AccountOrDexId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Account(account) => write!(f, "Account({account})"),
+            Self::Dex(dex_id) => write!(f, "Dex({dex_id})"),
+        }
+    }
+}
+
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh, json])]
+pub enum Operation {
+    /// Register storage for assets. No-op if assets are
+    /// already registered for the given account or dex.
+    RegisterAssets {
+        asset_ids: Vec<AssetId>,
+        r#for: Option<AccountOrDexId>,
+    },
+    /// Deploy new code to your dex.
+    DeployDexCode {
+        last_part_of_id: String,
+        code_base64: Base64VecU8,
+    },
+    /// Withdraw assets from the dex engine contract's inner
+    /// balance to the user. If amount is None, the entire
+    /// balance of the asset will be withdrawn.
+    Withdraw {
+        asset_id: AssetId,
+        amount: Option<U128>,
+        to: Option<AccountId>,
+        /// If the withdrawal fails and current user doesn't have
+        /// a registerd balance in this asset, the assets will be
+        /// refunded to this address. It's required that either
+        /// the user address or rescue address is registered.
+        rescue_address: Option<AccountId>,
+        /// If present, the asset is sent with `*_transfer_call`
+        /// and this message, and the amount that the receiver
+        /// didn't use is refunded. Not supported for NEAR.
+        #[serde(default)]
+        msg: Option<String>,
+        /// If the receiver isn't registered on the token contract,
+        /// register it with `storage_deposit`, paid from the
+        /// user's NEAR balance. Only supported for NEP-141.
+        #[serde(default)]
+        register_receiver: bool,
+    },
+    /// Swap assets between two assets on the selected dex.
+    SwapSimple {
+        dex_id: DexId,
+        message: Base64VecU8,
+        asset_in: AssetId,
+        asset_out: AssetId,
+        amount: SwapOperationAmount,
+        #[serde(default)]
+        referral: Option<Referral>,
+        /// The most that the dexes may charge the trader for the
+        /// storage that this operation creates.
+        #[serde(default)]
+        max_storage_payment: NearToken,
+    },
+    /// Swap through a route of dexes. `limit` is the minimum
+    /// amount out for `ExactIn`, and the maximum amount in for
+    /// `ExactOut`.
+    SwapRoute {
+        asset_in: AssetId,
+        hops: Vec<SwapRouteHop>,
+        amount: SwapOperationAmount,
+        limit: U128,
+        /// The most that the dexes may charge the trader for the
+        /// storage that this operation creates.
+        #[serde(default)]
+        max_storage_payment: NearToken,
+    },
+    /// Swap on whichever of the candidates gives the best price.
+    /// `limit` is the minimum amount out for `ExactIn`, and the
+    /// maximum amount in for `ExactOut`.
+    SwapBestPrice {
+        candidates: Vec<SwapCandidate>,
+        asset_in: AssetId,
+        asset_out: AssetId,
+        amount: SwapOperationAmount,
+        limit: U128,
+        /// The most that the dexes may charge the trader for the
+        /// storage that this operation creates.
+        #[serde(default)]
+        max_storage_payment: NearToken,
+    },
+    /// Split the input between several routes by weight.
+    /// Only `ExactIn` amounts are supported.
+    SwapSplit {
+        asset_in: AssetId,
+        amount: SwapOperationAmount,
+        legs: Vec<SwapSplitLeg>,
+        min_amount_out: U128,
+        /// The most that the dexes may charge the trader for the
+        /// storage that this operation creates.
+        #[serde(default)]
+        max_storage_payment: NearToken,
+    },
+    /// Transfer assets from `owner`'s balance, using the
+    /// allowance that `owner` gave with `approve_asset`.
+    TransferFrom {
+        owner: AccountId,
+        to: AccountOrDexId,
+        asset_id: AssetId,
+        amount: U128,
+    },
+    /// Swap on behalf of `owner`, using the allowance that
+    /// `owner` gave with `approve_asset` for `asset_in`. The
+    /// output goes to `owner`.
+    SwapSimpleFrom {
+        owner: AccountId,
+        dex_id: DexId,
+        message: Base64VecU8,
+        asset_in: AssetId,
+        asset_out: AssetId,
+        amount: SwapRequestAmount,
+        #[serde(default)]
+        referral: Option<Referral>,
+    },
+    /// Borrow assets from a dex that supports flash loans. The
+    /// borrowed amount plus the dex's fee must be repaid with
+    /// `FlashRepay` before the end of the batch.
+    FlashBorrow {
+        dex_id: DexId,
+        asset_id: AssetId,
+        amount: U128,
+    },
+    /// Repay flash loans taken earlier in the batch. If `amount`
+    /// is not specified, everything that's owed to this dex in
+    /// this asset is repaid.
+    FlashRepay {
+        dex_id: DexId,
+        asset_id: AssetId,
+        amount: Option<U128>,
+    },
+    /// Call a method on a dex.
+    DexCall {
+        dex_id: DexId,
+        method: String,
+        args: Base64VecU8,
+        attached_assets: HashMap<AssetId, U128>,
+        /// The most that the dexes may charge the trader for the
+        /// storage that this operation creates.
+        #[serde(default)]
+        max_storage_payment: NearToken,
+    },
+    /// Transfer assets to a different account or dex.
+    TransferAsset {
+        to: AccountOrDexId,
+        asset_id: AssetId,
+        amount: U128,
+    },
+    /// Convert some of AssetId::Near to storage for an account
+    /// or a dex.
+    StorageDeposit {
+        amount: U128,
+        r#for: Option<AccountOrDexId>,
+    },
+    /// Unregister zero balances of your account or your dex and
+    /// free their storage. Non-zero balances are transferred to
+    /// `sweep_dust_to` first, if it's provided.
+    UnregisterAssets {
+        asset_ids: Vec<AssetId>,
+        r#for: Option<AccountOrDexId>,
+        sweep_dust_to: Option<AccountOrDexId>,
+    },
+}
+
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh, json])]
+pub enum SwapOperationAmount {
+    Amount(SwapRequestAmount),
+    OutputOfLastIn,
+    EntireBalanceIn,
+}
+
+/// One step of a route. The asset in of a hop is the asset
+/// out of the previous hop, or the route's asset in for the
+/// first hop.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh, json])]
+pub struct SwapRouteHop {
+    pub dex_id: DexId,
+    pub message: Base64VecU8,
+    pub asset_out: AssetId,
+}
+
+/// A swap that was executed as part of a route.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[json])]
+pub struct ExecutedHop {
+    pub dex_id: DexId,
+    pub request: SwapRequest,
+    pub amount_in: U128,
+    pub amount_out: U128,
+}
+
+/// One leg of a split swap. Legs receive a share of the
+/// input proportional to their weight.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh, json])]
+pub struct SwapSplitLeg {
+    pub weight: u32,
+    pub hops: Vec<SwapRouteHop>,
+}
+
+/// A leg of a split swap that was executed.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[json])]
+pub struct ExecutedSplitLeg {
+    pub weight: u32,
+    pub amount_in: U128,
+    pub amount_out: U128,
+}
+
+/// A dex that can be chosen by the best-price router.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh, json])]
+pub struct SwapCandidate {
+    pub dex_id: DexId,
+    pub message: Base64VecU8,
+}
+
+/// The quote that a candidate gave to the best-price router.
+/// Amounts are `None` if the dex failed to quote the swap.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[json])]
+pub struct CandidateQuote {
+    pub dex_id: DexId,
+    pub amount_in: Option<U128>,
+    pub amount_out: Option<U128>,
+}
+
+/// An integrator that receives a part of the swap.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh, json])]
+pub struct Referral {
+    pub account_id: AccountId,
+    pub fee_bps: u16,
+}
+
+/// Fees that were charged on a swap, in `asset_id`.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[json])]
+pub struct SwapFees {
+    pub asset_id: AssetId,
+    pub protocol_fee: U128,
+    pub protocol_fee_recipient: AccountId,
+    pub referral_fee: U128,
+    pub referral: Option<AccountId>,
+}
+
+impl SwapFees {
+    pub fn total(&self) -> u128 {
+        self.protocol_fee
+            .0
+            .checked_add(self.referral_fee.0)
+            .expect("Fee overflow")
+    }
+}
+
+/// What the bytes charged to a storage balance are used for.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh, json])]
+pub enum StorageCategory {
+    /// Wasm code of a dex.
+    Code,
+    /// Storage written by a dex during swaps and calls.
+    DexState,
+    /// Balance records of registered assets, for users or dexes.
+    AssetRegistrations,
+    /// Signing keys, allowances and other account settings.
+    AccountData,
+}
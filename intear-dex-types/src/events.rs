@@ -0,0 +1,345 @@
+//! Events of the dex engine. Fields that were added in later
+//! versions of an event default to `None`, so logs of older
+//! versions can still be deserialized.
+
+use std::collections::HashMap;
+
+use near_sdk::{
+    AccountId, NearToken, PublicKey,
+    json_types::{Base58CryptoHash, U64, U128},
+    near,
+    serde::Deserialize,
+};
+
+use crate::{
+    AccountOrDexId, AssetId, AssetWithdrawRequest, CandidateQuote, DexId, ExecutedHop,
+    ExecutedSplitLeg, StorageCategory, SwapFees, SwapRequest,
+};
+
+// Events are emitted right after creation, so size doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(event_json(standard = "inteardex"))]
+#[derive(Deserialize)]
+pub enum IntearDexEvent {
+    #[event_version("1.0.0")]
+    DexDeployed {
+        dex_id: DexId,
+        code_hash: Base58CryptoHash,
+    },
+    #[event_version("1.0.0")]
+    DexEvent {
+        dex_id: DexId,
+        event: near_sdk::serde_json::Value,
+    },
+    #[event_version("1.0.0")]
+    UserDeposit {
+        account_id: AccountId,
+        asset_id: AssetId,
+        amount: U128,
+    },
+    #[event_version("1.1.0")]
+    Withdraw {
+        from: AccountOrDexId,
+        to: AccountId,
+        asset_id: AssetId,
+        amount: U128,
+        /// NEAR paid to register `to` on the token contract.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        storage_deposit: Option<NearToken>,
+    },
+    #[event_version("1.0.0")]
+    UserBalanceUpdate {
+        account_id: AccountId,
+        asset_id: AssetId,
+        balance: U128,
+    },
+    #[event_version("1.0.0")]
+    DexBalanceUpdate {
+        dex_id: DexId,
+        asset_id: AssetId,
+        balance: U128,
+    },
+    #[event_version("1.2.0")]
+    Swap {
+        dex_id: DexId,
+        request: SwapRequest,
+        amount_in: U128,
+        amount_out: U128,
+        trader: AccountId,
+        /// Quotes of all candidates, if the dex was chosen by
+        /// the best-price router.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        quotes: Option<Vec<CandidateQuote>>,
+        /// Protocol and referral fees, if any were charged.
+        /// `amount_in` and `amount_out` already include them.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fees: Option<SwapFees>,
+    },
+    #[event_version("1.1.0")]
+    SwapRoute {
+        asset_in: AssetId,
+        asset_out: AssetId,
+        amount_in: U128,
+        amount_out: U128,
+        hops: Vec<ExecutedHop>,
+        trader: AccountId,
+        /// Protocol fee, if it was charged. `amount_in` and
+        /// `amount_out` already include it. Legs of a split
+        /// swap are charged in the `SwapSplit` event instead.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fees: Option<SwapFees>,
+    },
+    #[event_version("1.1.0")]
+    SwapSplit {
+        asset_in: AssetId,
+        asset_out: AssetId,
+        amount_in: U128,
+        amount_out: U128,
+        legs: Vec<ExecutedSplitLeg>,
+        trader: AccountId,
+        /// Protocol fee, if it was charged. `amount_in` and
+        /// `amount_out` already include it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fees: Option<SwapFees>,
+    },
+    #[event_version("1.0.0")]
+    FlashBorrow {
+        dex_id: DexId,
+        asset_id: AssetId,
+        amount: U128,
+        fee: U128,
+        borrower: AccountId,
+    },
+    #[event_version("1.0.0")]
+    FlashRepay {
+        dex_id: DexId,
+        asset_id: AssetId,
+        amount: U128,
+        borrower: AccountId,
+    },
+    #[event_version("1.0.0")]
+    SigningKeyAdded {
+        account_id: AccountId,
+        public_key: PublicKey,
+    },
+    #[event_version("1.0.0")]
+    SigningKeyRemoved {
+        account_id: AccountId,
+        public_key: PublicKey,
+    },
+    #[event_version("1.0.0")]
+    SignedOperationsExecuted {
+        signer_id: AccountId,
+        public_key: PublicKey,
+        nonce: U64,
+        relayer_id: AccountId,
+    },
+    #[event_version("1.0.0")]
+    AssetApproved {
+        owner: AccountId,
+        spender: AccountId,
+        asset_id: AssetId,
+        amount: U128,
+        expires_at: Option<U64>,
+    },
+    #[event_version("1.0.0")]
+    AssetApprovalRevoked {
+        owner: AccountId,
+        spender: AccountId,
+        asset_id: AssetId,
+    },
+    #[event_version("1.0.0")]
+    AllowanceSpent {
+        owner: AccountId,
+        spender: AccountId,
+        asset_id: AssetId,
+        amount: U128,
+        remaining: U128,
+    },
+    #[event_version("1.0.0")]
+    CustodyReconciled {
+        asset_id: AssetId,
+        /// Amount that the contract actually holds.
+        balance: U128,
+        total_in_custody: U128,
+        pending_withdrawals: U128,
+        surplus: U128,
+        /// Non-zero if the contract holds less than it owes.
+        deficit: U128,
+    },
+    #[event_version("1.0.0")]
+    CustodySurplusSwept {
+        asset_id: AssetId,
+        amount: U128,
+        treasury: AccountId,
+    },
+    #[event_version("1.0.0")]
+    OwnerProposed {
+        owner: AccountId,
+        pending_owner: Option<AccountId>,
+    },
+    #[event_version("1.0.0")]
+    OwnerChanged {
+        old_owner: AccountId,
+        new_owner: AccountId,
+    },
+    /// A swap fee that wasn't charged because the recipient
+    /// doesn't have the asset registered.
+    #[event_version("1.0.0")]
+    SwapFeeSkipped {
+        recipient: AccountId,
+        asset_id: AssetId,
+        amount: U128,
+    },
+    #[event_version("1.0.0")]
+    FeeConfigUpdated {
+        protocol_fee_bps: u16,
+        protocol_fee_recipient: AccountId,
+        max_referral_fee_bps: u16,
+    },
+    #[event_version("1.0.0")]
+    TreasuryUpdated {
+        old_treasury: AccountId,
+        new_treasury: AccountId,
+    },
+    #[event_version("1.0.0")]
+    EnginePaused {},
+    #[event_version("1.0.0")]
+    EngineUnpaused {},
+    #[event_version("1.0.0")]
+    DexPaused { dex_id: DexId },
+    #[event_version("1.0.0")]
+    DexUnpaused { dex_id: DexId },
+    #[event_version("1.0.0")]
+    AssetPaused { asset_id: AssetId },
+    #[event_version("1.0.0")]
+    AssetUnpaused { asset_id: AssetId },
+    #[event_version("1.0.0")]
+    EngineMigrated { from_version: u32, to_version: u32 },
+    #[event_version("1.0.0")]
+    DexStorageDeposit {
+        dex_id: DexId,
+        amount: NearToken,
+        total: NearToken,
+        available: NearToken,
+    },
+    #[event_version("1.0.0")]
+    DexStorageWithdraw {
+        dex_id: DexId,
+        amount: NearToken,
+        total: NearToken,
+        available: NearToken,
+    },
+    #[event_version("1.0.0")]
+    DexStorageUnregister { dex_id: DexId, refund: NearToken },
+    #[event_version("1.0.0")]
+    AssetsHeld {
+        owner: AccountOrDexId,
+        asset_id: AssetId,
+        amount: U128,
+    },
+    #[event_version("1.0.0")]
+    StorageToppedUp {
+        r#for: AccountOrDexId,
+        amount: NearToken,
+        /// Total amount moved since top-ups were enabled.
+        used: NearToken,
+        cap: NearToken,
+    },
+    #[event_version("1.0.0")]
+    AccountClosed {
+        account_id: AccountId,
+        /// Storage deposit that was refunded.
+        refund: NearToken,
+    },
+    #[event_version("1.0.0")]
+    TraderStoragePaid {
+        dex_id: DexId,
+        trader: AccountId,
+        bytes: u64,
+        /// Moved to the storage balance of the dex.
+        amount: NearToken,
+    },
+    #[event_version("1.0.0")]
+    StorageDeposit {
+        account_id: AccountId,
+        amount: NearToken,
+        total: NearToken,
+        available: NearToken,
+    },
+    #[event_version("1.0.0")]
+    StorageWithdraw {
+        account_id: AccountId,
+        amount: NearToken,
+        total: NearToken,
+        available: NearToken,
+    },
+    #[event_version("1.0.0")]
+    StorageUnregister {
+        account_id: AccountId,
+        refund: NearToken,
+    },
+    #[event_version("1.0.0")]
+    AssetsRegistered {
+        r#for: AccountOrDexId,
+        /// Only the assets that weren't registered before.
+        asset_ids: Vec<AssetId>,
+        storage_payer: AccountId,
+    },
+    #[event_version("1.0.0")]
+    AssetsUnregistered {
+        r#for: AccountOrDexId,
+        asset_ids: Vec<AssetId>,
+        /// Account whose storage balance got the refund.
+        storage_refunded_to: AccountId,
+    },
+    #[event_version("1.0.0")]
+    AssetTransfer {
+        from: AccountOrDexId,
+        to: AccountOrDexId,
+        asset_id: AssetId,
+        amount: U128,
+        /// Whether the assets came from the sandboxed assets
+        /// attached to the call rather than the inner balance of
+        /// `from`.
+        sandboxed: bool,
+    },
+    #[event_version("1.0.0")]
+    DexCall {
+        dex_id: DexId,
+        method: String,
+        caller: AccountId,
+        sandboxed: bool,
+        attached_assets: HashMap<AssetId, U128>,
+        asset_withdraw_requests: Vec<AssetWithdrawRequest>,
+    },
+    /// NEAR moved from the inner balance of a dex to its storage
+    /// balance with `add_storage_deposit` of a dex call response.
+    #[event_version("1.0.0")]
+    DexStorageDepositAdded {
+        dex_id: DexId,
+        amount: NearToken,
+        total: NearToken,
+        available: NearToken,
+    },
+    #[event_version("1.0.0")]
+    StorageThresholdCrossed {
+        of: AccountOrDexId,
+        category: StorageCategory,
+        /// Bytes in the category after the change.
+        bytes: u64,
+        /// Multiple of `STORAGE_THRESHOLD_STEP` that was crossed,
+        /// upwards or downwards.
+        threshold: u64,
+    },
+    /// Assets left the holding area to be withdrawn, the
+    /// withdrawal itself emits `Withdraw` once it resolves.
+    #[event_version("1.0.0")]
+    HeldAssetsWithdrawn {
+        owner: AccountOrDexId,
+        asset_id: AssetId,
+        amount: U128,
+    },
+}
@@ -1,5 +1,15 @@
 #![deny(clippy::arithmetic_side_effects)]
 
+#[cfg(feature = "json")]
+mod engine;
+#[cfg(feature = "json")]
+mod events;
+
+#[cfg(feature = "json")]
+pub use engine::*;
+#[cfg(feature = "json")]
+pub use events::IntearDexEvent;
+
 use std::{collections::HashMap, fmt, fmt::Display, str::FromStr};
 
 #[cfg(feature = "json")]
@@ -36,10 +46,16 @@ pub struct SwapRequest {
 /// assets to the user, the dex must panic.
 #[derive(Clone)]
 #[cfg_attr(debug_assertions, derive(Debug))]
-#[near(serializers=[borsh])]
+#[cfg_attr(not(feature = "json"), near(serializers=[borsh]))]
+#[cfg_attr(feature = "json", near(serializers=[borsh, json]))]
 pub struct SwapResponse {
     pub amount_in: U128,
     pub amount_out: U128,
+    /// How many of the bytes the dex wrote during this swap are
+    /// paid by the trader instead of the dex. Anything above the
+    /// storage growth of the swap is ignored, so `u64::MAX`
+    /// charges all of it to the trader.
+    pub trader_storage_bytes: u64,
 }
 
 #[derive(Clone)]
@@ -57,11 +73,17 @@ pub struct DexCallResponse {
     pub asset_withdraw_requests: Vec<AssetWithdrawRequest>,
     pub add_storage_deposit: NearToken,
     pub response: Vec<u8>,
+    /// How many of the bytes the dex wrote during this call are
+    /// paid by the caller instead of the dex. Anything above the
+    /// storage growth of the call is ignored, so `u64::MAX`
+    /// charges all of it to the caller.
+    pub trader_storage_bytes: u64,
 }
 
 #[derive(Clone)]
 #[cfg_attr(debug_assertions, derive(Debug))]
-#[near(serializers=[borsh])]
+#[cfg_attr(not(feature = "json"), near(serializers=[borsh]))]
+#[cfg_attr(feature = "json", near(serializers=[borsh, json]))]
 pub struct AssetWithdrawRequest {
     pub asset_id: AssetId,
     pub amount: U128,
@@ -70,11 +92,16 @@ pub struct AssetWithdrawRequest {
 
 #[derive(Clone)]
 #[cfg_attr(debug_assertions, derive(Debug))]
-#[near(serializers=[borsh])]
+#[cfg_attr(not(feature = "json"), near(serializers=[borsh]))]
+#[cfg_attr(feature = "json", near(serializers=[borsh, json]))]
 pub enum AssetWithdrawalType {
     ToInternalUserBalance(AccountId),
     ToInternalDexBalance(DexId),
     WithdrawUnderlyingAsset(AccountId),
+    /// Withdraw with `ft_transfer_call`, `nft_transfer_call` or
+    /// `mt_transfer_call` and the given `msg`. The amount that
+    /// the receiver doesn't use is refunded to the dex.
+    WithdrawUnderlyingAssetCall(AccountId, String),
 }
 
 #[derive(PartialEq, Eq, Hash, Clone, PartialOrd, Ord, Debug)]
@@ -242,6 +269,188 @@ impl near_sdk::schemars::JsonSchema for DexId {
 
 pub trait Dex {
     fn swap(&mut self, request: SwapRequest) -> SwapResponse;
+    /// Same as `swap`, but without changing the state. Called
+    /// in view mode, so any writes fail.
+    fn quote(&self, request: SwapRequest) -> SwapResponse;
+    fn metadata(&self) -> DexMetadata;
+    /// Up to `limit` pairs that the dex can swap, starting at
+    /// `from_index`.
+    fn pairs(&self, from_index: u64, limit: u64) -> Vec<DexPair>;
+}
+
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[cfg_attr(not(feature = "json"), near(serializers=[borsh]))]
+#[cfg_attr(feature = "json", near(serializers=[borsh, json]))]
+pub struct DexMetadata {
+    pub name: String,
+    pub version: String,
+    pub fee_model: DexFeeModel,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[cfg_attr(not(feature = "json"), near(serializers=[borsh]))]
+#[cfg_attr(feature = "json", near(serializers=[borsh, json]))]
+pub enum DexFeeModel {
+    NoFee,
+    /// A fee in basis points of the amount in, which is already
+    /// included in quotes.
+    FixedBps(u32),
+    /// A fee that depends on the pool or the trade. Quotes
+    /// include it.
+    Variable,
+}
+
+/// Two assets that a dex can swap in both directions.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[cfg_attr(not(feature = "json"), near(serializers=[borsh]))]
+#[cfg_attr(feature = "json", near(serializers=[borsh, json]))]
+pub struct DexPair {
+    pub assets: (AssetId, AssetId),
+    /// `message` of a [`SwapRequest`] that swaps this pair, such
+    /// as the pool ID.
+    pub message: Base64VecU8,
+}
+
+/// Request for the optional `flash_loan_fee` export.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh])]
+pub struct FlashLoanFeeRequest {
+    pub asset_id: AssetId,
+    pub amount: U128,
+}
+
+/// Dexes that implement this allow anyone to borrow their
+/// balances within a single batch of operations. The returned
+/// fee (in `asset_id`, not in basis points) has to be repaid
+/// together with the borrowed amount, and is added to the
+/// dex balance.
+pub trait FlashLoanDex {
+    fn flash_loan_fee(&self, request: FlashLoanFeeRequest) -> U128;
+}
+
+/// Response of the optional `storage_balance_bounds` export.
+#[derive(Clone, Copy)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh])]
+pub struct DexStorageBalanceBounds {
+    /// Minimum storage deposit of the dex. Can't be lower than
+    /// the engine's minimum.
+    pub min: NearToken,
+    /// Deposits above this are refunded.
+    pub max: Option<NearToken>,
+}
+
+/// Dexes that implement this set their own bounds for
+/// `dex_storage_deposit`, for example to require enough
+/// storage for their pools upfront.
+pub trait StorageBoundsDex {
+    fn storage_balance_bounds(&self) -> DexStorageBalanceBounds;
+}
+
+/// Custom wasm section where `#[dex]` embeds the borsh-serialized
+/// [`DexMethod`]s of a dex, one after another.
+pub const DEX_METHODS_SECTION: &str = "intear_dex_methods";
+
+/// A method that a dex declared with `#[dex_method]`.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[cfg_attr(not(feature = "json"), near(serializers=[borsh]))]
+#[cfg_attr(feature = "json", near(serializers=[borsh, json]))]
+pub struct DexMethod {
+    pub name: String,
+    pub kind: DexMethodKind,
+    /// Requires a `dex_call` from the caller's own balance, so it
+    /// can't be called from sandboxed operations.
+    pub authorized: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[cfg_attr(not(feature = "json"), near(serializers=[borsh]))]
+#[cfg_attr(feature = "json", near(serializers=[borsh, json]))]
+pub enum DexMethodKind {
+    /// Called with `dex_call`.
+    Call,
+    /// Called with `dex_view`.
+    View,
+    /// Called with `dex_call` once, to initialize the state.
+    Init,
+}
+
+/// Custom wasm section where `#[dex]` embeds a borsh-serialized
+/// [`DexAbiMethod`] for each `#[dex_method]`, one after another.
+pub const DEX_ABI_SECTION: &str = "intear_dex_abi";
+
+/// Borsh schema of the args and result of a dex method.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[cfg_attr(not(feature = "json"), near(serializers=[borsh]))]
+#[cfg_attr(feature = "json", near(serializers=[borsh, json]))]
+pub struct DexAbiMethod {
+    pub name: String,
+    /// Parameters of the method, serialized one after another
+    /// as its `args`.
+    pub args: Vec<(String, DexAbiType)>,
+    /// The value that a view returns, or the `response` of a
+    /// call. `None` if the method returns nothing, or a
+    /// `DexCallResponse` with a response of its own.
+    pub result: Option<DexAbiType>,
+}
+
+/// Borsh layout of a type. Types that have a different JSON
+/// representation, such as [`AssetId`], have their own variants.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[cfg_attr(not(feature = "json"), near(serializers=[borsh]))]
+#[cfg_attr(feature = "json", near(serializers=[borsh, json]))]
+pub enum DexAbiType {
+    Bool,
+    U8,
+    U16,
+    U32,
+    U64,
+    U128,
+    I8,
+    I16,
+    I32,
+    I64,
+    I128,
+    String,
+    /// Serialized as a `String`.
+    AccountId,
+    AssetId,
+    DexId,
+    /// Serialized as a `Vec<u8>` of the curve type and the key.
+    PublicKey,
+    /// A `Vec<u8>` written as base64 in JSON.
+    Base64,
+    Vec(Box<DexAbiType>),
+    Option(Box<DexAbiType>),
+    /// Serialized as a `Vec` of key-value pairs.
+    Map(Box<DexAbiType>, Box<DexAbiType>),
+    Tuple(Vec<DexAbiType>),
+    Struct {
+        name: String,
+        fields: DexAbiFields,
+    },
+    Enum {
+        name: String,
+        variants: Vec<(String, DexAbiFields)>,
+    },
+}
+
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[cfg_attr(not(feature = "json"), near(serializers=[borsh]))]
+#[cfg_attr(feature = "json", near(serializers=[borsh, json]))]
+pub enum DexAbiFields {
+    Named(Vec<(String, DexAbiType)>),
+    Unnamed(Vec<DexAbiType>),
+    Unit,
 }
 
 #[macro_export]
@@ -1,13 +1,9 @@
-use base64::{Engine, prelude::BASE64_STANDARD};
-use borsh::BorshSerialize;
 use clap::{Parser, Subcommand};
-use near_api::{
-    Contract, NearToken, NetworkConfig, RPCEndpoint, Signer,
-    types::{AccountId, PublicKey},
-};
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use serde_json::json;
-use std::{collections::HashMap, fmt::Display, str::FromStr, sync::Arc};
+use intear_dex_client::{DexEngineClient, FunctionCall, Otc, ViewCall};
+use intear_dex_types::{AssetId, DexId};
+use near_api::{Contract, NearToken, NetworkConfig, RPCEndpoint, Signer, types::AccountId};
+use near_sdk::{PublicKey, json_types::U128, serde_json};
+use std::{str::FromStr, sync::Arc};
 use tokio::process::Command;
 
 #[derive(Parser)]
@@ -24,6 +20,15 @@ enum Commands {
         #[command(subcommand)]
         action: OtcAction,
     },
+    /// Calls a method of any dex, with JSON args that are encoded
+    /// to borsh following the ABI of the dex.
+    DexCall {
+        account_id: AccountId,
+        dex_id: DexId,
+        method: String,
+        #[arg(default_value = "{}")]
+        args: String,
+    },
 }
 
 #[derive(Subcommand)]
@@ -77,6 +82,32 @@ fn network() -> NetworkConfig {
     }
 }
 
+async fn send(
+    call: FunctionCall,
+    account_id: AccountId,
+    signer: Arc<Signer>,
+) -> Result<near_api::types::transaction::result::ExecutionFinalResult, Box<dyn std::error::Error>>
+{
+    Ok(Contract(call.receiver_id)
+        .call_function_raw(&call.method_name, call.args)
+        .transaction()
+        .gas(call.gas)
+        .deposit(call.deposit)
+        .with_signer(account_id, signer)
+        .send_to(&network())
+        .await?)
+}
+
+async fn view<T>(call: ViewCall<T>) -> Result<T, Box<dyn std::error::Error>> {
+    let result = Contract(call.receiver_id.clone())
+        .call_function_raw(&call.method_name, call.args.clone())
+        .read_only::<serde_json::Value>()
+        .fetch_from(&network())
+        .await?
+        .data;
+    Ok(call.decode(&serde_json::to_vec(&result)?)?)
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
@@ -84,6 +115,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     println!("Loaded config: deployer_id = {}", config.deployer_id);
 
+    let engine = DexEngineClient::new(config.dex_contract_id.clone());
+    let otc = Otc::new(
+        engine.clone(),
+        DexId {
+            deployer: config.deployer_id.clone(),
+            id: "otc".to_string(),
+        },
+    );
+
     match cli.command {
         Commands::Otc { action } => match action {
             OtcAction::Deploy => {
@@ -119,78 +159,39 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 println!("Deploying otc-dex");
                 let wasm =
                     std::fs::read("./target/wasm32-unknown-unknown/release/otc_dex.wasm").unwrap();
-                let wasm_base64 = BASE64_STANDARD.encode(&wasm);
-                let args = json!({
-                    "last_part_of_id": "otc",
-                    "code_base64": wasm_base64,
-                });
-                let result = Contract(config.dex_contract_id.clone())
-                    .call_function("deploy_dex_code", args)
-                    .transaction()
-                    .max_gas()
-                    .deposit(NearToken::from_yoctonear(1))
-                    .with_signer(config.deployer_id.clone(), Arc::clone(&config.signer))
-                    .send_to(&network())
-                    .await?;
+                let result = send(
+                    engine.deploy_dex_code("otc", wasm),
+                    config.deployer_id.clone(),
+                    Arc::clone(&config.signer),
+                )
+                .await?;
                 println!("Deployed. Result: {:?}", result.outcome());
             }
             OtcAction::SetAuthorizedKey { account_id, key } => {
                 let account_signer =
                     Signer::from_keystore_with_search_for_keys(account_id.clone(), &network())
                         .await?;
-                let dex_id = format!("{}/{}", config.deployer_id.clone(), "otc");
-                #[derive(BorshSerialize)]
-                struct OtcSetAuthorizedKeyArgs {
-                    key_bytes: Vec<u8>,
-                }
-                let args = json!({
-                    "dex_id": dex_id,
-                    "method": "set_authorized_key",
-                    "args": BASE64_STANDARD.encode(borsh::to_vec(&OtcSetAuthorizedKeyArgs {
-                        key_bytes: match key {
-                            PublicKey::ED25519(public_key) => [vec![0], public_key.0.to_vec()].concat(),
-                            PublicKey::SECP256K1(public_key) => [vec![1], public_key.0.to_vec()].concat(),
-                        },
-                    }).unwrap()),
-                    "attached_assets": {},
-                });
-                let result = Contract(config.dex_contract_id.clone())
-                    .call_function("dex_call", args)
-                    .transaction()
-                    .max_gas()
-                    .deposit(NearToken::from_yoctonear(1))
-                    .with_signer(account_id.clone(), account_signer)
-                    .send_to(&network())
-                    .await?;
+                let result = send(
+                    otc.set_authorized_key(key).build(),
+                    account_id,
+                    account_signer,
+                )
+                .await?;
                 println!("Set the authorized key. Result: {:?}", result.outcome());
             }
             OtcAction::StorageDeposit { account_id, amount } => {
                 let account_signer =
                     Signer::from_keystore_with_search_for_keys(account_id.clone(), &network())
                         .await?;
-                let dex_id = format!("{}/{}", config.deployer_id.clone(), "otc");
-                #[derive(BorshSerialize)]
-                struct OtcStorageDepositArgs;
-                let args = json!({
-                    "operations": [{
-                        "DexCall": {
-                            "dex_id": dex_id,
-                            "method": "storage_deposit",
-                            "args": BASE64_STANDARD.encode(borsh::to_vec(&OtcStorageDepositArgs).unwrap()),
-                            "attached_assets": {
-                                "near": amount,
-                            },
-                        }
-                    }]
-                });
-                let result = Contract(config.dex_contract_id.clone())
-                    .call_function("deposit_near", args)
-                    .transaction()
-                    .max_gas()
-                    .deposit(amount)
-                    .with_signer(account_id.clone(), account_signer)
-                    .send_to(&network())
-                    .await?;
+                let result = send(
+                    engine.deposit_near(
+                        amount,
+                        Some(vec![otc.storage_deposit(amount).into_operation()]),
+                    ),
+                    account_id,
+                    account_signer,
+                )
+                .await?;
                 println!("Storage deposit completed. Result: {:?}", result.outcome());
             }
             OtcAction::DepositAssets {
@@ -201,111 +202,32 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 let account_signer =
                     Signer::from_keystore_with_search_for_keys(account_id.clone(), &network())
                         .await?;
-                let dex_id = format!("{}/{}", config.deployer_id.clone(), "otc");
-                #[derive(BorshSerialize)]
-                struct OtcDepositAssetsArgs;
-                // U128 serializes as a number
-                let attached_assets: HashMap<AssetId, String> =
-                    HashMap::from_iter([(asset_id, amount.to_string())]);
-                let args = json!({
-                    "dex_id": dex_id,
-                    "method": "deposit_assets",
-                    "args": BASE64_STANDARD.encode(borsh::to_vec(&OtcDepositAssetsArgs).unwrap()),
-                    "attached_assets": attached_assets,
-                });
-                let result = Contract(config.dex_contract_id.clone())
-                    .call_function("dex_call", args)
-                    .transaction()
-                    .max_gas()
-                    .deposit(NearToken::from_yoctonear(1))
-                    .with_signer(account_id.clone(), account_signer)
-                    .send_to(&network())
-                    .await?;
+                let result = send(
+                    otc.deposit_assets().attach(asset_id, U128(amount)).build(),
+                    account_id,
+                    account_signer,
+                )
+                .await?;
                 println!("Deposit assets completed. Result: {:?}", result.outcome());
             }
         },
-    }
-
-    Ok(())
-}
-
-#[derive(
-    PartialEq, Eq, Hash, Clone, PartialOrd, Ord, Debug, BorshSerialize, borsh::BorshDeserialize,
-)]
-pub enum AssetId {
-    Near,
-    Nep141(AccountId),
-    Nep245(AccountId, String),
-    Nep171(AccountId, String),
-}
-
-impl Display for AssetId {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Near => write!(f, "near"),
-            Self::Nep141(contract_id) => write!(f, "nep141:{contract_id}"),
-            Self::Nep245(contract_id, token_id) => write!(f, "nep245:{contract_id}:{token_id}"),
-            Self::Nep171(contract_id, token_id) => write!(f, "nep171:{contract_id}:{token_id}"),
+        Commands::DexCall {
+            account_id,
+            dex_id,
+            method,
+            args,
+        } => {
+            let abi = view(engine.dex_abi(dex_id.clone())).await?;
+            let call = engine
+                .dex_call(dex_id, method)
+                .json_args(&abi, &serde_json::from_str(&args)?)?
+                .build();
+            let account_signer =
+                Signer::from_keystore_with_search_for_keys(account_id.clone(), &network()).await?;
+            let result = send(call, account_id, account_signer).await?;
+            println!("Dex call completed. Result: {:?}", result.outcome());
         }
     }
-}
 
-impl FromStr for AssetId {
-    type Err = String;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "near" => Ok(Self::Near),
-            _ => match s.split_once(':') {
-                Some(("nep141", contract_id)) => {
-                    Ok(Self::Nep141(contract_id.parse().map_err(|e| {
-                        format!("Invalid account id {contract_id}: {e}")
-                    })?))
-                }
-                Some(("nep245", rest)) => {
-                    if let Some((contract_id, token_id)) = rest.split_once(':') {
-                        Ok(Self::Nep245(
-                            contract_id
-                                .parse()
-                                .map_err(|e| format!("Invalid account id {contract_id}: {e}"))?,
-                            token_id.to_string(),
-                        ))
-                    } else {
-                        Err(format!("Invalid asset id: {s}"))
-                    }
-                }
-                Some(("nep171", rest)) => {
-                    if let Some((contract_id, token_id)) = rest.split_once(':') {
-                        Ok(Self::Nep171(
-                            contract_id
-                                .parse()
-                                .map_err(|e| format!("Invalid account id {contract_id}: {e}"))?,
-                            token_id.to_string(),
-                        ))
-                    } else {
-                        Err(format!("Invalid asset id: {s}"))
-                    }
-                }
-                _ => Err(format!("Invalid asset id: {s}")),
-            },
-        }
-    }
-}
-
-impl Serialize for AssetId {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        serde::Serialize::serialize(&self.to_string(), serializer)
-    }
-}
-
-impl<'de> Deserialize<'de> for AssetId {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let s: String = Deserialize::deserialize(deserializer)?;
-        Self::from_str(&s).map_err(serde::de::Error::custom)
-    }
+    Ok(())
 }
@@ -0,0 +1,169 @@
+use intear_dex_types::{AssetId, DexId, expect};
+use near_sdk::{AccountId, near};
+
+use crate::{DexEngine, DexEngineExt, IntearDexEvent};
+
+impl DexEngine {
+    pub(crate) fn assert_owner(&self) {
+        expect!(
+            near_sdk::env::predecessor_account_id() == self.owner,
+            "Only the owner can call this method"
+        );
+    }
+
+    /// Panics if the engine is paused. Withdrawals of users' own
+    /// balances don't check this, so they keep working.
+    pub(crate) fn assert_not_paused(&self) {
+        expect!(!self.paused, "Dex engine is paused");
+    }
+
+    pub(crate) fn assert_dex_not_paused(&self, dex_id: &DexId) {
+        self.assert_not_paused();
+        expect!(
+            !self.paused_dexes.contains(dex_id),
+            "Dex {dex_id} is paused"
+        );
+    }
+
+    pub(crate) fn assert_asset_not_paused(&self, asset_id: &AssetId) {
+        self.assert_not_paused();
+        expect!(
+            !self.paused_assets.contains(asset_id),
+            "Asset {asset_id} is paused"
+        );
+    }
+
+    /// Whether a swap between these assets on this dex is
+    /// blocked by any of the pauses.
+    pub(crate) fn is_swap_paused(
+        &self,
+        dex_id: &DexId,
+        asset_in: &AssetId,
+        asset_out: &AssetId,
+    ) -> bool {
+        self.paused
+            || self.paused_dexes.contains(dex_id)
+            || self.paused_assets.contains(asset_in)
+            || self.paused_assets.contains(asset_out)
+    }
+}
+
+#[near]
+impl DexEngine {
+    /// Propose a new owner, who has to call `accept_owner` to
+    /// become the owner. `None` cancels the proposal.
+    #[payable]
+    pub fn propose_owner(&mut self, new_owner: Option<AccountId>) {
+        near_sdk::assert_one_yocto();
+        self.assert_owner();
+        self.pending_owner = new_owner.clone();
+        IntearDexEvent::OwnerProposed {
+            owner: self.owner.clone(),
+            pending_owner: new_owner,
+        }
+        .emit();
+    }
+
+    #[payable]
+    pub fn accept_owner(&mut self) {
+        near_sdk::assert_one_yocto();
+        let new_owner = near_sdk::env::predecessor_account_id();
+        expect!(
+            self.pending_owner.as_ref() == Some(&new_owner),
+            "Only the proposed owner can accept ownership"
+        );
+        self.pending_owner = None;
+        let old_owner = std::mem::replace(&mut self.owner, new_owner.clone());
+        IntearDexEvent::OwnerChanged {
+            old_owner,
+            new_owner,
+        }
+        .emit();
+    }
+
+    /// Pause swaps, dex calls, flash loans, transfers and
+    /// deposits. Users can still withdraw their balances.
+    #[payable]
+    pub fn pause(&mut self) {
+        near_sdk::assert_one_yocto();
+        self.assert_owner();
+        expect!(!self.paused, "Dex engine is already paused");
+        self.paused = true;
+        IntearDexEvent::EnginePaused {}.emit();
+    }
+
+    #[payable]
+    pub fn unpause(&mut self) {
+        near_sdk::assert_one_yocto();
+        self.assert_owner();
+        expect!(self.paused, "Dex engine is not paused");
+        self.paused = false;
+        IntearDexEvent::EngineUnpaused {}.emit();
+    }
+
+    /// Block swaps, dex calls and flash loans on a dex.
+    #[payable]
+    pub fn pause_dex(&mut self, dex_id: DexId) {
+        near_sdk::assert_one_yocto();
+        self.assert_owner();
+        expect!(
+            self.paused_dexes.insert(dex_id.clone()),
+            "Dex {dex_id} is already paused"
+        );
+        IntearDexEvent::DexPaused { dex_id }.emit();
+    }
+
+    #[payable]
+    pub fn unpause_dex(&mut self, dex_id: DexId) {
+        near_sdk::assert_one_yocto();
+        self.assert_owner();
+        expect!(
+            self.paused_dexes.remove(&dex_id),
+            "Dex {dex_id} is not paused"
+        );
+        IntearDexEvent::DexUnpaused { dex_id }.emit();
+    }
+
+    /// Block deposits and swaps of an asset.
+    #[payable]
+    pub fn pause_asset(&mut self, asset_id: AssetId) {
+        near_sdk::assert_one_yocto();
+        self.assert_owner();
+        expect!(
+            self.paused_assets.insert(asset_id.clone()),
+            "Asset {asset_id} is already paused"
+        );
+        IntearDexEvent::AssetPaused { asset_id }.emit();
+    }
+
+    #[payable]
+    pub fn unpause_asset(&mut self, asset_id: AssetId) {
+        near_sdk::assert_one_yocto();
+        self.assert_owner();
+        expect!(
+            self.paused_assets.remove(&asset_id),
+            "Asset {asset_id} is not paused"
+        );
+        IntearDexEvent::AssetUnpaused { asset_id }.emit();
+    }
+
+    pub fn owner(&self) -> AccountId {
+        self.owner.clone()
+    }
+
+    pub fn pending_owner(&self) -> Option<AccountId> {
+        self.pending_owner.clone()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn paused_dexes(&self) -> Vec<DexId> {
+        self.paused_dexes.iter().cloned().collect()
+    }
+
+    pub fn paused_assets(&self) -> Vec<AssetId> {
+        self.paused_assets.iter().cloned().collect()
+    }
+}
@@ -0,0 +1,208 @@
+use intear_dex_types::{AssetId, expect};
+use near_sdk::{
+    AccountId,
+    json_types::{U64, U128},
+    near,
+};
+
+use crate::{
+    DexEngine, DexEngineExt, IntearDexEvent, internal_asset_operations::AccountOrDexId,
+    storage_breakdown::StorageCategory,
+};
+
+/// Maximum number of allowances that an account can have.
+pub const MAX_ALLOWANCES: usize = 50;
+
+/// Permission for `spender` to use up to `amount` of the
+/// owner's `asset_id` balance.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh, json])]
+pub struct Allowance {
+    pub spender: AccountId,
+    pub asset_id: AssetId,
+    pub amount: U128,
+    /// Block timestamp in nanoseconds after which the
+    /// allowance can't be used.
+    pub expires_at: Option<U64>,
+}
+
+impl Allowance {
+    fn is_active(&self) -> bool {
+        self.expires_at
+            .is_none_or(|expires_at| near_sdk::env::block_timestamp() < expires_at.0)
+    }
+}
+
+impl DexEngine {
+    /// Reduces the allowance that `owner` gave to `spender` by
+    /// `amount`, and removes it once it's fully used.
+    pub(crate) fn internal_spend_allowance(
+        &mut self,
+        owner: &AccountId,
+        spender: &AccountId,
+        asset_id: &AssetId,
+        amount: U128,
+    ) {
+        let storage_usage_before = near_sdk::env::storage_usage();
+        let allowances = self
+            .allowances
+            .get_mut(owner)
+            .unwrap_or_else(|| panic!("{owner} has no allowances"));
+        let index = allowances
+            .iter()
+            .position(|allowance| &allowance.spender == spender && &allowance.asset_id == asset_id)
+            .unwrap_or_else(|| panic!("{owner} didn't approve {asset_id} for {spender}"));
+        let allowance = &mut allowances[index];
+        expect!(allowance.is_active(), "Allowance has expired");
+        allowance.amount.0 = allowance.amount.0.checked_sub(amount.0).unwrap_or_else(|| {
+            panic!(
+                "Allowance of {spender} for {asset_id} is {}, but {} was requested",
+                allowance.amount.0, amount.0
+            )
+        });
+        let remaining = allowance.amount;
+        if remaining.0 == 0 {
+            allowances.remove(index);
+            if allowances.is_empty() {
+                self.allowances.remove(owner);
+            }
+        }
+        self.allowances.flush();
+        let storage_usage_after = near_sdk::env::storage_usage();
+        self.internal_charge_storage(
+            AccountOrDexId::Account(owner.clone()),
+            StorageCategory::AccountData,
+            storage_usage_before,
+            storage_usage_after,
+        );
+        IntearDexEvent::AllowanceSpent {
+            owner: owner.clone(),
+            spender: spender.clone(),
+            asset_id: asset_id.clone(),
+            amount,
+            remaining,
+        }
+        .emit();
+    }
+}
+
+#[near]
+impl DexEngine {
+    /// Allow `spender` to use up to `amount` of the caller's
+    /// `asset_id` balance with `TransferFrom` and
+    /// `SwapSimpleFrom` operations. Replaces the previous
+    /// allowance for the same spender and asset.
+    #[payable]
+    pub fn approve_asset(
+        &mut self,
+        spender: AccountId,
+        asset_id: AssetId,
+        amount: U128,
+        expires_at: Option<U64>,
+    ) {
+        near_sdk::assert_one_yocto();
+        let owner = near_sdk::env::predecessor_account_id();
+        expect!(spender != owner, "Can't approve assets for yourself");
+        expect!(amount.0 > 0, "Amount must be greater than 0");
+        let storage_usage_before = near_sdk::env::storage_usage();
+        let allowances = self.allowances.entry(owner.clone()).or_default();
+        let new_allowance = Allowance {
+            spender: spender.clone(),
+            asset_id: asset_id.clone(),
+            amount,
+            expires_at,
+        };
+        match allowances
+            .iter_mut()
+            .find(|allowance| allowance.spender == spender && allowance.asset_id == asset_id)
+        {
+            Some(allowance) => *allowance = new_allowance,
+            None => {
+                expect!(
+                    allowances.len() < MAX_ALLOWANCES,
+                    "Can't have more than {MAX_ALLOWANCES} allowances"
+                );
+                allowances.push(new_allowance);
+            }
+        }
+        self.allowances.flush();
+        let storage_usage_after = near_sdk::env::storage_usage();
+        self.internal_charge_storage(
+            AccountOrDexId::Account(owner.clone()),
+            StorageCategory::AccountData,
+            storage_usage_before,
+            storage_usage_after,
+        );
+        IntearDexEvent::AssetApproved {
+            owner,
+            spender,
+            asset_id,
+            amount,
+            expires_at,
+        }
+        .emit();
+    }
+
+    #[payable]
+    pub fn revoke_asset(&mut self, spender: AccountId, asset_id: AssetId) {
+        near_sdk::assert_one_yocto();
+        let owner = near_sdk::env::predecessor_account_id();
+        let storage_usage_before = near_sdk::env::storage_usage();
+        let allowances = self
+            .allowances
+            .get_mut(&owner)
+            .expect("No allowances found");
+        let index = allowances
+            .iter()
+            .position(|allowance| allowance.spender == spender && allowance.asset_id == asset_id)
+            .expect("Allowance not found");
+        allowances.remove(index);
+        if allowances.is_empty() {
+            self.allowances.remove(&owner);
+        }
+        self.allowances.flush();
+        let storage_usage_after = near_sdk::env::storage_usage();
+        self.internal_charge_storage(
+            AccountOrDexId::Account(owner.clone()),
+            StorageCategory::AccountData,
+            storage_usage_before,
+            storage_usage_after,
+        );
+        IntearDexEvent::AssetApprovalRevoked {
+            owner,
+            spender,
+            asset_id,
+        }
+        .emit();
+    }
+
+    /// Allowances of `owner` that haven't expired.
+    pub fn allowances_of(&self, owner: AccountId) -> Vec<Allowance> {
+        self.allowances
+            .get(&owner)
+            .map(|allowances| {
+                allowances
+                    .iter()
+                    .filter(|allowance| allowance.is_active())
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// How much of `owner`'s `asset_id` the `spender` can use.
+    pub fn allowance(&self, owner: AccountId, spender: AccountId, asset_id: AssetId) -> U128 {
+        self.allowances
+            .get(&owner)
+            .and_then(|allowances| {
+                allowances.iter().find(|allowance| {
+                    allowance.spender == spender
+                        && allowance.asset_id == asset_id
+                        && allowance.is_active()
+                })
+            })
+            .map(|allowance| allowance.amount)
+            .unwrap_or_default()
+    }
+}
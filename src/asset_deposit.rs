@@ -19,6 +19,7 @@ impl DexEngine {
     /// balance for the user.
     pub fn deposit_near(&mut self, operations: Option<Vec<Operation>>) {
         let deposit = U128(near_sdk::env::attached_deposit().as_yoctonear());
+        self.assert_asset_not_paused(&AssetId::Near);
         self.total_in_custody
             .entry(AssetId::Near)
             .and_modify(|b| {
@@ -66,6 +67,7 @@ impl FungibleTokenReceiver for DexEngine {
         msg: String,
     ) -> PromiseOrValue<U128> {
         let contract_id = near_sdk::env::predecessor_account_id();
+        self.assert_asset_not_paused(&AssetId::Nep141(contract_id.clone()));
         let operations: Option<Vec<Operation>> = if msg.is_empty() {
             None
         } else {
@@ -122,6 +124,7 @@ impl NonFungibleTokenReceiver for DexEngine {
         msg: String,
     ) -> PromiseOrValue<bool> {
         let contract_id = near_sdk::env::predecessor_account_id();
+        self.assert_asset_not_paused(&AssetId::Nep171(contract_id.clone(), token_id.clone()));
         let operations: Option<Vec<Operation>> = if msg.is_empty() {
             None
         } else {
@@ -193,6 +196,9 @@ impl DexEngine {
         );
 
         let contract_id = near_sdk::env::predecessor_account_id();
+        for token_id in token_ids.iter() {
+            self.assert_asset_not_paused(&AssetId::Nep245(contract_id.clone(), token_id.clone()));
+        }
 
         let operations: Option<Vec<Operation>> = if msg.is_empty() {
             None
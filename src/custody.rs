@@ -0,0 +1,315 @@
+use intear_dex_types::{AssetId, expect};
+use near_contract_standards::{
+    fungible_token::core::ext_ft_core, non_fungible_token::core::ext_nft_core,
+};
+use near_sdk::{
+    AccountId, Gas, NearToken, Promise, PromiseError, PromiseOrValue, PromiseResult,
+    json_types::U128, near,
+};
+
+use crate::{DexEngine, DexEngineExt, IntearDexEvent};
+
+const GAS_FOR_BALANCE_VIEW: Gas = Gas::from_tgas(5);
+const GAS_FOR_RECONCILE_CALLBACK: Gas = Gas::from_tgas(10);
+/// Enough for the transfer and its callback.
+const GAS_FOR_SWEEP_CALLBACK: Gas = Gas::from_tgas(30);
+const GAS_FOR_SWEEP_TRANSFER: Gas = Gas::from_tgas(10);
+const GAS_FOR_SWEEP_TRANSFER_CALLBACK: Gas = Gas::from_tgas(5);
+
+/// Comparison of what the contract holds and what it owes.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[json])]
+pub struct CustodyReconciliation {
+    pub asset_id: AssetId,
+    /// Amount that the contract actually holds.
+    pub balance: U128,
+    pub total_in_custody: U128,
+    pub pending_withdrawals: U128,
+    pub surplus: U128,
+    pub deficit: U128,
+}
+
+impl DexEngine {
+    /// Marks `amount` as leaving the contract. NEAR is not
+    /// tracked, since it leaves the balance as soon as the
+    /// transfer is created.
+    pub(crate) fn internal_add_pending_withdrawal(&mut self, asset_id: AssetId, amount: U128) {
+        if asset_id == AssetId::Near {
+            return;
+        }
+        let pending = self.pending_withdrawals.entry(asset_id).or_default();
+        pending.0 = pending
+            .0
+            .checked_add(amount.0)
+            .expect("Pending withdrawals overflow");
+    }
+
+    /// Called when the transfer of `amount` has resolved,
+    /// whether it succeeded or not. Withdrawals that started
+    /// before `pending_withdrawals` was added were never
+    /// tracked, so this saturates at zero instead of panicking
+    /// before their refund.
+    pub(crate) fn internal_remove_pending_withdrawal(&mut self, asset_id: AssetId, amount: U128) {
+        if asset_id == AssetId::Near {
+            return;
+        }
+        let Some(pending) = self.pending_withdrawals.get_mut(&asset_id) else {
+            return;
+        };
+        pending.0 = pending.0.saturating_sub(amount.0);
+        if pending.0 == 0 {
+            self.pending_withdrawals.remove(&asset_id);
+        }
+    }
+
+    /// Queries the balance of the contract in `asset_id` and
+    /// passes it as the only promise result to `callback`.
+    fn internal_query_own_balance(asset_id: &AssetId, callback: Promise) -> Promise {
+        let (contract_id, method, args) = match asset_id {
+            AssetId::Near => panic!("NEAR balance doesn't need a query"),
+            AssetId::Nep141(contract_id) => (
+                contract_id,
+                "ft_balance_of",
+                near_sdk::serde_json::json!({
+                    "account_id": near_sdk::env::current_account_id(),
+                }),
+            ),
+            AssetId::Nep171(contract_id, token_id) => (
+                contract_id,
+                "nft_token",
+                near_sdk::serde_json::json!({
+                    "token_id": token_id,
+                }),
+            ),
+            AssetId::Nep245(contract_id, token_id) => (
+                contract_id,
+                "mt_balance_of",
+                near_sdk::serde_json::json!({
+                    "account_id": near_sdk::env::current_account_id(),
+                    "token_id": token_id,
+                }),
+            ),
+        };
+        Promise::new(contract_id.clone())
+            .function_call(
+                method.to_string(),
+                args.to_string().into_bytes(),
+                NearToken::from_yoctonear(0),
+                GAS_FOR_BALANCE_VIEW,
+            )
+            .then(callback)
+    }
+
+    /// NEAR balance that isn't locked for storage. It also
+    /// includes unused storage deposits of users and dexes.
+    fn internal_free_near_balance() -> U128 {
+        let locked = near_sdk::env::storage_byte_cost()
+            .saturating_mul(u128::from(near_sdk::env::storage_usage()));
+        U128(
+            near_sdk::env::account_balance()
+                .saturating_sub(locked)
+                .as_yoctonear(),
+        )
+    }
+
+    fn internal_reconcile(&self, asset_id: AssetId, balance: U128) -> CustodyReconciliation {
+        let total_in_custody = self.total_in_custody(asset_id.clone()).unwrap_or_default();
+        let pending_withdrawals = self
+            .pending_withdrawals
+            .get(&asset_id)
+            .copied()
+            .unwrap_or_default();
+        let owed = total_in_custody
+            .0
+            .checked_add(pending_withdrawals.0)
+            .expect("Custody overflow");
+        let reconciliation = CustodyReconciliation {
+            asset_id,
+            balance,
+            total_in_custody,
+            pending_withdrawals,
+            surplus: U128(balance.0.saturating_sub(owed)),
+            deficit: U128(owed.saturating_sub(balance.0)),
+        };
+        if reconciliation.deficit.0 > 0 {
+            near_sdk::env::log_str(&format!(
+                "Custody deficit of {} in {}",
+                reconciliation.deficit.0, reconciliation.asset_id
+            ));
+        }
+        IntearDexEvent::CustodyReconciled {
+            asset_id: reconciliation.asset_id.clone(),
+            balance: reconciliation.balance,
+            total_in_custody: reconciliation.total_in_custody,
+            pending_withdrawals: reconciliation.pending_withdrawals,
+            surplus: reconciliation.surplus,
+            deficit: reconciliation.deficit,
+        }
+        .emit();
+        reconciliation
+    }
+}
+
+/// Parses the result of `ft_balance_of`, `nft_token` or
+/// `mt_balance_of` into the amount the contract holds.
+fn parse_own_balance(asset_id: &AssetId, result: &[u8]) -> Option<U128> {
+    match asset_id {
+        AssetId::Near => None,
+        AssetId::Nep141(_) | AssetId::Nep245(_, _) => near_sdk::serde_json::from_slice(result).ok(),
+        AssetId::Nep171(_, _) => {
+            let token: Option<near_sdk::serde_json::Value> =
+                near_sdk::serde_json::from_slice(result).ok()?;
+            let is_owner = token.is_some_and(|token| {
+                token["owner_id"].as_str() == Some(near_sdk::env::current_account_id().as_str())
+            });
+            Some(U128(if is_owner { 1 } else { 0 }))
+        }
+    }
+}
+
+fn own_balance_from_promise(asset_id: &AssetId) -> U128 {
+    match near_sdk::env::promise_result(0) {
+        PromiseResult::Successful(result) => parse_own_balance(asset_id, &result)
+            .unwrap_or_else(|| panic!("Invalid balance response for {asset_id}")),
+        PromiseResult::Failed => panic!("Failed to query balance of {asset_id}"),
+    }
+}
+
+#[near]
+impl DexEngine {
+    /// Compares the amount of `asset_id` that the contract holds
+    /// with `total_in_custody` and emits `CustodyReconciled`.
+    /// For NEAR, the balance also includes unused storage
+    /// deposits, so a surplus is expected.
+    pub fn reconcile_custody(
+        &mut self,
+        asset_id: AssetId,
+    ) -> PromiseOrValue<CustodyReconciliation> {
+        if asset_id == AssetId::Near {
+            return PromiseOrValue::Value(
+                self.internal_reconcile(asset_id, Self::internal_free_near_balance()),
+            );
+        }
+        PromiseOrValue::Promise(Self::internal_query_own_balance(
+            &asset_id,
+            Self::ext(near_sdk::env::current_account_id())
+                .with_static_gas(GAS_FOR_RECONCILE_CALLBACK)
+                .after_reconcile_custody(asset_id.clone()),
+        ))
+    }
+
+    #[private]
+    pub fn after_reconcile_custody(&mut self, asset_id: AssetId) -> CustodyReconciliation {
+        let balance = own_balance_from_promise(&asset_id);
+        self.internal_reconcile(asset_id, balance)
+    }
+
+    /// Send `amount` of the surplus of `asset_id` to the
+    /// treasury, or nothing if the surplus is smaller than
+    /// `amount`. Can only be called by the treasury. NEAR can't
+    /// be swept, because its surplus includes storage deposits.
+    ///
+    /// Deposits that have been transferred to the contract, but
+    /// whose `*_on_transfer` hasn't run yet, look like surplus,
+    /// so the surplus can't be swept as a whole. The treasury
+    /// should only sweep an amount that has stayed in the
+    /// surplus over several reconciliations.
+    pub fn sweep_custody_surplus(&mut self, asset_id: AssetId, amount: U128) -> Promise {
+        expect!(
+            near_sdk::env::predecessor_account_id() == self.treasury,
+            "Only the treasury can sweep the surplus"
+        );
+        expect!(asset_id != AssetId::Near, "NEAR surplus can't be swept");
+        Self::internal_query_own_balance(
+            &asset_id,
+            Self::ext(near_sdk::env::current_account_id())
+                .with_static_gas(GAS_FOR_SWEEP_CALLBACK)
+                .after_sweep_custody_surplus_balance(asset_id.clone(), amount),
+        )
+    }
+
+    #[private]
+    pub fn after_sweep_custody_surplus_balance(
+        &mut self,
+        asset_id: AssetId,
+        amount: U128,
+    ) -> PromiseOrValue<U128> {
+        let balance = own_balance_from_promise(&asset_id);
+        let surplus = self.internal_reconcile(asset_id.clone(), balance).surplus;
+        if amount.0 == 0 || amount.0 > surplus.0 {
+            return PromiseOrValue::Value(U128(0));
+        }
+        self.internal_add_pending_withdrawal(asset_id.clone(), amount);
+        let treasury = self.treasury.clone();
+        let transfer = match &asset_id {
+            AssetId::Near => unreachable!("Checked in sweep_custody_surplus"),
+            AssetId::Nep141(contract_id) => ext_ft_core::ext(contract_id.clone())
+                .with_attached_deposit(NearToken::from_yoctonear(1))
+                .with_static_gas(GAS_FOR_SWEEP_TRANSFER)
+                .ft_transfer(treasury.clone(), amount, None),
+            AssetId::Nep171(contract_id, token_id) => ext_nft_core::ext(contract_id.clone())
+                .with_attached_deposit(NearToken::from_yoctonear(1))
+                .with_static_gas(GAS_FOR_SWEEP_TRANSFER)
+                .nft_transfer(treasury.clone(), token_id.clone(), None, None),
+            AssetId::Nep245(contract_id, token_id) => Promise::new(contract_id.clone())
+                .function_call(
+                    "mt_transfer",
+                    near_sdk::serde_json::json!({
+                        "receiver_id": treasury,
+                        "token_id": token_id,
+                        "amount": amount,
+                        "approval": null,
+                        "memo": null,
+                    })
+                    .to_string()
+                    .into_bytes(),
+                    NearToken::from_yoctonear(1),
+                    GAS_FOR_SWEEP_TRANSFER,
+                ),
+        };
+        PromiseOrValue::Promise(
+            transfer.then(
+                Self::ext(near_sdk::env::current_account_id())
+                    .with_static_gas(GAS_FOR_SWEEP_TRANSFER_CALLBACK)
+                    .after_sweep_custody_surplus(asset_id, amount, treasury),
+            ),
+        )
+    }
+
+    #[private]
+    pub fn after_sweep_custody_surplus(
+        &mut self,
+        asset_id: AssetId,
+        amount: U128,
+        treasury: AccountId,
+        #[callback_result] result: Result<(), PromiseError>,
+    ) -> U128 {
+        self.internal_remove_pending_withdrawal(asset_id.clone(), amount);
+        if result.is_err() {
+            return U128(0);
+        }
+        IntearDexEvent::CustodySurplusSwept {
+            asset_id,
+            amount,
+            treasury,
+        }
+        .emit();
+        amount
+    }
+
+    #[payable]
+    pub fn set_treasury(&mut self, treasury: AccountId) {
+        near_sdk::assert_one_yocto();
+        self.assert_owner();
+        IntearDexEvent::TreasuryUpdated {
+            old_treasury: std::mem::replace(&mut self.treasury, treasury.clone()),
+            new_treasury: treasury,
+        }
+        .emit();
+    }
+
+    pub fn treasury(&self) -> AccountId {
+        self.treasury.clone()
+    }
+}
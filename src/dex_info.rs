@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+
+use intear_dex_types::{
+    DexId, DexMetadata, DexPair, SwapRequest, SwapRequestAmount, SwapResponse, expect,
+};
+use near_sdk::near;
+
+use crate::{
+    CallType, DexEngine, DexEngineExt, RunnerData,
+    internal_operations::{dex_exports_function, run_dex_method},
+};
+
+/// Names of the `Dex` trait exports that describe a dex
+/// without executing a swap.
+pub const QUOTE_METHOD: &str = "quote";
+pub const METADATA_METHOD: &str = "metadata";
+pub const PAIRS_METHOD: &str = "pairs";
+
+/// Pairs returned by `dex_pairs` when no `limit` is given.
+pub const DEFAULT_PAIRS_LIMIT: u64 = 100;
+
+impl DexEngine {
+    /// Calls an export of the dex in view mode and decodes its
+    /// result. Panics if the dex doesn't export `method`.
+    fn internal_dex_info<T: near_sdk::borsh::BorshDeserialize>(
+        &self,
+        dex_id: &DexId,
+        method: &str,
+        request: Vec<u8>,
+    ) -> T {
+        let code = self.dex_codes.get(dex_id).expect("Dex code not found");
+        expect!(
+            dex_exports_function(code, method),
+            "Dex {dex_id} doesn't export `{method}`"
+        );
+        let response = run_dex_method(
+            code,
+            method,
+            RunnerData {
+                request,
+                response: None,
+                registers: HashMap::new(),
+                call_type: CallType::View {
+                    dex_storage: &self.dex_storage,
+                },
+                dex_id: dex_id.clone(),
+                dex_storage_balances: &self.dex_storage_balances,
+                dex_storage_usage_before_transaction: near_sdk::env::storage_usage(),
+            },
+        )
+        .unwrap_or_else(|err| panic!("Failed to call `{method}` on {dex_id}: {err:?}"))
+        .unwrap_or_else(|| panic!("Dex {dex_id} didn't return a response to `{method}`"));
+        near_sdk::borsh::from_slice(&response)
+            .unwrap_or_else(|_| panic!("Failed to deserialize the response to `{method}`"))
+    }
+}
+
+#[near]
+impl DexEngine {
+    /// Amounts of a swap on the dex, as returned by its `quote`
+    /// export, without executing it.
+    pub fn dex_quote(&self, dex_id: DexId, request: SwapRequest) -> SwapResponse {
+        self.assert_dex_not_paused(&dex_id);
+        self.assert_asset_not_paused(&request.asset_in);
+        self.assert_asset_not_paused(&request.asset_out);
+        let response: SwapResponse = self.internal_dex_info(
+            &dex_id,
+            QUOTE_METHOD,
+            near_sdk::borsh::to_vec(&request).expect("Failed to serialize swap request"),
+        );
+        let amount_matches = match request.amount {
+            SwapRequestAmount::ExactIn(exact_in) => exact_in == response.amount_in,
+            SwapRequestAmount::ExactOut(exact_out) => exact_out == response.amount_out,
+        };
+        expect!(
+            amount_matches,
+            "Dex {dex_id} returned a quote that doesn't match the requested amount"
+        );
+        response
+    }
+
+    pub fn dex_metadata(&self, dex_id: DexId) -> DexMetadata {
+        self.internal_dex_info(&dex_id, METADATA_METHOD, Vec::new())
+    }
+
+    /// Pairs that the dex can swap, with the `message` to swap
+    /// each of them.
+    pub fn dex_pairs(
+        &self,
+        dex_id: DexId,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<DexPair> {
+        self.internal_dex_info(
+            &dex_id,
+            PAIRS_METHOD,
+            near_sdk::borsh::to_vec(&(
+                from_index.unwrap_or(0),
+                limit.unwrap_or(DEFAULT_PAIRS_LIMIT),
+            ))
+            .expect("Failed to serialize pairs request"),
+        )
+    }
+}
@@ -0,0 +1,220 @@
+use intear_dex_types::{AssetId, SwapRequestAmount, expect};
+use near_sdk::{AccountId, json_types::U128, near};
+
+use crate::{
+    DexEngine, DexEngineExt, IntearDexEvent, internal_asset_operations::AccountOrDexId,
+    internal_operations::TradeAccount,
+};
+
+pub use intear_dex_types::{Referral, SwapFees};
+
+/// 100% in basis points.
+pub const BPS_DENOMINATOR: u16 = 10_000;
+/// The protocol fee can never be set higher than this.
+pub const MAX_PROTOCOL_FEE_BPS: u16 = 1_000;
+
+/// Fees that the engine charges on swaps.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh, json])]
+pub struct FeeConfig {
+    /// Fee that goes to `protocol_fee_recipient`.
+    pub protocol_fee_bps: u16,
+    pub protocol_fee_recipient: AccountId,
+    /// Referrals that ask for more than this are rejected.
+    pub max_referral_fee_bps: u16,
+}
+
+impl Default for FeeConfig {
+    fn default() -> Self {
+        Self {
+            protocol_fee_bps: 0,
+            protocol_fee_recipient: near_sdk::env::current_account_id(),
+            max_referral_fee_bps: 100,
+        }
+    }
+}
+
+/// `amount * fee_bps / 10000`, rounded down.
+fn fee_amount(amount: u128, fee_bps: u16) -> u128 {
+    let denominator = u128::from(BPS_DENOMINATOR);
+    let fee_bps = u128::from(fee_bps);
+    // Split the amount to avoid overflowing on large amounts
+    let whole = amount
+        .checked_div(denominator)
+        .and_then(|whole| whole.checked_mul(fee_bps));
+    let remainder = amount
+        .checked_rem(denominator)
+        .and_then(|remainder| remainder.checked_mul(fee_bps))
+        .and_then(|remainder| remainder.checked_div(denominator));
+    whole
+        .zip(remainder)
+        .and_then(|(whole, remainder)| whole.checked_add(remainder))
+        .expect("Fee overflow")
+}
+
+impl DexEngine {
+    /// Takes the fees of a swap from the trader. Fees are taken
+    /// from the output for `ExactIn`, so that the amount in is
+    /// exact, and added to the input for `ExactOut`. Returns the
+    /// amounts in and out of the swap including the fees.
+    pub(crate) fn internal_apply_swap_fees(
+        &mut self,
+        trader: &mut TradeAccount,
+        amount: SwapRequestAmount,
+        asset_in: &AssetId,
+        asset_out: &AssetId,
+        amount_in: U128,
+        amount_out: U128,
+        referral: Option<Referral>,
+    ) -> (U128, U128, Option<SwapFees>) {
+        match amount {
+            SwapRequestAmount::ExactIn(_) => {
+                let fees = self.internal_collect_swap_fees(
+                    trader,
+                    asset_out.clone(),
+                    amount_out,
+                    referral,
+                );
+                let total_fee = fees.as_ref().map_or(0, SwapFees::total);
+                let amount_out = amount_out
+                    .0
+                    .checked_sub(total_fee)
+                    .expect("Fee is greater than amount out");
+                (amount_in, U128(amount_out), fees)
+            }
+            SwapRequestAmount::ExactOut(_) => {
+                let fees =
+                    self.internal_collect_swap_fees(trader, asset_in.clone(), amount_in, referral);
+                let total_fee = fees.as_ref().map_or(0, SwapFees::total);
+                let amount_in = amount_in
+                    .0
+                    .checked_add(total_fee)
+                    .expect("Amount in overflow");
+                (U128(amount_in), amount_out, fees)
+            }
+        }
+    }
+
+    /// Takes the protocol and referral fees from the trader and
+    /// credits them to the recipients. `amount` is the amount
+    /// of `asset_id` the fees are calculated from. A fee is not
+    /// charged if the recipient doesn't have `asset_id`
+    /// registered, so misconfigured recipients can't block
+    /// swaps. `SwapFeeSkipped` is emitted for it instead.
+    pub(crate) fn internal_collect_swap_fees(
+        &mut self,
+        trader: &mut TradeAccount,
+        asset_id: AssetId,
+        amount: U128,
+        referral: Option<Referral>,
+    ) -> Option<SwapFees> {
+        if let Some(referral) = &referral {
+            expect!(
+                referral.fee_bps <= self.fee_config.max_referral_fee_bps,
+                "Referral fee {} bps is higher than the maximum {} bps",
+                referral.fee_bps,
+                self.fee_config.max_referral_fee_bps
+            );
+        }
+        let protocol_fee_recipient = self.fee_config.protocol_fee_recipient.clone();
+        let protocol_fee = self.internal_fee_for_recipient(
+            &protocol_fee_recipient,
+            &asset_id,
+            fee_amount(amount.0, self.fee_config.protocol_fee_bps),
+        );
+        let referral_fee = match &referral {
+            Some(referral) => self.internal_fee_for_recipient(
+                &referral.account_id,
+                &asset_id,
+                fee_amount(amount.0, referral.fee_bps),
+            ),
+            None => 0,
+        };
+        if protocol_fee == 0 && referral_fee == 0 {
+            return None;
+        }
+
+        let total_fee = protocol_fee
+            .checked_add(referral_fee)
+            .expect("Fee overflow");
+        self.internal_take_from_trader(trader, asset_id.clone(), U128(total_fee));
+        if protocol_fee > 0 {
+            self.internal_increase_assets(
+                AccountOrDexId::Account(protocol_fee_recipient.clone()),
+                asset_id.clone(),
+                U128(protocol_fee),
+            );
+        }
+        if let Some(referral) = &referral {
+            if referral_fee > 0 {
+                self.internal_increase_assets(
+                    AccountOrDexId::Account(referral.account_id.clone()),
+                    asset_id.clone(),
+                    U128(referral_fee),
+                );
+            }
+        }
+        Some(SwapFees {
+            asset_id,
+            protocol_fee: U128(protocol_fee),
+            protocol_fee_recipient,
+            referral_fee: U128(referral_fee),
+            referral: referral.map(|referral| referral.account_id),
+        })
+    }
+
+    /// `fee`, or 0 if `recipient` doesn't have `asset_id`
+    /// registered.
+    fn internal_fee_for_recipient(
+        &self,
+        recipient: &AccountId,
+        asset_id: &AssetId,
+        fee: u128,
+    ) -> u128 {
+        if fee == 0
+            || self
+                .asset_is_registered(AccountOrDexId::Account(recipient.clone()), asset_id.clone())
+        {
+            return fee;
+        }
+        IntearDexEvent::SwapFeeSkipped {
+            recipient: recipient.clone(),
+            asset_id: asset_id.clone(),
+            amount: U128(fee),
+        }
+        .emit();
+        0
+    }
+}
+
+#[near]
+impl DexEngine {
+    #[payable]
+    pub fn set_fee_config(&mut self, fee_config: FeeConfig) {
+        near_sdk::assert_one_yocto();
+        self.assert_owner();
+        expect!(
+            fee_config.protocol_fee_bps <= MAX_PROTOCOL_FEE_BPS,
+            "Protocol fee can't be higher than {MAX_PROTOCOL_FEE_BPS} bps"
+        );
+        expect!(
+            fee_config
+                .protocol_fee_bps
+                .checked_add(fee_config.max_referral_fee_bps)
+                .is_some_and(|total| total <= BPS_DENOMINATOR),
+            "Total fee can't be higher than 100%"
+        );
+        IntearDexEvent::FeeConfigUpdated {
+            protocol_fee_bps: fee_config.protocol_fee_bps,
+            protocol_fee_recipient: fee_config.protocol_fee_recipient.clone(),
+            max_referral_fee_bps: fee_config.max_referral_fee_bps,
+        }
+        .emit();
+        self.fee_config = fee_config;
+    }
+
+    pub fn fee_config(&self) -> FeeConfig {
+        self.fee_config.clone()
+    }
+}
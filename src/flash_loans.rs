@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+
+use intear_dex_types::{AssetId, DexId, FlashLoanFeeRequest, expect};
+use near_sdk::json_types::U128;
+
+use crate::{
+    CallType, DexEngine, IntearDexEvent, RunnerData,
+    internal_asset_operations::AccountOrDexId,
+    internal_operations::{TradeAccount, dex_exports_function, run_dex_method},
+};
+
+/// Name of the export that dexes use to opt in to flash loans.
+pub const FLASH_LOAN_FEE_METHOD: &str = "flash_loan_fee";
+
+/// A flash loan taken in the current batch of operations.
+pub(crate) struct FlashLoan {
+    pub dex_id: DexId,
+    pub asset_id: AssetId,
+    /// Borrowed amount plus fee that hasn't been repaid yet.
+    pub owed: u128,
+}
+
+impl DexEngine {
+    /// Asks the dex how much it charges for the loan. Panics if
+    /// the dex doesn't support flash loans.
+    fn internal_flash_loan_fee(&self, dex_id: &DexId, asset_id: &AssetId, amount: U128) -> U128 {
+        let code = self.dex_codes.get(dex_id).expect("Dex code not found");
+        expect!(
+            dex_exports_function(code, FLASH_LOAN_FEE_METHOD),
+            "Dex {dex_id} doesn't support flash loans"
+        );
+        let response = run_dex_method(
+            code,
+            FLASH_LOAN_FEE_METHOD,
+            RunnerData {
+                request: near_sdk::borsh::to_vec(&FlashLoanFeeRequest {
+                    asset_id: asset_id.clone(),
+                    amount,
+                })
+                .expect("Failed to serialize flash loan fee request"),
+                response: None,
+                registers: HashMap::new(),
+                call_type: CallType::View {
+                    dex_storage: &self.dex_storage,
+                },
+                dex_id: dex_id.clone(),
+                dex_storage_balances: &self.dex_storage_balances,
+                dex_storage_usage_before_transaction: near_sdk::env::storage_usage(),
+            },
+        )
+        .unwrap_or_else(|err| panic!("Failed to get flash loan fee: {err:?}"))
+        .expect("Dex didn't return flash loan fee");
+        near_sdk::borsh::from_slice(&response).expect("Failed to deserialize flash loan fee")
+    }
+
+    /// Moves `amount` from the dex balance to the trader. The
+    /// caller is responsible for checking that the returned
+    /// loan is repaid before the end of the batch.
+    pub(crate) fn internal_flash_borrow(
+        &mut self,
+        dex_id: DexId,
+        asset_id: AssetId,
+        amount: U128,
+        trader: &mut TradeAccount,
+    ) -> FlashLoan {
+        expect!(amount.0 > 0, "Flash loan amount must be greater than 0");
+        self.assert_dex_not_paused(&dex_id);
+        let fee = self.internal_flash_loan_fee(&dex_id, &asset_id, amount);
+        self.internal_decrease_assets(
+            AccountOrDexId::Dex(dex_id.clone()),
+            asset_id.clone(),
+            amount,
+        );
+        self.internal_give_to_trader(trader, asset_id.clone(), amount);
+        IntearDexEvent::FlashBorrow {
+            dex_id: dex_id.clone(),
+            asset_id: asset_id.clone(),
+            amount,
+            fee,
+            borrower: trader.trader_id().clone(),
+        }
+        .emit();
+        FlashLoan {
+            dex_id,
+            asset_id,
+            owed: amount
+                .0
+                .checked_add(fee.0)
+                .expect("Flash loan fee overflow"),
+        }
+    }
+
+    /// Repays `amount`, or everything that's owed if not
+    /// specified, to the loans taken from this dex in this
+    /// asset, oldest first.
+    pub(crate) fn internal_flash_repay(
+        &mut self,
+        loans: &mut [FlashLoan],
+        dex_id: DexId,
+        asset_id: AssetId,
+        amount: Option<U128>,
+        trader: &mut TradeAccount,
+    ) {
+        let total_owed = loans
+            .iter()
+            .filter(|loan| loan.dex_id == dex_id && loan.asset_id == asset_id)
+            .try_fold(0u128, |total, loan| total.checked_add(loan.owed))
+            .expect("Flash loan overflow");
+        expect!(
+            total_owed > 0,
+            "No outstanding flash loans of {asset_id} from {dex_id}"
+        );
+        let amount = amount.unwrap_or(U128(total_owed));
+        expect!(
+            amount.0 <= total_owed,
+            "Repaying {} is more than the owed {}",
+            amount.0,
+            total_owed
+        );
+
+        let mut remaining = amount.0;
+        for loan in loans
+            .iter_mut()
+            .filter(|loan| loan.dex_id == dex_id && loan.asset_id == asset_id)
+        {
+            let repaid = remaining.min(loan.owed);
+            loan.owed = loan.owed.checked_sub(repaid).expect("Just checked");
+            remaining = remaining.checked_sub(repaid).expect("Just checked");
+        }
+
+        self.internal_take_from_trader(trader, asset_id.clone(), amount);
+        self.internal_increase_assets(
+            AccountOrDexId::Dex(dex_id.clone()),
+            asset_id.clone(),
+            amount,
+        );
+        IntearDexEvent::FlashRepay {
+            dex_id,
+            asset_id,
+            amount,
+            borrower: trader.trader_id().clone(),
+        }
+        .emit();
+    }
+}
+
+/// Panics if any of the loans hasn't been fully repaid.
+pub(crate) fn assert_flash_loans_repaid(loans: &[FlashLoan]) {
+    for loan in loans {
+        expect!(
+            loan.owed == 0,
+            "Flash loan of {} from {} was not repaid: {} is still owed",
+            loan.asset_id,
+            loan.dex_id,
+            loan.owed
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use intear_dex_types::{AssetId, expect};
+use near_sdk::{PromiseOrValue, json_types::U128, near};
+
+use crate::{
+    DexEngine, DexEngineExt, IntearDexEvent, internal_asset_operations::AccountOrDexId,
+    internal_operations::PendingWithdrawal,
+};
+
+impl DexEngine {
+    /// Returns assets of a withdrawal that didn't go through. If
+    /// `to` has unregistered the asset in the meantime, they're
+    /// kept in the holding area instead.
+    pub(crate) fn internal_refund_withdrawal(
+        &mut self,
+        to: AccountOrDexId,
+        asset_id: AssetId,
+        amount: U128,
+    ) {
+        self.internal_increase_custody(asset_id.clone(), amount);
+        if self.asset_is_registered(to.clone(), asset_id.clone()) {
+            self.internal_increase_assets(to, asset_id, amount);
+            return;
+        }
+        // Paid by the contract, since the owner may not have a
+        // storage balance anymore
+        let held = self
+            .held_assets
+            .entry((to.clone(), asset_id.clone()))
+            .or_default();
+        held.0 = held.0.checked_add(amount.0).expect("Held assets overflow");
+        IntearDexEvent::AssetsHeld {
+            owner: to,
+            asset_id,
+            amount,
+        }
+        .emit();
+    }
+}
+
+#[near]
+impl DexEngine {
+    /// Withdraw assets from the holding area to the caller. Held
+    /// assets of a dex can be withdrawn by its deployer.
+    #[payable]
+    pub fn withdraw_held_assets(
+        &mut self,
+        asset_id: AssetId,
+        r#for: Option<AccountOrDexId>,
+    ) -> PromiseOrValue<bool> {
+        near_sdk::assert_one_yocto();
+        let caller = near_sdk::env::predecessor_account_id();
+        let owner = r#for.unwrap_or_else(|| AccountOrDexId::Account(caller.clone()));
+        match &owner {
+            AccountOrDexId::Account(account) => expect!(
+                *account == caller,
+                "Only {account} can withdraw its held assets"
+            ),
+            AccountOrDexId::Dex(dex_id) => expect!(
+                dex_id.deployer == caller,
+                "Only the deployer can withdraw held assets of {dex_id}"
+            ),
+        }
+        let amount = self
+            .held_assets
+            .remove(&(owner.clone(), asset_id.clone()))
+            .unwrap_or_else(|| panic!("No held assets of {asset_id} for {owner}"));
+        IntearDexEvent::HeldAssetsWithdrawn {
+            owner: owner.clone(),
+            asset_id: asset_id.clone(),
+            amount,
+        }
+        .emit();
+        self.internal_decrease_custody(asset_id.clone(), amount);
+        self.internal_add_pending_withdrawal(asset_id.clone(), amount);
+        self.internal_withdraw_unchecked(PendingWithdrawal {
+            asset_id,
+            amount,
+            withdraw_to: caller,
+            withdraw_from: owner,
+            msg: None,
+            storage_deposit: None,
+        })
+    }
+
+    pub fn held_assets_of(&self, of: AccountOrDexId, asset_id: AssetId) -> Option<U128> {
+        self.held_assets.get(&(of, asset_id)).copied()
+    }
+}
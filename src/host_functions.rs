@@ -353,12 +353,15 @@ pub fn value_return(mut caller: Caller<'_, RunnerData>, value_len: u64, value_pt
     caller.data_mut().response = Some(buf);
 }
 
-pub fn panic(caller: Caller<'_, RunnerData>) {
+pub fn panic(caller: Caller<'_, RunnerData>) -> Result<(), wasmi::Error> {
     let dex_id = caller.data().dex_id.clone();
+    if caller.data().call_type.is_simulation() {
+        return Err(wasmi::Error::new(format!("[{dex_id}] Dex panicked")));
+    }
     panic!("[{dex_id}] Dex panicked");
 }
 
-pub fn panic_utf8(caller: Caller<'_, RunnerData>, len: u64, ptr: u64) {
+pub fn panic_utf8(caller: Caller<'_, RunnerData>, len: u64, ptr: u64) -> Result<(), wasmi::Error> {
     let dex_id = caller.data().dex_id.clone();
     let memory = caller
         .get_export("memory")
@@ -369,6 +372,11 @@ pub fn panic_utf8(caller: Caller<'_, RunnerData>, len: u64, ptr: u64) {
         .read(&caller, ptr as usize, &mut buf)
         .expect("Failed to read panic message");
     let message = String::from_utf8(buf).expect("Failed to parse panic message");
+    if caller.data().call_type.is_simulation() {
+        return Err(wasmi::Error::new(format!(
+            "[{dex_id}] Dex panicked: {message}"
+        )));
+    }
     panic!("[{dex_id}] Dex panicked: {message}");
 }
 
@@ -394,10 +402,10 @@ pub fn storage_write(
         .read(&caller, value_ptr as usize, &mut value_buf)
         .expect("Failed to read value from guest memory");
 
-    let Some(dex_storage_mut) = caller.data_mut().call_type.dex_storage_mut() else {
-        panic!("storage_write is not allowed in view functions");
-    };
-    let old_value = dex_storage_mut.insert((dex_id, key_buf), value_buf);
+    let old_value = caller
+        .data_mut()
+        .call_type
+        .storage_insert((dex_id, key_buf), value_buf);
 
     if let Some(old_val) = old_value {
         caller.data_mut().registers.insert(register_id, old_val);
@@ -423,13 +431,7 @@ pub fn storage_read(
         .read(&caller, key_ptr as usize, &mut key_buf)
         .expect("Failed to read key from guest memory");
 
-    if let Some(value) = caller
-        .data()
-        .call_type
-        .dex_storage()
-        .get(&(dex_id, key_buf))
-        .cloned()
-    {
+    if let Some(value) = caller.data().call_type.storage_get(&(dex_id, key_buf)) {
         caller.data_mut().registers.insert(register_id, value);
         1
     } else {
@@ -453,10 +455,11 @@ pub fn storage_remove(
         .read(&caller, key_ptr as usize, &mut key_buf)
         .expect("Failed to read key from guest memory");
 
-    let Some(dex_storage_mut) = caller.data_mut().call_type.dex_storage_mut() else {
-        panic!("storage_write is not allowed in view functions");
-    };
-    if let Some(old_value) = dex_storage_mut.remove(&(dex_id, key_buf)) {
+    if let Some(old_value) = caller
+        .data_mut()
+        .call_type
+        .storage_remove((dex_id, key_buf))
+    {
         caller.data_mut().registers.insert(register_id, old_value);
         1
     } else {
@@ -478,8 +481,8 @@ pub fn storage_has_key(caller: Caller<'_, RunnerData>, key_len: u64, key_ptr: u6
     if caller
         .data()
         .call_type
-        .dex_storage()
-        .contains_key(&(dex_id, key_buf))
+        .storage_get(&(dex_id, key_buf))
+        .is_some()
     {
         1
     } else {
@@ -510,6 +513,7 @@ pub fn storage_usage(mut caller: Caller<'_, RunnerData>) -> u64 {
             i64::try_from(caller.data().dex_storage_usage_before_transaction)
                 .expect("Storage usage overflow"),
         )
+        .and_then(|usage| usage.checked_add(caller.data().call_type.simulated_storage_delta()))
         .expect("Storage usage underflow");
     let data_used_before_transaction = caller
         .data()
@@ -722,6 +726,10 @@ pub fn log_utf8(caller: Caller<'_, RunnerData>, len: u64, ptr: u64) {
         buf
     };
     let message = String::from_utf8(msg_bytes).expect("log_utf8 received invalid UTF-8");
+    if caller.data().call_type.is_simulation() {
+        // Quotes are not executed, so they shouldn't produce events
+        return;
+    }
     if let Some(event) = message.strip_prefix("EVENT_JSON:") {
         if let Ok(event) = near_sdk::serde_json::from_str(event) {
             IntearDexEvent::DexEvent {
@@ -757,5 +765,8 @@ pub fn log_utf16(caller: Caller<'_, RunnerData>, len: u64, ptr: u64) {
             .collect()
     };
     let message = String::from_utf16(&utf16).expect("log_utf16 received invalid UTF-16");
+    if caller.data().call_type.is_simulation() {
+        return;
+    }
     near_sdk::env::log_str(&format!("[{dex_id}] {message}"));
 }
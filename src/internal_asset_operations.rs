@@ -1,26 +1,9 @@
-use std::fmt::Display;
-
-use intear_dex_types::{AssetId, DexId, expect};
-use near_sdk::{AccountId, json_types::U128, near};
+use intear_dex_types::{AssetId, expect};
+use near_sdk::json_types::U128;
 
 use crate::{DexEngine, IntearDexEvent};
 
-#[derive(Clone, PartialEq, Eq)]
-#[cfg_attr(debug_assertions, derive(Debug))]
-#[near(serializers=[json])]
-pub enum AccountOrDexId {
-    Account(AccountId),
-    Dex(DexId),
-}
-
-impl Display for AccountOrDexId {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Account(account) => write!(f, "Account({account})"),
-            Self::Dex(dex_id) => write!(f, "Dex({dex_id})"),
-        }
-    }
-}
+pub use intear_dex_types::AccountOrDexId;
 
 impl DexEngine {
     pub fn assert_asset_registered(&self, account_or_dex_id: AccountOrDexId, asset_id: AssetId) {
@@ -57,6 +40,27 @@ impl DexEngine {
         self.internal_increase_assets(to, asset_id, amount);
     }
 
+    /// Same as `internal_transfer_asset`, for transfers that
+    /// were explicitly requested rather than being a part of
+    /// another operation.
+    pub(crate) fn internal_transfer_asset_with_event(
+        &mut self,
+        from: AccountOrDexId,
+        to: AccountOrDexId,
+        asset_id: AssetId,
+        amount: U128,
+    ) {
+        self.internal_transfer_asset(from.clone(), to.clone(), asset_id.clone(), amount);
+        IntearDexEvent::AssetTransfer {
+            from,
+            to,
+            asset_id,
+            amount,
+            sandboxed: false,
+        }
+        .emit();
+    }
+
     pub fn assert_has_enough(
         &self,
         account_or_dex_id: AccountOrDexId,
@@ -165,4 +169,46 @@ impl DexEngine {
             }
         }
     }
+
+    /// Adds `amount` to the tracked balance of assets that the
+    /// contract holds.
+    pub(crate) fn internal_increase_custody(&mut self, asset_id: AssetId, amount: U128) {
+        self.total_in_custody
+            .entry(asset_id.clone())
+            .and_modify(|b| {
+                b.0 = b.0.checked_add(amount.0).unwrap_or_else(|| {
+                    panic!(
+                        "Balance overflow for contract and asset {asset_id}: {} + {} > {}",
+                        b.0,
+                        amount.0,
+                        u128::MAX
+                    )
+                });
+            })
+            .or_insert_with(|| {
+                panic!("Failed to refund assets to contract tracked balance: asset not registered")
+            });
+    }
+
+    /// Subtracts `amount` from the tracked balance of assets
+    /// that the contract holds.
+    pub(crate) fn internal_decrease_custody(&mut self, asset_id: AssetId, amount: U128) {
+        self.total_in_custody
+            .entry(asset_id.clone())
+            .and_modify(|b| {
+                b.0 = b.0.checked_sub(amount.0).unwrap_or_else(|| {
+                    panic!(
+                        "Balance underflow for contract and asset {asset_id}: {} - {} < {}",
+                        b.0,
+                        amount.0,
+                        u128::MIN,
+                    )
+                })
+            })
+            .or_insert_with(|| {
+                panic!(
+                    "Failed to withdraw assets from contract tracked balance: asset {asset_id} not registered"
+                )
+            });
+    }
 }
@@ -1,32 +1,28 @@
 use std::collections::HashMap;
 
 use intear_dex_types::{
-    AssetId, AssetWithdrawRequest, AssetWithdrawalType, DexCallRequest, DexCallResponse, DexId,
-    SwapRequest, SwapRequestAmount, SwapResponse, expect,
+    AssetId, AssetWithdrawRequest, AssetWithdrawalType, DEX_ABI_SECTION, DEX_METHODS_SECTION,
+    DexAbiMethod, DexCallRequest, DexCallResponse, DexId, DexMethod, SwapRequest,
+    SwapRequestAmount, SwapResponse, expect,
 };
 use near_contract_standards::{
     fungible_token::core::ext_ft_core, non_fungible_token::core::ext_nft_core,
 };
 use near_sdk::{
-    AccountId, Gas, NearToken, Promise, PromiseError, PromiseOrValue,
+    AccountId, Gas, GasWeight, NearToken, Promise, PromiseOrValue, PromiseResult,
     json_types::{Base58CryptoHash, Base64VecU8, U128},
     near,
 };
-use wasmi::{Engine, Func, Linker, Module, Store};
+use wasmi::{Engine, ExternType, Func, Linker, Module, Store};
 
 use crate::{
-    CallType, DexEngine, DexEngineExt, IntearDexEvent, RunnerData, impl_supported_host_functions,
+    CallType, DexEngine, DexEngineExt, IntearDexEvent, RunnerData, fees::Referral,
+    flash_loans::assert_flash_loans_repaid, impl_supported_host_functions,
     impl_unsupported_host_functions, internal_asset_operations::AccountOrDexId,
+    storage_breakdown::StorageCategory,
 };
 
-#[derive(Clone)]
-#[cfg_attr(debug_assertions, derive(Debug))]
-#[near(serializers=[json])]
-pub enum SwapOperationAmount {
-    Amount(SwapRequestAmount),
-    OutputOfLastIn,
-    EntireBalanceIn,
-}
+pub use intear_dex_types::{Operation, SwapOperationAmount};
 
 pub enum TradeAccount<'a> {
     User(AccountId),
@@ -36,61 +32,20 @@ pub enum TradeAccount<'a> {
     },
 }
 
-#[derive(Clone)]
-#[cfg_attr(debug_assertions, derive(Debug))]
-#[near(serializers=[json])]
-pub enum Operation {
-    /// Register storage for assets. No-op if assets are
-    /// already registered for the given account or dex.
-    RegisterAssets {
-        asset_ids: Vec<AssetId>,
-        r#for: Option<AccountOrDexId>,
-    },
-    /// Deploy new code to your dex.
-    DeployDexCode {
-        last_part_of_id: String,
-        code_base64: Base64VecU8,
-    },
-    /// Withdraw assets from the dex engine contract's inner
-    /// balance to the user. If amount is None, the entire
-    /// balance of the asset will be withdrawn.
-    Withdraw {
-        asset_id: AssetId,
-        amount: Option<U128>,
-        to: Option<AccountId>,
-        /// If the withdrawal fails and current user doesn't have
-        /// a registerd balance in this asset, the assets will be
-        /// refunded to this address. It's required that either
-        /// the user address or rescue address is registered.
-        rescue_address: Option<AccountId>,
-    },
-    /// Swap assets between two assets on the selected dex.
-    SwapSimple {
-        dex_id: DexId,
-        message: Base64VecU8,
-        asset_in: AssetId,
-        asset_out: AssetId,
-        amount: SwapOperationAmount,
-    },
-    /// Call a method on a dex.
-    DexCall {
-        dex_id: DexId,
-        method: String,
-        args: Base64VecU8,
-        attached_assets: HashMap<AssetId, U128>,
-    },
-    /// Transfer assets to a different account or dex.
-    TransferAsset {
-        to: AccountOrDexId,
-        asset_id: AssetId,
-        amount: U128,
-    },
-    /// Convert some of AssetId::Near to storage for an account
-    /// or a dex.
-    StorageDeposit {
-        amount: U128,
-        r#for: Option<AccountOrDexId>,
-    },
+impl TradeAccount<'_> {
+    pub fn trader_id(&self) -> &AccountId {
+        match self {
+            TradeAccount::User(account) => account,
+            TradeAccount::Sandboxed { alleged_trader, .. } => alleged_trader,
+        }
+    }
+
+    pub fn into_trader_id(self) -> AccountId {
+        match self {
+            TradeAccount::User(account) => account,
+            TradeAccount::Sandboxed { alleged_trader, .. } => alleged_trader,
+        }
+    }
 }
 
 impl DexEngine {
@@ -109,8 +64,12 @@ impl DexEngine {
         self.dex_codes.insert(dex_id.clone(), code_base64.0);
         self.dex_codes.flush();
         let storage_usage_after = near_sdk::env::storage_usage();
-        self.dex_storage_balances
-            .charge(&dex_id, storage_usage_before, storage_usage_after);
+        self.internal_charge_storage(
+            AccountOrDexId::Dex(dex_id.clone()),
+            StorageCategory::Code,
+            storage_usage_before,
+            storage_usage_after,
+        );
 
         IntearDexEvent::DexDeployed {
             dex_id: dex_id.clone(),
@@ -119,6 +78,7 @@ impl DexEngine {
         .emit();
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn internal_swap_simple(
         &mut self,
         dex_id: DexId,
@@ -126,6 +86,8 @@ impl DexEngine {
         asset_in: AssetId,
         asset_out: AssetId,
         amount: SwapRequestAmount,
+        referral: Option<Referral>,
+        mut max_storage_payment: NearToken,
         mut trader: TradeAccount,
     ) -> (U128, U128) {
         let swap_request = SwapRequest {
@@ -134,17 +96,58 @@ impl DexEngine {
             asset_out,
             amount,
         };
+        let response =
+            self.internal_execute_swap(dex_id.clone(), swap_request.clone(), &mut trader);
+        if response.trader_storage_bytes != 0 {
+            self.internal_take_storage_from_trader(
+                &mut trader,
+                &dex_id,
+                response.trader_storage_bytes,
+                &mut max_storage_payment,
+            );
+        }
+        let (amount_in, amount_out, fees) = self.internal_apply_swap_fees(
+            &mut trader,
+            amount,
+            &swap_request.asset_in,
+            &swap_request.asset_out,
+            response.amount_in,
+            response.amount_out,
+            referral,
+        );
+        IntearDexEvent::Swap {
+            dex_id: dex_id.clone(),
+            request: swap_request,
+            amount_in,
+            amount_out,
+            trader: trader.into_trader_id(),
+            quotes: None,
+            fees,
+        }
+        .emit();
 
-        let code = self.dex_codes.get(&dex_id).expect("Dex code not found");
-        let engine = Engine::default();
-        let module = match Module::new(&engine, code) {
-            Ok(module) => module,
-            Err(err) => panic!("Failed to load module: {err:?}"),
-        };
+        (amount_in, amount_out)
+    }
 
+    /// Runs the swap on the dex and settles it between the dex
+    /// and the trader, without emitting a `Swap` event. Storage
+    /// that the dex wants the trader to pay for is returned in
+    /// `trader_storage_bytes`, capped to the actual storage
+    /// growth, and has to be charged by the caller.
+    pub(crate) fn internal_execute_swap(
+        &mut self,
+        dex_id: DexId,
+        swap_request: SwapRequest,
+        trader: &mut TradeAccount,
+    ) -> SwapResponse {
+        self.assert_dex_not_paused(&dex_id);
+        self.assert_asset_not_paused(&swap_request.asset_in);
+        self.assert_asset_not_paused(&swap_request.asset_out);
+        let code = self.dex_codes.get(&dex_id).expect("Dex code not found");
         let storage_usage_before = near_sdk::env::storage_usage();
-        let mut store = Store::new(
-            &engine,
+        let response = run_dex_method(
+            code,
+            "swap",
             RunnerData {
                 request: near_sdk::borsh::to_vec(&swap_request)
                     .expect("Failed to serialize swap request"),
@@ -157,39 +160,26 @@ impl DexEngine {
                 dex_storage_balances: &self.dex_storage_balances,
                 dex_storage_usage_before_transaction: storage_usage_before,
             },
-        );
-        let mut linker = Linker::new(&engine);
-
-        impl_supported_host_functions!(linker);
-        impl_unsupported_host_functions!(linker);
-
-        let instance = match linker.instantiate_and_start(&mut store, &module) {
-            Ok(i) => i,
-            Err(err) => panic!("Failed to instantiate module: {err:?}"),
-        };
-        let swap_func: Func = match instance.get_func(&mut store, "swap") {
-            Some(f) => f,
-            None => panic!("Failed to get function"),
-        };
-        match swap_func.call(&mut store, &[], &mut []) {
-            Ok(()) => (),
-            Err(err) => panic!("Failed to call function: {err:?}"),
-        };
-        let response = store.data_mut().response.take();
-        drop(store);
-        drop(linker);
+        )
+        .unwrap_or_else(|err| panic!("Failed to call function: {err:?}"));
 
         self.dex_storage.flush();
         let storage_usage_after = near_sdk::env::storage_usage();
-        self.dex_storage_balances
-            .charge(&dex_id, storage_usage_before, storage_usage_after);
 
-        let response: SwapResponse = match response {
+        let mut response = match response {
             Some(response) => {
-                near_sdk::borsh::from_slice(&response).expect("Failed to deserialize swap response")
+                decode_swap_response(&response).expect("Failed to deserialize swap response")
             }
             None => panic!("No response from swap"),
         };
+        let storage_growth = storage_usage_after.saturating_sub(storage_usage_before);
+        response.trader_storage_bytes = response.trader_storage_bytes.min(storage_growth);
+        self.internal_charge_storage(
+            AccountOrDexId::Dex(dex_id.clone()),
+            StorageCategory::DexState,
+            storage_usage_before,
+            storage_usage_after,
+        );
         match swap_request.amount {
             SwapRequestAmount::ExactIn(exact_in) => {
                 expect!(exact_in == response.amount_in, "Amount in does not match");
@@ -202,64 +192,184 @@ impl DexEngine {
             }
         }
 
-        match &mut trader {
-            TradeAccount::User(user_trader) => {
-                // asset in
-                self.internal_transfer_asset(
-                    AccountOrDexId::Account(user_trader.clone()),
-                    AccountOrDexId::Dex(dex_id.clone()),
-                    swap_request.asset_in.clone(),
-                    response.amount_in,
-                );
-                // asset out
-                self.internal_transfer_asset(
-                    AccountOrDexId::Dex(dex_id.clone()),
-                    AccountOrDexId::Account(user_trader.clone()),
-                    swap_request.asset_out.clone(),
-                    response.amount_out,
-                );
+        // asset in
+        self.internal_take_from_trader(trader, swap_request.asset_in.clone(), response.amount_in);
+        if response.amount_in.0 != 0 {
+            self.internal_increase_assets(
+                AccountOrDexId::Dex(dex_id.clone()),
+                swap_request.asset_in.clone(),
+                response.amount_in,
+            );
+        }
+        // asset out
+        if response.amount_out.0 != 0 {
+            self.internal_decrease_assets(
+                AccountOrDexId::Dex(dex_id.clone()),
+                swap_request.asset_out.clone(),
+                response.amount_out,
+            );
+        }
+        self.internal_give_to_trader(trader, swap_request.asset_out.clone(), response.amount_out);
+
+        response
+    }
+
+    /// Runs the swap on the dex without committing any changes
+    /// to its storage or balances. Returns `None` if the dex
+    /// has no code, panicked or the response doesn't match the
+    /// request.
+    pub(crate) fn internal_quote_swap(
+        &self,
+        dex_id: &DexId,
+        swap_request: &SwapRequest,
+    ) -> Option<SwapResponse> {
+        if self.is_swap_paused(dex_id, &swap_request.asset_in, &swap_request.asset_out) {
+            return None;
+        }
+        let Some(code) = self.dex_codes.get(dex_id) else {
+            near_sdk::env::log_str(&format!("Failed to quote swap on {dex_id}: no code"));
+            return None;
+        };
+        let response = run_dex_method(
+            code,
+            "swap",
+            RunnerData {
+                request: near_sdk::borsh::to_vec(swap_request)
+                    .expect("Failed to serialize swap request"),
+                response: None,
+                registers: HashMap::new(),
+                call_type: CallType::Simulation {
+                    dex_storage: &self.dex_storage,
+                    overlay: HashMap::new(),
+                    storage_delta: 0,
+                },
+                dex_id: dex_id.clone(),
+                dex_storage_balances: &self.dex_storage_balances,
+                dex_storage_usage_before_transaction: near_sdk::env::storage_usage(),
+            },
+        );
+        let response = match response {
+            Ok(Some(response)) => response,
+            Ok(None) => return None,
+            Err(err) => {
+                near_sdk::env::log_str(&format!("Failed to quote swap on {dex_id}: {err}"));
+                return None;
+            }
+        };
+        let response = decode_swap_response(&response)?;
+        let amount_matches = match swap_request.amount {
+            SwapRequestAmount::ExactIn(exact_in) => exact_in == response.amount_in,
+            SwapRequestAmount::ExactOut(exact_out) => exact_out == response.amount_out,
+        };
+        amount_matches.then_some(response)
+    }
+
+    pub(crate) fn internal_take_from_trader(
+        &mut self,
+        trader: &mut TradeAccount,
+        asset_id: AssetId,
+        amount: U128,
+    ) {
+        match trader {
+            TradeAccount::User(account) => {
+                if amount.0 != 0 {
+                    self.internal_decrease_assets(
+                        AccountOrDexId::Account(account.clone()),
+                        asset_id,
+                        amount,
+                    );
+                }
             }
             TradeAccount::Sandboxed { assets, .. } => {
-                // asset in
-                let anon_swap_balance_in = assets
-                    .get_mut(&swap_request.asset_in)
-                    .expect("Asset in not found in anonymous assets");
-                anon_swap_balance_in.0 = anon_swap_balance_in
+                let balance = assets
+                    .get_mut(&asset_id)
+                    .unwrap_or_else(|| panic!("Asset {asset_id} not found in anonymous assets"));
+                balance.0 = balance
                     .0
-                    .checked_sub(response.amount_in.0)
-                    .expect("Not enough input balance in anonymous assets");
-                self.internal_increase_assets(
-                    AccountOrDexId::Dex(dex_id.clone()),
-                    swap_request.asset_in.clone(),
-                    response.amount_in,
-                );
-                // asset out
-                self.internal_decrease_assets(
-                    AccountOrDexId::Dex(dex_id.clone()),
-                    swap_request.asset_out.clone(),
-                    response.amount_out,
-                );
-                let anon_swap_balance_out =
-                    assets.entry(swap_request.asset_out.clone()).or_default();
-                anon_swap_balance_out.0 = anon_swap_balance_out
+                    .checked_sub(amount.0)
+                    .unwrap_or_else(|| panic!("Not enough {asset_id} balance in anonymous assets"));
+            }
+        }
+    }
+
+    pub(crate) fn internal_give_to_trader(
+        &mut self,
+        trader: &mut TradeAccount,
+        asset_id: AssetId,
+        amount: U128,
+    ) {
+        match trader {
+            TradeAccount::User(account) => {
+                if amount.0 != 0 {
+                    self.internal_increase_assets(
+                        AccountOrDexId::Account(account.clone()),
+                        asset_id,
+                        amount,
+                    );
+                }
+            }
+            TradeAccount::Sandboxed { assets, .. } => {
+                let balance = assets.entry(asset_id).or_default();
+                balance.0 = balance.0.checked_add(amount.0).expect("Balance overflow");
+            }
+        }
+    }
+
+    /// Moves the cost of `bytes` of storage from the trader to
+    /// the storage balance of the dex. Users pay from their
+    /// storage balance, sandboxed traders from the NEAR they
+    /// attached. The cost is deducted from `max_storage_payment`,
+    /// the amount that the trader still agrees to pay, and the
+    /// call panics if it's not enough.
+    pub(crate) fn internal_take_storage_from_trader(
+        &mut self,
+        trader: &mut TradeAccount,
+        dex_id: &DexId,
+        bytes: u64,
+        max_storage_payment: &mut NearToken,
+    ) {
+        let amount = near_sdk::env::storage_byte_cost().saturating_mul(u128::from(bytes));
+        *max_storage_payment = max_storage_payment
+            .checked_sub(amount)
+            .unwrap_or_else(|| {
+                panic!(
+                    "Dex {dex_id} asked to charge {amount} for storage, more than max_storage_payment allows"
+                )
+            });
+        match trader {
+            TradeAccount::User(account) => {
+                let available = self
+                    .user_storage_balances
+                    .storage_balance_of(account.clone())
+                    .map(|balance| balance.available)
+                    .unwrap_or_default();
+                if available < amount {
+                    self.internal_top_up_storage(
+                        &AccountOrDexId::Account(account.clone()),
+                        amount.saturating_sub(available),
+                    );
+                }
+                self.user_storage_balances.take(account, amount);
+            }
+            TradeAccount::Sandboxed { assets, .. } => {
+                let near = assets.entry(AssetId::Near).or_default();
+                near.0 = near
                     .0
-                    .checked_add(response.amount_out.0)
-                    .expect("Balance overflow");
+                    .checked_sub(amount.as_yoctonear())
+                    .unwrap_or_else(|| {
+                        panic!("Not enough NEAR attached to pay for {bytes} bytes of dex storage")
+                    });
+                self.internal_decrease_custody(AssetId::Near, U128(amount.as_yoctonear()));
             }
         }
-        IntearDexEvent::Swap {
+        self.dex_storage_balances.deposit(dex_id, amount);
+        IntearDexEvent::TraderStoragePaid {
             dex_id: dex_id.clone(),
-            request: swap_request,
-            amount_in: response.amount_in,
-            amount_out: response.amount_out,
-            trader: match trader {
-                TradeAccount::User(account) => account,
-                TradeAccount::Sandboxed { alleged_trader, .. } => alleged_trader,
-            },
+            trader: trader.trader_id().clone(),
+            bytes,
+            amount,
         }
         .emit();
-
-        (response.amount_in, response.amount_out)
     }
 
     pub(crate) fn internal_dex_call(
@@ -269,12 +379,14 @@ impl DexEngine {
         args: Base64VecU8,
         attached_assets: HashMap<AssetId, U128>,
         predecessor: AccountId,
+        mut max_storage_payment: NearToken,
         anon_swap_available_assets: Option<&mut HashMap<AssetId, U128>>,
     ) -> Base64VecU8 {
         expect!(
             method != "swap",
             "Method name 'swap' is reserved for the swap operation"
         );
+        self.assert_dex_not_paused(&dex_id);
 
         if anon_swap_available_assets.is_none() {
             for (asset_id, amount) in attached_assets.clone() {
@@ -287,19 +399,14 @@ impl DexEngine {
         }
 
         let code = self.dex_codes.get(&dex_id).expect("Dex code not found");
-        let engine = Engine::default();
-        let module = match Module::new(&engine, code) {
-            Ok(module) => module,
-            Err(err) => panic!("Failed to load module: {err:?}"),
-        };
-
         let storage_usage_before = near_sdk::env::storage_usage();
         let request = DexCallRequest {
             args: args.0,
             attached_assets,
         };
-        let mut store = Store::new(
-            &engine,
+        let response = run_dex_method(
+            code,
+            &method,
             RunnerData {
                 request: near_sdk::borsh::to_vec(&request).expect("Failed to serialize request"),
                 response: None,
@@ -313,38 +420,51 @@ impl DexEngine {
                 dex_storage_balances: &self.dex_storage_balances,
                 dex_storage_usage_before_transaction: storage_usage_before,
             },
-        );
-        let mut linker = Linker::new(&engine);
-
-        impl_supported_host_functions!(linker);
-        impl_unsupported_host_functions!(linker);
-
-        let instance = match linker.instantiate_and_start(&mut store, &module) {
-            Ok(i) => i,
-            Err(err) => panic!("Failed to instantiate module: {err:?}"),
-        };
-        let dex_call_func: Func = match instance.get_func(&mut store, method.as_str()) {
-            Some(f) => f,
-            None => panic!("Failed to get function"),
-        };
-        match dex_call_func.call(&mut store, &[], &mut []) {
-            Ok(()) => (),
-            Err(err) => panic!("Failed to call function: {err:?}"),
-        };
-        let response = store.data_mut().response.take();
-        drop(store);
-        drop(linker);
+        )
+        .unwrap_or_else(|err| panic!("Failed to call function: {err:?}"));
 
         self.dex_storage.flush();
         let storage_usage_after = near_sdk::env::storage_usage();
-        self.dex_storage_balances
-            .charge(&dex_id, storage_usage_before, storage_usage_after);
 
-        let response: DexCallResponse = match response {
-            Some(response) => near_sdk::borsh::from_slice(&response)
+        let response = match response {
+            Some(response) => decode_dex_call_response(&response)
                 .expect("Failed to deserialize dex call response"),
-            None => Default::default(),
+            None => DexCallResponse::default(),
         };
+        IntearDexEvent::DexCall {
+            dex_id: dex_id.clone(),
+            method,
+            caller: predecessor.clone(),
+            sandboxed: anon_swap_available_assets.is_some(),
+            attached_assets: request.attached_assets.clone(),
+            asset_withdraw_requests: response.asset_withdraw_requests.clone(),
+        }
+        .emit();
+        let storage_growth = storage_usage_after.saturating_sub(storage_usage_before);
+        let trader_storage_bytes = response.trader_storage_bytes.min(storage_growth);
+        let mut anon_swap_available_assets = anon_swap_available_assets;
+        if trader_storage_bytes != 0 {
+            let mut trader = match anon_swap_available_assets.as_deref_mut() {
+                Some(assets) => TradeAccount::Sandboxed {
+                    assets,
+                    alleged_trader: predecessor.clone(),
+                },
+                None => TradeAccount::User(predecessor.clone()),
+            };
+            self.internal_take_storage_from_trader(
+                &mut trader,
+                &dex_id,
+                trader_storage_bytes,
+                &mut max_storage_payment,
+            );
+        }
+        self.internal_charge_storage(
+            AccountOrDexId::Dex(dex_id.clone()),
+            StorageCategory::DexState,
+            storage_usage_before,
+            storage_usage_after,
+        );
+
         if let Some(anon_swap_available_assets) = anon_swap_available_assets {
             for (asset_id, amount) in request.attached_assets {
                 anon_swap_available_assets
@@ -402,6 +522,19 @@ impl DexEngine {
                         asset_id.clone(),
                         Some(amount),
                         Some(to_account_id.clone()),
+                        None,
+                        false,
+                        AccountOrDexId::Dex(dex_id.clone()),
+                    )
+                    .detach();
+                }
+                AssetWithdrawalType::WithdrawUnderlyingAssetCall(to_account_id, msg) => {
+                    self.internal_withdraw(
+                        asset_id.clone(),
+                        Some(amount),
+                        Some(to_account_id.clone()),
+                        Some(msg.clone()),
+                        false,
                         AccountOrDexId::Dex(dex_id.clone()),
                     )
                     .detach();
@@ -415,8 +548,23 @@ impl DexEngine {
                 AssetId::Near,
                 U128(response.add_storage_deposit.as_yoctonear()),
             );
+            self.internal_decrease_custody(
+                AssetId::Near,
+                U128(response.add_storage_deposit.as_yoctonear()),
+            );
             self.dex_storage_balances
                 .deposit(&dex_id, response.add_storage_deposit);
+            let balance = self
+                .dex_storage_balances
+                .storage_balance_of(dex_id.clone())
+                .expect("Just deposited");
+            IntearDexEvent::DexStorageDepositAdded {
+                dex_id,
+                amount: response.add_storage_deposit,
+                total: balance.total,
+                available: balance.available,
+            }
+            .emit();
         }
         Base64VecU8::from(response.response)
     }
@@ -431,17 +579,12 @@ impl DexEngine {
             method != "swap",
             "Method name 'swap' is reserved for the swap operation"
         );
+        self.assert_dex_not_paused(&dex_id);
 
         let code = self.dex_codes.get(&dex_id).expect("Dex code not found");
-        let engine = Engine::default();
-        let module = match Module::new(&engine, code) {
-            Ok(module) => module,
-            Err(err) => panic!("Failed to load module: {err:?}"),
-        };
-
-        let storage_usage_before = near_sdk::env::storage_usage();
-        let mut store = Store::new(
-            &engine,
+        let response = run_dex_method(
+            code,
+            &method,
             RunnerData {
                 request: args.0,
                 response: None,
@@ -451,29 +594,10 @@ impl DexEngine {
                 },
                 dex_id: dex_id.clone(),
                 dex_storage_balances: &self.dex_storage_balances,
-                dex_storage_usage_before_transaction: storage_usage_before,
+                dex_storage_usage_before_transaction: near_sdk::env::storage_usage(),
             },
-        );
-        let mut linker = Linker::new(&engine);
-
-        impl_supported_host_functions!(linker);
-        impl_unsupported_host_functions!(linker);
-
-        let instance = match linker.instantiate_and_start(&mut store, &module) {
-            Ok(i) => i,
-            Err(err) => panic!("Failed to instantiate module: {err:?}"),
-        };
-        let dex_call_func: Func = match instance.get_func(&mut store, method.as_str()) {
-            Some(f) => f,
-            None => panic!("Failed to get function"),
-        };
-        match dex_call_func.call(&mut store, &[], &mut []) {
-            Ok(()) => (),
-            Err(err) => panic!("Failed to call function: {err:?}"),
-        };
-        let response = store.data_mut().response.take();
-        drop(store);
-        drop(linker);
+        )
+        .unwrap_or_else(|err| panic!("Failed to call function: {err:?}"));
 
         Base64VecU8::from(response.unwrap_or_default())
     }
@@ -486,6 +610,7 @@ impl DexEngine {
     ) {
         let r#for = r#for.unwrap_or_else(|| AccountOrDexId::Account(storage_payer.clone()));
         let storage_usage_before = near_sdk::env::storage_usage();
+        let mut registered = Vec::new();
         for asset_id in asset_ids {
             match r#for.clone() {
                 AccountOrDexId::Account(account) => {
@@ -495,7 +620,12 @@ impl DexEngine {
                         .is_none()
                     {
                         self.user_balances
-                            .insert((account, asset_id.clone()), U128(0));
+                            .insert((account.clone(), asset_id.clone()), U128(0));
+                        self.user_assets
+                            .entry(account)
+                            .or_default()
+                            .push(asset_id.clone());
+                        registered.push(asset_id.clone());
                     }
                 }
                 AccountOrDexId::Dex(dex_id) => {
@@ -506,6 +636,7 @@ impl DexEngine {
                     {
                         self.dex_balances
                             .insert((dex_id, asset_id.clone()), U128(0));
+                        registered.push(asset_id.clone());
                     }
                 }
             }
@@ -514,14 +645,105 @@ impl DexEngine {
             }
         }
         self.user_balances.flush();
+        self.user_assets.flush();
         self.dex_balances.flush();
         self.total_in_custody.flush();
         let storage_usage_after = near_sdk::env::storage_usage();
-        self.user_storage_balances.charge(
-            &storage_payer,
+        self.internal_charge_storage(
+            AccountOrDexId::Account(storage_payer.clone()),
+            StorageCategory::AssetRegistrations,
+            storage_usage_before,
+            storage_usage_after,
+        );
+        if !registered.is_empty() {
+            IntearDexEvent::AssetsRegistered {
+                r#for,
+                asset_ids: registered,
+                storage_payer,
+            }
+            .emit();
+        }
+    }
+
+    /// Removes zero balances of `r#for` and refunds the freed
+    /// storage to `by`, the same way `internal_register_assets`
+    /// charges the caller. Non-zero balances are transferred to
+    /// `sweep_dust_to` first, or cause a panic if it's `None`.
+    pub(crate) fn internal_unregister_assets(
+        &mut self,
+        asset_ids: Vec<AssetId>,
+        r#for: Option<AccountOrDexId>,
+        sweep_dust_to: Option<AccountOrDexId>,
+        by: AccountId,
+    ) {
+        let r#for = r#for.unwrap_or_else(|| AccountOrDexId::Account(by.clone()));
+        match &r#for {
+            AccountOrDexId::Account(account) => {
+                expect!(*account == by, "Only {account} can unregister its assets")
+            }
+            AccountOrDexId::Dex(dex_id) => expect!(
+                dex_id.deployer == by,
+                "Only the deployer can unregister assets of {dex_id}"
+            ),
+        }
+        expect!(
+            sweep_dust_to.as_ref() != Some(&r#for),
+            "Can't sweep dust to {for} itself"
+        );
+        let storage_usage_before = near_sdk::env::storage_usage();
+        let mut unregistered = Vec::new();
+        for asset_id in asset_ids {
+            let Some(balance) = self.asset_balance_of(r#for.clone(), asset_id.clone()) else {
+                continue;
+            };
+            if balance.0 != 0 {
+                let Some(sweep_dust_to) = &sweep_dust_to else {
+                    panic!("Balance of {asset_id} is not zero");
+                };
+                self.assert_not_paused();
+                self.internal_transfer_asset_with_event(
+                    r#for.clone(),
+                    sweep_dust_to.clone(),
+                    asset_id.clone(),
+                    balance,
+                );
+            }
+            match &r#for {
+                AccountOrDexId::Account(account) => {
+                    self.user_balances
+                        .remove(&(account.clone(), asset_id.clone()));
+                    if let Some(assets) = self.user_assets.get_mut(account) {
+                        assets.retain(|registered| *registered != asset_id);
+                        if assets.is_empty() {
+                            self.user_assets.remove(account);
+                        }
+                    }
+                }
+                AccountOrDexId::Dex(dex_id) => {
+                    self.dex_balances
+                        .remove(&(dex_id.clone(), asset_id.clone()));
+                }
+            }
+            unregistered.push(asset_id);
+        }
+        self.user_balances.flush();
+        self.user_assets.flush();
+        self.dex_balances.flush();
+        let storage_usage_after = near_sdk::env::storage_usage();
+        self.internal_charge_storage(
+            AccountOrDexId::Account(by.clone()),
+            StorageCategory::AssetRegistrations,
             storage_usage_before,
             storage_usage_after,
         );
+        if !unregistered.is_empty() {
+            IntearDexEvent::AssetsUnregistered {
+                r#for,
+                asset_ids: unregistered,
+                storage_refunded_to: by,
+            }
+            .emit();
+        }
     }
 
     pub(crate) fn internal_withdraw(
@@ -529,8 +751,18 @@ impl DexEngine {
         asset_id: AssetId,
         amount: Option<U128>,
         withdraw_to: Option<AccountId>,
+        msg: Option<String>,
+        register_receiver: bool,
         withdraw_from: AccountOrDexId,
     ) -> PromiseOrValue<bool> {
+        expect!(
+            msg.is_none() || asset_id != AssetId::Near,
+            "NEAR can't be withdrawn with a msg"
+        );
+        expect!(
+            !register_receiver || matches!(asset_id, AssetId::Nep141(_)),
+            "Receiver registration is only supported for NEP-141 tokens"
+        );
         let amount = amount.unwrap_or_else(|| {
             self.asset_balance_of(withdraw_from.clone(), asset_id.clone())
                 .unwrap_or_default()
@@ -539,23 +771,8 @@ impl DexEngine {
             return PromiseOrValue::Value(true);
         }
         self.internal_decrease_assets(withdraw_from.clone(), asset_id.clone(), amount);
-        self.total_in_custody
-            .entry(asset_id.clone())
-            .and_modify(|b| {
-                b.0 = b.0.checked_sub(amount.0).unwrap_or_else(|| {
-                    panic!(
-                        "Balance underflow for contract and asset {asset_id}: {} - {} < {}",
-                        b.0,
-                        amount.0,
-                        u128::MIN,
-                    )
-                })
-            })
-            .or_insert_with(|| {
-                panic!(
-                    "Failed to withdraw assets from contract tracked balance: asset {asset_id} not registered"
-                )
-            });
+        self.internal_decrease_custody(asset_id.clone(), amount);
+        self.internal_add_pending_withdrawal(asset_id.clone(), amount);
 
         let withdraw_to = withdraw_to.unwrap_or_else(|| match withdraw_from.clone() {
             AccountOrDexId::Account(account) => account,
@@ -563,49 +780,69 @@ impl DexEngine {
                 panic!("withdraw_to must be present when withdrawing from a dex")
             }
         });
-        self.internal_withdraw_unchecked(asset_id, amount, withdraw_to, withdraw_from)
+        let withdrawal = PendingWithdrawal {
+            asset_id,
+            amount,
+            withdraw_to,
+            withdraw_from,
+            msg,
+            storage_deposit: None,
+        };
+        if register_receiver {
+            self.internal_register_receiver_and_withdraw(withdrawal)
+        } else {
+            self.internal_withdraw_unchecked(withdrawal)
+        }
     }
 
     /// Withdraws assets without reducing or checking any balances.
-    fn internal_withdraw_unchecked(
+    /// If `msg` is present, `*_transfer_call` is used instead of
+    /// a plain transfer. NEAR withdrawals with a `msg` are
+    /// rejected by `internal_withdraw`.
+    pub(crate) fn internal_withdraw_unchecked(
         &mut self,
-        asset_id: AssetId,
-        amount: U128,
-        withdraw_to: AccountId,
-        withdraw_from: AccountOrDexId,
+        withdrawal: PendingWithdrawal,
     ) -> PromiseOrValue<bool> {
         const GAS_FOR_FT_TRANSFER: Gas = Gas::from_tgas(10);
         const GAS_FOR_NFT_TRANSFER: Gas = Gas::from_tgas(10);
         const GAS_FOR_MT_TRANSFER: Gas = Gas::from_tgas(10);
+        const GAS_FOR_TRANSFER_CALL: Gas = Gas::from_tgas(50);
         const GAS_FOR_WITHDRAWAL_CALLBACK: Gas = Gas::from_tgas(5);
 
-        PromiseOrValue::Promise(match &asset_id {
-            AssetId::Near => Promise::new(withdraw_to.clone())
-                .transfer(NearToken::from_yoctonear(amount.0))
-                .then(
-                    Self::ext(near_sdk::env::current_account_id())
-                        .with_static_gas(GAS_FOR_WITHDRAWAL_CALLBACK)
-                        .after_withdraw(asset_id, amount, withdraw_to, withdraw_from),
-                ),
-            AssetId::Nep141(contract_id) => ext_ft_core::ext(contract_id.clone())
+        let PendingWithdrawal {
+            asset_id,
+            amount,
+            withdraw_to,
+            withdraw_from,
+            msg,
+            storage_deposit,
+        } = withdrawal;
+        let transfer = match (&asset_id, msg.clone()) {
+            (AssetId::Near, None) => {
+                Promise::new(withdraw_to.clone()).transfer(NearToken::from_yoctonear(amount.0))
+            }
+            (AssetId::Near, Some(_)) => unreachable!("Checked in internal_withdraw"),
+            (AssetId::Nep141(contract_id), None) => ext_ft_core::ext(contract_id.clone())
                 .with_attached_deposit(NearToken::from_yoctonear(1))
                 .with_static_gas(GAS_FOR_FT_TRANSFER)
-                .ft_transfer(withdraw_to.clone(), amount, None)
-                .then(
-                    Self::ext(near_sdk::env::current_account_id())
-                        .with_static_gas(GAS_FOR_WITHDRAWAL_CALLBACK)
-                        .after_withdraw(asset_id, amount, withdraw_to, withdraw_from),
-                ),
-            AssetId::Nep171(contract_id, token_id) => ext_nft_core::ext(contract_id.clone())
+                .ft_transfer(withdraw_to.clone(), amount, None),
+            (AssetId::Nep141(contract_id), Some(msg)) => ext_ft_core::ext(contract_id.clone())
                 .with_attached_deposit(NearToken::from_yoctonear(1))
-                .with_static_gas(GAS_FOR_NFT_TRANSFER)
-                .nft_transfer(withdraw_to.clone(), token_id.clone(), None, None)
-                .then(
-                    Self::ext(near_sdk::env::current_account_id())
-                        .with_static_gas(GAS_FOR_WITHDRAWAL_CALLBACK)
-                        .after_withdraw(asset_id, amount, withdraw_to, withdraw_from),
-                ),
-            AssetId::Nep245(contract_id, token_id) => Promise::new(contract_id.clone())
+                .with_static_gas(GAS_FOR_TRANSFER_CALL)
+                .ft_transfer_call(withdraw_to.clone(), amount, None, msg),
+            (AssetId::Nep171(contract_id, token_id), None) => {
+                ext_nft_core::ext(contract_id.clone())
+                    .with_attached_deposit(NearToken::from_yoctonear(1))
+                    .with_static_gas(GAS_FOR_NFT_TRANSFER)
+                    .nft_transfer(withdraw_to.clone(), token_id.clone(), None, None)
+            }
+            (AssetId::Nep171(contract_id, token_id), Some(msg)) => {
+                ext_nft_core::ext(contract_id.clone())
+                    .with_attached_deposit(NearToken::from_yoctonear(1))
+                    .with_static_gas(GAS_FOR_TRANSFER_CALL)
+                    .nft_transfer_call(withdraw_to.clone(), token_id.clone(), None, None, msg)
+            }
+            (AssetId::Nep245(contract_id, token_id), None) => Promise::new(contract_id.clone())
                 .function_call(
                     "mt_transfer",
                     near_sdk::serde_json::json!({
@@ -619,13 +856,75 @@ impl DexEngine {
                     .into_bytes(),
                     NearToken::from_yoctonear(1),
                     GAS_FOR_MT_TRANSFER,
-                )
-                .then(
-                    Self::ext(near_sdk::env::current_account_id())
-                        .with_static_gas(GAS_FOR_WITHDRAWAL_CALLBACK)
-                        .after_withdraw(asset_id, amount, withdraw_to, withdraw_from),
                 ),
-        })
+            (AssetId::Nep245(contract_id, token_id), Some(msg)) => {
+                Promise::new(contract_id.clone()).function_call_weight(
+                    "mt_transfer_call",
+                    near_sdk::serde_json::json!({
+                        "receiver_id": withdraw_to,
+                        "token_id": token_id,
+                        "amount": amount,
+                        "approval": null,
+                        "memo": null,
+                        "msg": msg,
+                    })
+                    .to_string()
+                    .into_bytes(),
+                    NearToken::from_yoctonear(1),
+                    GAS_FOR_TRANSFER_CALL,
+                    GasWeight(1),
+                )
+            }
+        };
+        PromiseOrValue::Promise(
+            transfer.then(
+                Self::ext(near_sdk::env::current_account_id())
+                    .with_static_gas(GAS_FOR_WITHDRAWAL_CALLBACK)
+                    .after_withdraw(
+                        asset_id,
+                        amount,
+                        withdraw_to,
+                        withdraw_from,
+                        msg,
+                        storage_deposit,
+                    ),
+            ),
+        )
+    }
+
+    fn resolve_swap_operation_amount(
+        &self,
+        amount: SwapOperationAmount,
+        asset_in: &AssetId,
+        last_output: Option<(AssetId, U128)>,
+        anon_swap_available_assets: Option<&HashMap<AssetId, U128>>,
+        by: &AccountId,
+    ) -> SwapRequestAmount {
+        match amount {
+            SwapOperationAmount::Amount(amount) => amount,
+            SwapOperationAmount::OutputOfLastIn => match last_output {
+                Some((last_asset_out, amount)) => {
+                    if last_asset_out == *asset_in {
+                        SwapRequestAmount::ExactIn(amount)
+                    } else {
+                        panic!(
+                            "Amount can only be omitted if the last swap asset out matches the current asset in"
+                        );
+                    }
+                }
+                None => panic!("Amount is required for first SwapSimple operation"),
+            },
+            SwapOperationAmount::EntireBalanceIn => {
+                SwapRequestAmount::ExactIn(match anon_swap_available_assets {
+                    Some(assets) => *assets
+                        .get(asset_in)
+                        .expect("Asset in not found in anonymous assets"),
+                    None => self
+                        .asset_balance_of(AccountOrDexId::Account(by.clone()), asset_in.clone())
+                        .unwrap_or_default(),
+                })
+            }
+        }
     }
 
     pub(crate) fn internal_execute_operations(
@@ -637,6 +936,7 @@ impl DexEngine {
         let fully_authorized = anon_swap_available_assets.as_ref().is_none();
         near_sdk::env::log_str(&format!("Fully authorized: {fully_authorized}"));
         let mut last_output = None;
+        let mut flash_loans = Vec::new();
         for operation in operations {
             match operation {
                 Operation::RegisterAssets { asset_ids, r#for } => {
@@ -645,6 +945,16 @@ impl DexEngine {
                     }
                     self.internal_register_assets(asset_ids, r#for, by.clone());
                 }
+                Operation::UnregisterAssets {
+                    asset_ids,
+                    r#for,
+                    sweep_dust_to,
+                } => {
+                    if !fully_authorized {
+                        panic!("Operation only available in execute_actions");
+                    }
+                    self.internal_unregister_assets(asset_ids, r#for, sweep_dust_to, by.clone());
+                }
                 Operation::DeployDexCode {
                     last_part_of_id,
                     code_base64,
@@ -659,8 +969,14 @@ impl DexEngine {
                     amount,
                     to,
                     rescue_address,
+                    msg,
+                    register_receiver,
                 } => {
                     if let Some(anonymous_assets) = &mut anon_swap_available_assets {
+                        expect!(
+                            !register_receiver,
+                            "Receiver registration is not available when depositing with operations"
+                        );
                         let asset_balance = anonymous_assets
                             .get_mut(&asset_id)
                             .expect("Asset to withdraw not found in anonymous assets");
@@ -685,28 +1001,21 @@ impl DexEngine {
                                 "No rescue address provided and user doesn't have a registered balance for this asset"
                             );
                         };
-                        self.total_in_custody
-                            .entry(asset_id.clone())
-                            .and_modify(|b| {
-                                b.0 = b.0.checked_sub(amount.0).unwrap_or_else(|| {
-                                    panic!(
-                                        "Balance underflow for contract and asset {asset_id}: {} - {} < {}",
-                                        b.0,
-                                        amount.0,
-                                        u128::MIN,
-                                    )
-                                })
-                            })
-                            .or_insert_with(|| {
-                                panic!(
-                                    "Failed to withdraw assets from contract tracked balance: asset not registered"
-                                )
-                            });
-                        self.internal_withdraw_unchecked(
-                            asset_id,
-                            amount,
-                            by.clone(),
+                        // Passes through the inner balance of the
+                        // rescue address, which gets the refund if
+                        // the withdrawal fails
+                        self.internal_increase_assets(
                             AccountOrDexId::Account(rescue_address.clone()),
+                            asset_id.clone(),
+                            amount,
+                        );
+                        self.internal_withdraw(
+                            asset_id,
+                            Some(amount),
+                            Some(by.clone()),
+                            msg,
+                            false,
+                            AccountOrDexId::Account(rescue_address),
                         )
                         .detach();
                     } else {
@@ -714,6 +1023,8 @@ impl DexEngine {
                             asset_id,
                             amount,
                             to,
+                            msg,
+                            register_receiver,
                             AccountOrDexId::Account(by.clone()),
                         )
                         .detach();
@@ -725,41 +1036,24 @@ impl DexEngine {
                     asset_in,
                     asset_out,
                     amount,
+                    referral,
+                    max_storage_payment,
                 } => {
-                    let amount = match amount {
-                        SwapOperationAmount::Amount(amount) => amount,
-                        SwapOperationAmount::OutputOfLastIn => match last_output {
-                            Some((last_asset_out, amount)) => {
-                                if last_asset_out == asset_in {
-                                    SwapRequestAmount::ExactIn(amount)
-                                } else {
-                                    panic!(
-                                        "Amount can only be omitted if the last swap asset out matches the current asset in"
-                                    );
-                                }
-                            }
-                            None => panic!("Amount is required for first SwapSimple operation"),
-                        },
-                        SwapOperationAmount::EntireBalanceIn => {
-                            SwapRequestAmount::ExactIn(match &anon_swap_available_assets {
-                                Some(assets) => *assets
-                                    .get(&asset_in)
-                                    .expect("Asset in not found in anonymous assets"),
-                                None => self
-                                    .asset_balance_of(
-                                        AccountOrDexId::Account(by.clone()),
-                                        asset_in.clone(),
-                                    )
-                                    .unwrap_or_default(),
-                            })
-                        }
-                    };
+                    let amount = self.resolve_swap_operation_amount(
+                        amount,
+                        &asset_in,
+                        last_output.take(),
+                        anon_swap_available_assets.as_ref(),
+                        &by,
+                    );
                     let (_amount_in, amount_out) = self.internal_swap_simple(
                         dex_id,
                         message,
                         asset_in,
                         asset_out.clone(),
                         amount,
+                        referral,
+                        max_storage_payment,
                         match &mut anon_swap_available_assets {
                             Some(assets) => TradeAccount::Sandboxed {
                                 assets,
@@ -770,11 +1064,198 @@ impl DexEngine {
                     );
                     last_output = Some((asset_out, amount_out));
                 }
+                Operation::SwapRoute {
+                    asset_in,
+                    hops,
+                    amount,
+                    limit,
+                    max_storage_payment,
+                } => {
+                    let amount = self.resolve_swap_operation_amount(
+                        amount,
+                        &asset_in,
+                        last_output.take(),
+                        anon_swap_available_assets.as_ref(),
+                        &by,
+                    );
+                    let asset_out = hops
+                        .last()
+                        .map(|hop| hop.asset_out.clone())
+                        .expect("Route must have at least one hop");
+                    let (_amount_in, amount_out) = self.internal_swap_route(
+                        asset_in,
+                        hops,
+                        amount,
+                        limit,
+                        max_storage_payment,
+                        match &mut anon_swap_available_assets {
+                            Some(assets) => TradeAccount::Sandboxed {
+                                assets,
+                                alleged_trader: by.clone(),
+                            },
+                            None => TradeAccount::User(by.clone()),
+                        },
+                    );
+                    last_output = Some((asset_out, amount_out));
+                }
+                Operation::SwapBestPrice {
+                    candidates,
+                    asset_in,
+                    asset_out,
+                    amount,
+                    limit,
+                    max_storage_payment,
+                } => {
+                    let amount = self.resolve_swap_operation_amount(
+                        amount,
+                        &asset_in,
+                        last_output.take(),
+                        anon_swap_available_assets.as_ref(),
+                        &by,
+                    );
+                    let (_amount_in, amount_out) = self.internal_swap_best_price(
+                        asset_in,
+                        asset_out.clone(),
+                        amount,
+                        candidates,
+                        limit,
+                        max_storage_payment,
+                        match &mut anon_swap_available_assets {
+                            Some(assets) => TradeAccount::Sandboxed {
+                                assets,
+                                alleged_trader: by.clone(),
+                            },
+                            None => TradeAccount::User(by.clone()),
+                        },
+                    );
+                    last_output = Some((asset_out, amount_out));
+                }
+                Operation::SwapSplit {
+                    asset_in,
+                    amount,
+                    legs,
+                    min_amount_out,
+                    max_storage_payment,
+                } => {
+                    let SwapRequestAmount::ExactIn(amount_in) = self.resolve_swap_operation_amount(
+                        amount,
+                        &asset_in,
+                        last_output.take(),
+                        anon_swap_available_assets.as_ref(),
+                        &by,
+                    ) else {
+                        panic!("SwapSplit only supports ExactIn amounts");
+                    };
+                    let asset_out = legs
+                        .first()
+                        .and_then(|leg| leg.hops.last())
+                        .map(|hop| hop.asset_out.clone())
+                        .expect("Split swap must have at least one leg");
+                    let amount_out = self.internal_swap_split(
+                        asset_in,
+                        amount_in,
+                        legs,
+                        min_amount_out,
+                        max_storage_payment,
+                        match &mut anon_swap_available_assets {
+                            Some(assets) => TradeAccount::Sandboxed {
+                                assets,
+                                alleged_trader: by.clone(),
+                            },
+                            None => TradeAccount::User(by.clone()),
+                        },
+                    );
+                    last_output = Some((asset_out, amount_out));
+                }
+                Operation::TransferFrom {
+                    owner,
+                    to,
+                    asset_id,
+                    amount,
+                } => {
+                    if !fully_authorized {
+                        panic!("Operation only available in execute_actions");
+                    }
+                    self.assert_not_paused();
+                    self.internal_spend_allowance(&owner, &by, &asset_id, amount);
+                    self.internal_transfer_asset(
+                        AccountOrDexId::Account(owner),
+                        to,
+                        asset_id,
+                        amount,
+                    );
+                }
+                Operation::SwapSimpleFrom {
+                    owner,
+                    dex_id,
+                    message,
+                    asset_in,
+                    asset_out,
+                    amount,
+                    referral,
+                } => {
+                    if !fully_authorized {
+                        panic!("Operation only available in execute_actions");
+                    }
+                    let (amount_in, _amount_out) = self.internal_swap_simple(
+                        dex_id,
+                        message,
+                        asset_in.clone(),
+                        asset_out,
+                        amount,
+                        referral,
+                        // The spender can't make the owner pay for
+                        // storage of the dex
+                        NearToken::from_yoctonear(0),
+                        TradeAccount::User(owner.clone()),
+                    );
+                    self.internal_spend_allowance(&owner, &by, &asset_in, amount_in);
+                }
+                Operation::FlashBorrow {
+                    dex_id,
+                    asset_id,
+                    amount,
+                } => {
+                    let loan = self.internal_flash_borrow(
+                        dex_id,
+                        asset_id.clone(),
+                        amount,
+                        &mut match &mut anon_swap_available_assets {
+                            Some(assets) => TradeAccount::Sandboxed {
+                                assets,
+                                alleged_trader: by.clone(),
+                            },
+                            None => TradeAccount::User(by.clone()),
+                        },
+                    );
+                    flash_loans.push(loan);
+                    last_output = Some((asset_id, amount));
+                }
+                Operation::FlashRepay {
+                    dex_id,
+                    asset_id,
+                    amount,
+                } => {
+                    self.internal_flash_repay(
+                        &mut flash_loans,
+                        dex_id,
+                        asset_id,
+                        amount,
+                        &mut match &mut anon_swap_available_assets {
+                            Some(assets) => TradeAccount::Sandboxed {
+                                assets,
+                                alleged_trader: by.clone(),
+                            },
+                            None => TradeAccount::User(by.clone()),
+                        },
+                    );
+                }
                 Operation::DexCall {
                     dex_id,
                     method,
                     args,
                     attached_assets,
+                    max_storage_payment,
                 } => {
                     self.internal_dex_call(
                         dex_id,
@@ -782,6 +1263,7 @@ impl DexEngine {
                         args,
                         attached_assets,
                         by.clone(),
+                        max_storage_payment,
                         anon_swap_available_assets.as_mut(),
                     );
                 }
@@ -789,26 +1271,37 @@ impl DexEngine {
                     to,
                     asset_id,
                     amount,
-                } => match &mut anon_swap_available_assets {
-                    Some(assets) => {
-                        let asset_balance = assets
-                            .get_mut(&asset_id)
-                            .expect("Asset to transfer not found in anonymous assets");
-                        asset_balance.0 = asset_balance
-                            .0
-                            .checked_sub(amount.0)
-                            .expect("Not enough balance in anonymous assets");
-                        self.internal_increase_assets(to, asset_id, amount);
-                    }
-                    None => {
-                        self.internal_transfer_asset(
-                            AccountOrDexId::Account(by.clone()),
-                            to,
-                            asset_id,
-                            amount,
-                        );
+                } => {
+                    self.assert_not_paused();
+                    match &mut anon_swap_available_assets {
+                        Some(assets) => {
+                            let asset_balance = assets
+                                .get_mut(&asset_id)
+                                .expect("Asset to transfer not found in anonymous assets");
+                            asset_balance.0 = asset_balance
+                                .0
+                                .checked_sub(amount.0)
+                                .expect("Not enough balance in anonymous assets");
+                            self.internal_increase_assets(to.clone(), asset_id.clone(), amount);
+                            IntearDexEvent::AssetTransfer {
+                                from: AccountOrDexId::Account(by.clone()),
+                                to,
+                                asset_id,
+                                amount,
+                                sandboxed: true,
+                            }
+                            .emit();
+                        }
+                        None => {
+                            self.internal_transfer_asset_with_event(
+                                AccountOrDexId::Account(by.clone()),
+                                to,
+                                asset_id,
+                                amount,
+                            );
+                        }
                     }
-                },
+                }
                 Operation::StorageDeposit { amount, r#for } => {
                     if let Some(sandboxed_assets) = &mut anon_swap_available_assets {
                         let near_balance = sandboxed_assets
@@ -818,17 +1311,42 @@ impl DexEngine {
                             .0
                             .checked_sub(amount.0)
                             .expect("Not enough near balance in anonymous assets");
+                    } else {
+                        self.internal_decrease_assets(
+                            AccountOrDexId::Account(by.clone()),
+                            AssetId::Near,
+                            amount,
+                        );
                     }
+                    // The NEAR is no longer custodied, but locked
+                    // for storage instead
+                    self.internal_decrease_custody(AssetId::Near, amount);
                     match r#for {
                         Some(AccountOrDexId::Account(account)) => {
-                            self.user_storage_balances.storage_deposit(
+                            self.internal_storage_deposit(
                                 account,
                                 Some(false),
                                 NearToken::from_yoctonear(amount.0),
                             );
                         }
                         Some(AccountOrDexId::Dex(dex_id)) => {
-                            self.dex_storage_balances.storage_deposit(
+                            // The excess above the maximum would be
+                            // refunded to the predecessor, not to the
+                            // inner balance
+                            if let Some(max) = self.internal_dex_storage_balance_bounds(&dex_id).max
+                            {
+                                let total = self
+                                    .dex_storage_balances
+                                    .storage_balance_of(dex_id.clone())
+                                    .map(|b| b.total)
+                                    .unwrap_or_default();
+                                expect!(
+                                    total.saturating_add(NearToken::from_yoctonear(amount.0))
+                                        <= max,
+                                    "Storage deposit exceeds the maximum bound {max} of {dex_id}"
+                                );
+                            }
+                            self.internal_dex_storage_deposit(
                                 dex_id,
                                 Some(false),
                                 NearToken::from_yoctonear(amount.0),
@@ -841,6 +1359,7 @@ impl DexEngine {
                 }
             }
         }
+        assert_flash_loans_repaid(&flash_loans);
         for (asset_id, amount) in anon_swap_available_assets.unwrap_or_default() {
             expect!(
                 amount.0 == 0,
@@ -850,8 +1369,163 @@ impl DexEngine {
     }
 }
 
+/// Layout of [`SwapResponse`] before dexes could charge the
+/// trader for storage, still returned by older dexes.
+#[near(serializers=[borsh])]
+struct LegacySwapResponse {
+    amount_in: U128,
+    amount_out: U128,
+}
+
+/// Layout of [`DexCallResponse`] before dexes could charge the
+/// caller for storage, still returned by older dexes.
+#[near(serializers=[borsh])]
+struct LegacyDexCallResponse {
+    asset_withdraw_requests: Vec<AssetWithdrawRequest>,
+    add_storage_deposit: NearToken,
+    response: Vec<u8>,
+}
+
+fn decode_swap_response(bytes: &[u8]) -> Option<SwapResponse> {
+    if let Ok(response) = near_sdk::borsh::from_slice(bytes) {
+        return Some(response);
+    }
+    let LegacySwapResponse {
+        amount_in,
+        amount_out,
+    } = near_sdk::borsh::from_slice(bytes).ok()?;
+    Some(SwapResponse {
+        amount_in,
+        amount_out,
+        trader_storage_bytes: 0,
+    })
+}
+
+fn decode_dex_call_response(bytes: &[u8]) -> Option<DexCallResponse> {
+    if let Ok(response) = near_sdk::borsh::from_slice(bytes) {
+        return Some(response);
+    }
+    let LegacyDexCallResponse {
+        asset_withdraw_requests,
+        add_storage_deposit,
+        response,
+    } = near_sdk::borsh::from_slice(bytes).ok()?;
+    Some(DexCallResponse {
+        asset_withdraw_requests,
+        add_storage_deposit,
+        response,
+        trader_storage_bytes: 0,
+    })
+}
+
+/// Checks whether the dex code exports a function named
+/// `name`, without instantiating it.
+pub(crate) fn dex_exports_function(code: &[u8], name: &str) -> bool {
+    let engine = Engine::default();
+    let module = match Module::new(&engine, code) {
+        Ok(module) => module,
+        Err(err) => panic!("Failed to load module: {err:?}"),
+    };
+    matches!(module.get_export(name), Some(ExternType::Func(_)))
+}
+
+/// Methods that the dex declared in its `intear_dex_methods`
+/// section. Empty for dexes that weren't built with `#[dex]`.
+pub(crate) fn dex_methods(code: &[u8]) -> Vec<DexMethod> {
+    custom_section_entries(code, DEX_METHODS_SECTION)
+}
+
+/// Schemas of the methods in the `intear_dex_abi` section.
+/// Empty for dexes that weren't built with `#[dex]`.
+pub(crate) fn dex_abi(code: &[u8]) -> Vec<DexAbiMethod> {
+    custom_section_entries(code, DEX_ABI_SECTION)
+}
+
+/// Borsh values written one after another in the custom
+/// sections named `section`.
+fn custom_section_entries<T: near_sdk::borsh::BorshDeserialize>(
+    code: &[u8],
+    section: &str,
+) -> Vec<T> {
+    let engine = Engine::default();
+    let module = match Module::new(&engine, code) {
+        Ok(module) => module,
+        Err(err) => panic!("Failed to load module: {err:?}"),
+    };
+    let mut entries = Vec::new();
+    for custom_section in module
+        .custom_sections()
+        .filter(|custom_section| custom_section.name() == section)
+    {
+        let mut data = custom_section.data();
+        while !data.is_empty() {
+            let entry = T::deserialize(&mut data)
+                .unwrap_or_else(|err| panic!("Invalid {section} section: {err}"));
+            entries.push(entry);
+        }
+    }
+    entries
+}
+
+/// Instantiates the dex code and calls `method` on it. Returns
+/// the value that the dex returned with `value_return`, or an
+/// error if the call trapped.
+pub(crate) fn run_dex_method(
+    code: &[u8],
+    method: &str,
+    runner_data: RunnerData,
+) -> Result<Option<Vec<u8>>, wasmi::Error> {
+    let engine = Engine::default();
+    let module = match Module::new(&engine, code) {
+        Ok(module) => module,
+        Err(err) => panic!("Failed to load module: {err:?}"),
+    };
+    let mut store = Store::new(&engine, runner_data);
+    let mut linker = Linker::new(&engine);
+
+    impl_supported_host_functions!(linker);
+    impl_unsupported_host_functions!(linker);
+
+    let instance = match linker.instantiate_and_start(&mut store, &module) {
+        Ok(i) => i,
+        Err(err) => panic!("Failed to instantiate module: {err:?}"),
+    };
+    let func: Func = match instance.get_func(&mut store, method) {
+        Some(f) => f,
+        None => panic!("Failed to get function"),
+    };
+    func.call(&mut store, &[], &mut [])?;
+    Ok(store.data_mut().response.take())
+}
+
+/// A withdrawal that was already deducted from the balance of
+/// `withdraw_from`, and is passed between the callbacks.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[json])]
+pub struct PendingWithdrawal {
+    pub asset_id: AssetId,
+    pub amount: U128,
+    pub withdraw_to: AccountId,
+    pub withdraw_from: AccountOrDexId,
+    /// If present, the asset is sent with `*_transfer_call`.
+    pub msg: Option<String>,
+    /// NEAR that was paid to register `withdraw_to` on the
+    /// token contract.
+    pub storage_deposit: Option<NearToken>,
+}
+
 #[near]
 impl DexEngine {
+    /// Emits the withdrawal event for the amount that was
+    /// transferred, and refunds the rest to `withdraw_from`.
+    /// For `*_transfer_call` withdrawals, the amount the
+    /// receiver didn't use is refunded.
+    ///
+    /// Takes the fields of `PendingWithdrawal` as separate
+    /// arguments, with `msg` and `storage_deposit` optional, so
+    /// that withdrawals started by the code from before they
+    /// were added still resolve after an upgrade.
     #[private]
     pub fn after_withdraw(
         &mut self,
@@ -859,42 +1533,60 @@ impl DexEngine {
         amount: U128,
         withdraw_to: AccountId,
         withdraw_from: AccountOrDexId,
-        #[callback_result] result: Result<(), PromiseError>,
+        msg: Option<String>,
+        storage_deposit: Option<NearToken>,
     ) -> bool {
-        near_sdk::env::log_str(&format!("After withdraw: {result:?}"));
-        match result {
-            Ok(()) => {
-                IntearDexEvent::Withdraw {
-                    from: withdraw_from,
-                    to: withdraw_to,
-                    asset_id,
-                    amount,
-                }
-                .emit();
-                true
+        self.internal_remove_pending_withdrawal(asset_id.clone(), amount);
+        let used = match near_sdk::env::promise_result(0) {
+            PromiseResult::Successful(result) if msg.is_some() => {
+                transfer_call_used_amount(&asset_id, amount, &result)
             }
-            Err(error) => {
+            PromiseResult::Successful(_) => amount,
+            PromiseResult::Failed => {
                 near_sdk::env::log_str(&format!(
-                    "Refunding to {withdraw_from} because withdrawal to {withdraw_to} failed: {error:?}"
+                    "Refunding to {withdraw_from} because withdrawal to {withdraw_to} failed"
                 ));
-                self.internal_increase_assets(withdraw_from, asset_id.clone(), amount);
-                self.total_in_custody
-                    .entry(asset_id.clone())
-                    .and_modify(|b| {
-                        b.0 = b.0.checked_add(amount.0).unwrap_or_else(|| {
-                            panic!(
-                                "Balance overflow for contract and asset {asset_id}: {} + {} > {}",
-                                b.0,
-                                amount.0,
-                                u128::MAX
-                            )
-                        });
-                    })
-                    .or_insert_with(|| {
-                        panic!("Failed to refund assets to contract tracked balance: asset not registered")
-                    });
-                false
+                U128(0)
+            }
+        };
+        let unused = U128(
+            amount
+                .0
+                .checked_sub(used.0)
+                .expect("Used more than withdrawn"),
+        );
+        if used.0 > 0 {
+            IntearDexEvent::Withdraw {
+                from: withdraw_from.clone(),
+                to: withdraw_to,
+                asset_id: asset_id.clone(),
+                amount: used,
+                storage_deposit,
             }
+            .emit();
         }
+        if unused.0 > 0 {
+            self.internal_refund_withdrawal(withdraw_from, asset_id, unused);
+        }
+        used.0 > 0
     }
 }
+
+/// Parses the result of `ft_transfer_call` (used amount),
+/// `nft_transfer_call` (whether the token was transferred) or
+/// `mt_transfer_call` (used amounts). If the token contract
+/// returns something unexpected, the whole amount is considered
+/// used, so that the engine never credits more than it holds.
+fn transfer_call_used_amount(asset_id: &AssetId, amount: U128, result: &[u8]) -> U128 {
+    let used = match asset_id {
+        AssetId::Near => None,
+        AssetId::Nep141(_) => near_sdk::serde_json::from_slice::<U128>(result).ok(),
+        AssetId::Nep171(_, _) => near_sdk::serde_json::from_slice::<bool>(result)
+            .ok()
+            .map(|transferred| if transferred { amount } else { U128(0) }),
+        AssetId::Nep245(_, _) => near_sdk::serde_json::from_slice::<Vec<U128>>(result)
+            .ok()
+            .and_then(|used| used.first().copied()),
+    };
+    U128(used.unwrap_or(amount).0.min(amount.0))
+}
@@ -0,0 +1,421 @@
+use std::collections::HashMap;
+
+use intear_dex_types::{AssetId, SwapFees, SwapRequest, SwapRequestAmount, SwapResponse, expect};
+use near_sdk::{AccountId, NearToken, json_types::U128};
+
+use crate::{DexEngine, IntearDexEvent, internal_operations::TradeAccount};
+
+pub use intear_dex_types::{
+    CandidateQuote, ExecutedHop, ExecutedSplitLeg, SwapCandidate, SwapRouteHop, SwapSplitLeg,
+};
+
+impl DexEngine {
+    /// Swaps `asset_in` through all `hops` in order. For
+    /// `ExactIn`, `limit` is the minimum amount out, and for
+    /// `ExactOut`, it's the maximum amount in.
+    ///
+    /// Intermediate assets never touch the trader's balance,
+    /// only the final output (and for `ExactOut`, the unused
+    /// input, if any) is settled with the trader. The protocol
+    /// fee is charged once for the whole route, and `limit`
+    /// includes it.
+    pub(crate) fn internal_swap_route(
+        &mut self,
+        asset_in: AssetId,
+        hops: Vec<SwapRouteHop>,
+        amount: SwapRequestAmount,
+        limit: U128,
+        mut max_storage_payment: NearToken,
+        mut trader: TradeAccount,
+    ) -> (U128, U128) {
+        let (amount_in, amount_out, executed_hops) = self.internal_execute_route(
+            asset_in.clone(),
+            hops,
+            amount,
+            &mut max_storage_payment,
+            &mut trader,
+        );
+        let asset_out = executed_hops
+            .last()
+            .map(|hop| hop.request.asset_out.clone())
+            .expect("Route has at least one hop");
+        let (amount_in, amount_out, fees) = self.internal_apply_swap_fees(
+            &mut trader,
+            amount,
+            &asset_in,
+            &asset_out,
+            amount_in,
+            amount_out,
+            None,
+        );
+        assert_within_limit(amount, amount_in, amount_out, limit);
+        emit_route_event(
+            asset_in,
+            amount_in,
+            amount_out,
+            executed_hops,
+            trader.into_trader_id(),
+            fees,
+        );
+        (amount_in, amount_out)
+    }
+
+    /// Splits `amount_in` between `legs` proportionally to
+    /// their weights and swaps each part through its leg's
+    /// route. The last leg also receives the rounding
+    /// remainder. All legs must end with the same asset. The
+    /// protocol fee is taken from the total output.
+    pub(crate) fn internal_swap_split(
+        &mut self,
+        asset_in: AssetId,
+        amount_in: U128,
+        legs: Vec<SwapSplitLeg>,
+        min_amount_out: U128,
+        mut max_storage_payment: NearToken,
+        mut trader: TradeAccount,
+    ) -> U128 {
+        expect!(!legs.is_empty(), "Split swap must have at least one leg");
+        let asset_out = legs[0]
+            .hops
+            .last()
+            .map(|hop| hop.asset_out.clone())
+            .expect("Route must have at least one hop");
+        expect!(
+            legs.iter()
+                .all(|leg| leg.hops.last().map(|hop| &hop.asset_out) == Some(&asset_out)),
+            "All legs must have the same asset out"
+        );
+        expect!(
+            legs.iter().all(|leg| leg.weight > 0),
+            "Leg weight must be greater than 0"
+        );
+        let total_weight = legs
+            .iter()
+            .try_fold(0u128, |total, leg| {
+                total.checked_add(u128::from(leg.weight))
+            })
+            .expect("Total weight overflow");
+
+        let legs_count = legs.len();
+        let mut remaining_in = amount_in.0;
+        let mut total_out = 0u128;
+        let mut executed_legs = Vec::with_capacity(legs_count);
+        for (i, leg) in legs.into_iter().enumerate() {
+            let leg_amount_in = if i == legs_count.saturating_sub(1) {
+                remaining_in
+            } else {
+                amount_in
+                    .0
+                    .checked_mul(u128::from(leg.weight))
+                    .and_then(|amount| amount.checked_div(total_weight))
+                    .expect("Leg amount overflow")
+            };
+            remaining_in = remaining_in
+                .checked_sub(leg_amount_in)
+                .expect("Leg amounts exceed the input");
+            if leg_amount_in == 0 {
+                executed_legs.push(ExecutedSplitLeg {
+                    weight: leg.weight,
+                    amount_in: U128(0),
+                    amount_out: U128(0),
+                });
+                continue;
+            }
+            let (leg_in, leg_out, executed_hops) = self.internal_execute_route(
+                asset_in.clone(),
+                leg.hops,
+                SwapRequestAmount::ExactIn(U128(leg_amount_in)),
+                &mut max_storage_payment,
+                &mut trader,
+            );
+            emit_route_event(
+                asset_in.clone(),
+                leg_in,
+                leg_out,
+                executed_hops,
+                trader.trader_id().clone(),
+                None,
+            );
+            total_out = total_out
+                .checked_add(leg_out.0)
+                .expect("Amount out overflow");
+            executed_legs.push(ExecutedSplitLeg {
+                weight: leg.weight,
+                amount_in: leg_in,
+                amount_out: leg_out,
+            });
+        }
+        let (amount_in, amount_out, fees) = self.internal_apply_swap_fees(
+            &mut trader,
+            SwapRequestAmount::ExactIn(amount_in),
+            &asset_in,
+            &asset_out,
+            amount_in,
+            U128(total_out),
+            None,
+        );
+        expect!(
+            amount_out.0 >= min_amount_out.0,
+            "Amount out {} is less than the minimum {}",
+            amount_out.0,
+            min_amount_out.0
+        );
+        IntearDexEvent::SwapSplit {
+            asset_in,
+            asset_out,
+            amount_in,
+            amount_out,
+            legs: executed_legs,
+            trader: trader.into_trader_id(),
+            fees,
+        }
+        .emit();
+        amount_out
+    }
+
+    /// Quotes the swap on every candidate without executing it,
+    /// and executes it only on the one with the best price:
+    /// the highest amount out for `ExactIn`, or the lowest
+    /// amount in for `ExactOut`. Ties go to the earlier
+    /// candidate. All quotes are included in the `Swap` event.
+    /// Candidates are compared without the protocol fee, since
+    /// it's the same for all of them. `limit` is checked against
+    /// the executed swap including the fee, the same way as in
+    /// [`Self::internal_swap_route`].
+    pub(crate) fn internal_swap_best_price(
+        &mut self,
+        asset_in: AssetId,
+        asset_out: AssetId,
+        amount: SwapRequestAmount,
+        candidates: Vec<SwapCandidate>,
+        limit: U128,
+        mut max_storage_payment: NearToken,
+        mut trader: TradeAccount,
+    ) -> (U128, U128) {
+        expect!(!candidates.is_empty(), "At least one candidate is required");
+        let mut quotes = Vec::with_capacity(candidates.len());
+        let mut best: Option<(usize, SwapRequest)> = None;
+        let mut best_response: Option<SwapResponse> = None;
+        for (i, candidate) in candidates.into_iter().enumerate() {
+            let request = SwapRequest {
+                message: candidate.message,
+                asset_in: asset_in.clone(),
+                asset_out: asset_out.clone(),
+                amount,
+            };
+            let quote = self.internal_quote_swap(&candidate.dex_id, &request);
+            quotes.push(CandidateQuote {
+                dex_id: candidate.dex_id,
+                amount_in: quote.as_ref().map(|quote| quote.amount_in),
+                amount_out: quote.as_ref().map(|quote| quote.amount_out),
+            });
+            let Some(quote) = quote else {
+                continue;
+            };
+            let is_better = match &best_response {
+                None => true,
+                Some(best_response) => match amount {
+                    SwapRequestAmount::ExactIn(_) => {
+                        quote.amount_out.0 > best_response.amount_out.0
+                    }
+                    SwapRequestAmount::ExactOut(_) => quote.amount_in.0 < best_response.amount_in.0,
+                },
+            };
+            if is_better {
+                best = Some((i, request));
+                best_response = Some(quote);
+            }
+        }
+        let Some((best_index, request)) = best else {
+            panic!("None of the candidates could quote the swap");
+        };
+
+        let dex_id = quotes[best_index].dex_id.clone();
+        let response = self.internal_execute_swap(dex_id.clone(), request.clone(), &mut trader);
+        if response.trader_storage_bytes != 0 {
+            self.internal_take_storage_from_trader(
+                &mut trader,
+                &dex_id,
+                response.trader_storage_bytes,
+                &mut max_storage_payment,
+            );
+        }
+        let (amount_in, amount_out, fees) = self.internal_apply_swap_fees(
+            &mut trader,
+            amount,
+            &asset_in,
+            &asset_out,
+            response.amount_in,
+            response.amount_out,
+            None,
+        );
+        assert_within_limit(amount, amount_in, amount_out, limit);
+        IntearDexEvent::Swap {
+            dex_id,
+            request,
+            amount_in,
+            amount_out,
+            trader: trader.into_trader_id(),
+            quotes: Some(quotes),
+            fees,
+        }
+        .emit();
+        (amount_in, amount_out)
+    }
+
+    /// Executes the route without checking limits or emitting
+    /// events. Returns the total amount in, the total amount
+    /// out, and each executed hop. Storage that the dexes charge
+    /// to the trader is paid by `trader` once the route is
+    /// settled, not by the assets in flight.
+    pub(crate) fn internal_execute_route(
+        &mut self,
+        asset_in: AssetId,
+        hops: Vec<SwapRouteHop>,
+        amount: SwapRequestAmount,
+        max_storage_payment: &mut NearToken,
+        trader: &mut TradeAccount,
+    ) -> (U128, U128, Vec<ExecutedHop>) {
+        expect!(!hops.is_empty(), "Route must have at least one hop");
+        let asset_out = hops
+            .last()
+            .map(|hop| hop.asset_out.clone())
+            .expect("Just checked");
+        let hop_assets_in = std::iter::once(asset_in.clone())
+            .chain(hops.iter().map(|hop| hop.asset_out.clone()))
+            .collect::<Vec<_>>();
+
+        // For ExactOut, the amount out requested from each hop,
+        // and the amount that the route takes from the trader
+        let (hop_amounts_out, amount_to_take) = match amount {
+            SwapRequestAmount::ExactIn(amount_in) => (None, amount_in),
+            SwapRequestAmount::ExactOut(amount_out) => {
+                // Solve backwards: each hop has to output exactly
+                // what the next hop needs as its input
+                let mut hop_amounts_out = Vec::with_capacity(hops.len());
+                let mut needed = amount_out;
+                for (hop, hop_asset_in) in hops.iter().zip(hop_assets_in.iter()).rev() {
+                    let request = SwapRequest {
+                        message: hop.message.clone(),
+                        asset_in: hop_asset_in.clone(),
+                        asset_out: hop.asset_out.clone(),
+                        amount: SwapRequestAmount::ExactOut(needed),
+                    };
+                    let quote = self
+                        .internal_quote_swap(&hop.dex_id, &request)
+                        .unwrap_or_else(|| {
+                            panic!(
+                                "Failed to quote {} -> {} on {}",
+                                request.asset_in, request.asset_out, hop.dex_id
+                            )
+                        });
+                    hop_amounts_out.push(needed);
+                    needed = quote.amount_in;
+                }
+                hop_amounts_out.reverse();
+                (Some(hop_amounts_out), needed)
+            }
+        };
+
+        let mut route_assets = HashMap::new();
+        self.internal_take_from_trader(trader, asset_in.clone(), amount_to_take);
+        route_assets.insert(asset_in.clone(), amount_to_take);
+
+        let mut executed_hops = Vec::with_capacity(hops.len());
+        let mut trader_storage = Vec::new();
+        let mut next_amount_in = amount_to_take;
+        for (i, (hop, hop_asset_in)) in hops.into_iter().zip(hop_assets_in).enumerate() {
+            let request = SwapRequest {
+                message: hop.message,
+                asset_in: hop_asset_in,
+                asset_out: hop.asset_out,
+                amount: match &hop_amounts_out {
+                    Some(hop_amounts_out) => SwapRequestAmount::ExactOut(hop_amounts_out[i]),
+                    None => SwapRequestAmount::ExactIn(next_amount_in),
+                },
+            };
+            let response = self.internal_execute_swap(
+                hop.dex_id.clone(),
+                request.clone(),
+                &mut TradeAccount::Sandboxed {
+                    assets: &mut route_assets,
+                    alleged_trader: trader.trader_id().clone(),
+                },
+            );
+            next_amount_in = response.amount_out;
+            if response.trader_storage_bytes != 0 {
+                trader_storage.push((hop.dex_id.clone(), response.trader_storage_bytes));
+            }
+            executed_hops.push(ExecutedHop {
+                dex_id: hop.dex_id,
+                request,
+                amount_in: response.amount_in,
+                amount_out: response.amount_out,
+            });
+        }
+
+        // Whatever is left is the output, plus unused input if
+        // the dexes needed less than quoted
+        let amount_out = route_assets.get(&asset_out).copied().unwrap_or_default();
+        let unused_in = if asset_in == asset_out {
+            U128(0)
+        } else {
+            route_assets.get(&asset_in).copied().unwrap_or_default()
+        };
+        for (asset_id, amount) in route_assets {
+            self.internal_give_to_trader(trader, asset_id, amount);
+        }
+        for (dex_id, bytes) in trader_storage {
+            self.internal_take_storage_from_trader(trader, &dex_id, bytes, max_storage_payment);
+        }
+        let amount_in = U128(
+            amount_to_take
+                .0
+                .checked_sub(unused_in.0)
+                .expect("Unused input can't be greater than the input"),
+        );
+        (amount_in, amount_out, executed_hops)
+    }
+}
+
+/// For `ExactIn`, `limit` is the minimum amount out, and for
+/// `ExactOut`, it's the maximum amount in.
+fn assert_within_limit(amount: SwapRequestAmount, amount_in: U128, amount_out: U128, limit: U128) {
+    match amount {
+        SwapRequestAmount::ExactIn(_) => expect!(
+            amount_out.0 >= limit.0,
+            "Amount out {} is less than the minimum {}",
+            amount_out.0,
+            limit.0
+        ),
+        SwapRequestAmount::ExactOut(_) => expect!(
+            amount_in.0 <= limit.0,
+            "Amount in {} is greater than the maximum {}",
+            amount_in.0,
+            limit.0
+        ),
+    }
+}
+
+fn emit_route_event(
+    asset_in: AssetId,
+    amount_in: U128,
+    amount_out: U128,
+    hops: Vec<ExecutedHop>,
+    trader: AccountId,
+    fees: Option<SwapFees>,
+) {
+    IntearDexEvent::SwapRoute {
+        asset_in,
+        asset_out: hops
+            .last()
+            .map(|hop| hop.request.asset_out.clone())
+            .expect("Route has at least one hop"),
+        amount_in,
+        amount_out,
+        hops,
+        trader,
+        fees,
+    }
+    .emit();
+}
@@ -1,26 +1,50 @@
 #![deny(clippy::arithmetic_side_effects)]
+// near-sdk doesn't forward lint attributes of contract methods
+// to the `DexEngineExt` functions that it generates for them
+#![allow(clippy::too_many_arguments)]
 
+pub mod admin;
+pub mod allowances;
 pub mod asset_deposit;
+pub mod custody;
+pub mod dex_info;
+pub mod fees;
+pub mod flash_loans;
+pub mod held_assets;
 pub mod host_functions;
 pub mod internal_asset_operations;
 pub mod internal_operations;
+pub mod internal_routing;
+pub mod migration;
+pub mod receiver_registration;
+pub mod signed_operations;
+pub mod storage_breakdown;
 pub mod storage_management;
+pub mod storage_top_up;
 
 use std::collections::HashMap;
 
 use crate::{
+    allowances::Allowance,
+    fees::{FeeConfig, Referral},
     internal_asset_operations::AccountOrDexId,
     internal_operations::{Operation, TradeAccount},
+    internal_routing::{SwapCandidate, SwapRouteHop, SwapSplitLeg},
+    signed_operations::SigningKey,
+    storage_breakdown::StorageBreakdown,
     storage_management::StorageBalances,
+    storage_top_up::StorageTopUp,
 };
-use intear_dex_types::{AssetId, DexId, SwapRequest, SwapRequestAmount};
+pub use intear_dex_types::IntearDexEvent;
+use intear_dex_types::{AssetId, DexAbiMethod, DexId, DexMethod, SwapRequestAmount};
 use near_sdk::{
-    AccountId, BorshStorageKey, PromiseOrValue,
-    json_types::{Base58CryptoHash, Base64VecU8, U128},
+    AccountId, BorshStorageKey, NearToken, PanicOnDefault, PromiseOrValue,
+    json_types::{Base64VecU8, U128},
     near,
-    store::{IterableMap, LookupMap},
+    store::{IterableMap, IterableSet, LookupMap},
 };
 
+#[derive(PanicOnDefault)]
 #[near(contract_state)]
 pub struct DexEngine {
     /// Assets that are custodied by the dex engine contract
@@ -54,6 +78,48 @@ pub struct DexEngine {
     /// than this stored amount, it can be freely taken out
     /// without causing any issues.
     total_in_custody: IterableMap<AssetId, U128>,
+    /// Protocol fee and referral limits for swaps.
+    fee_config: FeeConfig,
+    /// Keys that can sign operations on behalf of users, to be
+    /// submitted to `execute_signed_operations` by anyone.
+    signing_keys: LookupMap<AccountId, Vec<SigningKey>>,
+    /// Allowances that users gave to other accounts to spend
+    /// their balances.
+    allowances: LookupMap<AccountId, Vec<Allowance>>,
+    /// Assets that were already subtracted from
+    /// `total_in_custody` but are still held by the contract
+    /// until the outgoing transfer resolves.
+    pending_withdrawals: LookupMap<AssetId, U128>,
+    /// Account that can sweep the surplus above
+    /// `total_in_custody`.
+    treasury: AccountId,
+    /// Can pause the engine and change its configuration.
+    owner: AccountId,
+    /// Account that can accept the ownership.
+    pending_owner: Option<AccountId>,
+    paused: bool,
+    paused_dexes: IterableSet<DexId>,
+    paused_assets: IterableSet<AssetId>,
+    /// Assets registered for each user, so that the account can
+    /// be closed with `storage_unregister(force: true)`. Doesn't
+    /// include registrations made before it was added, which
+    /// have to be listed in `close_account`.
+    user_assets: LookupMap<AccountId, Vec<AssetId>>,
+    /// Refunds of failed withdrawals whose owner no longer has
+    /// the asset registered. Can be taken out with
+    /// `withdraw_held_assets`.
+    held_assets: LookupMap<(AccountOrDexId, AssetId), U128>,
+    /// Accounts and dexes that allowed moving NEAR from their
+    /// inner balance to their storage balance.
+    storage_top_ups: LookupMap<AccountOrDexId, StorageTopUp>,
+    /// Bytes charged to each storage balance by category. Only
+    /// includes charges made after it was added.
+    storage_breakdowns: LookupMap<AccountOrDexId, StorageBreakdown>,
+    /// Signing keys that were removed, with the last nonce they
+    /// were used with, so that a key that is added back can't
+    /// be used to replay what it signed before. Kept after the
+    /// account is closed.
+    removed_signing_keys: LookupMap<AccountId, Vec<SigningKey>>,
 }
 
 #[derive(BorshStorageKey)]
@@ -66,10 +132,21 @@ enum StorageKey {
     UserBalances,
     UserStorageBalances,
     ContractTrackedBalance,
+    SigningKeys,
+    Allowances,
+    PendingWithdrawals,
+    PausedDexes,
+    PausedAssets,
+    UserAssets,
+    HeldAssets,
+    StorageTopUps,
+    StorageBreakdowns,
+    RemovedSigningKeys,
 }
 
-impl Default for DexEngine {
-    fn default() -> Self {
+impl DexEngine {
+    /// State of a newly deployed engine.
+    fn empty() -> Self {
         Self {
             dex_balances: LookupMap::new(StorageKey::DexBalances),
             dex_storage: LookupMap::new(StorageKey::DexStorage),
@@ -78,57 +155,25 @@ impl Default for DexEngine {
             user_balances: LookupMap::new(StorageKey::UserBalances),
             user_storage_balances: StorageBalances::new(StorageKey::UserStorageBalances),
             total_in_custody: IterableMap::new(StorageKey::ContractTrackedBalance),
+            fee_config: FeeConfig::default(),
+            signing_keys: LookupMap::new(StorageKey::SigningKeys),
+            allowances: LookupMap::new(StorageKey::Allowances),
+            pending_withdrawals: LookupMap::new(StorageKey::PendingWithdrawals),
+            treasury: near_sdk::env::current_account_id(),
+            owner: near_sdk::env::current_account_id(),
+            pending_owner: None,
+            paused: false,
+            paused_dexes: IterableSet::new(StorageKey::PausedDexes),
+            paused_assets: IterableSet::new(StorageKey::PausedAssets),
+            user_assets: LookupMap::new(StorageKey::UserAssets),
+            held_assets: LookupMap::new(StorageKey::HeldAssets),
+            storage_top_ups: LookupMap::new(StorageKey::StorageTopUps),
+            storage_breakdowns: LookupMap::new(StorageKey::StorageBreakdowns),
+            removed_signing_keys: LookupMap::new(StorageKey::RemovedSigningKeys),
         }
     }
 }
 
-#[near(event_json(standard = "inteardex"))]
-pub enum IntearDexEvent {
-    #[event_version("1.0.0")]
-    DexDeployed {
-        dex_id: DexId,
-        code_hash: Base58CryptoHash,
-    },
-    #[event_version("1.0.0")]
-    DexEvent {
-        dex_id: DexId,
-        event: near_sdk::serde_json::Value,
-    },
-    #[event_version("1.0.0")]
-    UserDeposit {
-        account_id: AccountId,
-        asset_id: AssetId,
-        amount: U128,
-    },
-    #[event_version("1.0.0")]
-    Withdraw {
-        from: AccountOrDexId,
-        to: AccountId,
-        asset_id: AssetId,
-        amount: U128,
-    },
-    #[event_version("1.0.0")]
-    UserBalanceUpdate {
-        account_id: AccountId,
-        asset_id: AssetId,
-        balance: U128,
-    },
-    #[event_version("1.0.0")]
-    DexBalanceUpdate {
-        dex_id: DexId,
-        asset_id: AssetId,
-        balance: U128,
-    },
-    #[event_version("1.0.0")]
-    Swap {
-        dex_id: DexId,
-        request: SwapRequest,
-        amount_in: U128,
-        amount_out: U128,
-        trader: AccountId,
-    },
-}
-
 enum CallType<'a> {
     Trade {
         dex_storage_mut: &'a mut DexStorage,
@@ -141,10 +186,34 @@ enum CallType<'a> {
         predecessor_id: AccountId,
         is_authorized: bool,
     },
+    /// Same as `Trade`, but all storage writes go to an
+    /// in-memory overlay that is discarded after the call,
+    /// and dex panics are returned as errors instead of
+    /// aborting the transaction. Used to quote swaps.
+    Simulation {
+        dex_storage: &'a DexStorage,
+        overlay: HashMap<(DexId, Vec<u8>), Option<Vec<u8>>>,
+        storage_delta: i64,
+    },
 }
 
 type DexStorage = LookupMap<(DexId, Vec<u8>), Vec<u8>>;
 
+/// Approximate number of bytes that a storage record takes
+/// on top of its key and value, same as in nearcore.
+const STORAGE_RECORD_OVERHEAD: i64 = 40;
+
+fn storage_record_size(key: &[u8], value: Option<&Vec<u8>>) -> i64 {
+    match value {
+        Some(value) => i64::try_from(key.len())
+            .ok()
+            .and_then(|key_len| key_len.checked_add(i64::try_from(value.len()).ok()?))
+            .and_then(|len| len.checked_add(STORAGE_RECORD_OVERHEAD))
+            .expect("Storage record size overflow"),
+        None => 0,
+    }
+}
+
 impl CallType<'_> {
     pub const fn dex_storage(&self) -> &DexStorage {
         match self {
@@ -153,6 +222,7 @@ impl CallType<'_> {
             CallType::Call {
                 dex_storage_mut, ..
             } => dex_storage_mut,
+            CallType::Simulation { dex_storage, .. } => dex_storage,
         }
     }
 
@@ -163,6 +233,70 @@ impl CallType<'_> {
             CallType::Call {
                 dex_storage_mut, ..
             } => Some(dex_storage_mut),
+            CallType::Simulation { .. } => None,
+        }
+    }
+
+    pub const fn is_simulation(&self) -> bool {
+        matches!(self, CallType::Simulation { .. })
+    }
+
+    pub fn storage_get(&self, key: &(DexId, Vec<u8>)) -> Option<Vec<u8>> {
+        if let CallType::Simulation { overlay, .. } = self {
+            if let Some(value) = overlay.get(key) {
+                return value.clone();
+            }
+        }
+        self.dex_storage().get(key).cloned()
+    }
+
+    pub fn storage_insert(&mut self, key: (DexId, Vec<u8>), value: Vec<u8>) -> Option<Vec<u8>> {
+        self.storage_replace(key, Some(value))
+    }
+
+    pub fn storage_remove(&mut self, key: (DexId, Vec<u8>)) -> Option<Vec<u8>> {
+        self.storage_replace(key, None)
+    }
+
+    fn storage_replace(
+        &mut self,
+        key: (DexId, Vec<u8>),
+        value: Option<Vec<u8>>,
+    ) -> Option<Vec<u8>> {
+        if self.is_simulation() {
+            let old_value = self.storage_get(&key);
+            let CallType::Simulation {
+                overlay,
+                storage_delta,
+                ..
+            } = self
+            else {
+                unreachable!("Just checked");
+            };
+            *storage_delta = storage_delta
+                .checked_add(storage_record_size(&key.1, value.as_ref()))
+                .and_then(|delta| {
+                    delta.checked_sub(storage_record_size(&key.1, old_value.as_ref()))
+                })
+                .expect("Storage delta overflow");
+            overlay.insert(key, value);
+            return old_value;
+        }
+        let Some(dex_storage_mut) = self.dex_storage_mut() else {
+            panic!("Storage writes are not allowed in view functions");
+        };
+        match value {
+            Some(value) => dex_storage_mut.insert(key, value),
+            None => dex_storage_mut.remove(&key),
+        }
+    }
+
+    /// Bytes written to the simulation overlay, which are not
+    /// reflected in the real storage usage.
+    pub const fn simulated_storage_delta(&self) -> i64 {
+        match self {
+            CallType::Simulation { storage_delta, .. } => *storage_delta,
+            _ => 0,
         }
     }
 }
@@ -179,6 +313,15 @@ pub struct RunnerData<'a> {
 
 #[near]
 impl DexEngine {
+    /// Initialize the state of a newly deployed engine. The
+    /// engine account becomes the owner and the treasury.
+    #[private]
+    #[init]
+    pub fn new() -> Self {
+        migration::write_state_version();
+        Self::empty()
+    }
+
     /// Deploy or upgrade the code for a dex.
     #[payable]
     pub fn deploy_dex_code(&mut self, last_part_of_id: String, code_base64: Base64VecU8) {
@@ -190,8 +333,16 @@ impl DexEngine {
         )
     }
 
-    /// Swap one asset for another on a specific dex.
-    /// Multi-step aggregator method coming soon.
+    /// Swap one asset for another on a specific dex. To swap
+    /// through multiple dexes, use `swap_route`.
+    ///
+    /// The protocol fee and the optional referral fee are taken
+    /// from the output for `ExactIn`, and added to the input for
+    /// `ExactOut`. The returned amounts include the fees.
+    ///
+    /// The dex may charge the storage that the swap creates to
+    /// the caller's storage balance, up to `max_storage_payment`
+    /// (0 if not specified).
     #[payable]
     pub fn swap_simple(
         &mut self,
@@ -200,6 +351,8 @@ impl DexEngine {
         asset_in: AssetId,
         asset_out: AssetId,
         amount: SwapRequestAmount,
+        referral: Option<Referral>,
+        max_storage_payment: Option<NearToken>,
     ) -> (U128, U128) {
         near_sdk::assert_one_yocto();
         self.internal_swap_simple(
@@ -208,6 +361,86 @@ impl DexEngine {
             asset_in,
             asset_out,
             amount,
+            referral,
+            max_storage_payment.unwrap_or_default(),
+            TradeAccount::User(near_sdk::env::predecessor_account_id()),
+        )
+    }
+
+    /// Swap one asset for another on whichever of the candidate
+    /// dexes gives the best price. Every candidate is quoted
+    /// without executing the swap, and only the best one is
+    /// executed. For `ExactIn`, `limit` is the minimum amount
+    /// out, and for `ExactOut`, it's the maximum amount in.
+    /// `max_storage_payment` is the same as in `swap_simple`.
+    #[payable]
+    pub fn swap_best_price(
+        &mut self,
+        candidates: Vec<SwapCandidate>,
+        asset_in: AssetId,
+        asset_out: AssetId,
+        amount: SwapRequestAmount,
+        limit: U128,
+        max_storage_payment: Option<NearToken>,
+    ) -> (U128, U128) {
+        near_sdk::assert_one_yocto();
+        self.internal_swap_best_price(
+            asset_in,
+            asset_out,
+            amount,
+            candidates,
+            limit,
+            max_storage_payment.unwrap_or_default(),
+            TradeAccount::User(near_sdk::env::predecessor_account_id()),
+        )
+    }
+
+    /// Swap `asset_in` through a route of dexes, where the
+    /// output of each hop is the input of the next one. For
+    /// `ExactIn`, `limit` is the minimum amount out, and for
+    /// `ExactOut`, it's the maximum amount in. The storage that
+    /// the dexes charge to the trader, up to `max_storage_payment`
+    /// in total, is paid by the caller after the route settles.
+    #[payable]
+    pub fn swap_route(
+        &mut self,
+        asset_in: AssetId,
+        hops: Vec<SwapRouteHop>,
+        amount: SwapRequestAmount,
+        limit: U128,
+        max_storage_payment: Option<NearToken>,
+    ) -> (U128, U128) {
+        near_sdk::assert_one_yocto();
+        self.internal_swap_route(
+            asset_in,
+            hops,
+            amount,
+            limit,
+            max_storage_payment.unwrap_or_default(),
+            TradeAccount::User(near_sdk::env::predecessor_account_id()),
+        )
+    }
+
+    /// Split `amount_in` between several routes by weight, and
+    /// swap each part through its route. Returns the total
+    /// amount out, which must be at least `min_amount_out`.
+    /// `max_storage_payment` is shared by all legs.
+    #[payable]
+    pub fn swap_split(
+        &mut self,
+        asset_in: AssetId,
+        amount_in: U128,
+        legs: Vec<SwapSplitLeg>,
+        min_amount_out: U128,
+        max_storage_payment: Option<NearToken>,
+    ) -> U128 {
+        near_sdk::assert_one_yocto();
+        self.internal_swap_split(
+            asset_in,
+            amount_in,
+            legs,
+            min_amount_out,
+            max_storage_payment.unwrap_or_default(),
             TradeAccount::User(near_sdk::env::predecessor_account_id()),
         )
     }
@@ -215,7 +448,9 @@ impl DexEngine {
     /// An arbitrary call to a dex method. Can be used for
     /// operations such as adding liquidity, removing liquidity,
     /// oracle updates, manual curve / strategy updates by the
-    /// developer, etc.
+    /// developer, etc. The dex may charge the storage that the
+    /// call creates to the caller, up to `max_storage_payment`
+    /// (0 if not specified).
     #[payable]
     pub fn dex_call(
         &mut self,
@@ -223,6 +458,7 @@ impl DexEngine {
         method: String,
         args: Base64VecU8,
         attached_assets: HashMap<AssetId, U128>,
+        max_storage_payment: Option<NearToken>,
     ) -> Base64VecU8 {
         near_sdk::assert_one_yocto();
         self.internal_dex_call(
@@ -231,6 +467,7 @@ impl DexEngine {
             args,
             attached_assets,
             near_sdk::env::predecessor_account_id(),
+            max_storage_payment.unwrap_or_default(),
             None,
         )
     }
@@ -238,7 +475,8 @@ impl DexEngine {
     #[payable]
     pub fn transfer_asset(&mut self, to: AccountOrDexId, asset_id: AssetId, amount: U128) {
         near_sdk::assert_one_yocto();
-        self.internal_transfer_asset(
+        self.assert_not_paused();
+        self.internal_transfer_asset_with_event(
             AccountOrDexId::Account(near_sdk::env::predecessor_account_id()),
             to,
             asset_id,
@@ -247,14 +485,36 @@ impl DexEngine {
     }
 
     /// Register assets for an account or dex, reserving storage
-    /// for the balance. Unregistering is not possible. No-op if
-    /// already registered.
+    /// for the balance. Use `unregister_assets` to free it. No-op
+    /// if already registered.
     #[payable]
     pub fn register_assets(&mut self, asset_ids: Vec<AssetId>, r#for: Option<AccountOrDexId>) {
         near_sdk::assert_one_yocto();
         self.internal_register_assets(asset_ids, r#for, near_sdk::env::predecessor_account_id());
     }
 
+    /// Unregister assets of the caller, or of a dex deployed by
+    /// the caller, and refund the freed storage to the caller's
+    /// storage balance. Only zero balances can be unregistered, unless
+    /// `sweep_dust_to` is provided, in which case the remaining
+    /// balances are transferred there first. Skips assets that
+    /// aren't registered.
+    #[payable]
+    pub fn unregister_assets(
+        &mut self,
+        asset_ids: Vec<AssetId>,
+        r#for: Option<AccountOrDexId>,
+        sweep_dust_to: Option<AccountOrDexId>,
+    ) {
+        near_sdk::assert_one_yocto();
+        self.internal_unregister_assets(
+            asset_ids,
+            r#for,
+            sweep_dust_to,
+            near_sdk::env::predecessor_account_id(),
+        );
+    }
+
     /// Withdraw assets from the dex engine contract's inner
     /// balance for the user. If `withdraw_to` is not provided,
     /// the assets will be withdrawn to the user's account.
@@ -262,18 +522,31 @@ impl DexEngine {
     /// Returns `true` if the withdrawal was successful, `false`
     /// otherwise. If a withdrawal fails, the assets will be
     /// refunded to the contract's custody balance of the user.
+    ///
+    /// If `msg` is provided, the asset is sent with
+    /// `*_transfer_call`, and the amount that the receiver
+    /// didn't use is refunded. Attach enough gas for the
+    /// receiver in this case.
+    ///
+    /// If `register_receiver` is `true` and the receiver isn't
+    /// registered on the NEP-141 token contract, it's registered
+    /// with `storage_deposit`, paid from the user's NEAR balance.
     #[payable]
     pub fn withdraw(
         &mut self,
         asset_id: AssetId,
         amount: Option<U128>,
         withdraw_to: Option<AccountId>,
+        msg: Option<String>,
+        register_receiver: Option<bool>,
     ) -> PromiseOrValue<bool> {
         near_sdk::assert_one_yocto();
         self.internal_withdraw(
             asset_id,
             amount,
             withdraw_to,
+            msg,
+            register_receiver.unwrap_or(false),
             AccountOrDexId::Account(near_sdk::env::predecessor_account_id()),
         )
     }
@@ -293,6 +566,15 @@ impl DexEngine {
         }
     }
 
+    /// Assets registered for `account_id`, except the ones
+    /// registered before this index was added.
+    pub fn registered_assets_of(&self, account_id: AccountId) -> Vec<AssetId> {
+        self.user_assets
+            .get(&account_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
     pub fn total_in_custody(&self, asset_id: AssetId) -> Option<U128> {
         self.total_in_custody.get(&asset_id).copied()
     }
@@ -301,4 +583,18 @@ impl DexEngine {
     pub fn dex_view(&self, dex_id: DexId, method: String, args: Base64VecU8) -> Base64VecU8 {
         self.internal_dex_view(dex_id, method, args)
     }
+
+    /// Methods that the dex declared with `#[dex_method]`.
+    /// Empty for dexes that weren't built with `#[dex]`.
+    pub fn dex_methods(&self, dex_id: DexId) -> Vec<DexMethod> {
+        let code = self.dex_codes.get(&dex_id).expect("Dex code not found");
+        internal_operations::dex_methods(code)
+    }
+
+    /// Borsh schemas of the args and results of the methods
+    /// that the dex declared with `#[dex_method]`.
+    pub fn dex_abi(&self, dex_id: DexId) -> Vec<DexAbiMethod> {
+        let code = self.dex_codes.get(&dex_id).expect("Dex code not found");
+        internal_operations::dex_abi(code)
+    }
 }
@@ -0,0 +1,139 @@
+use intear_dex_types::{AssetId, DexId, expect};
+use near_sdk::{
+    AccountId, Gas, GasWeight, NearToken, Promise,
+    borsh::{self, BorshDeserialize},
+    json_types::{Base64VecU8, U128},
+    near,
+    store::{IterableMap, LookupMap},
+};
+
+use crate::{
+    DexEngine, DexEngineExt, DexStorage, IntearDexEvent, storage_management::StorageBalances,
+};
+
+/// Key of the contract state written by near-sdk.
+const STATE_KEY: &[u8] = b"STATE";
+/// Key of the borsh-serialized `u32` state version. Written by
+/// `new` and `migrate`, missing in deployments from before it
+/// was added that were never migrated.
+const STATE_VERSION_KEY: &[u8] = b"STATE_VERSION";
+/// Version of the `DexEngine` layout in this code. Bump it and
+/// add a variant to `VersionedDexEngine` when changing fields.
+pub const CURRENT_STATE_VERSION: u32 = 1;
+
+const GAS_FOR_MIGRATE: Gas = Gas::from_tgas(20);
+
+/// Layout of the state before versioning was added.
+#[near(serializers=[borsh])]
+pub struct DexEngineV0 {
+    dex_balances: LookupMap<(DexId, AssetId), U128>,
+    dex_storage: DexStorage,
+    dex_codes: LookupMap<DexId, Vec<u8>>,
+    dex_storage_balances: StorageBalances<DexId>,
+    user_balances: LookupMap<(AccountId, AssetId), U128>,
+    user_storage_balances: StorageBalances<AccountId>,
+    total_in_custody: IterableMap<AssetId, U128>,
+}
+
+pub enum VersionedDexEngine {
+    V0(DexEngineV0),
+    V1(DexEngine),
+}
+
+impl VersionedDexEngine {
+    fn read() -> Self {
+        let state = near_sdk::env::storage_read(STATE_KEY).expect("Contract state not found");
+        match near_sdk::env::storage_read(STATE_VERSION_KEY) {
+            Some(version) => match u32::try_from_slice(&version).expect("Invalid state version") {
+                1 => Self::V1(borsh::from_slice(&state).expect("Invalid v1 state")),
+                version => panic!("Unknown state version {version}"),
+            },
+            // Only the baseline layout was written without a
+            // version
+            None => Self::V0(borsh::from_slice(&state).expect("Invalid v0 state")),
+        }
+    }
+
+    fn version(&self) -> u32 {
+        match self {
+            Self::V0(_) => 0,
+            Self::V1(_) => 1,
+        }
+    }
+
+    fn into_current(self) -> DexEngine {
+        match self {
+            Self::V0(state) => DexEngine {
+                dex_balances: state.dex_balances,
+                dex_storage: state.dex_storage,
+                dex_codes: state.dex_codes,
+                dex_storage_balances: state.dex_storage_balances,
+                user_balances: state.user_balances,
+                user_storage_balances: state.user_storage_balances,
+                total_in_custody: state.total_in_custody,
+                ..DexEngine::empty()
+            },
+            Self::V1(state) => state,
+        }
+    }
+}
+
+#[near]
+impl DexEngine {
+    /// Deploy new code to the engine contract and migrate the
+    /// state. If the migration fails, the old code stays.
+    #[payable]
+    pub fn upgrade_engine(&mut self, code: Base64VecU8) -> Promise {
+        near_sdk::assert_one_yocto();
+        self.assert_owner();
+        expect!(!code.0.is_empty(), "Code is empty");
+        Promise::new(near_sdk::env::current_account_id())
+            .deploy_contract(code.0)
+            .function_call_weight(
+                "migrate",
+                Vec::new(),
+                NearToken::from_yoctonear(0),
+                GAS_FOR_MIGRATE,
+                GasWeight(1),
+            )
+    }
+
+    /// Convert the state of any previous version to the
+    /// current layout. Called by `upgrade_engine`.
+    ///
+    /// The baseline code didn't remove NEAR from
+    /// `total_in_custody` when a dex moved it to its storage
+    /// balance with `add_storage_deposit`. Those amounts can't
+    /// be found in the state, so after migrating from it the
+    /// NEAR `total_in_custody` stays higher than the sum of the
+    /// NEAR inner balances by what dexes moved before the
+    /// upgrade.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let state = VersionedDexEngine::read();
+        let from_version = state.version();
+        write_state_version();
+        IntearDexEvent::EngineMigrated {
+            from_version,
+            to_version: CURRENT_STATE_VERSION,
+        }
+        .emit();
+        state.into_current()
+    }
+
+    /// Version of the state layout, as stored by the last
+    /// initialization or migration.
+    pub fn state_version(&self) -> u32 {
+        let version =
+            near_sdk::env::storage_read(STATE_VERSION_KEY).expect("State version not found");
+        u32::try_from_slice(&version).expect("Invalid state version")
+    }
+}
+
+pub(crate) fn write_state_version() {
+    near_sdk::env::storage_write(
+        STATE_VERSION_KEY,
+        &borsh::to_vec(&CURRENT_STATE_VERSION).expect("Failed to serialize state version"),
+    );
+}
@@ -0,0 +1,142 @@
+use intear_dex_types::AssetId;
+use near_contract_standards::storage_management::{
+    StorageBalance, StorageBalanceBounds, ext_storage_management,
+};
+use near_sdk::{Gas, NearToken, PromiseError, PromiseOrValue, json_types::U128, near};
+
+use crate::{DexEngine, DexEngineExt, internal_operations::PendingWithdrawal};
+
+const GAS_FOR_STORAGE_VIEW: Gas = Gas::from_tgas(5);
+const GAS_FOR_STORAGE_DEPOSIT: Gas = Gas::from_tgas(10);
+/// Enough for `storage_deposit`, the transfer and its callback.
+const GAS_FOR_STORAGE_BALANCE_CALLBACK: Gas = Gas::from_tgas(100);
+/// Enough for the transfer and its callback.
+const GAS_FOR_STORAGE_DEPOSIT_CALLBACK: Gas = Gas::from_tgas(70);
+
+impl DexEngine {
+    /// Checks whether the receiver is registered on the token
+    /// contract, registers it if it isn't, and then withdraws.
+    pub(crate) fn internal_register_receiver_and_withdraw(
+        &mut self,
+        withdrawal: PendingWithdrawal,
+    ) -> PromiseOrValue<bool> {
+        let AssetId::Nep141(contract_id) = &withdrawal.asset_id else {
+            panic!("Receiver registration is only supported for NEP-141 tokens");
+        };
+        PromiseOrValue::Promise(
+            ext_storage_management::ext(contract_id.clone())
+                .with_static_gas(GAS_FOR_STORAGE_VIEW)
+                .storage_balance_of(withdrawal.withdraw_to.clone())
+                .and(
+                    ext_storage_management::ext(contract_id.clone())
+                        .with_static_gas(GAS_FOR_STORAGE_VIEW)
+                        .storage_balance_bounds(),
+                )
+                .then(
+                    Self::ext(near_sdk::env::current_account_id())
+                        .with_static_gas(GAS_FOR_STORAGE_BALANCE_CALLBACK)
+                        .after_receiver_storage_balance_of(withdrawal),
+                ),
+        )
+    }
+
+    /// Returns the withdrawn assets to `withdraw_from` when the
+    /// withdrawal can't proceed.
+    fn internal_cancel_withdrawal(&mut self, withdrawal: PendingWithdrawal, reason: &str) -> bool {
+        near_sdk::env::log_str(&format!(
+            "Refunding to {} because receiver {} can't be registered: {reason}",
+            withdrawal.withdraw_from, withdrawal.withdraw_to
+        ));
+        self.internal_remove_pending_withdrawal(withdrawal.asset_id.clone(), withdrawal.amount);
+        self.internal_refund_withdrawal(
+            withdrawal.withdraw_from,
+            withdrawal.asset_id,
+            withdrawal.amount,
+        );
+        false
+    }
+}
+
+#[near]
+impl DexEngine {
+    #[private]
+    pub fn after_receiver_storage_balance_of(
+        &mut self,
+        withdrawal: PendingWithdrawal,
+        #[callback_result] storage_balance: Result<Option<StorageBalance>, PromiseError>,
+        #[callback_result] storage_balance_bounds: Result<StorageBalanceBounds, PromiseError>,
+    ) -> PromiseOrValue<bool> {
+        let (storage_balance, storage_balance_bounds) =
+            match (storage_balance, storage_balance_bounds) {
+                (Ok(storage_balance), Ok(storage_balance_bounds)) => {
+                    (storage_balance, storage_balance_bounds)
+                }
+                _ => {
+                    return PromiseOrValue::Value(self.internal_cancel_withdrawal(
+                        withdrawal,
+                        "token contract doesn't support storage management",
+                    ));
+                }
+            };
+        if storage_balance.is_some() {
+            return self.internal_withdraw_unchecked(withdrawal);
+        }
+
+        let cost = storage_balance_bounds.min;
+        let near_balance = self
+            .asset_balance_of(withdrawal.withdraw_from.clone(), AssetId::Near)
+            .unwrap_or_default();
+        if near_balance.0 < cost.as_yoctonear() {
+            return PromiseOrValue::Value(self.internal_cancel_withdrawal(
+                withdrawal,
+                &format!("storage deposit of {cost} is more than the NEAR balance"),
+            ));
+        }
+        self.internal_decrease_assets(
+            withdrawal.withdraw_from.clone(),
+            AssetId::Near,
+            U128(cost.as_yoctonear()),
+        );
+        self.internal_decrease_custody(AssetId::Near, U128(cost.as_yoctonear()));
+
+        let AssetId::Nep141(contract_id) = &withdrawal.asset_id else {
+            unreachable!("Checked in internal_register_receiver_and_withdraw")
+        };
+        PromiseOrValue::Promise(
+            ext_storage_management::ext(contract_id.clone())
+                .with_attached_deposit(cost)
+                .with_static_gas(GAS_FOR_STORAGE_DEPOSIT)
+                .storage_deposit(Some(withdrawal.withdraw_to.clone()), Some(true))
+                .then(
+                    Self::ext(near_sdk::env::current_account_id())
+                        .with_static_gas(GAS_FOR_STORAGE_DEPOSIT_CALLBACK)
+                        .after_receiver_storage_deposit(PendingWithdrawal {
+                            storage_deposit: Some(cost),
+                            ..withdrawal
+                        }),
+                ),
+        )
+    }
+
+    #[private]
+    pub fn after_receiver_storage_deposit(
+        &mut self,
+        withdrawal: PendingWithdrawal,
+        #[callback_result] result: Result<StorageBalance, PromiseError>,
+    ) -> PromiseOrValue<bool> {
+        if result.is_err() {
+            // The token contract refunds the attached deposit
+            let cost = U128(
+                withdrawal
+                    .storage_deposit
+                    .unwrap_or(NearToken::from_yoctonear(0))
+                    .as_yoctonear(),
+            );
+            self.internal_refund_withdrawal(withdrawal.withdraw_from.clone(), AssetId::Near, cost);
+            return PromiseOrValue::Value(
+                self.internal_cancel_withdrawal(withdrawal, "storage_deposit failed"),
+            );
+        }
+        self.internal_withdraw_unchecked(withdrawal)
+    }
+}
@@ -0,0 +1,230 @@
+use intear_dex_types::expect;
+use near_sdk::{
+    AccountId, CurveType, PublicKey,
+    json_types::{Base64VecU8, U64},
+    near,
+};
+
+use crate::{
+    DexEngine, DexEngineExt, IntearDexEvent, internal_asset_operations::AccountOrDexId,
+    internal_operations::Operation, storage_breakdown::StorageCategory,
+};
+
+/// Maximum number of signing keys that an account can have.
+pub const MAX_SIGNING_KEYS: usize = 10;
+
+/// A key that can sign operations on behalf of an account.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh, json])]
+pub struct SigningKey {
+    pub public_key: PublicKey,
+    /// Payloads signed by this key must have a greater nonce.
+    pub last_nonce: U64,
+}
+
+/// The data that is signed by the user. The signature is made
+/// over the sha256 hash of its borsh serialization.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh])]
+pub struct SignedOperationsPayload {
+    /// Account id of the dex engine contract, so that the same
+    /// payload can't be executed on another deployment.
+    pub domain: AccountId,
+    pub signer_id: AccountId,
+    pub nonce: u64,
+    /// Block timestamp in nanoseconds, after which the payload
+    /// can no longer be executed.
+    pub expires_at: u64,
+    pub operations: Vec<Operation>,
+}
+
+/// Borsh-serialized `SignedOperationsPayload` with a signature
+/// by one of the signer's signing keys.
+#[derive(Clone)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[json])]
+pub struct SignedOperations {
+    pub payload: Base64VecU8,
+    pub public_key: PublicKey,
+    /// 64 bytes for ed25519, or 65 bytes (with the recovery id
+    /// as the last byte) for secp256k1.
+    pub signature: Base64VecU8,
+}
+
+fn verify_signature(public_key: &PublicKey, hash: &[u8; 32], signature: &[u8]) -> bool {
+    match public_key.curve_type() {
+        CurveType::ED25519 => {
+            let Ok(signature) = signature.try_into() else {
+                return false;
+            };
+            near_sdk::env::ed25519_verify(
+                signature,
+                hash,
+                &public_key.as_bytes()[1..]
+                    .try_into()
+                    .expect("Invalid ed25519 public key"),
+            )
+        }
+        CurveType::SECP256K1 => {
+            let Some((v, signature)) = signature.split_last() else {
+                return false;
+            };
+            near_sdk::env::ecrecover(hash, signature, *v, true)
+                .is_some_and(|recovered| recovered == public_key.as_bytes()[1..])
+        }
+    }
+}
+
+#[near]
+impl DexEngine {
+    /// Allow `public_key` to sign operations that are executed
+    /// on behalf of the caller by anyone who submits them. A key
+    /// that was removed before keeps its last nonce.
+    #[payable]
+    pub fn add_signing_key(&mut self, public_key: PublicKey) {
+        near_sdk::assert_one_yocto();
+        let account_id = near_sdk::env::predecessor_account_id();
+        let storage_usage_before = near_sdk::env::storage_usage();
+        let mut last_nonce = U64(0);
+        if let Some(removed_keys) = self.removed_signing_keys.get_mut(&account_id) {
+            if let Some(index) = removed_keys
+                .iter()
+                .position(|key| key.public_key == public_key)
+            {
+                last_nonce = removed_keys.swap_remove(index).last_nonce;
+            }
+            if removed_keys.is_empty() {
+                self.removed_signing_keys.remove(&account_id);
+            }
+        }
+        let keys = self.signing_keys.entry(account_id.clone()).or_default();
+        expect!(
+            keys.iter().all(|key| key.public_key != public_key),
+            "Signing key is already added"
+        );
+        expect!(
+            keys.len() < MAX_SIGNING_KEYS,
+            "Can't have more than {MAX_SIGNING_KEYS} signing keys"
+        );
+        keys.push(SigningKey {
+            public_key: public_key.clone(),
+            last_nonce,
+        });
+        self.signing_keys.flush();
+        self.removed_signing_keys.flush();
+        let storage_usage_after = near_sdk::env::storage_usage();
+        self.internal_charge_storage(
+            AccountOrDexId::Account(account_id.clone()),
+            StorageCategory::AccountData,
+            storage_usage_before,
+            storage_usage_after,
+        );
+        IntearDexEvent::SigningKeyAdded {
+            account_id,
+            public_key,
+        }
+        .emit();
+    }
+
+    #[payable]
+    pub fn remove_signing_key(&mut self, public_key: PublicKey) {
+        near_sdk::assert_one_yocto();
+        let account_id = near_sdk::env::predecessor_account_id();
+        let storage_usage_before = near_sdk::env::storage_usage();
+        let keys = self
+            .signing_keys
+            .get_mut(&account_id)
+            .expect("No signing keys found");
+        let index = keys
+            .iter()
+            .position(|key| key.public_key == public_key)
+            .expect("Signing key not found");
+        let removed_key = keys.remove(index);
+        if keys.is_empty() {
+            self.signing_keys.remove(&account_id);
+        }
+        self.removed_signing_keys
+            .entry(account_id.clone())
+            .or_default()
+            .push(removed_key);
+        self.signing_keys.flush();
+        self.removed_signing_keys.flush();
+        let storage_usage_after = near_sdk::env::storage_usage();
+        self.internal_charge_storage(
+            AccountOrDexId::Account(account_id.clone()),
+            StorageCategory::AccountData,
+            storage_usage_before,
+            storage_usage_after,
+        );
+        IntearDexEvent::SigningKeyRemoved {
+            account_id,
+            public_key,
+        }
+        .emit();
+    }
+
+    pub fn signing_keys_of(&self, account_id: AccountId) -> Vec<SigningKey> {
+        self.signing_keys
+            .get(&account_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Execute operations signed by a user's signing key. Can
+    /// be called by anyone, the operations are executed as if
+    /// they were called by the signer with `execute_operations`.
+    pub fn execute_signed_operations(&mut self, signed_operations: SignedOperations) {
+        let SignedOperations {
+            payload,
+            public_key,
+            signature,
+        } = signed_operations;
+        let hash = near_sdk::env::sha256_array(&payload.0);
+        let SignedOperationsPayload {
+            domain,
+            signer_id,
+            nonce,
+            expires_at,
+            operations,
+        } = near_sdk::borsh::from_slice(&payload.0).expect("Invalid payload");
+        expect!(
+            domain == near_sdk::env::current_account_id(),
+            "Payload is signed for another contract: {domain}"
+        );
+        expect!(
+            near_sdk::env::block_timestamp() < expires_at,
+            "Payload has expired"
+        );
+        expect!(
+            verify_signature(&public_key, &hash, &signature.0),
+            "Invalid signature"
+        );
+        let key = self
+            .signing_keys
+            .get_mut(&signer_id)
+            .and_then(|keys| keys.iter_mut().find(|key| key.public_key == public_key))
+            .unwrap_or_else(|| {
+                panic!(
+                    "{} is not a signing key of {signer_id}",
+                    String::from(&public_key)
+                )
+            });
+        expect!(
+            nonce > key.last_nonce.0,
+            "Nonce must be greater than {}",
+            key.last_nonce.0
+        );
+        key.last_nonce = U64(nonce);
+
+        IntearDexEvent::SignedOperationsExecuted {
+            signer_id: signer_id.clone(),
+            public_key,
+            nonce: U64(nonce),
+            relayer_id: near_sdk::env::predecessor_account_id(),
+        }
+        .emit();
+        self.internal_execute_operations(operations, signer_id, None);
+    }
+}
@@ -0,0 +1,165 @@
+use near_sdk::near;
+
+use crate::{DexEngine, DexEngineExt, IntearDexEvent, internal_asset_operations::AccountOrDexId};
+
+pub use intear_dex_types::StorageCategory;
+
+/// A `StorageThresholdCrossed` event is emitted every time a
+/// category crosses a multiple of this many bytes.
+pub const STORAGE_THRESHOLD_STEP: u64 = 10_000;
+
+/// Bytes charged to a storage balance in each category.
+#[derive(Clone, Copy, Default)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh, json])]
+pub struct StorageBreakdown {
+    pub code: u64,
+    pub dex_state: u64,
+    pub asset_registrations: u64,
+    pub account_data: u64,
+}
+
+impl StorageBreakdown {
+    fn get_mut(&mut self, category: StorageCategory) -> &mut u64 {
+        match category {
+            StorageCategory::Code => &mut self.code,
+            StorageCategory::DexState => &mut self.dex_state,
+            StorageCategory::AssetRegistrations => &mut self.asset_registrations,
+            StorageCategory::AccountData => &mut self.account_data,
+        }
+    }
+
+    fn sum(&self) -> u64 {
+        self.code
+            .saturating_add(self.dex_state)
+            .saturating_add(self.asset_registrations)
+            .saturating_add(self.account_data)
+    }
+}
+
+#[derive(Clone, Copy)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[json])]
+pub struct StorageBreakdownView {
+    pub breakdown: StorageBreakdown,
+    /// Bytes that aren't in any category, such as the storage
+    /// balance record itself, or usage from before the
+    /// breakdown was tracked.
+    pub other: u64,
+    /// All bytes charged to the storage balance.
+    pub total: u64,
+}
+
+impl DexEngine {
+    /// Records the change in storage usage of `of` under
+    /// `category`. Returns `storage_usage_after` adjusted by the
+    /// bytes of the breakdown record, if it had to be created,
+    /// so that they're charged together.
+    pub(crate) fn internal_record_storage_usage(
+        &mut self,
+        of: &AccountOrDexId,
+        category: StorageCategory,
+        storage_usage_before: u64,
+        storage_usage_after: u64,
+    ) -> u64 {
+        if storage_usage_before == storage_usage_after {
+            return storage_usage_after;
+        }
+        let record_usage_before = near_sdk::env::storage_usage();
+        let breakdown = self.storage_breakdowns.entry(of.clone()).or_default();
+        let bytes = breakdown.get_mut(category);
+        let bytes_before = *bytes;
+        *bytes = if storage_usage_after > storage_usage_before {
+            bytes_before.saturating_add(storage_usage_after.saturating_sub(storage_usage_before))
+        } else {
+            // Bytes from before the breakdown was tracked aren't
+            // in any category
+            bytes_before.saturating_sub(storage_usage_before.saturating_sub(storage_usage_after))
+        };
+        let bytes_after = *bytes;
+        self.storage_breakdowns.flush();
+        let record_usage_after = near_sdk::env::storage_usage();
+
+        let steps_before = bytes_before.saturating_div(STORAGE_THRESHOLD_STEP);
+        let steps_after = bytes_after.saturating_div(STORAGE_THRESHOLD_STEP);
+        if steps_before != steps_after {
+            IntearDexEvent::StorageThresholdCrossed {
+                of: of.clone(),
+                category,
+                bytes: bytes_after,
+                threshold: steps_before
+                    .max(steps_after)
+                    .saturating_mul(STORAGE_THRESHOLD_STEP),
+            }
+            .emit();
+        }
+
+        storage_usage_after.saturating_add(record_usage_after.saturating_sub(record_usage_before))
+    }
+
+    /// Removes the breakdown record of `of` and refunds its
+    /// bytes, so that the storage balance can be unregistered.
+    pub(crate) fn internal_remove_storage_breakdown(&mut self, of: &AccountOrDexId) {
+        let registered = match of {
+            AccountOrDexId::Account(account) => self
+                .user_storage_balances
+                .storage_balance_of(account.clone())
+                .is_some(),
+            AccountOrDexId::Dex(dex_id) => self
+                .dex_storage_balances
+                .storage_balance_of(dex_id.clone())
+                .is_some(),
+        };
+        if !registered {
+            return;
+        }
+        let storage_usage_before = near_sdk::env::storage_usage();
+        if self.storage_breakdowns.remove(of).is_none() {
+            return;
+        }
+        self.storage_breakdowns.flush();
+        let storage_usage_after = near_sdk::env::storage_usage();
+        match of {
+            AccountOrDexId::Account(account) => self.user_storage_balances.charge(
+                account,
+                storage_usage_before,
+                storage_usage_after,
+            ),
+            AccountOrDexId::Dex(dex_id) => {
+                self.dex_storage_balances
+                    .charge(dex_id, storage_usage_before, storage_usage_after)
+            }
+        }
+    }
+}
+
+#[near]
+impl DexEngine {
+    /// Bytes charged to the storage balance of an account or a
+    /// dex, split by what they're used for. `None` if it has no
+    /// storage balance.
+    pub fn storage_breakdown(&self, of: AccountOrDexId) -> Option<StorageBreakdownView> {
+        let total = match &of {
+            AccountOrDexId::Account(account) => {
+                self.user_storage_balances
+                    .storage_balance_of(account.clone())?;
+                self.user_storage_balances.get_bytes_used(account)
+            }
+            AccountOrDexId::Dex(dex_id) => {
+                self.dex_storage_balances
+                    .storage_balance_of(dex_id.clone())?;
+                self.dex_storage_balances.get_bytes_used(dex_id)
+            }
+        };
+        let breakdown = self
+            .storage_breakdowns
+            .get(&of)
+            .copied()
+            .unwrap_or_default();
+        Some(StorageBreakdownView {
+            breakdown,
+            other: total.saturating_sub(breakdown.sum()),
+            total,
+        })
+    }
+}
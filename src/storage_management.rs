@@ -1,4 +1,6 @@
-use intear_dex_types::{DexId, expect};
+use std::collections::HashMap;
+
+use intear_dex_types::{AssetId, DexId, DexStorageBalanceBounds, expect};
 use near_contract_standards::storage_management::{
     StorageBalance, StorageBalanceBounds, StorageManagement,
 };
@@ -9,7 +11,12 @@ use near_sdk::{
     store::LookupMap,
 };
 
-use crate::{DexEngine, DexEngineExt};
+use crate::{
+    CallType, DexEngine, DexEngineExt, IntearDexEvent, RunnerData,
+    internal_asset_operations::AccountOrDexId,
+    internal_operations::{dex_exports_function, run_dex_method},
+    storage_breakdown::StorageCategory,
+};
 
 #[derive(Clone, Copy, Default)]
 #[near(serializers=[borsh])]
@@ -32,6 +39,10 @@ impl From<StorageUsed> for StorageBalance {
 
 const STORAGE_MIN_BOUND: NearToken = NearToken::from_millinear(10); // 0.01 NEAR = 1KB
 
+/// Name of the export that dexes use to set their own storage
+/// balance bounds.
+pub const STORAGE_BALANCE_BOUNDS_METHOD: &str = "storage_balance_bounds";
+
 #[near(serializers=[borsh])]
 pub struct StorageBalances<K: Ord + BorshSerialize + BorshDeserialize> {
     storage_balances: LookupMap<K, StorageUsed>,
@@ -50,6 +61,21 @@ impl<K: Ord + BorshSerialize + BorshDeserialize + Clone> StorageBalances<K> {
         self.storage_balances.flush();
     }
 
+    /// Removes `amount` from the available balance without
+    /// sending it anywhere. Panics if it's not available.
+    pub fn take(&mut self, account_id: &K, amount: NearToken) {
+        let Some(b) = self.storage_balances.get_mut(account_id) else {
+            panic!("Storage balance not found");
+        };
+        let available = b.total.saturating_sub(b.used);
+        expect!(
+            available >= amount,
+            "Not enough storage balance: {available} < {amount}"
+        );
+        b.total = b.total.saturating_sub(amount);
+        self.storage_balances.flush();
+    }
+
     pub fn charge(&mut self, account_id: &K, storage_usage_before: u64, storage_usage_after: u64) {
         match storage_usage_after.cmp(&storage_usage_before) {
             std::cmp::Ordering::Greater => {
@@ -89,6 +115,30 @@ impl<K: Ord + BorshSerialize + BorshDeserialize + Clone> StorageBalances<K> {
         }
     }
 
+    /// How much `total` lacks to be charged for the growth of
+    /// storage usage from `storage_usage_before` to
+    /// `storage_usage_after`.
+    pub fn shortfall(
+        &self,
+        account_id: &K,
+        storage_usage_before: u64,
+        storage_usage_after: u64,
+    ) -> NearToken {
+        let Some(growth) = storage_usage_after.checked_sub(storage_usage_before) else {
+            return NearToken::from_yoctonear(0);
+        };
+        let storage_cost = near_sdk::env::storage_byte_cost().saturating_mul(u128::from(growth));
+        let balance = self
+            .storage_balances
+            .get(account_id)
+            .copied()
+            .unwrap_or_default();
+        balance
+            .used
+            .saturating_add(storage_cost)
+            .saturating_sub(balance.total)
+    }
+
     pub fn get_bytes_used(&self, account_id: &K) -> u64 {
         self.storage_balances
             .get(account_id)
@@ -105,14 +155,20 @@ impl<K: Ord + BorshSerialize + BorshDeserialize + Clone> StorageBalances<K> {
             .unwrap_or_default()
     }
 
+    /// Adds `deposit` to the storage balance. The part above
+    /// `bounds.max` is refunded to the predecessor.
     pub fn storage_deposit(
         &mut self,
         account_id: K,
         registration_only: Option<bool>,
         mut deposit: NearToken,
+        bounds: &StorageBalanceBounds,
     ) -> StorageBalance {
-        if deposit < STORAGE_MIN_BOUND {
-            panic!("Deposit amount {deposit} is less than the minimum bound {STORAGE_MIN_BOUND}");
+        if deposit < bounds.min {
+            panic!(
+                "Deposit amount {deposit} is less than the minimum bound {}",
+                bounds.min
+            );
         }
         if registration_only.is_some_and(|r| r) {
             if let Some(balance) = self.storage_balances.get(&account_id) {
@@ -121,11 +177,26 @@ impl<K: Ord + BorshSerialize + BorshDeserialize + Clone> StorageBalances<K> {
                     .detach();
                 return (*balance).into();
             }
-            if let Some(above_minimum) = deposit.checked_sub(STORAGE_MIN_BOUND) {
+            if let Some(above_minimum) = deposit.checked_sub(bounds.min) {
                 Promise::new(near_sdk::env::predecessor_account_id())
                     .transfer(above_minimum)
                     .detach();
-                deposit = STORAGE_MIN_BOUND;
+                deposit = bounds.min;
+            }
+        }
+        if let Some(max) = bounds.max {
+            let total = self
+                .storage_balances
+                .get(&account_id)
+                .map(|b| b.total)
+                .unwrap_or_default();
+            let room = max.saturating_sub(total);
+            let above_maximum = deposit.saturating_sub(room);
+            if !above_maximum.is_zero() {
+                Promise::new(near_sdk::env::predecessor_account_id())
+                    .transfer(above_maximum)
+                    .detach();
+                deposit = room;
             }
         }
 
@@ -177,15 +248,16 @@ impl<K: Ord + BorshSerialize + BorshDeserialize + Clone> StorageBalances<K> {
         (*storage_used).into()
     }
 
-    pub fn storage_unregister(&mut self, account_id: K, force: Option<bool>) -> bool {
+    /// Removes the storage balance and refunds its total to the
+    /// predecessor. Returns the refunded amount, or `None` if
+    /// `account_id` isn't registered.
+    pub fn storage_unregister(&mut self, account_id: K, force: Option<bool>) -> Option<NearToken> {
         if force.is_some_and(|f| f) {
             panic!("Force unregistration is not supported");
         }
 
         let storage_usage_before = near_sdk::env::storage_usage();
-        let Some(storage_used) = self.storage_balances.remove(&account_id) else {
-            return false;
-        };
+        let storage_used = self.storage_balances.remove(&account_id)?;
         self.storage_balances.flush();
         let storage_usage_after = near_sdk::env::storage_usage();
         let storage_freed = near_sdk::env::storage_byte_cost().saturating_mul(
@@ -193,16 +265,17 @@ impl<K: Ord + BorshSerialize + BorshDeserialize + Clone> StorageBalances<K> {
                 .checked_sub(storage_usage_after as u128)
                 .expect("Storage somehow grew after removing data"),
         );
-        if let Some(leftover) = storage_used.used.checked_sub(storage_freed) {
-            if !leftover.is_zero() {
-                panic!("User is using {leftover} worth of storage")
-            } else {
-                true
-            }
-        } else {
-            // freed up more than needed, but it's ok
-            true
+        // freeing up more than needed is ok
+        let leftover = storage_used.used.saturating_sub(storage_freed);
+        if !leftover.is_zero() {
+            panic!("User is using {leftover} worth of storage")
+        }
+        if !storage_used.total.is_zero() {
+            Promise::new(near_sdk::env::predecessor_account_id())
+                .transfer(storage_used.total)
+                .detach();
         }
+        Some(storage_used.total)
     }
 
     pub const fn storage_balance_bounds(&self) -> StorageBalanceBounds {
@@ -225,7 +298,7 @@ impl StorageManagement for DexEngine {
         account_id: Option<AccountId>,
         registration_only: Option<bool>,
     ) -> StorageBalance {
-        self.user_storage_balances.storage_deposit(
+        self.internal_storage_deposit(
             account_id.unwrap_or_else(near_sdk::env::predecessor_account_id),
             registration_only,
             near_sdk::env::attached_deposit(),
@@ -235,15 +308,47 @@ impl StorageManagement for DexEngine {
     #[payable]
     fn storage_withdraw(&mut self, amount: Option<NearToken>) -> StorageBalance {
         near_sdk::assert_one_yocto();
-        self.user_storage_balances
-            .storage_withdraw(near_sdk::env::predecessor_account_id(), amount)
+        let account_id = near_sdk::env::predecessor_account_id();
+        let total_before = self
+            .user_storage_balances
+            .storage_balance_of(account_id.clone())
+            .map(|b| b.total)
+            .unwrap_or_default();
+        let balance = self
+            .user_storage_balances
+            .storage_withdraw(account_id.clone(), amount);
+        IntearDexEvent::StorageWithdraw {
+            account_id,
+            amount: total_before.saturating_sub(balance.total),
+            total: balance.total,
+            available: balance.available,
+        }
+        .emit();
+        balance
     }
 
+    /// With `force`, withdraws all balances to the caller,
+    /// unregisters all assets, removes signing keys and
+    /// allowances, and then unregisters. Attach enough gas for a
+    /// withdrawal of every non-zero balance. Assets registered
+    /// before the asset index was added are only closed by
+    /// `close_account`.
     #[payable]
     fn storage_unregister(&mut self, force: Option<bool>) -> bool {
         near_sdk::assert_one_yocto();
-        self.user_storage_balances
-            .storage_unregister(near_sdk::env::predecessor_account_id(), force)
+        let account_id = near_sdk::env::predecessor_account_id();
+        if force.is_some_and(|f| f) {
+            return self.internal_close_account(account_id, Vec::new());
+        }
+        self.internal_remove_storage_breakdown(&AccountOrDexId::Account(account_id.clone()));
+        let Some(refund) = self
+            .user_storage_balances
+            .storage_unregister(account_id.clone(), force)
+        else {
+            return false;
+        };
+        IntearDexEvent::StorageUnregister { account_id, refund }.emit();
+        true
     }
 
     fn storage_balance_bounds(&self) -> StorageBalanceBounds {
@@ -255,15 +360,223 @@ impl StorageManagement for DexEngine {
     }
 }
 
+impl DexEngine {
+    /// Bounds set by the dex with the `storage_balance_bounds`
+    /// export, or the default ones if the dex has no code or
+    /// doesn't export it. The minimum can't be lower than the
+    /// default one.
+    pub(crate) fn internal_dex_storage_balance_bounds(
+        &self,
+        dex_id: &DexId,
+    ) -> StorageBalanceBounds {
+        let default_bounds = self.dex_storage_balances.storage_balance_bounds();
+        let Some(code) = self.dex_codes.get(dex_id) else {
+            return default_bounds;
+        };
+        if !dex_exports_function(code, STORAGE_BALANCE_BOUNDS_METHOD) {
+            return default_bounds;
+        }
+        let response = run_dex_method(
+            code,
+            STORAGE_BALANCE_BOUNDS_METHOD,
+            RunnerData {
+                request: Vec::new(),
+                response: None,
+                registers: HashMap::new(),
+                call_type: CallType::View {
+                    dex_storage: &self.dex_storage,
+                },
+                dex_id: dex_id.clone(),
+                dex_storage_balances: &self.dex_storage_balances,
+                dex_storage_usage_before_transaction: near_sdk::env::storage_usage(),
+            },
+        )
+        .unwrap_or_else(|err| panic!("Failed to get storage balance bounds: {err:?}"))
+        .expect("Dex didn't return storage balance bounds");
+        let bounds: DexStorageBalanceBounds = near_sdk::borsh::from_slice(&response)
+            .expect("Failed to deserialize storage balance bounds");
+        let min = bounds.min.max(default_bounds.min);
+        expect!(
+            bounds.max.is_none_or(|max| max >= min),
+            "Dex {dex_id} has a maximum storage bound below the minimum"
+        );
+        StorageBalanceBounds {
+            min,
+            max: bounds.max,
+        }
+    }
+
+    /// Implementation of `storage_unregister(force: true)` and
+    /// `close_account`. Closes `legacy_asset_ids` along with the
+    /// indexed assets. Failed withdrawals end up in the holding
+    /// area, since the assets are no longer registered by then.
+    fn internal_close_account(
+        &mut self,
+        account_id: AccountId,
+        legacy_asset_ids: Vec<AssetId>,
+    ) -> bool {
+        if self
+            .user_storage_balances
+            .storage_balance_of(account_id.clone())
+            .is_none()
+        {
+            return false;
+        }
+        let mut asset_ids = self
+            .user_assets
+            .get(&account_id)
+            .cloned()
+            .unwrap_or_default();
+        for asset_id in legacy_asset_ids {
+            if !asset_ids.contains(&asset_id) {
+                asset_ids.push(asset_id);
+            }
+        }
+        for asset_id in asset_ids.iter() {
+            self.internal_withdraw(
+                asset_id.clone(),
+                None,
+                None,
+                None,
+                false,
+                AccountOrDexId::Account(account_id.clone()),
+            )
+            .detach();
+        }
+        self.internal_unregister_assets(
+            asset_ids,
+            Some(AccountOrDexId::Account(account_id.clone())),
+            None,
+            account_id.clone(),
+        );
+
+        let storage_usage_before = near_sdk::env::storage_usage();
+        let signing_keys = self.signing_keys.remove(&account_id).unwrap_or_default();
+        let mut removed_signing_keys = self
+            .removed_signing_keys
+            .remove(&account_id)
+            .unwrap_or_default();
+        let allowances = self.allowances.remove(&account_id).unwrap_or_default();
+        self.signing_keys.flush();
+        self.removed_signing_keys.flush();
+        self.allowances.flush();
+        let storage_usage_after = near_sdk::env::storage_usage();
+        self.internal_charge_storage(
+            AccountOrDexId::Account(account_id.clone()),
+            StorageCategory::AccountData,
+            storage_usage_before,
+            storage_usage_after,
+        );
+        // The nonces outlive the account, so that its keys can't
+        // replay payloads if it's registered again. The engine
+        // pays for their storage from here on.
+        removed_signing_keys.extend(signing_keys.iter().cloned());
+        if !removed_signing_keys.is_empty() {
+            self.removed_signing_keys
+                .insert(account_id.clone(), removed_signing_keys);
+            self.removed_signing_keys.flush();
+        }
+        for key in signing_keys {
+            IntearDexEvent::SigningKeyRemoved {
+                account_id: account_id.clone(),
+                public_key: key.public_key,
+            }
+            .emit();
+        }
+        for allowance in allowances {
+            IntearDexEvent::AssetApprovalRevoked {
+                owner: account_id.clone(),
+                spender: allowance.spender,
+                asset_id: allowance.asset_id,
+            }
+            .emit();
+        }
+
+        self.internal_remove_storage_breakdown(&AccountOrDexId::Account(account_id.clone()));
+        let refund = self
+            .user_storage_balances
+            .storage_unregister(account_id.clone(), None)
+            .expect("Checked above");
+        IntearDexEvent::AccountClosed { account_id, refund }.emit();
+        true
+    }
+
+    pub(crate) fn internal_storage_deposit(
+        &mut self,
+        account_id: AccountId,
+        registration_only: Option<bool>,
+        deposit: NearToken,
+    ) -> StorageBalance {
+        let bounds = self.user_storage_balances.storage_balance_bounds();
+        let total_before = self
+            .user_storage_balances
+            .storage_balance_of(account_id.clone())
+            .map(|b| b.total)
+            .unwrap_or_default();
+        let balance = self.user_storage_balances.storage_deposit(
+            account_id.clone(),
+            registration_only,
+            deposit,
+            &bounds,
+        );
+        IntearDexEvent::StorageDeposit {
+            account_id,
+            amount: balance.total.saturating_sub(total_before),
+            total: balance.total,
+            available: balance.available,
+        }
+        .emit();
+        balance
+    }
+
+    pub(crate) fn internal_dex_storage_deposit(
+        &mut self,
+        dex_id: DexId,
+        registration_only: Option<bool>,
+        deposit: NearToken,
+    ) -> StorageBalance {
+        let bounds = self.internal_dex_storage_balance_bounds(&dex_id);
+        let total_before = self
+            .dex_storage_balances
+            .storage_balance_of(dex_id.clone())
+            .map(|b| b.total)
+            .unwrap_or_default();
+        let balance = self.dex_storage_balances.storage_deposit(
+            dex_id.clone(),
+            registration_only,
+            deposit,
+            &bounds,
+        );
+        IntearDexEvent::DexStorageDeposit {
+            dex_id,
+            amount: balance.total.saturating_sub(total_before),
+            total: balance.total,
+            available: balance.available,
+        }
+        .emit();
+        balance
+    }
+}
+
 #[near]
 impl DexEngine {
+    /// `storage_unregister(force: true)` that also closes
+    /// `asset_ids`. Assets registered before the asset index was
+    /// added aren't in `registered_assets_of`, so they have to
+    /// be listed here, or their registrations are left behind.
+    #[payable]
+    pub fn close_account(&mut self, asset_ids: Vec<AssetId>) -> bool {
+        near_sdk::assert_one_yocto();
+        self.internal_close_account(near_sdk::env::predecessor_account_id(), asset_ids)
+    }
+
     #[payable]
     pub fn dex_storage_deposit(
         &mut self,
         dex_id: DexId,
         registration_only: Option<bool>,
     ) -> StorageBalance {
-        self.dex_storage_balances.storage_deposit(
+        self.internal_dex_storage_deposit(
             dex_id,
             registration_only,
             near_sdk::env::attached_deposit(),
@@ -281,11 +594,57 @@ impl DexEngine {
             dex_id.deployer == near_sdk::env::predecessor_account_id(),
             "Only the deployer can withdraw dex storage"
         );
-        self.dex_storage_balances.storage_withdraw(dex_id, amount)
+        let total_before = self
+            .dex_storage_balances
+            .storage_balance_of(dex_id.clone())
+            .map(|b| b.total)
+            .unwrap_or_default();
+        let balance = self
+            .dex_storage_balances
+            .storage_withdraw(dex_id.clone(), amount);
+        IntearDexEvent::DexStorageWithdraw {
+            dex_id,
+            amount: total_before.saturating_sub(balance.total),
+            total: balance.total,
+            available: balance.available,
+        }
+        .emit();
+        balance
     }
 
-    pub const fn dex_storage_balance_bounds(&self) -> StorageBalanceBounds {
-        self.dex_storage_balances.storage_balance_bounds()
+    /// Remove the storage balance of a dex and refund it to the
+    /// deployer. Only possible if the dex has no code and uses
+    /// no storage. Returns `false` if the dex isn't registered.
+    #[payable]
+    pub fn dex_storage_unregister(&mut self, dex_id: DexId, force: Option<bool>) -> bool {
+        near_sdk::assert_one_yocto();
+        expect!(
+            dex_id.deployer == near_sdk::env::predecessor_account_id(),
+            "Only the deployer can unregister dex storage"
+        );
+        expect!(
+            !self.dex_codes.contains_key(&dex_id),
+            "Dex {dex_id} has code deployed"
+        );
+        self.internal_remove_storage_breakdown(&AccountOrDexId::Dex(dex_id.clone()));
+        let Some(refund) = self
+            .dex_storage_balances
+            .storage_unregister(dex_id.clone(), force)
+        else {
+            return false;
+        };
+        IntearDexEvent::DexStorageUnregister { dex_id, refund }.emit();
+        true
+    }
+
+    /// Bounds of the dex storage balance. Without `dex_id`,
+    /// returns the default bounds for dexes that don't set
+    /// their own.
+    pub fn dex_storage_balance_bounds(&self, dex_id: Option<DexId>) -> StorageBalanceBounds {
+        match dex_id {
+            Some(dex_id) => self.internal_dex_storage_balance_bounds(&dex_id),
+            None => self.dex_storage_balances.storage_balance_bounds(),
+        }
     }
 
     pub fn dex_storage_balance_of(&self, dex_id: DexId) -> Option<StorageBalance> {
@@ -0,0 +1,172 @@
+use intear_dex_types::{AssetId, expect};
+use near_sdk::{NearToken, json_types::U128, near};
+
+use crate::{
+    DexEngine, DexEngineExt, IntearDexEvent, internal_asset_operations::AccountOrDexId,
+    storage_breakdown::StorageCategory,
+};
+
+/// Permission to move NEAR from the inner balance to the
+/// storage balance when it runs out.
+#[derive(Clone, Copy)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[borsh, json])]
+pub struct StorageTopUp {
+    /// Maximum amount of NEAR that can be moved in total.
+    pub cap: NearToken,
+    /// Amount that was already moved.
+    pub used: NearToken,
+}
+
+impl DexEngine {
+    /// Charges `of` for the change in storage usage, recording
+    /// it under `category`. If the storage balance doesn't cover
+    /// it and `of` has enabled top-ups, the missing part is moved
+    /// from its NEAR inner balance first.
+    pub(crate) fn internal_charge_storage(
+        &mut self,
+        of: AccountOrDexId,
+        category: StorageCategory,
+        storage_usage_before: u64,
+        storage_usage_after: u64,
+    ) {
+        let storage_usage_after = self.internal_record_storage_usage(
+            &of,
+            category,
+            storage_usage_before,
+            storage_usage_after,
+        );
+        let shortfall = match &of {
+            AccountOrDexId::Account(account) => self.user_storage_balances.shortfall(
+                account,
+                storage_usage_before,
+                storage_usage_after,
+            ),
+            AccountOrDexId::Dex(dex_id) => self.dex_storage_balances.shortfall(
+                dex_id,
+                storage_usage_before,
+                storage_usage_after,
+            ),
+        };
+        if !shortfall.is_zero() {
+            self.internal_top_up_storage(&of, shortfall);
+        }
+        match &of {
+            AccountOrDexId::Account(account) => self.user_storage_balances.charge(
+                account,
+                storage_usage_before,
+                storage_usage_after,
+            ),
+            AccountOrDexId::Dex(dex_id) => {
+                self.dex_storage_balances
+                    .charge(dex_id, storage_usage_before, storage_usage_after)
+            }
+        }
+    }
+
+    /// Moves `amount` of NEAR from the inner balance to the
+    /// storage balance if it's allowed by the top-up settings.
+    /// Otherwise does nothing, and the charge fails as usual.
+    pub(crate) fn internal_top_up_storage(&mut self, of: &AccountOrDexId, amount: NearToken) {
+        let Some(top_up) = self.storage_top_ups.get(of).copied() else {
+            return;
+        };
+        let used = top_up.used.saturating_add(amount);
+        if used > top_up.cap {
+            near_sdk::env::log_str(&format!(
+                "Storage top-up of {amount} for {of} would exceed the cap of {}",
+                top_up.cap
+            ));
+            return;
+        }
+        let near_balance = self
+            .asset_balance_of(of.clone(), AssetId::Near)
+            .unwrap_or_default();
+        if near_balance.0 < amount.as_yoctonear() {
+            return;
+        }
+        // Registered accounts only, so that no new storage
+        // record is created after the usage was measured
+        let registered = match of {
+            AccountOrDexId::Account(account) => self
+                .user_storage_balances
+                .storage_balance_of(account.clone())
+                .is_some(),
+            AccountOrDexId::Dex(dex_id) => self
+                .dex_storage_balances
+                .storage_balance_of(dex_id.clone())
+                .is_some(),
+        };
+        if !registered {
+            return;
+        }
+
+        self.internal_decrease_assets(of.clone(), AssetId::Near, U128(amount.as_yoctonear()));
+        self.internal_decrease_custody(AssetId::Near, U128(amount.as_yoctonear()));
+        match of {
+            AccountOrDexId::Account(account) => self.user_storage_balances.deposit(account, amount),
+            AccountOrDexId::Dex(dex_id) => self.dex_storage_balances.deposit(dex_id, amount),
+        }
+        self.storage_top_ups
+            .insert(of.clone(), StorageTopUp { used, ..top_up });
+        IntearDexEvent::StorageToppedUp {
+            r#for: of.clone(),
+            amount,
+            used,
+            cap: top_up.cap,
+        }
+        .emit();
+    }
+}
+
+#[near]
+impl DexEngine {
+    /// Allow the engine to move up to `cap` of NEAR from the
+    /// inner balance of the caller, or of a dex deployed by the
+    /// caller, to its storage balance whenever the storage
+    /// balance isn't enough. The amount moved so far is reset.
+    /// `None` disables top-ups.
+    #[payable]
+    pub fn set_storage_top_up(&mut self, cap: Option<NearToken>, r#for: Option<AccountOrDexId>) {
+        near_sdk::assert_one_yocto();
+        let caller = near_sdk::env::predecessor_account_id();
+        let r#for = r#for.unwrap_or_else(|| AccountOrDexId::Account(caller.clone()));
+        match &r#for {
+            AccountOrDexId::Account(account) => expect!(
+                *account == caller,
+                "Only {account} can set its storage top-ups"
+            ),
+            AccountOrDexId::Dex(dex_id) => expect!(
+                dex_id.deployer == caller,
+                "Only the deployer can set storage top-ups of {dex_id}"
+            ),
+        }
+        let storage_usage_before = near_sdk::env::storage_usage();
+        match cap {
+            Some(cap) => {
+                self.storage_top_ups.insert(
+                    r#for.clone(),
+                    StorageTopUp {
+                        cap,
+                        used: NearToken::from_yoctonear(0),
+                    },
+                );
+            }
+            None => {
+                self.storage_top_ups.remove(&r#for);
+            }
+        }
+        self.storage_top_ups.flush();
+        let storage_usage_after = near_sdk::env::storage_usage();
+        self.internal_charge_storage(
+            AccountOrDexId::Account(caller),
+            StorageCategory::AccountData,
+            storage_usage_before,
+            storage_usage_after,
+        );
+    }
+
+    pub fn storage_top_up_of(&self, of: AccountOrDexId) -> Option<StorageTopUp> {
+        self.storage_top_ups.get(&of).copied()
+    }
+}
@@ -1,10 +1,16 @@
 #![allow(unused)]
 
 use intear_dex::internal_asset_operations::AccountOrDexId;
-use intear_dex_types::AssetId;
+use intear_dex::internal_operations::Operation;
+use intear_dex_types::{AssetId, DexId};
 use near_crypto::KeyType;
 use near_sdk::serde_json::json;
-use near_sdk::{AccountId, NearToken, json_types::U128};
+use near_sdk::{
+    AccountId, NearToken,
+    base64::{Engine, prelude::BASE64_STANDARD},
+    json_types::{Base64VecU8, U128},
+    near,
+};
 use near_workspaces::result::ExecutionFinalResult;
 use near_workspaces::{Account, Contract};
 use tokio::process::Command;
@@ -15,6 +21,7 @@ pub struct CompiledWasms {
     pub simple_amm_dex_wasm: Vec<u8>,
     pub minimal_dex_wasm: Vec<u8>,
     pub otc_dex_wasm: Vec<u8>,
+    pub storage_test_dex_wasm: Vec<u8>,
     pub ft_wasm: Vec<u8>,
 }
 
@@ -113,6 +120,35 @@ pub async fn get_compiled_wasms() -> &'static CompiledWasms {
                     .success()
             );
 
+            println!("Compiling storage-test-dex");
+            assert!(
+                Command::new("cargo")
+                    .args([
+                        "build",
+                        "--package=storage-test-dex",
+                        "--release",
+                        "--target",
+                        "wasm32-unknown-unknown"
+                    ])
+                    .status()
+                    .await
+                    .unwrap()
+                    .success()
+            );
+            assert!(
+                Command::new("wasm-opt")
+                    .args([
+                        "-O",
+                        "./target/wasm32-unknown-unknown/release/storage_test_dex.wasm",
+                        "-o",
+                        "./target/wasm32-unknown-unknown/release/storage_test_dex.wasm"
+                    ])
+                    .status()
+                    .await
+                    .unwrap()
+                    .success()
+            );
+
             println!("Compilation complete");
 
             let simple_amm_dex_wasm =
@@ -122,6 +158,9 @@ pub async fn get_compiled_wasms() -> &'static CompiledWasms {
                 std::fs::read("./target/wasm32-unknown-unknown/release/minimal_dex.wasm").unwrap();
             let otc_dex_wasm =
                 std::fs::read("./target/wasm32-unknown-unknown/release/otc_dex.wasm").unwrap();
+            let storage_test_dex_wasm =
+                std::fs::read("./target/wasm32-unknown-unknown/release/storage_test_dex.wasm")
+                    .unwrap();
             let ft_wasm = include_bytes!("../assets/ft.wasm").to_vec();
 
             CompiledWasms {
@@ -129,12 +168,54 @@ pub async fn get_compiled_wasms() -> &'static CompiledWasms {
                 simple_amm_dex_wasm,
                 minimal_dex_wasm,
                 otc_dex_wasm,
+                storage_test_dex_wasm,
                 ft_wasm,
             }
         })
         .await
 }
 
+/// Commit of the engine from before the state was versioned.
+pub const BASELINE_ENGINE_COMMIT: &str = "60ca60d54dcf2424eafff3bdea6d2bbc1124f05f";
+
+static BASELINE_ENGINE_WASM: OnceCell<Vec<u8>> = OnceCell::const_new();
+
+/// Engine wasm built from [`BASELINE_ENGINE_COMMIT`], to test
+/// upgrades of state written by the old code.
+pub async fn get_baseline_engine_wasm() -> &'static [u8] {
+    BASELINE_ENGINE_WASM
+        .get_or_init(|| async {
+            println!("Compiling baseline intear-dex");
+            let archive = "./target/baseline-engine.tar";
+            let project = "./target/baseline-engine";
+            assert!(
+                Command::new("git")
+                    .args(["archive", "--output", archive, BASELINE_ENGINE_COMMIT])
+                    .status()
+                    .await
+                    .unwrap()
+                    .success()
+            );
+            if std::fs::exists(project).unwrap() {
+                std::fs::remove_dir_all(project).unwrap();
+            }
+            std::fs::create_dir_all(project).unwrap();
+            assert!(
+                Command::new("tar")
+                    .args(["-xf", archive, "-C", project])
+                    .status()
+                    .await
+                    .unwrap()
+                    .success()
+            );
+            // Build with the same dependency versions as the
+            // current code
+            std::fs::copy("./Cargo.lock", format!("{project}/Cargo.lock")).unwrap();
+            near_workspaces::compile_project(project).await.unwrap()
+        })
+        .await
+}
+
 /// Track tokens burnt from a transaction result and add to total_near_burnt.
 pub fn track_tokens_burnt(
     result: &near_workspaces::result::ExecutionFinalResult,
@@ -321,11 +402,31 @@ pub struct TestContext {
     pub ft3: Contract,
 }
 
+/// Initialize a newly deployed engine contract.
+pub async fn init_engine(dex_engine_contract: &Contract) {
+    let result = dex_engine_contract
+        .call("new")
+        .max_gas()
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+}
+
 /// Set up the basic test environment
 pub async fn setup_test_environment() -> TestContext {
+    let context =
+        setup_test_environment_with_engine(&get_compiled_wasms().await.contract_wasm).await;
+    init_engine(&context.dex_engine_contract).await;
+    context
+}
+
+/// Set up the basic test environment with the engine deployed
+/// from `engine_wasm` and not initialized.
+pub async fn setup_test_environment_with_engine(engine_wasm: &[u8]) -> TestContext {
     let wasms = get_compiled_wasms().await;
     let sandbox = near_workspaces::sandbox().await.unwrap();
-    let dex_engine_contract = sandbox.dev_deploy(&wasms.contract_wasm).await.unwrap();
+    let dex_engine_contract = sandbox.dev_deploy(engine_wasm).await.unwrap();
 
     let (user1, user1_key) = create_user(&sandbox, "user1").await;
     let (user2, user2_key) = create_user(&sandbox, "user2").await;
@@ -422,3 +523,322 @@ pub async fn setup_test_environment() -> TestContext {
         ft3,
     }
 }
+
+/// NEAR liquidity in the NEAR/ft1 pool created by [`setup_simple_amm_pools`].
+pub const SIMPLE_AMM_POOL0_NEAR: NearToken = NearToken::from_near(1);
+/// ft1 liquidity in the NEAR/ft1 pool created by [`setup_simple_amm_pools`].
+pub const SIMPLE_AMM_POOL0_FT1: u128 = 500_000;
+/// ft1 liquidity in the ft1/ft2 pool created by [`setup_simple_amm_pools`].
+pub const SIMPLE_AMM_POOL1_FT1: u128 = 200_000;
+/// ft2 liquidity in the ft1/ft2 pool created by [`setup_simple_amm_pools`].
+pub const SIMPLE_AMM_POOL1_FT2: u128 = 600_000;
+/// Amount of each ft that `user1` deposits in [`setup_simple_amm_pools`].
+pub const SIMPLE_AMM_FT_DEPOSIT: u128 = 1_000_000;
+/// Amount of NEAR that `user1` deposits in [`setup_simple_amm_pools`].
+pub const SIMPLE_AMM_NEAR_DEPOSIT: NearToken = NearToken::from_near(5);
+
+/// Register user1 on an engine that runs the code from
+/// [`BASELINE_ENGINE_COMMIT`], and deposit 1 NEAR and
+/// `ft_amount` of ft1 with the old API.
+pub async fn deposit_on_baseline_engine(context: &TestContext, ft_amount: u128) {
+    let TestContext {
+        dex_engine_contract,
+        ft1,
+        user1,
+        deployer,
+        ..
+    } = context;
+    ft_storage_deposit(ft1, user1).await;
+    ft_storage_deposit_for(ft1, user1, dex_engine_contract.id()).await;
+    let result = deployer
+        .call(ft1.id(), "ft_transfer")
+        .args_json(json!({
+            "receiver_id": user1.id(),
+            "amount": U128(ft_amount),
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user1
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(engine_user_storage_deposit())
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user1
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Near, AssetId::Nep141(ft1.id().clone())],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user1
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(NearToken::from_near(1))
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user1
+        .call(ft1.id(), "ft_transfer_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "receiver_id": dex_engine_contract.id(),
+            "amount": U128(ft_amount),
+            "msg": "",
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+}
+
+/// Deploy the current code to an engine that runs the code
+/// from [`BASELINE_ENGINE_COMMIT`] and migrate its state.
+pub async fn migrate_baseline_engine(dex_engine_contract: &Contract) {
+    let wasm = &get_compiled_wasms().await.contract_wasm;
+    let result = dex_engine_contract.as_account().deploy(wasm).await.unwrap();
+    assert!(result.is_success());
+    let result = dex_engine_contract
+        .call("migrate")
+        .max_gas()
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+}
+
+/// Borsh-encoded swap message for a simple-amm pool.
+pub fn simple_amm_swap_message(pool_id: u64) -> Base64VecU8 {
+    #[near(serializers=[borsh])]
+    struct SwapArgs {
+        pool_id: u64,
+    }
+    Base64VecU8(near_sdk::borsh::to_vec(&SwapArgs { pool_id }).unwrap())
+}
+
+/// Deploy simple-amm as `user1/dex`, and create two pools
+/// owned by `user1`: pool 0 is NEAR/ft1, and pool 1 is
+/// ft1/ft2. After this, `user1` has the remainder of
+/// [`SIMPLE_AMM_NEAR_DEPOSIT`] and [`SIMPLE_AMM_FT_DEPOSIT`]
+/// as inner balances.
+pub async fn setup_simple_amm_pools(context: &TestContext) -> DexId {
+    setup_simple_amm_pools_with_logs(context).await.0
+}
+
+/// [`setup_simple_amm_pools`] that also returns the logs of
+/// all transactions it made, in the order they were emitted.
+pub async fn setup_simple_amm_pools_with_logs(context: &TestContext) -> (DexId, Vec<String>) {
+    let TestContext {
+        dex_engine_contract,
+        ft1,
+        ft2,
+        user1,
+        deployer,
+        ..
+    } = context;
+    let wasms = get_compiled_wasms().await;
+    let mut logs = Vec::new();
+    let pool_creation_fee = NearToken::from_millinear(10);
+    let dex_id = DexId {
+        deployer: user1.id().clone(),
+        id: "dex".to_string(),
+    };
+
+    ft_storage_deposit(ft1, user1).await;
+    ft_storage_deposit(ft2, user1).await;
+    for ft in [ft1, ft2] {
+        let result = deployer
+            .call(ft.id(), "ft_transfer")
+            .args_json(json!({
+                "receiver_id": user1.id(),
+                "amount": U128(NearToken::from_near(1_000_000_000).as_yoctonear()),
+            }))
+            .deposit(NearToken::from_yoctonear(1))
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+        logs.extend(result.logs().into_iter().map(String::from));
+    }
+
+    let result = user1
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_dex_storage_deposit())
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    logs.extend(result.logs().into_iter().map(String::from));
+
+    let result = user1
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(NearToken::from_near(5))
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    logs.extend(result.logs().into_iter().map(String::from));
+
+    let asset_ids = [
+        AssetId::Near,
+        AssetId::Nep141(ft1.id().clone()),
+        AssetId::Nep141(ft2.id().clone()),
+    ];
+    for r#for in [
+        AccountOrDexId::Account(user1.id().clone()),
+        AccountOrDexId::Dex(dex_id.clone()),
+    ] {
+        let result = user1
+            .call(dex_engine_contract.id(), "register_assets")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "asset_ids": asset_ids,
+                "for": r#for,
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+        logs.extend(result.logs().into_iter().map(String::from));
+    }
+
+    ft_storage_deposit_for(ft1, user1, dex_engine_contract.id()).await;
+    ft_storage_deposit_for(ft2, user1, dex_engine_contract.id()).await;
+
+    let result = user1
+        .call(dex_engine_contract.id(), "deploy_dex_code")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "last_part_of_id": dex_id.id,
+            "code_base64": BASE64_STANDARD.encode(&wasms.simple_amm_dex_wasm),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    logs.extend(result.logs().into_iter().map(String::from));
+
+    let result = user1
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(SIMPLE_AMM_NEAR_DEPOSIT)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    logs.extend(result.logs().into_iter().map(String::from));
+
+    for ft in [ft1, ft2] {
+        let result = user1
+            .call(ft.id(), "ft_transfer_call")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "receiver_id": dex_engine_contract.id(),
+                "amount": U128(SIMPLE_AMM_FT_DEPOSIT),
+                "msg": "",
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+        logs.extend(result.logs().into_iter().map(String::from));
+    }
+
+    #[near(serializers=[borsh])]
+    struct CreatePoolArgs {
+        assets: (AssetId, AssetId),
+    }
+    #[near(serializers=[borsh])]
+    struct AddLiquidityArgs {
+        pool_id: u64,
+    }
+
+    let dex_call =
+        |method: &str, args: Vec<u8>, attached_assets: Vec<(AssetId, u128)>| Operation::DexCall {
+            dex_id: dex_id.clone(),
+            method: method.to_string(),
+            args: Base64VecU8(args),
+            attached_assets: attached_assets
+                .into_iter()
+                .map(|(asset_id, amount)| (asset_id, U128(amount)))
+                .collect(),
+            max_storage_payment: NearToken::from_yoctonear(0),
+        };
+    let operations = vec![
+        dex_call("new", vec![], vec![]),
+        dex_call(
+            "create_pool",
+            near_sdk::borsh::to_vec(&CreatePoolArgs {
+                assets: (AssetId::Near, AssetId::Nep141(ft1.id().clone())),
+            })
+            .unwrap(),
+            vec![(AssetId::Near, pool_creation_fee.as_yoctonear())],
+        ),
+        dex_call(
+            "create_pool",
+            near_sdk::borsh::to_vec(&CreatePoolArgs {
+                assets: (
+                    AssetId::Nep141(ft1.id().clone()),
+                    AssetId::Nep141(ft2.id().clone()),
+                ),
+            })
+            .unwrap(),
+            vec![(AssetId::Near, pool_creation_fee.as_yoctonear())],
+        ),
+        dex_call(
+            "add_liquidity",
+            near_sdk::borsh::to_vec(&AddLiquidityArgs { pool_id: 0 }).unwrap(),
+            vec![
+                (AssetId::Near, SIMPLE_AMM_POOL0_NEAR.as_yoctonear()),
+                (AssetId::Nep141(ft1.id().clone()), SIMPLE_AMM_POOL0_FT1),
+            ],
+        ),
+        dex_call(
+            "add_liquidity",
+            near_sdk::borsh::to_vec(&AddLiquidityArgs { pool_id: 1 }).unwrap(),
+            vec![
+                (AssetId::Nep141(ft1.id().clone()), SIMPLE_AMM_POOL1_FT1),
+                (AssetId::Nep141(ft2.id().clone()), SIMPLE_AMM_POOL1_FT2),
+            ],
+        ),
+    ];
+    let result = user1
+        .call(dex_engine_contract.id(), "execute_operations")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "operations": operations,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    logs.extend(result.logs().into_iter().map(String::from));
+
+    (dex_id, logs)
+}
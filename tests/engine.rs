@@ -1,17 +1,25 @@
 mod common;
 use common::*;
 
+use intear_dex::allowances::Allowance;
 use intear_dex::internal_operations::SwapOperationAmount;
+use intear_dex::signed_operations::{SignedOperationsPayload, SigningKey};
+use intear_dex::storage_breakdown::{STORAGE_THRESHOLD_STEP, StorageBreakdownView};
+use intear_dex::storage_top_up::StorageTopUp;
 use intear_dex::{internal_asset_operations::AccountOrDexId, internal_operations::Operation};
-use intear_dex_types::{AssetId, DexId, SwapRequestAmount};
+use intear_dex_types::{
+    AssetId, DexAbiFields, DexAbiMethod, DexAbiType, DexFeeModel, DexId, DexMetadata, DexMethod,
+    DexMethodKind, DexPair, SwapRequestAmount, SwapResponse,
+};
 use near_contract_standards::storage_management::{StorageBalance, StorageBalanceBounds};
 use near_sdk::serde_json::json;
 use near_sdk::{
     AccountId, NearToken,
     base64::{Engine, prelude::BASE64_STANDARD},
-    json_types::{Base64VecU8, U128},
+    json_types::{Base64VecU8, U64, U128},
     near,
 };
+use near_workspaces::{operations::Function, types::Gas};
 use std::collections::HashMap;
 
 #[tokio::test]
@@ -867,12 +875,16 @@ async fn test_execute_operations() {
             amount: SwapOperationAmount::Amount(SwapRequestAmount::ExactIn(U128(
                 swap_amount.as_yoctonear(),
             ))),
+            referral: None,
+            max_storage_payment: NearToken::from_yoctonear(0),
         },
         Operation::Withdraw {
             asset_id: AssetId::Near,
             amount: Some(U128(withdraw_amount.as_yoctonear())),
             to: None,
             rescue_address: None,
+            msg: None,
+            register_receiver: false,
         },
     ];
 
@@ -940,6 +952,8 @@ async fn test_execute_operations_failure_reverts() {
         amount: None,
         to: None,
         rescue_address: None,
+        msg: None,
+        register_receiver: false,
     }];
 
     let result = user1
@@ -1027,6 +1041,8 @@ async fn test_ft_transfer_call_failure_reverts() {
         amount: Some(U128(ft_withdraw_attempt)),
         to: None,
         rescue_address: None,
+        msg: None,
+        register_receiver: false,
     }];
 
     let initial_ft_balance = ft1
@@ -1245,6 +1261,7 @@ async fn test_execute_operations_liquidity_and_swaps() {
             method: "new".to_string(),
             args: Base64VecU8(vec![]),
             attached_assets: HashMap::new(),
+            max_storage_payment: NearToken::from_yoctonear(0),
         },
         Operation::DexCall {
             dex_id: DexId {
@@ -1262,6 +1279,7 @@ async fn test_execute_operations_liquidity_and_swaps() {
                 AssetId::Near,
                 U128(pool_creation_fee.as_yoctonear()),
             )]),
+            max_storage_payment: NearToken::from_yoctonear(0),
         },
         Operation::DexCall {
             dex_id: DexId {
@@ -1282,6 +1300,7 @@ async fn test_execute_operations_liquidity_and_swaps() {
                 AssetId::Near,
                 U128(pool_creation_fee.as_yoctonear()),
             )]),
+            max_storage_payment: NearToken::from_yoctonear(0),
         },
         Operation::DexCall {
             dex_id: DexId {
@@ -1294,6 +1313,7 @@ async fn test_execute_operations_liquidity_and_swaps() {
                 (AssetId::Near, U128(lp1_near_amount.as_yoctonear())),
                 (AssetId::Nep141(ft1.id().clone()), U128(lp1_ft1_amount)),
             ]),
+            max_storage_payment: NearToken::from_yoctonear(0),
         },
         Operation::DexCall {
             dex_id: DexId {
@@ -1306,6 +1326,7 @@ async fn test_execute_operations_liquidity_and_swaps() {
                 (AssetId::Nep141(ft1.id().clone()), U128(lp2_ft1_amount)),
                 (AssetId::Nep141(ft2.id().clone()), U128(lp2_ft2_amount)),
             ]),
+            max_storage_payment: NearToken::from_yoctonear(0),
         },
         Operation::SwapSimple {
             dex_id: DexId {
@@ -1318,6 +1339,8 @@ async fn test_execute_operations_liquidity_and_swaps() {
             amount: SwapOperationAmount::Amount(SwapRequestAmount::ExactIn(U128(
                 swap_amount_in.as_yoctonear(),
             ))),
+            referral: None,
+            max_storage_payment: NearToken::from_yoctonear(0),
         },
         Operation::SwapSimple {
             dex_id: DexId {
@@ -1328,6 +1351,8 @@ async fn test_execute_operations_liquidity_and_swaps() {
             asset_in: AssetId::Nep141(ft1.id().clone()),
             asset_out: AssetId::Nep141(ft2.id().clone()),
             amount: SwapOperationAmount::OutputOfLastIn,
+            referral: None,
+            max_storage_payment: NearToken::from_yoctonear(0),
         },
     ];
 
@@ -1371,10 +1396,30 @@ async fn test_execute_operations_liquidity_and_swaps() {
         .unwrap();
     assert_eq!(amount_out, 1493);
 
+    // Pool creation fees were converted to dex storage, so they're
+    // no longer in custody
+    let mut near_balances = 0;
+    for of in [
+        AccountOrDexId::Account(user1.id().clone()),
+        AccountOrDexId::Dex(dex_id.clone()),
+    ] {
+        near_balances += dex_engine_contract
+            .view("asset_balance_of")
+            .args_json(json!({
+                "of": of,
+                "asset_id": AssetId::Near,
+            }))
+            .await
+            .unwrap()
+            .json::<U128>()
+            .unwrap()
+            .0;
+    }
+    assert!(near_balances < initial_near_deposit.as_yoctonear());
     assert_total_in_custody(
         &dex_engine_contract,
         AssetId::Near,
-        Some(U128(initial_near_deposit.as_yoctonear())),
+        Some(U128(near_balances)),
     )
     .await
     .unwrap();
@@ -1430,6 +1475,119 @@ async fn test_execute_operations_liquidity_and_swaps() {
     .unwrap();
 }
 
+#[tokio::test]
+async fn test_storage_deposit_operation_debits_near() {
+    let near_deposit = NearToken::from_near(2);
+    let storage_amount = NearToken::from_near(1);
+
+    let TestContext {
+        dex_engine_contract,
+        user1,
+        ..
+    } = setup_test_environment().await;
+
+    let result = user1
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(NearToken::from_millinear(10))
+        .args_json(json!({ "registration_only": true }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user1
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({ "asset_ids": [AssetId::Near] }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user1
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(near_deposit)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let storage_before = dex_engine_contract
+        .view("storage_balance_of")
+        .args_json(json!({ "account_id": user1.id() }))
+        .await
+        .unwrap()
+        .json::<StorageBalance>()
+        .unwrap();
+
+    // Outside of a swap, the deposit is paid from the NEAR inner
+    // balance of the caller, and not credited for free
+    let result = user1
+        .call(dex_engine_contract.id(), "execute_operations")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "operations": [Operation::StorageDeposit {
+                amount: U128(storage_amount.as_yoctonear()),
+                r#for: None,
+            }],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let storage_after = dex_engine_contract
+        .view("storage_balance_of")
+        .args_json(json!({ "account_id": user1.id() }))
+        .await
+        .unwrap()
+        .json::<StorageBalance>()
+        .unwrap();
+    assert_eq!(
+        storage_after.total.as_yoctonear(),
+        storage_before.total.as_yoctonear() + storage_amount.as_yoctonear()
+    );
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Near,
+        Some(U128(
+            near_deposit.as_yoctonear() - storage_amount.as_yoctonear(),
+        )),
+    )
+    .await
+    .unwrap();
+    // The NEAR is locked for storage, so it's no longer custodied
+    assert_total_in_custody(
+        &dex_engine_contract,
+        AssetId::Near,
+        Some(U128(
+            near_deposit.as_yoctonear() - storage_amount.as_yoctonear(),
+        )),
+    )
+    .await
+    .unwrap();
+
+    // More than the inner balance can't be deposited
+    let result = user1
+        .call(dex_engine_contract.id(), "execute_operations")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "operations": [Operation::StorageDeposit {
+                amount: U128(near_deposit.as_yoctonear()),
+                r#for: None,
+            }],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(result.is_failure());
+}
+
 #[tokio::test]
 async fn test_operations_with_ft_deposit() {
     let ft_total_supply = NearToken::from_near(1_000_000_000);
@@ -1554,12 +1712,16 @@ async fn test_operations_with_ft_deposit() {
             asset_in: AssetId::Nep141(ft1.id().clone()),
             asset_out: AssetId::Nep141(ft1.id().clone()),
             amount: SwapOperationAmount::Amount(SwapRequestAmount::ExactIn(U128(ft_swap_amount))),
+            referral: None,
+            max_storage_payment: NearToken::from_yoctonear(0),
         },
         Operation::Withdraw {
             asset_id: AssetId::Nep141(ft1.id().clone()),
             amount: None,
             to: Some(user1.id().clone()),
             rescue_address: Some(user2.id().clone()),
+            msg: None,
+            register_receiver: false,
         },
     ];
 
@@ -2244,3 +2406,4011 @@ async fn test_regular_flow() {
         })
     );
 }
+
+#[tokio::test]
+async fn test_swap_route() {
+    let context = setup_test_environment().await;
+    let dex_id = setup_simple_amm_pools(&context).await;
+    let TestContext {
+        dex_engine_contract,
+        ft1,
+        ft2,
+        user1,
+        ..
+    } = &context;
+    let hops = json!([
+        {
+            "dex_id": dex_id,
+            "message": simple_amm_swap_message(0),
+            "asset_out": AssetId::Nep141(ft1.id().clone()),
+        },
+        {
+            "dex_id": dex_id,
+            "message": simple_amm_swap_message(1),
+            "asset_out": AssetId::Nep141(ft2.id().clone()),
+        },
+    ]);
+    let swap_amount_in = NearToken::from_millinear(1);
+    let expected_amount_out = 1493;
+
+    // Minimum amount out is not reached
+    let result = user1
+        .call(dex_engine_contract.id(), "swap_route")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_in": AssetId::Near,
+            "hops": hops,
+            "amount": SwapRequestAmount::ExactIn(U128(swap_amount_in.as_yoctonear())),
+            "limit": U128(expected_amount_out + 1),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+
+    let result = user1
+        .call(dex_engine_contract.id(), "swap_route")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_in": AssetId::Near,
+            "hops": hops,
+            "amount": SwapRequestAmount::ExactIn(U128(swap_amount_in.as_yoctonear())),
+            "limit": U128(expected_amount_out),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let route_events = result
+        .logs()
+        .into_iter()
+        .filter(|log| log.contains(r#""event":"swap_route""#))
+        .count();
+    assert_eq!(route_events, 1);
+    let swap_events = result
+        .logs()
+        .into_iter()
+        .filter(|log| log.contains(r#""event":"swap""#))
+        .count();
+    assert_eq!(swap_events, 0);
+    let (amount_in, amount_out) = result.json::<(U128, U128)>().unwrap();
+    assert_eq!(amount_in.0, swap_amount_in.as_yoctonear());
+    assert_eq!(amount_out.0, expected_amount_out);
+
+    assert_inner_asset_balance(
+        dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Nep141(ft2.id().clone()),
+        Some(U128(
+            SIMPLE_AMM_FT_DEPOSIT - SIMPLE_AMM_POOL1_FT2 + expected_amount_out,
+        )),
+    )
+    .await
+    .unwrap();
+    // intermediate asset stays where it was
+    assert_inner_asset_balance(
+        dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(
+            SIMPLE_AMM_FT_DEPOSIT - SIMPLE_AMM_POOL0_FT1 - SIMPLE_AMM_POOL1_FT1,
+        )),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        dex_engine_contract,
+        AccountOrDexId::Dex(dex_id.clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(SIMPLE_AMM_POOL0_FT1 + SIMPLE_AMM_POOL1_FT1)),
+    )
+    .await
+    .unwrap();
+
+    // ExactOut is solved backwards
+    let exact_amount_out = 1000u128;
+    let near_before = user1
+        .view(dex_engine_contract.id(), "asset_balance_of")
+        .args_json(json!({
+            "asset_id": AssetId::Near,
+            "of": AccountOrDexId::Account(user1.id().clone()),
+        }))
+        .await
+        .unwrap()
+        .json::<U128>()
+        .unwrap();
+    let result = user1
+        .call(dex_engine_contract.id(), "swap_route")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_in": AssetId::Near,
+            "hops": hops,
+            "amount": SwapRequestAmount::ExactOut(U128(exact_amount_out)),
+            "limit": U128(swap_amount_in.as_yoctonear()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let (amount_in, amount_out) = result.json::<(U128, U128)>().unwrap();
+    assert_eq!(amount_out.0, exact_amount_out);
+    assert!(amount_in.0 > 0 && amount_in.0 <= swap_amount_in.as_yoctonear());
+
+    assert_inner_asset_balance(
+        dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Near,
+        Some(U128(near_before.0 - amount_in.0)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Nep141(ft2.id().clone()),
+        Some(U128(
+            SIMPLE_AMM_FT_DEPOSIT - SIMPLE_AMM_POOL1_FT2 + expected_amount_out + exact_amount_out,
+        )),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        dex_engine_contract,
+        AccountOrDexId::Dex(dex_id),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(SIMPLE_AMM_POOL0_FT1 + SIMPLE_AMM_POOL1_FT1)),
+    )
+    .await
+    .unwrap();
+    assert_total_in_custody(
+        dex_engine_contract,
+        AssetId::Nep141(ft2.id().clone()),
+        Some(U128(SIMPLE_AMM_FT_DEPOSIT)),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_swap_split() {
+    let context = setup_test_environment().await;
+    let dex_id = setup_simple_amm_pools(&context).await;
+    let TestContext {
+        dex_engine_contract,
+        ft1,
+        ft2,
+        user1,
+        ..
+    } = &context;
+
+    #[near(serializers=[borsh])]
+    struct CreatePoolArgs {
+        assets: (AssetId, AssetId),
+    }
+    #[near(serializers=[borsh])]
+    struct AddLiquidityArgs {
+        pool_id: u64,
+    }
+    // Pool 2 is NEAR/ft2, so NEAR -> ft2 can go directly or through ft1
+    let result = user1
+        .call(dex_engine_contract.id(), "execute_operations")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "operations": [
+                Operation::DexCall {
+                    dex_id: dex_id.clone(),
+                    method: "create_pool".to_string(),
+                    args: Base64VecU8(
+                        near_sdk::borsh::to_vec(&CreatePoolArgs {
+                            assets: (AssetId::Near, AssetId::Nep141(ft2.id().clone())),
+                        })
+                        .unwrap(),
+                    ),
+                    attached_assets: HashMap::from_iter([(
+                        AssetId::Near,
+                        U128(NearToken::from_millinear(10).as_yoctonear()),
+                    )]),
+                    max_storage_payment: NearToken::from_yoctonear(0),
+                },
+                Operation::DexCall {
+                    dex_id: dex_id.clone(),
+                    method: "add_liquidity".to_string(),
+                    args: Base64VecU8(
+                        near_sdk::borsh::to_vec(&AddLiquidityArgs { pool_id: 2 }).unwrap(),
+                    ),
+                    attached_assets: HashMap::from_iter([
+                        (AssetId::Near, U128(NearToken::from_near(1).as_yoctonear())),
+                        (AssetId::Nep141(ft2.id().clone()), U128(SIMPLE_AMM_POOL1_FT2)),
+                    ]),
+                    max_storage_payment: NearToken::from_yoctonear(0),
+                },
+            ],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let legs = json!([
+        {
+            "weight": 60,
+            "hops": [
+                {
+                    "dex_id": dex_id,
+                    "message": simple_amm_swap_message(2),
+                    "asset_out": AssetId::Nep141(ft2.id().clone()),
+                },
+            ],
+        },
+        {
+            "weight": 40,
+            "hops": [
+                {
+                    "dex_id": dex_id,
+                    "message": simple_amm_swap_message(0),
+                    "asset_out": AssetId::Nep141(ft1.id().clone()),
+                },
+                {
+                    "dex_id": dex_id,
+                    "message": simple_amm_swap_message(1),
+                    "asset_out": AssetId::Nep141(ft2.id().clone()),
+                },
+            ],
+        },
+    ]);
+    let swap_amount_in = NearToken::from_millinear(1);
+    // 359 through the direct pool, 596 through ft1
+    let expected_amount_out = 955;
+
+    let result = user1
+        .call(dex_engine_contract.id(), "swap_split")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_in": AssetId::Near,
+            "amount_in": U128(swap_amount_in.as_yoctonear()),
+            "legs": legs,
+            "min_amount_out": U128(expected_amount_out + 1),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+
+    let ft2_balance_before = user1
+        .view(dex_engine_contract.id(), "asset_balance_of")
+        .args_json(json!({
+            "asset_id": AssetId::Nep141(ft2.id().clone()),
+            "of": AccountOrDexId::Account(user1.id().clone()),
+        }))
+        .await
+        .unwrap()
+        .json::<U128>()
+        .unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "swap_split")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_in": AssetId::Near,
+            "amount_in": U128(swap_amount_in.as_yoctonear()),
+            "legs": legs,
+            "min_amount_out": U128(expected_amount_out),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let route_events = result
+        .logs()
+        .into_iter()
+        .filter(|log| log.contains(r#""event":"swap_route""#))
+        .count();
+    assert_eq!(route_events, 2);
+    let split_events = result
+        .logs()
+        .into_iter()
+        .filter(|log| log.contains(r#""event":"swap_split""#))
+        .count();
+    assert_eq!(split_events, 1);
+    let amount_out = result.json::<U128>().unwrap();
+    assert_eq!(amount_out.0, expected_amount_out);
+
+    assert_inner_asset_balance(
+        dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Nep141(ft2.id().clone()),
+        Some(U128(ft2_balance_before.0 + expected_amount_out)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        dex_engine_contract,
+        AccountOrDexId::Dex(dex_id),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(SIMPLE_AMM_POOL0_FT1 + SIMPLE_AMM_POOL1_FT1)),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_swap_best_price() {
+    let context = setup_test_environment().await;
+    let dex_id = setup_simple_amm_pools(&context).await;
+    let TestContext {
+        dex_engine_contract,
+        ft1,
+        user1,
+        ..
+    } = &context;
+
+    #[near(serializers=[borsh])]
+    struct CreatePoolArgs {
+        assets: (AssetId, AssetId),
+    }
+    #[near(serializers=[borsh])]
+    struct AddLiquidityArgs {
+        pool_id: u64,
+    }
+    // Pool 2 is NEAR/ft1 with a better price than pool 0
+    let result = user1
+        .call(dex_engine_contract.id(), "execute_operations")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "operations": [
+                Operation::DexCall {
+                    dex_id: dex_id.clone(),
+                    method: "create_pool".to_string(),
+                    args: Base64VecU8(
+                        near_sdk::borsh::to_vec(&CreatePoolArgs {
+                            assets: (AssetId::Near, AssetId::Nep141(ft1.id().clone())),
+                        })
+                        .unwrap(),
+                    ),
+                    attached_assets: HashMap::from_iter([(
+                        AssetId::Near,
+                        U128(NearToken::from_millinear(10).as_yoctonear()),
+                    )]),
+                    max_storage_payment: NearToken::from_yoctonear(0),
+                },
+                Operation::DexCall {
+                    dex_id: dex_id.clone(),
+                    method: "add_liquidity".to_string(),
+                    args: Base64VecU8(
+                        near_sdk::borsh::to_vec(&AddLiquidityArgs { pool_id: 2 }).unwrap(),
+                    ),
+                    attached_assets: HashMap::from_iter([
+                        (
+                            AssetId::Near,
+                            U128(NearToken::from_millinear(250).as_yoctonear()),
+                        ),
+                        (AssetId::Nep141(ft1.id().clone()), U128(250_000)),
+                    ]),
+                    max_storage_payment: NearToken::from_yoctonear(0),
+                },
+            ],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let ft1_balance_before = user1
+        .view(dex_engine_contract.id(), "asset_balance_of")
+        .args_json(json!({
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+            "of": AccountOrDexId::Account(user1.id().clone()),
+        }))
+        .await
+        .unwrap()
+        .json::<U128>()
+        .unwrap();
+
+    let missing_dex_id = DexId {
+        deployer: user1.id().clone(),
+        id: "missing".to_string(),
+    };
+    let swap_best_price = |limit: u128| {
+        user1
+            .call(dex_engine_contract.id(), "swap_best_price")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "candidates": [
+                    // No code deployed
+                    { "dex_id": missing_dex_id, "message": simple_amm_swap_message(0) },
+                    // ft1/ft2 pool, can't quote this pair
+                    { "dex_id": dex_id, "message": simple_amm_swap_message(1) },
+                    { "dex_id": dex_id, "message": simple_amm_swap_message(0) },
+                    { "dex_id": dex_id, "message": simple_amm_swap_message(2) },
+                ],
+                "asset_in": AssetId::Near,
+                "asset_out": AssetId::Nep141(ft1.id().clone()),
+                "amount": SwapRequestAmount::ExactIn(U128(NearToken::from_millinear(1).as_yoctonear())),
+                "limit": U128(limit),
+            }))
+            .transact()
+    };
+    // The best candidate gives 996, less than the minimum
+    let result = swap_best_price(997).await.unwrap();
+    assert!(!result.is_success());
+
+    let result = swap_best_price(996).await.unwrap();
+    assert_success(&result).unwrap();
+    let swap_event = result
+        .logs()
+        .into_iter()
+        .find(|log| log.contains(r#""event":"swap""#))
+        .unwrap()
+        .to_string();
+    let swap_event: near_sdk::serde_json::Value =
+        near_sdk::serde_json::from_str(swap_event.strip_prefix("EVENT_JSON:").unwrap()).unwrap();
+    let quotes = swap_event["data"]["quotes"].as_array().unwrap();
+    assert_eq!(quotes.len(), 4);
+    assert!(quotes[0]["amount_out"].is_null());
+    assert!(quotes[1]["amount_out"].is_null());
+    assert_eq!(quotes[2]["amount_out"], json!("499"));
+    assert_eq!(quotes[3]["amount_out"], json!("996"));
+    let (_amount_in, amount_out) = result.json::<(U128, U128)>().unwrap();
+    assert_eq!(amount_out.0, 996);
+
+    assert_inner_asset_balance(
+        dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(ft1_balance_before.0 + 996)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        dex_engine_contract,
+        AccountOrDexId::Dex(dex_id),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(
+            SIMPLE_AMM_POOL0_FT1 + SIMPLE_AMM_POOL1_FT1 + 250_000 - 996,
+        )),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_swap_fees() {
+    let context = setup_test_environment().await;
+    let dex_id = setup_simple_amm_pools(&context).await;
+    let TestContext {
+        dex_engine_contract,
+        ft1,
+        user1,
+        user2,
+        user3,
+        user4,
+        ..
+    } = &context;
+
+    // user2 is the protocol fee recipient, user3 is a referral
+    for user in [user2, user3] {
+        let result = user
+            .call(dex_engine_contract.id(), "storage_deposit")
+            .max_gas()
+            .deposit(engine_user_storage_deposit())
+            .args_json(json!({}))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+        let result = user
+            .call(dex_engine_contract.id(), "register_assets")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "asset_ids": [AssetId::Near, AssetId::Nep141(ft1.id().clone())],
+                "for": AccountOrDexId::Account(user.id().clone()),
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+    }
+
+    let result = user1
+        .call(dex_engine_contract.id(), "set_fee_config")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "fee_config": {
+                "protocol_fee_bps": 30,
+                "protocol_fee_recipient": user2.id(),
+                "max_referral_fee_bps": 100,
+            },
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+    let result = dex_engine_contract
+        .call("set_fee_config")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "fee_config": {
+                "protocol_fee_bps": 30,
+                "protocol_fee_recipient": user2.id(),
+                "max_referral_fee_bps": 100,
+            },
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    // Referral fee is above the maximum
+    let result = user1
+        .call(dex_engine_contract.id(), "swap_simple")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id,
+            "message": simple_amm_swap_message(0),
+            "asset_in": AssetId::Near,
+            "asset_out": AssetId::Nep141(ft1.id().clone()),
+            "amount": SwapRequestAmount::ExactIn(U128(NearToken::from_millinear(100).as_yoctonear())),
+            "referral": { "account_id": user3.id(), "fee_bps": 101 },
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+
+    let ft1_balance_before = user1
+        .view(dex_engine_contract.id(), "asset_balance_of")
+        .args_json(json!({
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+            "of": AccountOrDexId::Account(user1.id().clone()),
+        }))
+        .await
+        .unwrap()
+        .json::<U128>()
+        .unwrap();
+
+    // ExactIn: the dex returns 45454, fees are taken from it
+    let result = user1
+        .call(dex_engine_contract.id(), "swap_simple")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id,
+            "message": simple_amm_swap_message(0),
+            "asset_in": AssetId::Near,
+            "asset_out": AssetId::Nep141(ft1.id().clone()),
+            "amount": SwapRequestAmount::ExactIn(U128(NearToken::from_millinear(100).as_yoctonear())),
+            "referral": { "account_id": user3.id(), "fee_bps": 50 },
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let swap_event = result
+        .logs()
+        .into_iter()
+        .find(|log| log.contains(r#""event":"swap""#))
+        .unwrap()
+        .to_string();
+    let swap_event: near_sdk::serde_json::Value =
+        near_sdk::serde_json::from_str(swap_event.strip_prefix("EVENT_JSON:").unwrap()).unwrap();
+    assert_eq!(
+        swap_event["data"]["fees"],
+        json!({
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+            "protocol_fee": "136",
+            "protocol_fee_recipient": user2.id(),
+            "referral_fee": "227",
+            "referral": user3.id(),
+        })
+    );
+    let (_amount_in, amount_out) = result.json::<(U128, U128)>().unwrap();
+    assert_eq!(amount_out.0, 45454 - 136 - 227);
+
+    assert_inner_asset_balance(
+        dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(ft1_balance_before.0 + 45454 - 136 - 227)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        dex_engine_contract,
+        AccountOrDexId::Account(user2.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(136)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        dex_engine_contract,
+        AccountOrDexId::Account(user3.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(227)),
+    )
+    .await
+    .unwrap();
+
+    // ExactOut: fees are added to the input
+    let dex_amount_in = 2425332821808592733703u128;
+    let protocol_fee = 7275998465425778201u128;
+    let referral_fee = 12126664109042963668u128;
+    let result = user1
+        .call(dex_engine_contract.id(), "swap_simple")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id,
+            "message": simple_amm_swap_message(0),
+            "asset_in": AssetId::Near,
+            "asset_out": AssetId::Nep141(ft1.id().clone()),
+            "amount": SwapRequestAmount::ExactOut(U128(1000)),
+            "referral": { "account_id": user3.id(), "fee_bps": 50 },
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let (amount_in, amount_out) = result.json::<(U128, U128)>().unwrap();
+    assert_eq!(amount_in.0, dex_amount_in + protocol_fee + referral_fee);
+    assert_eq!(amount_out.0, 1000);
+    assert_inner_asset_balance(
+        dex_engine_contract,
+        AccountOrDexId::Account(user2.id().clone()),
+        AssetId::Near,
+        Some(U128(protocol_fee)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        dex_engine_contract,
+        AccountOrDexId::Account(user3.id().clone()),
+        AssetId::Near,
+        Some(U128(referral_fee)),
+    )
+    .await
+    .unwrap();
+
+    // Routes, split swaps and best-price swaps pay the protocol
+    // fee from the output too
+    let event_data = |result: &near_workspaces::result::ExecutionFinalResult, event: &str| {
+        result
+            .logs()
+            .into_iter()
+            .find_map(|log| {
+                let log: near_sdk::serde_json::Value =
+                    near_sdk::serde_json::from_str(log.strip_prefix("EVENT_JSON:")?).ok()?;
+                (log["event"] == event).then(|| log["data"].clone())
+            })
+            .unwrap()
+    };
+    let amount_in = U128(NearToken::from_millinear(10).as_yoctonear());
+    let hops = json!([
+        {
+            "dex_id": dex_id,
+            "message": simple_amm_swap_message(0),
+            "asset_out": AssetId::Nep141(ft1.id().clone()),
+        },
+    ]);
+    let calls = [
+        (
+            "swap_route",
+            "swap_route",
+            json!({
+                "asset_in": AssetId::Near,
+                "hops": hops,
+                "amount": SwapRequestAmount::ExactIn(amount_in),
+                "limit": U128(0),
+            }),
+        ),
+        (
+            "swap_split",
+            "swap_split",
+            json!({
+                "asset_in": AssetId::Near,
+                "amount_in": amount_in,
+                "legs": [{ "weight": 1, "hops": hops }],
+                "min_amount_out": U128(0),
+            }),
+        ),
+        (
+            "swap_best_price",
+            "swap",
+            json!({
+                "candidates": [{ "dex_id": dex_id, "message": simple_amm_swap_message(0) }],
+                "asset_in": AssetId::Near,
+                "asset_out": AssetId::Nep141(ft1.id().clone()),
+                "amount": SwapRequestAmount::ExactIn(amount_in),
+                "limit": U128(0),
+            }),
+        ),
+    ];
+    let mut protocol_fees = 136;
+    for (method, event, args) in calls {
+        let result = user1
+            .call(dex_engine_contract.id(), method)
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(args)
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+        let data = event_data(&result, event);
+        let fees = &data["fees"];
+        assert_eq!(fees["asset_id"], json!(AssetId::Nep141(ft1.id().clone())));
+        assert_eq!(fees["protocol_fee_recipient"], json!(user2.id()));
+        assert_eq!(fees["referral_fee"], json!("0"));
+        let protocol_fee = fees["protocol_fee"]
+            .as_str()
+            .unwrap()
+            .parse::<u128>()
+            .unwrap();
+        let amount_out = data["amount_out"]
+            .as_str()
+            .unwrap()
+            .parse::<u128>()
+            .unwrap();
+        assert!(protocol_fee > 0);
+        assert_eq!(protocol_fee, (amount_out + protocol_fee) * 30 / 10_000);
+        protocol_fees += protocol_fee;
+        assert_inner_asset_balance(
+            dex_engine_contract,
+            AccountOrDexId::Account(user2.id().clone()),
+            AssetId::Nep141(ft1.id().clone()),
+            Some(U128(protocol_fees)),
+        )
+        .await
+        .unwrap();
+    }
+
+    // user4 doesn't have ft1 registered, so the protocol fee is
+    // skipped with an event
+    let result = dex_engine_contract
+        .call("set_fee_config")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "fee_config": {
+                "protocol_fee_bps": 30,
+                "protocol_fee_recipient": user4.id(),
+                "max_referral_fee_bps": 100,
+            },
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user1
+        .call(dex_engine_contract.id(), "swap_simple")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id,
+            "message": simple_amm_swap_message(0),
+            "asset_in": AssetId::Near,
+            "asset_out": AssetId::Nep141(ft1.id().clone()),
+            "amount": SwapRequestAmount::ExactIn(amount_in),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let skipped = event_data(&result, "swap_fee_skipped");
+    assert_eq!(skipped["recipient"], json!(user4.id()));
+    assert_eq!(
+        skipped["asset_id"],
+        json!(AssetId::Nep141(ft1.id().clone()))
+    );
+    assert_ne!(skipped["amount"], json!("0"));
+    assert!(event_data(&result, "swap")["fees"].is_null());
+}
+
+#[tokio::test]
+async fn test_flash_loans() {
+    let context = setup_test_environment().await;
+    let dex_id = setup_simple_amm_pools(&context).await;
+    let TestContext {
+        dex_engine_contract,
+        ft1,
+        ft2,
+        user1,
+        ..
+    } = &context;
+    let borrow_amount = 100_000u128;
+    // 30 bps, rounded up
+    let fee = 300u128;
+    let dex_ft1_balance = SIMPLE_AMM_POOL0_FT1 + SIMPLE_AMM_POOL1_FT1;
+    let user_ft1_balance = SIMPLE_AMM_FT_DEPOSIT - SIMPLE_AMM_POOL0_FT1 - SIMPLE_AMM_POOL1_FT1;
+
+    // Not repaid
+    let result = user1
+        .call(dex_engine_contract.id(), "execute_operations")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "operations": [
+                Operation::FlashBorrow {
+                    dex_id: dex_id.clone(),
+                    asset_id: AssetId::Nep141(ft1.id().clone()),
+                    amount: U128(borrow_amount),
+                },
+            ],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+
+    // Repaid without the fee
+    let result = user1
+        .call(dex_engine_contract.id(), "execute_operations")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "operations": [
+                Operation::FlashBorrow {
+                    dex_id: dex_id.clone(),
+                    asset_id: AssetId::Nep141(ft1.id().clone()),
+                    amount: U128(borrow_amount),
+                },
+                Operation::FlashRepay {
+                    dex_id: dex_id.clone(),
+                    asset_id: AssetId::Nep141(ft1.id().clone()),
+                    amount: Some(U128(borrow_amount)),
+                },
+            ],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+
+    assert_inner_asset_balance(
+        dex_engine_contract,
+        AccountOrDexId::Dex(dex_id.clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(dex_ft1_balance)),
+    )
+    .await
+    .unwrap();
+
+    // The borrowed amount can be used by other operations
+    let result = user1
+        .call(dex_engine_contract.id(), "execute_operations")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "operations": [
+                Operation::FlashBorrow {
+                    dex_id: dex_id.clone(),
+                    asset_id: AssetId::Nep141(ft1.id().clone()),
+                    amount: U128(borrow_amount),
+                },
+                Operation::SwapSimple {
+                    dex_id: dex_id.clone(),
+                    message: simple_amm_swap_message(1),
+                    asset_in: AssetId::Nep141(ft1.id().clone()),
+                    asset_out: AssetId::Nep141(ft2.id().clone()),
+                    amount: SwapOperationAmount::OutputOfLastIn,
+                    referral: None,
+                    max_storage_payment: NearToken::from_yoctonear(0),
+                },
+                Operation::SwapSimple {
+                    dex_id: dex_id.clone(),
+                    message: simple_amm_swap_message(1),
+                    asset_in: AssetId::Nep141(ft2.id().clone()),
+                    asset_out: AssetId::Nep141(ft1.id().clone()),
+                    amount: SwapOperationAmount::OutputOfLastIn,
+                    referral: None,
+                    max_storage_payment: NearToken::from_yoctonear(0),
+                },
+                Operation::FlashRepay {
+                    dex_id: dex_id.clone(),
+                    asset_id: AssetId::Nep141(ft1.id().clone()),
+                    amount: None,
+                },
+            ],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    for event in ["flash_borrow", "flash_repay"] {
+        assert_eq!(
+            result
+                .logs()
+                .into_iter()
+                .filter(|log| log.contains(&format!(r#""event":"{event}""#)))
+                .count(),
+            1
+        );
+    }
+
+    let user_ft1_balance_after = user1
+        .view(dex_engine_contract.id(), "asset_balance_of")
+        .args_json(json!({
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+            "of": AccountOrDexId::Account(user1.id().clone()),
+        }))
+        .await
+        .unwrap()
+        .json::<U128>()
+        .unwrap();
+    let dex_ft1_balance_after = user1
+        .view(dex_engine_contract.id(), "asset_balance_of")
+        .args_json(json!({
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+            "of": AccountOrDexId::Dex(dex_id.clone()),
+        }))
+        .await
+        .unwrap()
+        .json::<U128>()
+        .unwrap();
+    // The round trip lost some ft1 to the pool, and the fee
+    // was paid on top of it
+    let round_trip_loss = user_ft1_balance - fee - user_ft1_balance_after.0;
+    assert!(round_trip_loss > 0);
+    assert_eq!(
+        dex_ft1_balance_after.0,
+        dex_ft1_balance + fee + round_trip_loss
+    );
+}
+
+#[tokio::test]
+async fn test_execute_signed_operations() {
+    let context = setup_test_environment().await;
+    let dex_id = setup_simple_amm_pools(&context).await;
+    let TestContext {
+        dex_engine_contract,
+        ft1,
+        user1,
+        user2,
+        ..
+    } = &context;
+    let ed25519_key = near_crypto::SecretKey::from_random(near_crypto::KeyType::ED25519);
+    let secp256k1_key = near_crypto::SecretKey::from_random(near_crypto::KeyType::SECP256K1);
+    let unregistered_key = near_crypto::SecretKey::from_random(near_crypto::KeyType::ED25519);
+
+    for key in [&ed25519_key, &secp256k1_key] {
+        let result = user1
+            .call(dex_engine_contract.id(), "add_signing_key")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "public_key": key.public_key().to_string(),
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+    }
+    let signing_keys = user1
+        .view(dex_engine_contract.id(), "signing_keys_of")
+        .args_json(json!({
+            "account_id": user1.id(),
+        }))
+        .await
+        .unwrap()
+        .json::<Vec<SigningKey>>()
+        .unwrap();
+    assert_eq!(signing_keys.len(), 2);
+
+    let swap = Operation::SwapSimple {
+        dex_id: dex_id.clone(),
+        message: simple_amm_swap_message(0),
+        asset_in: AssetId::Near,
+        asset_out: AssetId::Nep141(ft1.id().clone()),
+        amount: SwapOperationAmount::Amount(SwapRequestAmount::ExactIn(U128(
+            NearToken::from_millinear(1).as_yoctonear(),
+        ))),
+        referral: None,
+        max_storage_payment: NearToken::from_yoctonear(0),
+    };
+    let sign = |key: &near_crypto::SecretKey, payload: SignedOperationsPayload| {
+        let payload = near_sdk::borsh::to_vec(&payload).unwrap();
+        let signature = match key.sign(&near_sdk::env::sha256_array(&payload)) {
+            near_crypto::Signature::ED25519(sig) => sig.to_bytes().to_vec(),
+            near_crypto::Signature::SECP256K1(sig) => <[u8; 65]>::from(sig).to_vec(),
+        };
+        json!({
+            "signed_operations": {
+                "payload": Base64VecU8(payload),
+                "public_key": key.public_key().to_string(),
+                "signature": Base64VecU8(signature),
+            },
+        })
+    };
+    let payload = |nonce: u64| SignedOperationsPayload {
+        domain: dex_engine_contract.id().clone(),
+        signer_id: user1.id().clone(),
+        nonce,
+        expires_at: u64::MAX,
+        operations: vec![swap.clone()],
+    };
+
+    let ft1_balance_before = user1
+        .view(dex_engine_contract.id(), "asset_balance_of")
+        .args_json(json!({
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+            "of": AccountOrDexId::Account(user1.id().clone()),
+        }))
+        .await
+        .unwrap()
+        .json::<U128>()
+        .unwrap();
+
+    // user2 relays user1's operations
+    let ed25519_args = sign(&ed25519_key, payload(1));
+    let result = user2
+        .call(dex_engine_contract.id(), "execute_signed_operations")
+        .max_gas()
+        .args_json(ed25519_args.clone())
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    // Replay
+    let result = user2
+        .call(dex_engine_contract.id(), "execute_signed_operations")
+        .max_gas()
+        .args_json(ed25519_args)
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+
+    // Nonces are tracked per key
+    let result = user2
+        .call(dex_engine_contract.id(), "execute_signed_operations")
+        .max_gas()
+        .args_json(sign(&secp256k1_key, payload(1)))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    assert_inner_asset_balance(
+        dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(ft1_balance_before.0 + 499 + 498)),
+    )
+    .await
+    .unwrap();
+
+    // Key that wasn't added
+    let result = user2
+        .call(dex_engine_contract.id(), "execute_signed_operations")
+        .max_gas()
+        .args_json(sign(&unregistered_key, payload(2)))
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+
+    // Signed for another engine
+    let result = user2
+        .call(dex_engine_contract.id(), "execute_signed_operations")
+        .max_gas()
+        .args_json(sign(
+            &ed25519_key,
+            SignedOperationsPayload {
+                domain: "another-engine.near".parse().unwrap(),
+                ..payload(2)
+            },
+        ))
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+
+    // Expired
+    let result = user2
+        .call(dex_engine_contract.id(), "execute_signed_operations")
+        .max_gas()
+        .args_json(sign(
+            &ed25519_key,
+            SignedOperationsPayload {
+                expires_at: 1,
+                ..payload(2)
+            },
+        ))
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+
+    // Signature doesn't match the payload
+    let mut tampered_args = sign(&ed25519_key, payload(2));
+    tampered_args["signed_operations"]["payload"] =
+        json!(Base64VecU8(near_sdk::borsh::to_vec(&payload(3)).unwrap()));
+    let result = user2
+        .call(dex_engine_contract.id(), "execute_signed_operations")
+        .max_gas()
+        .args_json(tampered_args)
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+
+    let result = user1
+        .call(dex_engine_contract.id(), "remove_signing_key")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "public_key": ed25519_key.public_key().to_string(),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user2
+        .call(dex_engine_contract.id(), "execute_signed_operations")
+        .max_gas()
+        .args_json(sign(&ed25519_key, payload(2)))
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+
+    // A key that is added back keeps its last nonce, so what it
+    // signed before can't be replayed
+    let result = user1
+        .call(dex_engine_contract.id(), "add_signing_key")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "public_key": ed25519_key.public_key().to_string(),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let signing_keys = user1
+        .view(dex_engine_contract.id(), "signing_keys_of")
+        .args_json(json!({
+            "account_id": user1.id(),
+        }))
+        .await
+        .unwrap()
+        .json::<Vec<SigningKey>>()
+        .unwrap();
+    let re_added_key = signing_keys
+        .iter()
+        .find(|key| String::from(&key.public_key) == ed25519_key.public_key().to_string())
+        .unwrap();
+    assert_eq!(re_added_key.last_nonce, U64(1));
+    let result = user2
+        .call(dex_engine_contract.id(), "execute_signed_operations")
+        .max_gas()
+        .args_json(sign(&ed25519_key, payload(1)))
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+    let result = user2
+        .call(dex_engine_contract.id(), "execute_signed_operations")
+        .max_gas()
+        .args_json(sign(&ed25519_key, payload(2)))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+}
+
+#[tokio::test]
+async fn test_allowances() {
+    let context = setup_test_environment().await;
+    let dex_id = setup_simple_amm_pools(&context).await;
+    let TestContext {
+        dex_engine_contract,
+        ft1,
+        user1,
+        user2,
+        ..
+    } = &context;
+    let result = user2
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(engine_user_storage_deposit())
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user2
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Nep141(ft1.id().clone())],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let user1_ft1_balance = SIMPLE_AMM_FT_DEPOSIT - SIMPLE_AMM_POOL0_FT1 - SIMPLE_AMM_POOL1_FT1;
+
+    for (asset_id, amount) in [
+        (AssetId::Nep141(ft1.id().clone()), 100_000),
+        (AssetId::Near, NearToken::from_millinear(1).as_yoctonear()),
+    ] {
+        let result = user1
+            .call(dex_engine_contract.id(), "approve_asset")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "spender": user2.id(),
+                "asset_id": asset_id,
+                "amount": U128(amount),
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+    }
+    let allowances = user1
+        .view(dex_engine_contract.id(), "allowances_of")
+        .args_json(json!({
+            "owner": user1.id(),
+        }))
+        .await
+        .unwrap()
+        .json::<Vec<Allowance>>()
+        .unwrap();
+    assert_eq!(allowances.len(), 2);
+
+    let transfer_from = |amount: u128| {
+        json!({
+            "operations": [
+                Operation::TransferFrom {
+                    owner: user1.id().clone(),
+                    to: AccountOrDexId::Account(user2.id().clone()),
+                    asset_id: AssetId::Nep141(ft1.id().clone()),
+                    amount: U128(amount),
+                },
+            ],
+        })
+    };
+    let result = user2
+        .call(dex_engine_contract.id(), "execute_operations")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(transfer_from(60_000))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    // More than what's left of the allowance
+    let result = user2
+        .call(dex_engine_contract.id(), "execute_operations")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(transfer_from(40_001))
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+    let allowance = user1
+        .view(dex_engine_contract.id(), "allowance")
+        .args_json(json!({
+            "owner": user1.id(),
+            "spender": user2.id(),
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+        }))
+        .await
+        .unwrap()
+        .json::<U128>()
+        .unwrap();
+    assert_eq!(allowance.0, 40_000);
+    assert_inner_asset_balance(
+        dex_engine_contract,
+        AccountOrDexId::Account(user2.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(60_000)),
+    )
+    .await
+    .unwrap();
+
+    // The output of a swap goes to the owner
+    let result = user2
+        .call(dex_engine_contract.id(), "execute_operations")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "operations": [
+                Operation::SwapSimpleFrom {
+                    owner: user1.id().clone(),
+                    dex_id: dex_id.clone(),
+                    message: simple_amm_swap_message(0),
+                    asset_in: AssetId::Near,
+                    asset_out: AssetId::Nep141(ft1.id().clone()),
+                    amount: SwapRequestAmount::ExactIn(U128(
+                        NearToken::from_millinear(1).as_yoctonear(),
+                    )),
+                    referral: None,
+                },
+            ],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    assert_inner_asset_balance(
+        dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(user1_ft1_balance - 60_000 + 499)),
+    )
+    .await
+    .unwrap();
+
+    // The NEAR allowance is used up, so only ft1 is left
+    let allowances = user1
+        .view(dex_engine_contract.id(), "allowances_of")
+        .args_json(json!({
+            "owner": user1.id(),
+        }))
+        .await
+        .unwrap()
+        .json::<Vec<Allowance>>()
+        .unwrap();
+    assert_eq!(allowances.len(), 1);
+
+    let result = user1
+        .call(dex_engine_contract.id(), "revoke_asset")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "spender": user2.id(),
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user2
+        .call(dex_engine_contract.id(), "execute_operations")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(transfer_from(1))
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+}
+
+#[tokio::test]
+async fn test_withdraw_with_transfer_call() {
+    let ft_deposit_amount = 1_000_000u128;
+    let ft_withdraw_amount = 300_000u128;
+
+    let TestContext {
+        sandbox,
+        dex_engine_contract,
+        ft1,
+        user1,
+        user2,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    // Another engine deployment, so that there's a receiver
+    // that implements ft_on_transfer
+    let receiver_engine_contract = sandbox
+        .dev_deploy(&get_compiled_wasms().await.contract_wasm)
+        .await
+        .unwrap();
+    init_engine(&receiver_engine_contract).await;
+
+    ft_storage_deposit(&ft1, &user1).await;
+    ft_storage_deposit(&ft1, &user2).await;
+    ft_storage_deposit_for(&ft1, &user1, dex_engine_contract.id()).await;
+    ft_storage_deposit_for(&ft1, &user1, receiver_engine_contract.id()).await;
+
+    let result = deployer
+        .call(ft1.id(), "ft_transfer")
+        .args_json(json!({
+            "receiver_id": user1.id(),
+            "amount": U128(ft_deposit_amount),
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    for (engine, account_id) in [
+        (&dex_engine_contract, user1.id()),
+        (&receiver_engine_contract, dex_engine_contract.id()),
+    ] {
+        let result = user1
+            .call(engine.id(), "storage_deposit")
+            .max_gas()
+            .deposit(NearToken::from_near(1))
+            .args_json(json!({
+                "account_id": account_id,
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+        let result = user1
+            .call(engine.id(), "register_assets")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "asset_ids": [AssetId::Nep141(ft1.id().clone())],
+                "for": AccountOrDexId::Account(account_id.clone()),
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+    }
+
+    let result = user1
+        .call(ft1.id(), "ft_transfer_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "receiver_id": dex_engine_contract.id(),
+            "amount": U128(ft_deposit_amount),
+            "msg": "",
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    // user2 has no contract, so ft_on_transfer fails and the
+    // whole amount is refunded
+    let result = user1
+        .call(dex_engine_contract.id(), "withdraw")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+            "amount": U128(ft_withdraw_amount),
+            "withdraw_to": user2.id(),
+            "msg": "",
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    assert!(!result.json::<bool>().unwrap());
+    assert_ft_balance(&user2, ft1.clone(), U128(0))
+        .await
+        .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(ft_deposit_amount)),
+    )
+    .await
+    .unwrap();
+    assert_total_in_custody(
+        &dex_engine_contract,
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(ft_deposit_amount)),
+    )
+    .await
+    .unwrap();
+
+    // NEAR can't be withdrawn with a msg
+    let result = user1
+        .call(dex_engine_contract.id(), "withdraw")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_id": AssetId::Near,
+            "amount": U128(1),
+            "msg": "",
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+
+    // The receiver engine credits the deposit to the sender
+    let result = user1
+        .call(dex_engine_contract.id(), "withdraw")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+            "amount": U128(ft_withdraw_amount),
+            "withdraw_to": receiver_engine_contract.id(),
+            "msg": "",
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    assert!(result.json::<bool>().unwrap());
+    assert_inner_asset_balance(
+        &receiver_engine_contract,
+        AccountOrDexId::Account(dex_engine_contract.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(ft_withdraw_amount)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(ft_deposit_amount - ft_withdraw_amount)),
+    )
+    .await
+    .unwrap();
+    assert_total_in_custody(
+        &dex_engine_contract,
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(ft_deposit_amount - ft_withdraw_amount)),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_withdraw_with_receiver_registration() {
+    let ft_deposit_amount = 1_000_000u128;
+    let ft_withdraw_amount = 100_000u128;
+
+    let TestContext {
+        dex_engine_contract,
+        ft1,
+        user1,
+        user2,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+
+    ft_storage_deposit(&ft1, &user1).await;
+    ft_storage_deposit_for(&ft1, &user1, dex_engine_contract.id()).await;
+    let result = deployer
+        .call(ft1.id(), "ft_transfer")
+        .args_json(json!({
+            "receiver_id": user1.id(),
+            "amount": U128(ft_deposit_amount),
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(NearToken::from_near(1))
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user1
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Near, AssetId::Nep141(ft1.id().clone())],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user1
+        .call(ft1.id(), "ft_transfer_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "receiver_id": dex_engine_contract.id(),
+            "amount": U128(ft_deposit_amount),
+            "msg": "",
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let storage_cost = ft1
+        .view("storage_balance_bounds")
+        .await
+        .unwrap()
+        .json::<StorageBalanceBounds>()
+        .unwrap()
+        .min;
+    let withdraw = |register_receiver: bool| {
+        user1
+            .call(dex_engine_contract.id(), "withdraw")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "asset_id": AssetId::Nep141(ft1.id().clone()),
+                "amount": U128(ft_withdraw_amount),
+                "withdraw_to": user2.id(),
+                "register_receiver": register_receiver,
+            }))
+            .transact()
+    };
+
+    // Without registration, the transfer to user2 fails
+    let result = withdraw(false).await.unwrap();
+    assert_success(&result).unwrap();
+    assert!(!result.json::<bool>().unwrap());
+
+    // There's no NEAR to pay for the registration yet
+    let result = withdraw(true).await.unwrap();
+    assert_success(&result).unwrap();
+    assert!(!result.json::<bool>().unwrap());
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(ft_deposit_amount)),
+    )
+    .await
+    .unwrap();
+
+    let near_deposit = NearToken::from_near(1);
+    let result = user1
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(near_deposit)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = withdraw(true).await.unwrap();
+    assert_success(&result).unwrap();
+    let withdraw_event = result
+        .logs()
+        .into_iter()
+        .find(|log| log.contains(r#""event":"withdraw""#))
+        .unwrap()
+        .to_string();
+    assert!(result.json::<bool>().unwrap());
+    let withdraw_event: near_sdk::serde_json::Value =
+        near_sdk::serde_json::from_str(withdraw_event.strip_prefix("EVENT_JSON:").unwrap())
+            .unwrap();
+    assert_eq!(
+        withdraw_event["data"]["storage_deposit"],
+        json!(storage_cost)
+    );
+    assert_ft_balance(&user2, ft1.clone(), U128(ft_withdraw_amount))
+        .await
+        .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Near,
+        Some(U128(
+            near_deposit.as_yoctonear() - storage_cost.as_yoctonear(),
+        )),
+    )
+    .await
+    .unwrap();
+    assert_total_in_custody(
+        &dex_engine_contract,
+        AssetId::Near,
+        Some(U128(
+            near_deposit.as_yoctonear() - storage_cost.as_yoctonear(),
+        )),
+    )
+    .await
+    .unwrap();
+
+    // user2 is registered now, so nothing is charged
+    let result = withdraw(true).await.unwrap();
+    assert_success(&result).unwrap();
+    assert!(
+        !result
+            .logs()
+            .into_iter()
+            .any(|log| log.contains("storage_deposit"))
+    );
+    assert!(result.json::<bool>().unwrap());
+    assert_ft_balance(&user2, ft1.clone(), U128(2 * ft_withdraw_amount))
+        .await
+        .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(ft_deposit_amount - 2 * ft_withdraw_amount)),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_reconcile_custody() {
+    let ft_deposit_amount = 1_000_000u128;
+    let ft_donation_amount = 5_000u128;
+
+    let TestContext {
+        dex_engine_contract,
+        ft1,
+        user1,
+        user2,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+
+    ft_storage_deposit(&ft1, &user1).await;
+    ft_storage_deposit(&ft1, &user2).await;
+    ft_storage_deposit_for(&ft1, &user1, dex_engine_contract.id()).await;
+    let result = deployer
+        .call(ft1.id(), "ft_transfer")
+        .args_json(json!({
+            "receiver_id": user1.id(),
+            "amount": U128(ft_deposit_amount),
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user1
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(NearToken::from_near(1))
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user1
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Nep141(ft1.id().clone())],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user1
+        .call(ft1.id(), "ft_transfer_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "receiver_id": dex_engine_contract.id(),
+            "amount": U128(ft_deposit_amount),
+            "msg": "",
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    // Tokens sent with ft_transfer are not credited to anyone
+    let result = deployer
+        .call(ft1.id(), "ft_transfer")
+        .args_json(json!({
+            "receiver_id": dex_engine_contract.id(),
+            "amount": U128(ft_donation_amount),
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let reconcile = || {
+        user1
+            .call(dex_engine_contract.id(), "reconcile_custody")
+            .max_gas()
+            .args_json(json!({
+                "asset_id": AssetId::Nep141(ft1.id().clone()),
+            }))
+            .transact()
+    };
+    let result = reconcile().await.unwrap();
+    assert_success(&result).unwrap();
+    assert!(
+        result
+            .logs()
+            .into_iter()
+            .any(|log| log.contains(r#""event":"custody_reconciled""#))
+    );
+    let reconciliation = result.json::<near_sdk::serde_json::Value>().unwrap();
+    assert_eq!(
+        reconciliation["balance"],
+        json!((ft_deposit_amount + ft_donation_amount).to_string())
+    );
+    assert_eq!(
+        reconciliation["total_in_custody"],
+        json!(ft_deposit_amount.to_string())
+    );
+    assert_eq!(
+        reconciliation["surplus"],
+        json!(ft_donation_amount.to_string())
+    );
+    assert_eq!(reconciliation["deficit"], json!("0"));
+
+    let result = user1
+        .call(dex_engine_contract.id(), "reconcile_custody")
+        .max_gas()
+        .args_json(json!({
+            "asset_id": AssetId::Near,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let reconciliation = result.json::<near_sdk::serde_json::Value>().unwrap();
+    assert_eq!(reconciliation["deficit"], json!("0"));
+
+    let sweep = |account: &near_workspaces::Account, amount: u128| {
+        account
+            .call(dex_engine_contract.id(), "sweep_custody_surplus")
+            .max_gas()
+            .args_json(json!({
+                "asset_id": AssetId::Nep141(ft1.id().clone()),
+                "amount": U128(amount),
+            }))
+            .transact()
+    };
+    let result = sweep(&user2, ft_donation_amount).await.unwrap();
+    assert!(!result.is_success());
+
+    let result = dex_engine_contract
+        .call("set_treasury")
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "treasury": user2.id(),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    // The amount has to be explicit
+    let result = user2
+        .call(dex_engine_contract.id(), "sweep_custody_surplus")
+        .max_gas()
+        .args_json(json!({
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+
+    // Nothing is swept if the surplus is smaller than the amount
+    let result = sweep(&user2, ft_donation_amount + 1).await.unwrap();
+    assert_success(&result).unwrap();
+    assert_eq!(result.json::<U128>().unwrap(), U128(0));
+    assert_ft_balance(&user2, ft1.clone(), U128(0))
+        .await
+        .unwrap();
+
+    let result = sweep(&user2, ft_donation_amount).await.unwrap();
+    assert_success(&result).unwrap();
+    assert_eq!(result.json::<U128>().unwrap(), U128(ft_donation_amount));
+    assert_ft_balance(&user2, ft1.clone(), U128(ft_donation_amount))
+        .await
+        .unwrap();
+    assert_total_in_custody(
+        &dex_engine_contract,
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(ft_deposit_amount)),
+    )
+    .await
+    .unwrap();
+
+    let result = reconcile().await.unwrap();
+    assert_success(&result).unwrap();
+    let reconciliation = result.json::<near_sdk::serde_json::Value>().unwrap();
+    assert_eq!(reconciliation["surplus"], json!("0"));
+    assert_eq!(reconciliation["deficit"], json!("0"));
+}
+
+#[tokio::test]
+async fn test_owner_and_pausing() {
+    let context = setup_test_environment().await;
+    let dex_id = setup_simple_amm_pools(&context).await;
+    let TestContext {
+        dex_engine_contract,
+        ft1,
+        user1,
+        user5,
+        ..
+    } = &context;
+
+    let owner = user1
+        .view(dex_engine_contract.id(), "owner")
+        .await
+        .unwrap()
+        .json::<AccountId>()
+        .unwrap();
+    assert_eq!(&owner, dex_engine_contract.id());
+
+    let result = user1
+        .call(dex_engine_contract.id(), "pause")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+
+    // Ownership is transferred in two steps
+    let result = dex_engine_contract
+        .call("propose_owner")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "new_owner": user5.id(),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user1
+        .call(dex_engine_contract.id(), "accept_owner")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+    let result = user5
+        .call(dex_engine_contract.id(), "accept_owner")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    assert!(
+        result
+            .logs()
+            .into_iter()
+            .any(|log| log.contains(r#""event":"owner_changed""#))
+    );
+    let owner = user1
+        .view(dex_engine_contract.id(), "owner")
+        .await
+        .unwrap()
+        .json::<AccountId>()
+        .unwrap();
+    assert_eq!(&owner, user5.id());
+
+    let owner_call = |method: &'static str, args: near_sdk::serde_json::Value| {
+        user5
+            .call(dex_engine_contract.id(), method)
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(args)
+            .transact()
+    };
+
+    let result = owner_call("set_treasury", json!({ "treasury": user1.id() }))
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    assert!(result.logs().into_iter().any(|log| {
+        log.contains(r#""event":"treasury_updated""#)
+            && log.contains(&format!(r#""new_treasury":"{}""#, user1.id()))
+    }));
+    let result = owner_call(
+        "set_fee_config",
+        json!({
+            "fee_config": {
+                "protocol_fee_bps": 30,
+                "protocol_fee_recipient": user1.id(),
+                "max_referral_fee_bps": 100,
+            },
+        }),
+    )
+    .await
+    .unwrap();
+    assert_success(&result).unwrap();
+    assert!(result.logs().into_iter().any(|log| {
+        log.contains(r#""event":"fee_config_updated""#) && log.contains(r#""protocol_fee_bps":30"#)
+    }));
+
+    let swap = || {
+        user1
+            .call(dex_engine_contract.id(), "swap_simple")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "dex_id": dex_id,
+                "message": simple_amm_swap_message(0),
+                "asset_in": AssetId::Near,
+                "asset_out": AssetId::Nep141(ft1.id().clone()),
+                "amount": SwapRequestAmount::ExactIn(U128(NearToken::from_millinear(1).as_yoctonear())),
+            }))
+            .transact()
+    };
+
+    // Dex pause
+    let result = owner_call("pause_dex", json!({ "dex_id": dex_id }))
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let paused_dexes = user1
+        .view(dex_engine_contract.id(), "paused_dexes")
+        .await
+        .unwrap()
+        .json::<Vec<DexId>>()
+        .unwrap();
+    assert_eq!(paused_dexes, vec![dex_id.clone()]);
+    assert!(!swap().await.unwrap().is_success());
+    let result = owner_call("unpause_dex", json!({ "dex_id": dex_id }))
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    assert_success(&swap().await.unwrap()).unwrap();
+
+    // Asset pause blocks deposits and swaps
+    let result = owner_call("pause_asset", json!({ "asset_id": AssetId::Near }))
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let paused_assets = user1
+        .view(dex_engine_contract.id(), "paused_assets")
+        .await
+        .unwrap()
+        .json::<Vec<AssetId>>()
+        .unwrap();
+    assert_eq!(paused_assets, vec![AssetId::Near]);
+    assert!(!swap().await.unwrap().is_success());
+    let result = user1
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(NearToken::from_near(1))
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+    let result = owner_call("unpause_asset", json!({ "asset_id": AssetId::Near }))
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    // Global pause still allows withdrawals
+    let result = owner_call("pause", json!({})).await.unwrap();
+    assert_success(&result).unwrap();
+    let is_paused = user1
+        .view(dex_engine_contract.id(), "is_paused")
+        .await
+        .unwrap()
+        .json::<bool>()
+        .unwrap();
+    assert!(is_paused);
+    assert!(!swap().await.unwrap().is_success());
+    let result = user1
+        .call(dex_engine_contract.id(), "transfer_asset")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "to": AccountOrDexId::Account(user5.id().clone()),
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+            "amount": U128(1),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+    let ft1_balance = user1
+        .view(dex_engine_contract.id(), "asset_balance_of")
+        .args_json(json!({
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+            "of": AccountOrDexId::Account(user1.id().clone()),
+        }))
+        .await
+        .unwrap()
+        .json::<U128>()
+        .unwrap();
+    let result = user1
+        .call(dex_engine_contract.id(), "withdraw")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+            "amount": U128(1000),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    assert!(result.json::<bool>().unwrap());
+    assert_inner_asset_balance(
+        dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(ft1_balance.0 - 1000)),
+    )
+    .await
+    .unwrap();
+
+    let result = owner_call("unpause", json!({})).await.unwrap();
+    assert_success(&result).unwrap();
+    assert_success(&swap().await.unwrap()).unwrap();
+}
+
+#[tokio::test]
+async fn test_upgrade_engine() {
+    let context = setup_test_environment_with_engine(get_baseline_engine_wasm().await).await;
+    let TestContext {
+        dex_engine_contract,
+        ft1,
+        user1,
+        ..
+    } = &context;
+    let wasm = &get_compiled_wasms().await.contract_wasm;
+    let ft_deposit_amount = 1_000_000u128;
+
+    // Write the state with the code from before versioning
+    deposit_on_baseline_engine(&context, ft_deposit_amount).await;
+    assert!(dex_engine_contract.view("state_version").await.is_err());
+
+    let balance_of = |asset_id: AssetId| {
+        dex_engine_contract
+            .view("asset_balance_of")
+            .args_json(json!({
+                "of": AccountOrDexId::Account(user1.id().clone()),
+                "asset_id": asset_id,
+            }))
+    };
+    let near_balance = balance_of(AssetId::Near)
+        .await
+        .unwrap()
+        .json::<U128>()
+        .unwrap();
+    assert_eq!(near_balance, U128(NearToken::from_near(1).as_yoctonear()));
+    let assert_balances_survived = || async {
+        assert_inner_asset_balance(
+            dex_engine_contract,
+            AccountOrDexId::Account(user1.id().clone()),
+            AssetId::Near,
+            Some(near_balance),
+        )
+        .await
+        .unwrap();
+        assert_inner_asset_balance(
+            dex_engine_contract,
+            AccountOrDexId::Account(user1.id().clone()),
+            AssetId::Nep141(ft1.id().clone()),
+            Some(U128(ft_deposit_amount)),
+        )
+        .await
+        .unwrap();
+        assert_total_in_custody(
+            dex_engine_contract,
+            AssetId::Nep141(ft1.id().clone()),
+            Some(U128(ft_deposit_amount)),
+        )
+        .await
+        .unwrap();
+    };
+    let state_version = || async {
+        dex_engine_contract
+            .view("state_version")
+            .await
+            .unwrap()
+            .json::<u32>()
+            .unwrap()
+    };
+
+    // The old code doesn't have upgrade_engine, so the first
+    // upgrade is deployed with the account key. The current
+    // code can't load the old state until it's migrated.
+    let result = dex_engine_contract.as_account().deploy(wasm).await.unwrap();
+    assert!(result.is_success());
+    assert!(balance_of(AssetId::Near).await.is_err());
+    let result = dex_engine_contract
+        .call("migrate")
+        .max_gas()
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    assert!(
+        result
+            .logs()
+            .into_iter()
+            .any(|log| log.contains(r#""from_version":0,"to_version":1"#))
+    );
+    assert_eq!(state_version().await, 1);
+    assert_balances_survived().await;
+    let owner = dex_engine_contract
+        .view("owner")
+        .await
+        .unwrap()
+        .json::<AccountId>()
+        .unwrap();
+    assert_eq!(&owner, dex_engine_contract.id());
+
+    let upgrade = |account: &near_workspaces::Account| {
+        account
+            .call(dex_engine_contract.id(), "upgrade_engine")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "code": Base64VecU8(wasm.clone()),
+            }))
+            .transact()
+    };
+    let result = upgrade(user1).await.unwrap();
+    assert!(!result.is_success());
+    let result = upgrade(dex_engine_contract.as_account()).await.unwrap();
+    assert_success(&result).unwrap();
+    assert!(
+        result
+            .logs()
+            .into_iter()
+            .any(|log| log.contains(r#""from_version":1,"to_version":1"#))
+    );
+    assert_eq!(state_version().await, 1);
+    assert_balances_survived().await;
+
+    // Balances written by the old code can still be withdrawn
+    let result = user1
+        .call(dex_engine_contract.id(), "withdraw")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    assert_ft_balance(user1, ft1.clone(), U128(ft_deposit_amount))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_upgrade_with_withdrawal_in_flight() {
+    let context = setup_test_environment_with_engine(get_baseline_engine_wasm().await).await;
+    let TestContext {
+        dex_engine_contract,
+        ft1,
+        user1,
+        user2,
+        ..
+    } = &context;
+    let wasm = &get_compiled_wasms().await.contract_wasm;
+    let ft1_asset = AssetId::Nep141(ft1.id().clone());
+    let ft_deposit_amount = 1_000_000u128;
+    deposit_on_baseline_engine(&context, ft_deposit_amount).await;
+
+    // The engine account withdraws its own balance, so that
+    // the withdrawal, the deployment and the migration can be
+    // in one transaction
+    let engine_account = dex_engine_contract.as_account();
+    let result = engine_account
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(engine_user_storage_deposit())
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = engine_account
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [ft1_asset.clone()],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user1
+        .call(dex_engine_contract.id(), "transfer_asset")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "to": AccountOrDexId::Account(dex_engine_contract.id().clone()),
+            "asset_id": ft1_asset.clone(),
+            "amount": U128(ft_deposit_amount),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    // user2 isn't registered on ft1, so the transfer fails, and
+    // the callback of the old code runs on the new code
+    let result = engine_account
+        .batch(dex_engine_contract.id())
+        .call(
+            Function::new("withdraw")
+                .args_json(json!({
+                    "asset_id": ft1_asset.clone(),
+                    "withdraw_to": user2.id(),
+                }))
+                .deposit(NearToken::from_yoctonear(1))
+                .gas(Gas::from_tgas(100)),
+        )
+        .deploy(wasm)
+        .call(Function::new("migrate").gas(Gas::from_tgas(100)))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    assert!(
+        result
+            .logs()
+            .into_iter()
+            .any(|log| log.contains("because withdrawal to"))
+    );
+    assert!(
+        !result
+            .logs()
+            .into_iter()
+            .any(|log| log.contains(r#""event":"withdraw""#))
+    );
+
+    assert_inner_asset_balance(
+        dex_engine_contract,
+        AccountOrDexId::Account(dex_engine_contract.id().clone()),
+        ft1_asset.clone(),
+        Some(U128(ft_deposit_amount)),
+    )
+    .await
+    .unwrap();
+    assert_total_in_custody(
+        dex_engine_contract,
+        ft1_asset,
+        Some(U128(ft_deposit_amount)),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_new_engine_state_version() {
+    let TestContext {
+        dex_engine_contract,
+        ..
+    } = setup_test_environment().await;
+
+    let state_version = dex_engine_contract
+        .view("state_version")
+        .await
+        .unwrap()
+        .json::<u32>()
+        .unwrap();
+    assert_eq!(state_version, 1);
+    let result = dex_engine_contract
+        .call("new")
+        .max_gas()
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+}
+
+#[tokio::test]
+async fn test_dex_storage_bounds_and_unregister() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        user1,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+
+    let dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: "dex".to_string(),
+    };
+    let bounds_of = |dex_id: &DexId| {
+        dex_engine_contract
+            .view("dex_storage_balance_bounds")
+            .args_json(json!({ "dex_id": dex_id }))
+    };
+    let storage_deposit = |deposit: NearToken| {
+        deployer
+            .call(dex_engine_contract.id(), "dex_storage_deposit")
+            .max_gas()
+            .deposit(deposit)
+            .args_json(json!({ "dex_id": dex_id.clone() }))
+            .transact()
+    };
+    let storage_unregister = |account: &near_workspaces::Account| {
+        account
+            .call(dex_engine_contract.id(), "dex_storage_unregister")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({ "dex_id": dex_id.clone() }))
+            .transact()
+    };
+
+    // Without code, the default bounds apply
+    let bounds = bounds_of(&dex_id)
+        .await
+        .unwrap()
+        .json::<StorageBalanceBounds>()
+        .unwrap();
+    assert_eq!(bounds.min, NearToken::from_millinear(10));
+    assert!(bounds.max.is_none());
+
+    let result = storage_deposit(NearToken::from_near(1)).await.unwrap();
+    assert_success(&result).unwrap();
+    assert!(
+        result
+            .logs()
+            .into_iter()
+            .any(|log| log.contains(r#""event":"dex_storage_deposit""#))
+    );
+
+    let result = storage_unregister(&user1).await.unwrap();
+    assert!(!result.is_success());
+
+    let result = storage_unregister(&deployer).await.unwrap();
+    assert_success(&result).unwrap();
+    assert!(result.logs().into_iter().any(|log| {
+        log.contains(r#""event":"dex_storage_unregister""#)
+            && log.contains(&format!(
+                r#""refund":"{}""#,
+                NearToken::from_near(1).as_yoctonear()
+            ))
+    }));
+    assert!(result.json::<bool>().unwrap());
+    let balance = dex_engine_contract
+        .view("dex_storage_balance_of")
+        .args_json(json!({ "dex_id": dex_id.clone() }))
+        .await
+        .unwrap()
+        .json::<Option<StorageBalance>>()
+        .unwrap();
+    assert!(balance.is_none());
+
+    let result = storage_unregister(&deployer).await.unwrap();
+    assert_success(&result).unwrap();
+    assert!(!result.json::<bool>().unwrap());
+
+    // The storage test dex sets its own bounds of 0.1 to 10 NEAR
+    let result = storage_deposit(NearToken::from_near(1)).await.unwrap();
+    assert_success(&result).unwrap();
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "last_part_of_id": dex_id.id,
+            "code_base64": BASE64_STANDARD.encode(&wasms.storage_test_dex_wasm),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let bounds = bounds_of(&dex_id)
+        .await
+        .unwrap()
+        .json::<StorageBalanceBounds>()
+        .unwrap();
+    assert_eq!(bounds.min, NearToken::from_millinear(100));
+    assert_eq!(bounds.max, Some(NearToken::from_near(10)));
+
+    let result = storage_deposit(NearToken::from_millinear(50))
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+
+    // The part above the maximum is refunded
+    let deployer_balance_before = deployer.view_account().await.unwrap().balance;
+    let result = storage_deposit(NearToken::from_near(15)).await.unwrap();
+    assert_success(&result).unwrap();
+    let balance = result.json::<StorageBalance>().unwrap();
+    assert_eq!(balance.total, NearToken::from_near(10));
+    let deployer_balance_after = deployer.view_account().await.unwrap().balance;
+    assert!(
+        deployer_balance_before.saturating_sub(deployer_balance_after) < NearToken::from_near(10)
+    );
+
+    let result = storage_unregister(&deployer).await.unwrap();
+    assert!(!result.is_success());
+}
+
+#[tokio::test]
+async fn test_unregister_assets() {
+    let TestContext {
+        dex_engine_contract,
+        user1,
+        user2,
+        deployer,
+        ft1,
+        ..
+    } = setup_test_environment().await;
+    let ft1_asset = AssetId::Nep141(ft1.id().clone());
+
+    for user in [&user1, &user2] {
+        let result = user
+            .call(dex_engine_contract.id(), "storage_deposit")
+            .max_gas()
+            .deposit(engine_user_storage_deposit())
+            .args_json(json!({}))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+        let result = user
+            .call(dex_engine_contract.id(), "register_assets")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "asset_ids": [AssetId::Near],
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+    }
+    let storage_balance_of = || {
+        dex_engine_contract
+            .view("storage_balance_of")
+            .args_json(json!({ "account_id": user1.id() }))
+    };
+    let available_before = storage_balance_of()
+        .await
+        .unwrap()
+        .json::<StorageBalance>()
+        .unwrap()
+        .available;
+
+    let result = user1
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [ft1_asset.clone()],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let available_registered = storage_balance_of()
+        .await
+        .unwrap()
+        .json::<StorageBalance>()
+        .unwrap()
+        .available;
+    assert!(available_registered < available_before);
+
+    // Zero balances are unregistered and the storage is refunded
+    let result = user1
+        .call(dex_engine_contract.id(), "unregister_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [ft1_asset.clone()],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let available_unregistered = storage_balance_of()
+        .await
+        .unwrap()
+        .json::<StorageBalance>()
+        .unwrap()
+        .available;
+    // The custody entry of the asset stays, so not everything is
+    // refunded
+    assert!(available_unregistered > available_registered);
+    assert!(available_unregistered <= available_before);
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        ft1_asset.clone(),
+        None,
+    )
+    .await
+    .unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(NearToken::from_millinear(1))
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    // Non-zero balances need somewhere to go
+    let result = user1
+        .call(dex_engine_contract.id(), "unregister_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Near],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+
+    // Only the account itself can unregister its assets
+    let result = user2
+        .call(dex_engine_contract.id(), "unregister_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Near],
+            "for": AccountOrDexId::Account(user1.id().clone()),
+            "sweep_dust_to": AccountOrDexId::Account(user2.id().clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+
+    let result = user1
+        .call(dex_engine_contract.id(), "unregister_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Near],
+            "sweep_dust_to": AccountOrDexId::Account(user2.id().clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Near,
+        None,
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(user2.id().clone()),
+        AssetId::Near,
+        Some(U128(NearToken::from_millinear(1).as_yoctonear())),
+    )
+    .await
+    .unwrap();
+
+    // Dex assets can only be unregistered by the deployer
+    let dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: "dex".to_string(),
+    };
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_user_storage_deposit())
+        .args_json(json!({ "dex_id": dex_id.clone() }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = deployer
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(engine_user_storage_deposit())
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = deployer
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Near],
+            "for": AccountOrDexId::Dex(dex_id.clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let unregister_dex_assets = |account: &near_workspaces::Account| {
+        account
+            .call(dex_engine_contract.id(), "unregister_assets")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "asset_ids": [AssetId::Near],
+                "for": AccountOrDexId::Dex(dex_id.clone()),
+            }))
+            .transact()
+    };
+    let result = unregister_dex_assets(&user1).await.unwrap();
+    assert!(!result.is_success());
+    let result = unregister_dex_assets(&deployer).await.unwrap();
+    assert_success(&result).unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Dex(dex_id),
+        AssetId::Near,
+        None,
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_force_storage_unregister() {
+    let context = setup_test_environment().await;
+    setup_simple_amm_pools(&context).await;
+    let TestContext {
+        dex_engine_contract,
+        ft1,
+        user1,
+        user2,
+        ..
+    } = &context;
+    let ft1_asset = AssetId::Nep141(ft1.id().clone());
+    let transferred = U128(1000);
+
+    // user2 isn't registered on ft1, so its withdrawal will fail
+    let result = user2
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(engine_user_storage_deposit())
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user2
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [ft1_asset.clone()],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user1
+        .call(dex_engine_contract.id(), "transfer_asset")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "to": AccountOrDexId::Account(user2.id().clone()),
+            "asset_id": ft1_asset.clone(),
+            "amount": transferred,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let signing_key = near_crypto::SecretKey::from_random(near_crypto::KeyType::ED25519);
+    let result = user1
+        .call(dex_engine_contract.id(), "add_signing_key")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "public_key": signing_key.public_key().to_string(),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let ft1_inner_balance = dex_engine_contract
+        .view("asset_balance_of")
+        .args_json(json!({
+            "of": AccountOrDexId::Account(user1.id().clone()),
+            "asset_id": ft1_asset.clone(),
+        }))
+        .await
+        .unwrap()
+        .json::<Option<U128>>()
+        .unwrap()
+        .unwrap();
+    let ft1_balance = ft1
+        .view("ft_balance_of")
+        .args_json(json!({ "account_id": user1.id() }))
+        .await
+        .unwrap()
+        .json::<U128>()
+        .unwrap();
+    let registered_assets = dex_engine_contract
+        .view("registered_assets_of")
+        .args_json(json!({ "account_id": user1.id() }))
+        .await
+        .unwrap()
+        .json::<Vec<AssetId>>()
+        .unwrap();
+    assert_eq!(registered_assets.len(), 3);
+
+    let force_unregister = |account: &near_workspaces::Account| {
+        account
+            .call(dex_engine_contract.id(), "storage_unregister")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({ "force": true }))
+            .transact()
+    };
+    let result = force_unregister(user1).await.unwrap();
+    assert_success(&result).unwrap();
+    assert!(
+        result
+            .logs()
+            .into_iter()
+            .any(|log| log.contains(r#""event":"account_closed""#))
+    );
+    assert!(result.json::<bool>().unwrap());
+
+    assert_ft_balance(
+        user1,
+        ft1.clone(),
+        U128(ft1_balance.0 + ft1_inner_balance.0),
+    )
+    .await
+    .unwrap();
+    for asset_id in registered_assets {
+        assert_inner_asset_balance(
+            dex_engine_contract,
+            AccountOrDexId::Account(user1.id().clone()),
+            asset_id,
+            None,
+        )
+        .await
+        .unwrap();
+    }
+    let storage_balance = dex_engine_contract
+        .view("storage_balance_of")
+        .args_json(json!({ "account_id": user1.id() }))
+        .await
+        .unwrap()
+        .json::<Option<StorageBalance>>()
+        .unwrap();
+    assert!(storage_balance.is_none());
+    let signing_keys = dex_engine_contract
+        .view("signing_keys_of")
+        .args_json(json!({ "account_id": user1.id() }))
+        .await
+        .unwrap()
+        .json::<Vec<near_sdk::serde_json::Value>>()
+        .unwrap();
+    assert!(signing_keys.is_empty());
+
+    let result = force_unregister(user1).await.unwrap();
+    assert_success(&result).unwrap();
+    assert!(!result.json::<bool>().unwrap());
+
+    // The failed withdrawal goes to the holding area
+    let result = force_unregister(user2).await.unwrap();
+    assert_success(&result).unwrap();
+    assert!(
+        result
+            .logs()
+            .into_iter()
+            .any(|log| log.contains(r#""event":"assets_held""#))
+    );
+    let held = dex_engine_contract
+        .view("held_assets_of")
+        .args_json(json!({
+            "of": AccountOrDexId::Account(user2.id().clone()),
+            "asset_id": ft1_asset.clone(),
+        }))
+        .await
+        .unwrap()
+        .json::<Option<U128>>()
+        .unwrap();
+    assert_eq!(held, Some(transferred));
+
+    ft_storage_deposit(ft1, user2).await;
+    let result = user2
+        .call(dex_engine_contract.id(), "withdraw_held_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({ "asset_id": ft1_asset.clone() }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    assert!(result.logs().into_iter().any(|log| {
+        log.contains(r#""event":"held_assets_withdrawn""#)
+            && log.contains(&format!(r#""amount":"{}""#, transferred.0))
+    }));
+    assert!(result.json::<bool>().unwrap());
+    assert_ft_balance(user2, ft1.clone(), transferred)
+        .await
+        .unwrap();
+    let held = dex_engine_contract
+        .view("held_assets_of")
+        .args_json(json!({
+            "of": AccountOrDexId::Account(user2.id().clone()),
+            "asset_id": ft1_asset,
+        }))
+        .await
+        .unwrap()
+        .json::<Option<U128>>()
+        .unwrap();
+    assert!(held.is_none());
+}
+
+#[tokio::test]
+async fn test_close_migrated_account() {
+    let context = setup_test_environment_with_engine(get_baseline_engine_wasm().await).await;
+    let TestContext {
+        dex_engine_contract,
+        ft1,
+        user1,
+        ..
+    } = &context;
+    let ft1_asset = AssetId::Nep141(ft1.id().clone());
+    let ft_deposit_amount = 1_000_000u128;
+    deposit_on_baseline_engine(&context, ft_deposit_amount).await;
+    migrate_baseline_engine(dex_engine_contract).await;
+
+    // The registrations were made before the asset index
+    let registered_assets = dex_engine_contract
+        .view("registered_assets_of")
+        .args_json(json!({ "account_id": user1.id() }))
+        .await
+        .unwrap()
+        .json::<Vec<AssetId>>()
+        .unwrap();
+    assert!(registered_assets.is_empty());
+    let result = user1
+        .call(dex_engine_contract.id(), "storage_unregister")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({ "force": true }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+
+    let close_account = |asset_ids: Vec<AssetId>| {
+        user1
+            .call(dex_engine_contract.id(), "close_account")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({ "asset_ids": asset_ids }))
+            .transact()
+    };
+    let result = close_account(vec![ft1_asset.clone()]).await.unwrap();
+    assert!(!result.is_success());
+    let near_balance = user1.view_account().await.unwrap().balance;
+    let result = close_account(vec![AssetId::Near, ft1_asset.clone()])
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    assert!(
+        result
+            .logs()
+            .into_iter()
+            .any(|log| log.contains(r#""event":"account_closed""#))
+    );
+    assert!(result.json::<bool>().unwrap());
+
+    assert_ft_balance(user1, ft1.clone(), U128(ft_deposit_amount))
+        .await
+        .unwrap();
+    let near_received = user1
+        .view_account()
+        .await
+        .unwrap()
+        .balance
+        .saturating_sub(near_balance);
+    assert!(near_received > NearToken::from_near(1));
+    for asset_id in [AssetId::Near, ft1_asset.clone()] {
+        assert_inner_asset_balance(
+            dex_engine_contract,
+            AccountOrDexId::Account(user1.id().clone()),
+            asset_id,
+            None,
+        )
+        .await
+        .unwrap();
+    }
+    let storage_balance = dex_engine_contract
+        .view("storage_balance_of")
+        .args_json(json!({ "account_id": user1.id() }))
+        .await
+        .unwrap()
+        .json::<Option<StorageBalance>>()
+        .unwrap();
+    assert!(storage_balance.is_none());
+    assert_total_in_custody(dex_engine_contract, ft1_asset, Some(U128(0)))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_storage_top_up() {
+    let TestContext {
+        dex_engine_contract,
+        user1,
+        ..
+    } = setup_test_environment().await;
+    let near_deposit = NearToken::from_near(1);
+
+    let result = user1
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(NearToken::from_millinear(10))
+        .args_json(json!({ "registration_only": true }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user1
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({ "asset_ids": [AssetId::Near] }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user1
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(near_deposit)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let register_many = |prefix: &'static str| {
+        let asset_ids = (0..30)
+            .map(|i| AssetId::Nep141(format!("{prefix}-token-{i}.near").parse().unwrap()))
+            .collect::<Vec<_>>();
+        user1
+            .call(dex_engine_contract.id(), "register_assets")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({ "asset_ids": asset_ids }))
+            .transact()
+    };
+    let set_top_up = |cap: Option<NearToken>| {
+        user1
+            .call(dex_engine_contract.id(), "set_storage_top_up")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({ "cap": cap }))
+            .transact()
+    };
+
+    // The storage balance isn't enough without top-ups
+    let result = register_many("first").await.unwrap();
+    assert!(!result.is_success());
+
+    let result = set_top_up(Some(NearToken::from_millinear(500)))
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = register_many("first").await.unwrap();
+    assert_success(&result).unwrap();
+    assert!(
+        result
+            .logs()
+            .into_iter()
+            .any(|log| log.contains(r#""event":"storage_topped_up""#))
+    );
+
+    let top_up = dex_engine_contract
+        .view("storage_top_up_of")
+        .args_json(json!({ "of": AccountOrDexId::Account(user1.id().clone()) }))
+        .await
+        .unwrap()
+        .json::<Option<StorageTopUp>>()
+        .unwrap()
+        .unwrap();
+    assert!(!top_up.used.is_zero());
+    let near_balance = dex_engine_contract
+        .view("asset_balance_of")
+        .args_json(json!({
+            "of": AccountOrDexId::Account(user1.id().clone()),
+            "asset_id": AssetId::Near,
+        }))
+        .await
+        .unwrap()
+        .json::<U128>()
+        .unwrap();
+    assert_eq!(
+        near_balance.0,
+        near_deposit.as_yoctonear() - top_up.used.as_yoctonear()
+    );
+    assert_total_in_custody(&dex_engine_contract, AssetId::Near, Some(near_balance))
+        .await
+        .unwrap();
+
+    // Top-ups stop at the cap
+    let result = set_top_up(Some(NearToken::from_yoctonear(1)))
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = register_many("second").await.unwrap();
+    assert!(!result.is_success());
+
+    let result = set_top_up(None).await.unwrap();
+    assert_success(&result).unwrap();
+    let top_up = dex_engine_contract
+        .view("storage_top_up_of")
+        .args_json(json!({ "of": AccountOrDexId::Account(user1.id().clone()) }))
+        .await
+        .unwrap()
+        .json::<Option<StorageTopUp>>()
+        .unwrap();
+    assert!(top_up.is_none());
+}
+
+#[tokio::test]
+async fn test_trader_paid_dex_storage() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        user1,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+    let dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: "dex".to_string(),
+    };
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_user_storage_deposit())
+        .args_json(json!({ "dex_id": dex_id }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = deployer
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(NearToken::from_near(5))
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "last_part_of_id": dex_id.id,
+            "code_base64": BASE64_STANDARD.encode(&wasms.storage_test_dex_wasm),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = deployer
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Near],
+            "for": AccountOrDexId::Dex(dex_id.clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(NearToken::from_near(1))
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user1
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({ "asset_ids": [AssetId::Near] }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user1
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(NearToken::from_near(1))
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let user_storage = || async {
+        dex_engine_contract
+            .view("storage_balance_of")
+            .args_json(json!({ "account_id": user1.id() }))
+            .await
+            .unwrap()
+            .json::<Option<StorageBalance>>()
+            .unwrap()
+            .unwrap()
+    };
+    let dex_storage = || async {
+        dex_engine_contract
+            .view("dex_storage_balance_of")
+            .args_json(json!({ "dex_id": dex_id }))
+            .await
+            .unwrap()
+            .json::<Option<StorageBalance>>()
+            .unwrap()
+            .unwrap()
+    };
+    let swap = |message: &[u8], max_storage_payment: Option<NearToken>| {
+        user1
+            .call(dex_engine_contract.id(), "swap_simple")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "dex_id": dex_id,
+                "message": BASE64_STANDARD.encode(message),
+                "asset_in": AssetId::Near,
+                "asset_out": AssetId::Near,
+                "amount": SwapRequestAmount::ExactIn(U128(10)),
+                "max_storage_payment": max_storage_payment,
+            }))
+            .transact()
+    };
+    let trader_storage_paid = |result: &near_workspaces::result::ExecutionFinalResult| {
+        result.logs().into_iter().find_map(|log| {
+            let event = log.strip_prefix("EVENT_JSON:")?;
+            let event: near_sdk::serde_json::Value = near_sdk::serde_json::from_str(event).ok()?;
+            (event["event"] == "trader_storage_paid").then(|| event["data"].clone())
+        })
+    };
+
+    // The dex doesn't write anything for empty messages
+    let user_storage_before = user_storage().await;
+    let dex_storage_before = dex_storage().await;
+    let result = swap(&[], None).await.unwrap();
+    assert_success(&result).unwrap();
+    assert!(
+        !result
+            .logs()
+            .into_iter()
+            .any(|log| log.contains(r#""event":"trader_storage_paid""#))
+    );
+    assert_eq!(user_storage().await.total, user_storage_before.total);
+    assert_eq!(dex_storage().await.total, dex_storage_before.total);
+
+    // The trader doesn't pay for storage unless they agree to
+    let result = swap(b"a message that the dex stores", None).await.unwrap();
+    assert!(!result.is_success());
+    let result = swap(
+        b"a message that the dex stores",
+        Some(NearToken::from_yoctonear(1)),
+    )
+    .await
+    .unwrap();
+    assert!(!result.is_success());
+    assert_eq!(user_storage().await.total, user_storage_before.total);
+
+    // A stored message is paid by the trader, so the available
+    // storage balance of the dex doesn't change
+    let result = swap(
+        b"a message that the dex stores",
+        Some(NearToken::from_millinear(10)),
+    )
+    .await
+    .unwrap();
+    assert_success(&result).unwrap();
+    let paid = trader_storage_paid(&result).expect("No trader_storage_paid event");
+    assert_eq!(paid["trader"], json!(user1.id()));
+    assert_eq!(paid["dex_id"], json!(dex_id));
+    let amount = paid["amount"].as_str().unwrap().parse::<u128>().unwrap();
+    assert!(amount > 0);
+
+    let user_storage_after = user_storage().await;
+    let dex_storage_after = dex_storage().await;
+    assert_eq!(
+        user_storage_after.total.as_yoctonear(),
+        user_storage_before.total.as_yoctonear() - amount
+    );
+    assert_eq!(
+        dex_storage_after.total.as_yoctonear(),
+        dex_storage_before.total.as_yoctonear() + amount
+    );
+    assert_eq!(dex_storage_after.available, dex_storage_before.available);
+
+    // In a route, the storage is paid by the trader, not by the
+    // assets in flight
+    let message = b"a longer message that the dex stores in a route";
+    let result = user1
+        .call(dex_engine_contract.id(), "swap_route")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_in": AssetId::Near,
+            "hops": [
+                {
+                    "dex_id": dex_id,
+                    "message": Base64VecU8(message.to_vec()),
+                    "asset_out": AssetId::Near,
+                },
+            ],
+            "amount": SwapRequestAmount::ExactIn(U128(10)),
+            "limit": U128(10),
+            "max_storage_payment": NearToken::from_millinear(10),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let paid = trader_storage_paid(&result).expect("No trader_storage_paid event");
+    assert_eq!(paid["trader"], json!(user1.id()));
+    let amount = paid["amount"].as_str().unwrap().parse::<u128>().unwrap();
+    assert!(amount > 0);
+    assert_eq!(
+        user_storage().await.total.as_yoctonear(),
+        user_storage_after.total.as_yoctonear() - amount
+    );
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Near,
+        Some(U128(NearToken::from_near(1).as_yoctonear())),
+    )
+    .await
+    .unwrap();
+
+    // Dex calls are capped the same way
+    let dex_call = |max_storage_payment: Option<NearToken>| {
+        user1
+            .call(dex_engine_contract.id(), "dex_call")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "dex_id": dex_id,
+                "method": "store_message",
+                "args": Base64VecU8(b"an even longer message that the dex stores in a call".to_vec()),
+                "attached_assets": {},
+                "max_storage_payment": max_storage_payment,
+            }))
+            .transact()
+    };
+    let result = dex_call(None).await.unwrap();
+    assert!(!result.is_success());
+    let result = dex_call(Some(NearToken::from_millinear(10))).await.unwrap();
+    assert_success(&result).unwrap();
+    assert!(trader_storage_paid(&result).is_some());
+}
+
+#[tokio::test]
+async fn test_storage_breakdown() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        user1,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+    let dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: "dex".to_string(),
+    };
+    let contract = &dex_engine_contract;
+    let storage_breakdown = |of: AccountOrDexId| async move {
+        contract
+            .view("storage_breakdown")
+            .args_json(json!({ "of": of }))
+            .await
+            .unwrap()
+            .json::<Option<StorageBreakdownView>>()
+            .unwrap()
+    };
+    let user1_id = AccountOrDexId::Account(user1.id().clone());
+
+    // The custody entry of NEAR is created by someone else, so
+    // that user1 can fully unregister later
+    let result = deployer
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(NearToken::from_near(1))
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = deployer
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({ "asset_ids": [AssetId::Near] }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    assert!(storage_breakdown(user1_id.clone()).await.is_none());
+    let result = user1
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(NearToken::from_near(1))
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let breakdown = storage_breakdown(user1_id.clone()).await.unwrap();
+    assert_eq!(breakdown.breakdown.asset_registrations, 0);
+    assert_eq!(breakdown.breakdown.account_data, 0);
+    assert!(breakdown.other > 0);
+    assert_eq!(breakdown.other, breakdown.total);
+
+    let result = user1
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({ "asset_ids": [AssetId::Near] }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user1
+        .call(dex_engine_contract.id(), "approve_asset")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "spender": deployer.id(),
+            "asset_id": AssetId::Near,
+            "amount": U128(1000),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let breakdown = storage_breakdown(user1_id.clone()).await.unwrap();
+    assert!(breakdown.breakdown.asset_registrations > 0);
+    assert!(breakdown.breakdown.account_data > 0);
+    assert_eq!(breakdown.breakdown.code, 0);
+    assert_eq!(breakdown.breakdown.dex_state, 0);
+    assert_eq!(
+        breakdown.total,
+        breakdown.breakdown.asset_registrations
+            + breakdown.breakdown.account_data
+            + breakdown.other
+    );
+
+    // Revoking the allowance releases the account data bytes
+    let result = user1
+        .call(dex_engine_contract.id(), "revoke_asset")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "spender": deployer.id(),
+            "asset_id": AssetId::Near,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let breakdown = storage_breakdown(user1_id.clone()).await.unwrap();
+    assert_eq!(breakdown.breakdown.account_data, 0);
+
+    // Dex code crosses a threshold on deployment
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_user_storage_deposit())
+        .args_json(json!({ "dex_id": dex_id }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "last_part_of_id": dex_id.id,
+            "code_base64": BASE64_STANDARD.encode(&wasms.minimal_dex_wasm),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let breakdown = storage_breakdown(AccountOrDexId::Dex(dex_id.clone()))
+        .await
+        .unwrap();
+    assert!(breakdown.breakdown.code >= wasms.minimal_dex_wasm.len() as u64);
+    let threshold = breakdown.breakdown.code / STORAGE_THRESHOLD_STEP * STORAGE_THRESHOLD_STEP;
+    assert!(result.logs().into_iter().any(|log| {
+        log.contains(r#""event":"storage_threshold_crossed""#)
+            && log.contains(r#""category":"Code""#)
+            && log.contains(&format!(r#""threshold":{threshold}"#))
+    }));
+
+    // The breakdown record is refunded on unregistration
+    let result = user1
+        .call(dex_engine_contract.id(), "unregister_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({ "asset_ids": [AssetId::Near] }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user1
+        .call(dex_engine_contract.id(), "storage_unregister")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    assert!(result.json::<bool>().unwrap());
+    assert!(storage_breakdown(user1_id).await.is_none());
+}
+
+#[tokio::test]
+async fn test_execute_operations_event_sequence() {
+    let context = setup_test_environment().await;
+    let dex_id = setup_simple_amm_pools(&context).await;
+    let TestContext {
+        dex_engine_contract,
+        user1,
+        user2,
+        ft2,
+        ..
+    } = &context;
+    let pool_creation_fee = NearToken::from_millinear(10);
+    let transfer_amount = 1000u128;
+
+    #[near(serializers=[borsh])]
+    struct CreatePoolArgs {
+        assets: (AssetId, AssetId),
+    }
+    let operations = vec![
+        Operation::StorageDeposit {
+            amount: U128(NearToken::from_millinear(50).as_yoctonear()),
+            r#for: Some(AccountOrDexId::Account(user2.id().clone())),
+        },
+        Operation::RegisterAssets {
+            asset_ids: vec![AssetId::Near],
+            r#for: Some(AccountOrDexId::Account(user2.id().clone())),
+        },
+        Operation::TransferAsset {
+            to: AccountOrDexId::Account(user2.id().clone()),
+            asset_id: AssetId::Near,
+            amount: U128(transfer_amount),
+        },
+        Operation::DexCall {
+            dex_id: dex_id.clone(),
+            method: "create_pool".to_string(),
+            args: Base64VecU8(
+                near_sdk::borsh::to_vec(&CreatePoolArgs {
+                    assets: (AssetId::Near, AssetId::Nep141(ft2.id().clone())),
+                })
+                .unwrap(),
+            ),
+            attached_assets: HashMap::from_iter([(
+                AssetId::Near,
+                U128(pool_creation_fee.as_yoctonear()),
+            )]),
+            max_storage_payment: NearToken::from_yoctonear(0),
+        },
+    ];
+    let result = user1
+        .call(dex_engine_contract.id(), "execute_operations")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({ "operations": operations }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let events = result
+        .logs()
+        .into_iter()
+        .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+        .map(|event| near_sdk::serde_json::from_str::<near_sdk::serde_json::Value>(event).unwrap())
+        .collect::<Vec<_>>();
+    for event in events.iter() {
+        assert_eq!(event["standard"], "inteardex");
+        assert_eq!(event["version"], "1.0.0");
+    }
+    let names = events
+        .iter()
+        .map(|event| event["event"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        [
+            // StorageDeposit
+            "user_balance_update",
+            "storage_deposit",
+            // RegisterAssets
+            "assets_registered",
+            // TransferAsset
+            "user_balance_update",
+            "user_balance_update",
+            "asset_transfer",
+            // DexCall: attached NEAR goes to the dex, the part not
+            // needed for storage is returned, and the rest becomes
+            // the storage deposit of the dex
+            "dex_call",
+            "user_balance_update",
+            "dex_balance_update",
+            "dex_balance_update",
+            "user_balance_update",
+            "dex_balance_update",
+            "dex_storage_deposit_added",
+        ]
+    );
+
+    let data = |index: usize| &events[index]["data"];
+    assert_eq!(data(1)["account_id"], json!(user2.id()));
+    assert_eq!(
+        data(1)["amount"],
+        json!(NearToken::from_millinear(50).as_yoctonear().to_string())
+    );
+    assert_eq!(
+        data(2)["for"],
+        json!(AccountOrDexId::Account(user2.id().clone()))
+    );
+    assert_eq!(data(2)["asset_ids"], json!([AssetId::Near]));
+    assert_eq!(data(2)["storage_payer"], json!(user1.id()));
+    assert_eq!(
+        data(5),
+        &json!({
+            "from": AccountOrDexId::Account(user1.id().clone()),
+            "to": AccountOrDexId::Account(user2.id().clone()),
+            "asset_id": AssetId::Near,
+            "amount": U128(transfer_amount),
+            "sandboxed": false,
+        })
+    );
+    assert_eq!(data(6)["dex_id"], json!(dex_id));
+    assert_eq!(data(6)["method"], "create_pool");
+    assert_eq!(data(6)["caller"], json!(user1.id()));
+    assert_eq!(data(6)["sandboxed"], false);
+    assert_eq!(
+        data(6)["asset_withdraw_requests"].as_array().unwrap().len(),
+        1
+    );
+    assert_eq!(
+        data(6)["attached_assets"],
+        json!({ "near": U128(pool_creation_fee.as_yoctonear()) })
+    );
+    let returned = data(6)["asset_withdraw_requests"][0]["amount"]
+        .as_str()
+        .unwrap()
+        .parse::<u128>()
+        .unwrap();
+    let added = data(12)["amount"]
+        .as_str()
+        .unwrap()
+        .parse::<u128>()
+        .unwrap();
+    assert_eq!(returned + added, pool_creation_fee.as_yoctonear());
+}
+
+#[tokio::test]
+async fn test_dex_methods() {
+    let context = setup_test_environment().await;
+    let dex_id = setup_simple_amm_pools(&context).await;
+
+    let mut methods = context
+        .dex_engine_contract
+        .view("dex_methods")
+        .args_json(json!({ "dex_id": dex_id }))
+        .await
+        .unwrap()
+        .json::<Vec<DexMethod>>()
+        .unwrap();
+    let method = |name: &str, kind: DexMethodKind, authorized: bool| DexMethod {
+        name: name.to_string(),
+        kind,
+        authorized,
+    };
+    let mut expected = vec![
+        method("new", DexMethodKind::Init, true),
+        method("create_pool", DexMethodKind::Call, true),
+        method("add_liquidity", DexMethodKind::Call, true),
+        method("remove_liquidity", DexMethodKind::Call, true),
+        method("get_pool", DexMethodKind::View, false),
+    ];
+    methods.sort_by(|a, b| a.name.cmp(&b.name));
+    expected.sort_by(|a, b| a.name.cmp(&b.name));
+    assert_eq!(methods, expected);
+}
+
+#[tokio::test]
+async fn test_dex_info() {
+    let context = setup_test_environment().await;
+    let dex_id = setup_simple_amm_pools(&context).await;
+    let TestContext {
+        dex_engine_contract,
+        ft1,
+        ft2,
+        ..
+    } = &context;
+
+    let metadata = dex_engine_contract
+        .view("dex_metadata")
+        .args_json(json!({ "dex_id": dex_id }))
+        .await
+        .unwrap()
+        .json::<DexMetadata>()
+        .unwrap();
+    assert_eq!(metadata.name, "Simple AMM");
+    assert_eq!(metadata.fee_model, DexFeeModel::NoFee);
+
+    let pairs = dex_engine_contract
+        .view("dex_pairs")
+        .args_json(json!({ "dex_id": dex_id }))
+        .await
+        .unwrap()
+        .json::<Vec<DexPair>>()
+        .unwrap();
+    assert_eq!(pairs.len(), 2);
+    assert_eq!(
+        pairs[0].assets,
+        (AssetId::Near, AssetId::Nep141(ft1.id().clone()))
+    );
+    assert_eq!(pairs[0].message, simple_amm_swap_message(0));
+    assert_eq!(
+        pairs[1].assets,
+        (
+            AssetId::Nep141(ft1.id().clone()),
+            AssetId::Nep141(ft2.id().clone())
+        )
+    );
+    assert_eq!(pairs[1].message, simple_amm_swap_message(1));
+
+    let pairs = dex_engine_contract
+        .view("dex_pairs")
+        .args_json(json!({ "dex_id": dex_id, "from_index": 1, "limit": 5 }))
+        .await
+        .unwrap()
+        .json::<Vec<DexPair>>()
+        .unwrap();
+    assert_eq!(pairs.len(), 1);
+    assert_eq!(pairs[0].message, simple_amm_swap_message(1));
+
+    let request = json!({
+        "message": simple_amm_swap_message(1),
+        "asset_in": AssetId::Nep141(ft1.id().clone()),
+        "asset_out": AssetId::Nep141(ft2.id().clone()),
+        "amount": SwapRequestAmount::ExactIn(U128(1000)),
+    });
+    let quote = || async {
+        dex_engine_contract
+            .view("dex_quote")
+            .args_json(json!({ "dex_id": dex_id, "request": request }))
+            .await
+            .unwrap()
+            .json::<SwapResponse>()
+            .unwrap()
+    };
+    let first = quote().await;
+    assert_eq!(first.amount_in, U128(1000));
+    assert_eq!(
+        first.amount_out,
+        U128(1000 * SIMPLE_AMM_POOL1_FT2 / (SIMPLE_AMM_POOL1_FT1 + 1000))
+    );
+    // Quotes don't change the pool
+    let second = quote().await;
+    assert_eq!(second.amount_out, first.amount_out);
+
+    let result = dex_engine_contract
+        .view("dex_quote")
+        .args_json(json!({
+            "dex_id": dex_id,
+            "request": {
+                "message": simple_amm_swap_message(5),
+                "asset_in": AssetId::Near,
+                "asset_out": AssetId::Nep141(ft1.id().clone()),
+                "amount": SwapRequestAmount::ExactIn(U128(1000)),
+            },
+        }))
+        .await;
+    assert!(format!("{:?}", result.unwrap_err()).contains("Pool not found"));
+}
+
+#[tokio::test]
+async fn test_dex_abi() {
+    let context = setup_test_environment().await;
+    let dex_id = setup_simple_amm_pools(&context).await;
+
+    let mut abi = context
+        .dex_engine_contract
+        .view("dex_abi")
+        .args_json(json!({ "dex_id": dex_id }))
+        .await
+        .unwrap()
+        .json::<Vec<DexAbiMethod>>()
+        .unwrap();
+    let method =
+        |name: &str, args: Vec<(&str, DexAbiType)>, result: Option<DexAbiType>| DexAbiMethod {
+            name: name.to_string(),
+            args: args
+                .into_iter()
+                .map(|(name, ty)| (name.to_string(), ty))
+                .collect(),
+            result,
+        };
+    let asset_with_balance = DexAbiType::Struct {
+        name: "AssetWithBalance".to_string(),
+        fields: DexAbiFields::Named(vec![
+            ("asset_id".to_string(), DexAbiType::AssetId),
+            ("balance".to_string(), DexAbiType::U128),
+        ]),
+    };
+    let simple_pool = DexAbiType::Struct {
+        name: "SimplePool".to_string(),
+        fields: DexAbiFields::Named(vec![
+            (
+                "assets".to_string(),
+                DexAbiType::Tuple(vec![asset_with_balance.clone(), asset_with_balance]),
+            ),
+            ("owner_id".to_string(), DexAbiType::AccountId),
+        ]),
+    };
+    let mut expected = vec![
+        method("new", vec![], None),
+        method(
+            "create_pool",
+            vec![(
+                "assets",
+                DexAbiType::Tuple(vec![DexAbiType::AssetId, DexAbiType::AssetId]),
+            )],
+            None,
+        ),
+        method("add_liquidity", vec![("pool_id", DexAbiType::U64)], None),
+        method(
+            "remove_liquidity",
+            vec![
+                ("pool_id", DexAbiType::U64),
+                (
+                    "assets_to_remove",
+                    DexAbiType::Tuple(vec![DexAbiType::U128, DexAbiType::U128]),
+                ),
+            ],
+            None,
+        ),
+        method(
+            "get_pool",
+            vec![("pool_id", DexAbiType::U64)],
+            Some(DexAbiType::Option(Box::new(simple_pool))),
+        ),
+    ];
+    abi.sort_by(|a, b| a.name.cmp(&b.name));
+    expected.sort_by(|a, b| a.name.cmp(&b.name));
+    assert_eq!(abi, expected);
+}
+
+/// Replays the logs of a real run through the indexer. With
+/// `UPDATE_INDEXER_FIXTURES=1`, the logs are also written to
+/// the fixture of the indexer's replay tests.
+#[tokio::test]
+async fn test_indexer_replays_engine_logs() {
+    let context = setup_test_environment().await;
+    let (dex_id, mut logs) = setup_simple_amm_pools_with_logs(&context).await;
+    let TestContext {
+        dex_engine_contract,
+        ft1,
+        ft2,
+        user1,
+        ..
+    } = &context;
+
+    let operations = vec![Operation::Withdraw {
+        asset_id: AssetId::Nep141(ft2.id().clone()),
+        amount: Some(U128(250_000)),
+        to: None,
+        rescue_address: None,
+        msg: None,
+        register_receiver: false,
+    }];
+    let result = user1
+        .call(dex_engine_contract.id(), "execute_operations")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "operations": operations,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    logs.extend(result.logs().into_iter().map(String::from));
+
+    for ft in [ft1, ft2] {
+        let result = user1
+            .call(dex_engine_contract.id(), "reconcile_custody")
+            .max_gas()
+            .args_json(json!({
+                "asset_id": AssetId::Nep141(ft.id().clone()),
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+        logs.extend(result.logs().into_iter().map(String::from));
+    }
+
+    if std::env::var("UPDATE_INDEXER_FIXTURES").is_ok() {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/indexer/tests/fixtures/simple_amm_setup.log"
+        );
+        std::fs::write(path, logs.join("\n") + "\n").unwrap();
+    }
+
+    let mut replay = intear_dex_indexer::Replay::new();
+    replay.apply_logs(logs.iter().map(String::as_str)).unwrap();
+    assert_eq!(replay.violations(), []);
+    let state = replay.state();
+    for owner in [
+        AccountOrDexId::Account(user1.id().clone()),
+        AccountOrDexId::Dex(dex_id),
+    ] {
+        for asset_id in [
+            AssetId::Near,
+            AssetId::Nep141(ft1.id().clone()),
+            AssetId::Nep141(ft2.id().clone()),
+        ] {
+            assert_inner_asset_balance(
+                dex_engine_contract,
+                owner.clone(),
+                asset_id.clone(),
+                state.balance_of(&owner, &asset_id),
+            )
+            .await
+            .unwrap();
+        }
+    }
+    for asset_id in [
+        AssetId::Nep141(ft1.id().clone()),
+        AssetId::Nep141(ft2.id().clone()),
+    ] {
+        assert_total_in_custody(
+            dex_engine_contract,
+            asset_id.clone(),
+            state.total_in_custody.get(&asset_id).copied(),
+        )
+        .await
+        .unwrap();
+    }
+}
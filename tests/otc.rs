@@ -465,6 +465,7 @@ async fn test_otc_regular_flow() {
             AssetId::Near,
             U128(trade_amount_near.as_yoctonear()),
         )]),
+        max_storage_payment: NearToken::from_yoctonear(0),
     }];
 
     let result = user2
@@ -743,6 +744,7 @@ async fn test_otc_relayed_by_third_party() {
                 method: "storage_deposit".to_string(),
                 args: Base64VecU8(near_sdk::borsh::to_vec(&OtcStorageDepositArgs).unwrap()),
                 attached_assets: HashMap::from_iter([(AssetId::Near, U128(storage_deposit_for_otc.as_yoctonear()))]),
+                max_storage_payment: NearToken::from_yoctonear(0),
             }],
         }))
         .transact()
@@ -840,6 +842,7 @@ async fn test_otc_relayed_by_third_party() {
             .unwrap(),
         ),
         attached_assets: HashMap::new(),
+        max_storage_payment: NearToken::from_yoctonear(0),
     }];
 
     let result = user3
@@ -1063,6 +1066,7 @@ async fn test_otc_single_intent_from_one_account_fails() {
             .unwrap(),
         ),
         attached_assets: HashMap::new(),
+        max_storage_payment: NearToken::from_yoctonear(0),
     }];
 
     let result = user1
@@ -1302,6 +1306,7 @@ async fn test_otc_mismatching_intents_fail() {
                 method: "storage_deposit".to_string(),
                 args: Base64VecU8(near_sdk::borsh::to_vec(&OtcStorageDepositArgs).unwrap()),
                 attached_assets: HashMap::from_iter([(AssetId::Near, U128(storage_deposit_for_otc.as_yoctonear()))]),
+                max_storage_payment: NearToken::from_yoctonear(0),
             }],
         }))
         .transact()
@@ -1399,6 +1404,7 @@ async fn test_otc_mismatching_intents_fail() {
             .unwrap(),
         ),
         attached_assets: HashMap::new(),
+        max_storage_payment: NearToken::from_yoctonear(0),
     }];
 
     let result = user1
@@ -1696,6 +1702,7 @@ async fn test_otc_three_intents_with_same_value() {
                 method: "storage_deposit".to_string(),
                 args: Base64VecU8(near_sdk::borsh::to_vec(&OtcStorageDepositArgs).unwrap()),
                 attached_assets: HashMap::from_iter([(AssetId::Near, U128(storage_deposit_for_otc.as_yoctonear()))]),
+                max_storage_payment: NearToken::from_yoctonear(0),
             }],
         }))
         .transact()
@@ -1747,6 +1754,7 @@ async fn test_otc_three_intents_with_same_value() {
                 method: "storage_deposit".to_string(),
                 args: Base64VecU8(near_sdk::borsh::to_vec(&OtcStorageDepositArgs).unwrap()),
                 attached_assets: HashMap::from_iter([(AssetId::Near, U128(storage_deposit_for_otc.as_yoctonear()))]),
+                max_storage_payment: NearToken::from_yoctonear(0),
             }],
         }))
         .transact()
@@ -1872,6 +1880,7 @@ async fn test_otc_three_intents_with_same_value() {
             .unwrap(),
         ),
         attached_assets: HashMap::new(),
+        max_storage_payment: NearToken::from_yoctonear(0),
     }];
 
     let result = user1
@@ -2175,6 +2184,7 @@ async fn test_otc_nonces() {
                 method: "storage_deposit".to_string(),
                 args: Base64VecU8(near_sdk::borsh::to_vec(&OtcStorageDepositArgs).unwrap()),
                 attached_assets: HashMap::from_iter([(AssetId::Near, U128(storage_deposit_for_otc.as_yoctonear()))]),
+                max_storage_payment: NearToken::from_yoctonear(0),
             }],
         }))
         .transact()
@@ -2277,6 +2287,7 @@ async fn test_otc_nonces() {
             .unwrap(),
         ),
         attached_assets: HashMap::new(),
+        max_storage_payment: NearToken::from_yoctonear(0),
     }];
 
     // First use should succeed
@@ -2355,6 +2366,7 @@ async fn test_otc_nonces() {
             .unwrap(),
         ),
         attached_assets: HashMap::new(),
+        max_storage_payment: NearToken::from_yoctonear(0),
     }];
 
     let result = user1
@@ -2437,6 +2449,7 @@ async fn test_otc_nonces() {
             .unwrap(),
         ),
         attached_assets: HashMap::new(),
+        max_storage_payment: NearToken::from_yoctonear(0),
     }];
 
     let result = user1
@@ -2519,6 +2532,7 @@ async fn test_otc_nonces() {
             .unwrap(),
         ),
         attached_assets: HashMap::new(),
+        max_storage_payment: NearToken::from_yoctonear(0),
     }];
 
     let result = user1
@@ -2598,6 +2612,7 @@ async fn test_otc_nonces() {
             .unwrap(),
         ),
         attached_assets: HashMap::new(),
+        max_storage_payment: NearToken::from_yoctonear(0),
     }];
 
     let result = user1